@@ -0,0 +1,906 @@
+use avian3d::prelude::*;
+use bevy::asset::io::file::FileAssetReader;
+use bevy::ecs::system::SystemParam;
+use bevy::input::mouse::MouseMotion;
+use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+use bevy_inspector_egui::bevy_egui::{EguiContexts, egui};
+use shared::level::{Level, LevelEntity, LevelObject, LevelSpawner, PrefabRegistry, Shape};
+use shared::state::{GameState, InGameEntity, PlayState, cleanup_game};
+use std::f32::consts::FRAC_PI_2;
+use std::path::PathBuf;
+
+use crate::game_states::loading::GameAssets;
+use crate::systems::free_camera::{FreeFlySettings, FreeFlyState, fly, fly_direction};
+use crate::systems::local_players::{InputDevices, PlayerCamera, uses_keyboard_mouse};
+
+pub struct EditorPlugin;
+
+impl Plugin for EditorPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .init_resource::<EditorSettings>()
+      .init_resource::<LevelEditor>()
+      .init_resource::<UndoHistory>()
+      .init_resource::<EditorCameraStart>()
+      .add_systems(
+        Update,
+        toggle_editor.run_if(in_state(PlayState::Playing).or(in_state(GameState::Editor))),
+      )
+      .add_systems(
+        OnEnter(GameState::Editor),
+        (spawn_editor_camera, spawn_editor_level),
+      )
+      .add_systems(
+        Update,
+        (
+          editor_panel,
+          editor_shortcuts,
+          editor_camera,
+          editor_pointer,
+          respawn_editor_level,
+          draw_editor_gizmos,
+        )
+          .chain()
+          .run_if(in_state(GameState::Editor)),
+      )
+      .add_systems(OnExit(GameState::Editor), (cleanup_game, end_editing));
+  }
+
+  fn finish(&self, app: &mut App) {
+    let file_path = app
+      .get_added_plugins::<AssetPlugin>()
+      .first()
+      .map(|plugin| plugin.file_path.clone())
+      .unwrap_or_else(|| AssetPlugin::default().file_path);
+    app.insert_resource(AssetFolder(
+      FileAssetReader::get_base_path().join(file_path),
+    ));
+  }
+}
+
+/// The folder assets are read from, levels are saved back into it
+#[derive(Resource, Debug, Clone)]
+pub struct AssetFolder(pub PathBuf);
+
+/// The configuration of the level editor
+#[derive(Resource)]
+pub struct EditorSettings {
+  /// Key to switch between editing and playtesting
+  pub toggle_key: KeyCode,
+  /// Snap positions, rotations and scales while moving objects
+  pub snap: bool,
+  /// Spacing of the snapping grid, in world units
+  pub grid: f32,
+  /// Rotation snapping step, in degrees
+  pub rotation_step: f32,
+  /// Scale snapping step
+  pub scale_step: f32,
+}
+
+impl Default for EditorSettings {
+  fn default() -> Self {
+    EditorSettings {
+      toggle_key: KeyCode::F4,
+      snap: true,
+      grid: 0.5,
+      rotation_step: 15.0,
+      scale_step: 0.25,
+    }
+  }
+}
+
+/// What the left mouse button does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditorTool {
+  /// Pick objects and drag them with the gizmo
+  Select,
+  /// Place a new object where the cursor points
+  Place,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GizmoMode {
+  Translate,
+  Rotate,
+  Scale,
+}
+
+/// The editing session: tools, what gets placed next and what is selected
+#[derive(Resource)]
+pub struct LevelEditor {
+  pub tool: EditorTool,
+  pub gizmo: GizmoMode,
+  /// Prefab, shape and material of placed objects
+  pub prefab: String,
+  pub shape: Shape,
+  pub material: Option<String>,
+  /// Index of the selected object in the level
+  pub selected: Option<usize>,
+  /// Outcome of the last save, shown in the panel
+  pub status: String,
+  drag: Option<Drag>,
+}
+
+impl Default for LevelEditor {
+  fn default() -> Self {
+    LevelEditor {
+      tool: EditorTool::Select,
+      gizmo: GizmoMode::Translate,
+      prefab: "static".to_string(),
+      shape: Shape::Cuboid(Vec3::ONE),
+      material: None,
+      selected: None,
+      status: String::new(),
+      drag: None,
+    }
+  }
+}
+
+/// An object being moved with the mouse, committed to the level when released
+#[derive(Clone, Copy, Debug)]
+struct Drag {
+  index: usize,
+  start: Transform,
+  /// Where the cursor hit the drag plane when the drag started
+  start_point: Vec3,
+  start_cursor: Vec2,
+}
+
+/// Snapshots of the level before each edit
+#[derive(Resource)]
+pub struct UndoHistory {
+  /// Most edits kept for undoing
+  pub max: usize,
+  undo: Vec<Level>,
+  redo: Vec<Level>,
+}
+
+impl Default for UndoHistory {
+  fn default() -> Self {
+    UndoHistory {
+      max: 100,
+      undo: Vec::new(),
+      redo: Vec::new(),
+    }
+  }
+}
+
+impl UndoHistory {
+  /// Remember `level` before editing it, dropping the oldest edits past `max`
+  pub fn record(&mut self, level: &Level) {
+    self.undo.push(level.clone());
+    if self.undo.len() > self.max {
+      self.undo.remove(0);
+    }
+    self.redo.clear();
+  }
+
+  /// Swap `level` with the state before the last edit, `false` if there is none
+  pub fn undo(&mut self, level: &mut Level) -> bool {
+    let Some(previous) = self.undo.pop() else {
+      return false;
+    };
+    self.redo.push(std::mem::replace(level, previous));
+    true
+  }
+
+  /// Swap `level` with the state before the last undo, `false` if there is none
+  pub fn redo(&mut self, level: &mut Level) -> bool {
+    let Some(next) = self.redo.pop() else {
+      return false;
+    };
+    self.undo.push(std::mem::replace(level, next));
+    true
+  }
+
+  pub fn can_undo(&self) -> bool {
+    !self.undo.is_empty()
+  }
+
+  pub fn can_redo(&self) -> bool {
+    !self.redo.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.undo.clear();
+    self.redo.clear();
+  }
+}
+
+/// Where the editor camera starts, the view of the player when switching from the game
+#[derive(Resource)]
+struct EditorCameraStart(Transform);
+
+impl Default for EditorCameraStart {
+  fn default() -> Self {
+    EditorCameraStart(Transform::from_xyz(0.0, 12.0, 18.0).looking_at(Vec3::ZERO, Vec3::Y))
+  }
+}
+
+#[derive(Component)]
+pub struct EditorCamera;
+
+/// Stand-in for the object at this index of the level
+#[derive(Component, Clone, Copy, Debug)]
+pub struct EditorObject(pub usize);
+
+/// The level being edited, edits go straight to its asset so playtesting picks them up
+#[derive(SystemParam)]
+pub struct LevelEdit<'w> {
+  levels: ResMut<'w, Assets<Level>>,
+  game_assets: Res<'w, GameAssets>,
+  history: ResMut<'w, UndoHistory>,
+  asset_server: Res<'w, AssetServer>,
+  asset_folder: Res<'w, AssetFolder>,
+}
+
+impl LevelEdit<'_> {
+  pub fn level(&self) -> Option<&Level> {
+    self.levels.get(&self.game_assets.level)
+  }
+
+  /// Change the level, recording the change for undo
+  pub fn edit(&mut self, change: impl FnOnce(&mut Level)) {
+    if let Some(level) = self.levels.get_mut(&self.game_assets.level) {
+      self.history.record(level);
+      change(level);
+    }
+  }
+
+  pub fn undo(&mut self) -> bool {
+    match self.levels.get_mut(&self.game_assets.level) {
+      Some(level) => self.history.undo(level),
+      None => false,
+    }
+  }
+
+  pub fn redo(&mut self) -> bool {
+    match self.levels.get_mut(&self.game_assets.level) {
+      Some(level) => self.history.redo(level),
+      None => false,
+    }
+  }
+
+  /// Write the level back to the file it was loaded from
+  pub fn save(&self) -> Result<PathBuf, String> {
+    let level = self.level().ok_or("the level is not loaded")?;
+    let asset_path = self
+      .asset_server
+      .get_path(&self.game_assets.level)
+      .ok_or("the level was not loaded from a file")?;
+    let path = self.asset_folder.0.join(asset_path.path());
+    let contents = ron::ser::to_string_pretty(level, ron::ser::PrettyConfig::default())
+      .map_err(|err| err.to_string())?;
+    std::fs::write(&path, contents).map_err(|err| format!("{}: {err}", path.display()))?;
+    Ok(path)
+  }
+}
+
+/// The cursor ray of the editor camera, and whether the UI is in the way
+#[derive(SystemParam)]
+pub struct EditorPointer<'w, 's> {
+  windows: Query<'w, 's, &'static Window, With<PrimaryWindow>>,
+  camera_q: Query<'w, 's, (&'static Camera, &'static GlobalTransform), With<EditorCamera>>,
+  contexts: EguiContexts<'w, 's>,
+}
+
+impl EditorPointer<'_, '_> {
+  pub fn cursor(&self) -> Option<Vec2> {
+    self.windows.get_single().ok()?.cursor_position()
+  }
+
+  pub fn ray(&self) -> Option<Ray3d> {
+    let (camera, camera_tfm) = self.camera_q.get_single().ok()?;
+    camera.viewport_to_world(camera_tfm, self.cursor()?).ok()
+  }
+
+  pub fn over_ui(&mut self) -> bool {
+    let ctx = self.contexts.ctx_mut();
+    ctx.wants_pointer_input() || ctx.is_pointer_over_area()
+  }
+}
+
+/// Switch between editing and playtesting the level, both start where the other left off
+fn toggle_editor(
+  kbd: Res<ButtonInput<KeyCode>>,
+  settings: Res<EditorSettings>,
+  state: Res<State<GameState>>,
+  mut next_state: ResMut<NextState<GameState>>,
+  mut camera_start: ResMut<EditorCameraStart>,
+  camera_q: Query<(&Transform, Option<&PlayerCamera>), With<Camera3d>>,
+  devices_q: Query<&InputDevices>,
+) {
+  if !kbd.just_pressed(settings.toggle_key) {
+    return;
+  }
+  match state.get() {
+    GameState::Game => {
+      if let Some((transform, _)) = camera_q
+        .iter()
+        .find(|(_, player_camera)| uses_keyboard_mouse(*player_camera, &devices_q))
+      {
+        camera_start.0 = *transform;
+      }
+      next_state.set(GameState::Editor);
+    }
+    GameState::Editor => next_state.set(GameState::Game),
+    _ => {}
+  }
+}
+
+fn spawn_editor_camera(mut commands: Commands, camera_start: Res<EditorCameraStart>) {
+  let (yaw, pitch, _) = camera_start.0.rotation.to_euler(EulerRot::YXZ);
+  commands.spawn((
+    Camera3d::default(),
+    camera_start.0,
+    FreeFlyState { yaw, pitch },
+    EditorCamera,
+    InGameEntity,
+  ));
+}
+
+fn spawn_editor_level(mut spawner: LevelSpawner, edit: LevelEdit) {
+  if let Some(level) = edit.level() {
+    for (index, entity) in spawner
+      .spawn_level_for_editing(level)
+      .into_iter()
+      .enumerate()
+    {
+      spawner.commands.entity(entity).insert(EditorObject(index));
+    }
+  }
+}
+
+/// Respawn the stand-ins whenever the level changes, through an edit or its file
+fn respawn_editor_level(
+  mut spawner: LevelSpawner,
+  mut evr_level: EventReader<AssetEvent<Level>>,
+  edit: LevelEdit,
+  level_q: Query<Entity, With<LevelEntity>>,
+) {
+  if !evr_level
+    .read()
+    .any(|ev| ev.is_modified(&edit.game_assets.level))
+  {
+    return;
+  }
+  let Some(level) = edit.level() else {
+    return;
+  };
+  for entity in &level_q {
+    spawner.commands.entity(entity).despawn_recursive();
+  }
+  for (index, entity) in spawner
+    .spawn_level_for_editing(level)
+    .into_iter()
+    .enumerate()
+  {
+    spawner.commands.entity(entity).insert(EditorObject(index));
+  }
+}
+
+fn end_editing(mut editor: ResMut<LevelEditor>, mut history: ResMut<UndoHistory>) {
+  editor.selected = None;
+  editor.drag = None;
+  history.clear();
+}
+
+/// Fly around while holding the right mouse button, the left one is for editing
+fn editor_camera(
+  time: Res<Time<Real>>,
+  kbd: Res<ButtonInput<KeyCode>>,
+  mouse: Res<ButtonInput<MouseButton>>,
+  settings: Res<FreeFlySettings>,
+  mut evr_motion: EventReader<MouseMotion>,
+  mut camera_q: Query<(&mut FreeFlyState, &mut Transform), With<EditorCamera>>,
+) {
+  let motion: Vec2 = evr_motion.read().map(|ev| ev.delta).sum();
+  if !mouse.pressed(MouseButton::Right) {
+    return;
+  }
+  let mut speed = settings.speed;
+  if kbd.pressed(settings.boost_key) {
+    speed *= settings.boost;
+  }
+  for (mut state, mut transform) in &mut camera_q {
+    fly(
+      &mut state,
+      &mut transform,
+      motion * settings.look_sensitivity,
+      fly_direction(&kbd),
+      speed * time.delta_secs(),
+    );
+  }
+}
+
+/// Round to the nearest multiple of `step`
+pub fn snap(value: f32, step: f32) -> f32 {
+  if step > 0.0 {
+    (value / step).round() * step
+  } else {
+    value
+  }
+}
+
+/// Half the height of a shape, to place it on top of what the cursor points at
+fn half_height(shape: &Shape) -> f32 {
+  match *shape {
+    Shape::Cuboid(size) => size.y / 2.0,
+    Shape::Sphere(radius) => radius,
+    Shape::Cylinder { height, .. } => height / 2.0,
+    Shape::Capsule { radius, length } => radius + length / 2.0,
+  }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn editor_pointer(
+  mouse: Res<ButtonInput<MouseButton>>,
+  kbd: Res<ButtonInput<KeyCode>>,
+  spatial_query: SpatialQuery,
+  mut pointer: EditorPointer,
+  settings: Res<EditorSettings>,
+  mut editor: ResMut<LevelEditor>,
+  mut edit: LevelEdit,
+  mut objects_q: Query<(&EditorObject, &mut Transform)>,
+  editor_objects: Query<&EditorObject>,
+) {
+  let Some(ray) = pointer.ray() else {
+    return;
+  };
+  let cursor = pointer.cursor().unwrap_or_default();
+  let grid = if settings.snap { settings.grid } else { 0.0 };
+
+  if mouse.just_pressed(MouseButton::Left) && !pointer.over_ui() {
+    let hit = spatial_query.cast_ray(
+      ray.origin,
+      ray.direction,
+      1000.0,
+      true,
+      &SpatialQueryFilter::default(),
+    );
+    match editor.tool {
+      EditorTool::Place => {
+        // on top of whatever the cursor points at, or on the ground
+        let point = match hit {
+          Some(hit) => Some(ray.get_point(hit.distance)),
+          None => ray
+            .intersect_plane(Vec3::ZERO, InfinitePlane3d::new(Vec3::Y))
+            .map(|distance| ray.get_point(distance)),
+        };
+        if let Some(point) = point {
+          let object = LevelObject {
+            prefab: editor.prefab.clone(),
+            shape: editor.shape,
+            material: editor.material.clone(),
+            translation: Vec3::new(snap(point.x, grid), point.y, snap(point.z, grid))
+              + Vec3::Y * half_height(&editor.shape),
+            rotation: Vec3::ZERO,
+            scale: Vec3::ONE,
+          };
+          let mut index = None;
+          edit.edit(|level| {
+            level.objects.push(object);
+            index = Some(level.objects.len() - 1);
+          });
+          editor.selected = index;
+        }
+      }
+      EditorTool::Select => {
+        let picked = hit.and_then(|hit| Some((hit, editor_objects.get(hit.entity).ok()?.0)));
+        editor.selected = picked.map(|(_, index)| index);
+        editor.drag = picked.and_then(|(hit, index)| {
+          let (_, transform) = objects_q.get(hit.entity).ok()?;
+          Some(Drag {
+            index,
+            start: *transform,
+            start_point: ray.get_point(hit.distance),
+            start_cursor: cursor,
+          })
+        });
+      }
+    }
+  }
+
+  let Some(drag) = editor.drag else {
+    return;
+  };
+  let Some((_, mut transform)) = objects_q
+    .iter_mut()
+    .find(|(object, _)| object.0 == drag.index)
+  else {
+    editor.drag = None;
+    return;
+  };
+
+  if mouse.pressed(MouseButton::Left) {
+    match editor.gizmo {
+      GizmoMode::Translate => {
+        // along the ground, or up and down while holding Y
+        let vertical = kbd.pressed(KeyCode::KeyY);
+        let normal = if vertical {
+          let towards_camera = (ray.origin - drag.start_point).with_y(0.0);
+          Dir3::new(towards_camera).unwrap_or(Dir3::Z)
+        } else {
+          Dir3::Y
+        };
+        if let Some(distance) = ray.intersect_plane(drag.start_point, InfinitePlane3d { normal }) {
+          let delta = ray.get_point(distance) - drag.start_point;
+          let mut translation = drag.start.translation;
+          if vertical {
+            translation.y = snap(translation.y + delta.y, grid);
+          } else {
+            translation.x = snap(translation.x + delta.x, grid);
+            translation.z = snap(translation.z + delta.z, grid);
+          }
+          transform.translation = translation;
+        }
+      }
+      GizmoMode::Rotate => {
+        let step = if settings.snap {
+          settings.rotation_step.to_radians()
+        } else {
+          0.0
+        };
+        let angle = snap((cursor.x - drag.start_cursor.x) * 0.01, step);
+        transform.rotation = Quat::from_rotation_y(angle) * drag.start.rotation;
+      }
+      GizmoMode::Scale => {
+        let step = if settings.snap {
+          settings.scale_step
+        } else {
+          0.0
+        };
+        let factor = ((drag.start_cursor.y - cursor.y) * 0.01).exp();
+        transform.scale =
+          (drag.start.scale * factor).map(|scale| snap(scale, step).max(step.max(0.01)));
+      }
+    }
+  } else {
+    editor.drag = None;
+    if *transform != drag.start {
+      let transform = *transform;
+      edit.edit(|level| {
+        if let Some(object) = level.objects.get_mut(drag.index) {
+          object.set_transform(&transform);
+        }
+      });
+    }
+  }
+}
+
+/// Ctrl+Z / Ctrl+Y to undo and redo, Ctrl+S to save, 1-3 for the gizmo, Tab for the tool
+fn editor_shortcuts(
+  kbd: Res<ButtonInput<KeyCode>>,
+  mut contexts: EguiContexts,
+  mut editor: ResMut<LevelEditor>,
+  mut edit: LevelEdit,
+) {
+  if contexts.ctx_mut().wants_keyboard_input() {
+    return;
+  }
+  let ctrl = kbd.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+  let shift = kbd.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+  if ctrl && kbd.just_pressed(KeyCode::KeyZ) {
+    if shift {
+      edit.redo()
+    } else {
+      edit.undo()
+    };
+  }
+  if ctrl && kbd.just_pressed(KeyCode::KeyY) {
+    edit.redo();
+  }
+  if ctrl && kbd.just_pressed(KeyCode::KeyS) {
+    editor.status = save_status(edit.save());
+  }
+  if kbd.just_pressed(KeyCode::Delete) {
+    delete_selected(&mut editor, &mut edit);
+  }
+  if kbd.just_pressed(KeyCode::Tab) {
+    editor.tool = match editor.tool {
+      EditorTool::Select => EditorTool::Place,
+      EditorTool::Place => EditorTool::Select,
+    };
+  }
+  for (key, gizmo) in [
+    (KeyCode::Digit1, GizmoMode::Translate),
+    (KeyCode::Digit2, GizmoMode::Rotate),
+    (KeyCode::Digit3, GizmoMode::Scale),
+  ] {
+    if kbd.just_pressed(key) {
+      editor.gizmo = gizmo;
+    }
+  }
+}
+
+fn save_status(result: Result<PathBuf, String>) -> String {
+  match result {
+    Ok(path) => format!("Saved to {}", path.display()),
+    Err(err) => {
+      error!("Cannot save the level: {err}");
+      format!("Cannot save: {err}")
+    }
+  }
+}
+
+fn delete_selected(editor: &mut LevelEditor, edit: &mut LevelEdit) {
+  if let Some(index) = editor.selected.take() {
+    edit.edit(|level| {
+      if index < level.objects.len() {
+        level.objects.remove(index);
+      }
+    });
+  }
+}
+
+fn editor_panel(
+  mut contexts: EguiContexts,
+  registry: Res<PrefabRegistry>,
+  mut settings: ResMut<EditorSettings>,
+  mut editor: ResMut<LevelEditor>,
+  mut edit: LevelEdit,
+) {
+  let materials: Vec<String> = edit
+    .level()
+    .map(|level| level.materials.keys().cloned().collect())
+    .unwrap_or_default();
+  let (can_undo, can_redo) = (edit.history.can_undo(), edit.history.can_redo());
+  let mut action = None;
+
+  egui::Window::new("Level editor")
+    .resizable(false)
+    .show(contexts.ctx_mut(), |ui| {
+      ui.horizontal(|ui| {
+        ui.selectable_value(&mut editor.tool, EditorTool::Select, "Select");
+        ui.selectable_value(&mut editor.tool, EditorTool::Place, "Place");
+      });
+      ui.horizontal(|ui| {
+        ui.selectable_value(&mut editor.gizmo, GizmoMode::Translate, "Move");
+        ui.selectable_value(&mut editor.gizmo, GizmoMode::Rotate, "Rotate");
+        ui.selectable_value(&mut editor.gizmo, GizmoMode::Scale, "Scale");
+      });
+      ui.separator();
+
+      egui::ComboBox::from_label("Prefab")
+        .selected_text(editor.prefab.clone())
+        .show_ui(ui, |ui| {
+          for name in registry.names() {
+            ui.selectable_value(&mut editor.prefab, name.to_string(), name);
+          }
+        });
+      shape_editor(ui, &mut editor.shape);
+      egui::ComboBox::from_label("Material")
+        .selected_text(editor.material.clone().unwrap_or("none".to_string()))
+        .show_ui(ui, |ui| {
+          ui.selectable_value(&mut editor.material, None, "none");
+          for name in &materials {
+            ui.selectable_value(&mut editor.material, Some(name.clone()), name);
+          }
+        });
+      ui.separator();
+
+      ui.horizontal(|ui| {
+        ui.checkbox(&mut settings.snap, "Snap to grid");
+        ui.add(
+          egui::DragValue::new(&mut settings.grid)
+            .speed(0.05)
+            .range(0.05..=10.0),
+        );
+      });
+      ui.horizontal(|ui| {
+        if ui
+          .add_enabled(can_undo, egui::Button::new("Undo"))
+          .clicked()
+        {
+          action = Some(PanelAction::Undo);
+        }
+        if ui
+          .add_enabled(can_redo, egui::Button::new("Redo"))
+          .clicked()
+        {
+          action = Some(PanelAction::Redo);
+        }
+        if ui
+          .add_enabled(editor.selected.is_some(), egui::Button::new("Delete"))
+          .clicked()
+        {
+          action = Some(PanelAction::Delete);
+        }
+        if ui.button("Save").clicked() {
+          action = Some(PanelAction::Save);
+        }
+      });
+      if !editor.status.is_empty() {
+        ui.label(&editor.status);
+      }
+      ui.small(
+        "LMB select/place, RMB + WASD fly, 1-3 gizmo, Tab tool, hold Y to move up,\n\
+         Ctrl+Z/Ctrl+Y undo/redo, Ctrl+S save, F4 playtest",
+      );
+    });
+
+  match action {
+    Some(PanelAction::Undo) => {
+      edit.undo();
+    }
+    Some(PanelAction::Redo) => {
+      edit.redo();
+    }
+    Some(PanelAction::Delete) => delete_selected(&mut editor, &mut edit),
+    Some(PanelAction::Save) => editor.status = save_status(edit.save()),
+    None => {}
+  }
+}
+
+enum PanelAction {
+  Undo,
+  Redo,
+  Delete,
+  Save,
+}
+
+fn shape_editor(ui: &mut egui::Ui, shape: &mut Shape) {
+  let kind = match shape {
+    Shape::Cuboid(_) => "Cuboid",
+    Shape::Sphere(_) => "Sphere",
+    Shape::Cylinder { .. } => "Cylinder",
+    Shape::Capsule { .. } => "Capsule",
+  };
+  egui::ComboBox::from_label("Shape")
+    .selected_text(kind)
+    .show_ui(ui, |ui| {
+      for (name, default) in [
+        ("Cuboid", Shape::Cuboid(Vec3::ONE)),
+        ("Sphere", Shape::Sphere(0.5)),
+        (
+          "Cylinder",
+          Shape::Cylinder {
+            radius: 0.5,
+            height: 1.0,
+          },
+        ),
+        (
+          "Capsule",
+          Shape::Capsule {
+            radius: 0.5,
+            length: 1.0,
+          },
+        ),
+      ] {
+        if ui.selectable_label(kind == name, name).clicked() && kind != name {
+          *shape = default;
+        }
+      }
+    });
+  let size = |ui: &mut egui::Ui, value: &mut f32, label: &str| {
+    ui.label(label);
+    ui.add(egui::DragValue::new(value).speed(0.05).range(0.05..=100.0));
+  };
+  ui.horizontal(|ui| match shape {
+    Shape::Cuboid(extents) => {
+      size(ui, &mut extents.x, "x");
+      size(ui, &mut extents.y, "y");
+      size(ui, &mut extents.z, "z");
+    }
+    Shape::Sphere(radius) => size(ui, radius, "radius"),
+    Shape::Cylinder { radius, height } => {
+      size(ui, radius, "radius");
+      size(ui, height, "height");
+    }
+    Shape::Capsule { radius, length } => {
+      size(ui, radius, "radius");
+      size(ui, length, "length");
+    }
+  });
+}
+
+fn draw_editor_gizmos(
+  mut gizmos: Gizmos,
+  editor: Res<LevelEditor>,
+  settings: Res<EditorSettings>,
+  objects_q: Query<(&EditorObject, &Transform)>,
+) {
+  // the snapping grid, on the ground
+  if settings.snap {
+    gizmos.grid(
+      Isometry3d::from_rotation(Quat::from_rotation_x(FRAC_PI_2)),
+      UVec2::splat((40.0 / settings.grid).round().clamp(1.0, 400.0) as u32),
+      Vec2::splat(settings.grid),
+      Color::srgba(1.0, 1.0, 1.0, 0.05),
+    );
+  }
+
+  let Some((_, transform)) = objects_q
+    .iter()
+    .find(|(object, _)| Some(object.0) == editor.selected)
+  else {
+    return;
+  };
+  let origin = transform.translation;
+  match editor.gizmo {
+    GizmoMode::Translate => {
+      gizmos.arrow(origin, origin + Vec3::X * 1.5, Color::srgb(1.0, 0.2, 0.2));
+      gizmos.arrow(origin, origin + Vec3::Y * 1.5, Color::srgb(0.2, 1.0, 0.2));
+      gizmos.arrow(origin, origin + Vec3::Z * 1.5, Color::srgb(0.2, 0.4, 1.0));
+    }
+    GizmoMode::Rotate => {
+      gizmos.circle(
+        Isometry3d::new(origin, Quat::from_rotation_x(FRAC_PI_2)),
+        1.5,
+        Color::srgb(0.2, 1.0, 0.2),
+      );
+    }
+    GizmoMode::Scale => {
+      gizmos.cuboid(*transform, Color::srgb(1.0, 0.8, 0.0));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn level(objects: usize) -> Level {
+    Level {
+      name: format!("{objects} objects"),
+      objects: (0..objects)
+        .map(|_| LevelObject {
+          prefab: "static".into(),
+          shape: Shape::Sphere(1.0),
+          material: None,
+          translation: Vec3::ZERO,
+          rotation: Vec3::ZERO,
+          scale: Vec3::ONE,
+        })
+        .collect(),
+      ..default()
+    }
+  }
+
+  #[test]
+  fn undo_and_redo_restore_edits() {
+    let mut history = UndoHistory::default();
+    let mut current = level(0);
+    for _ in 0..3 {
+      history.record(&current);
+      current = level(current.objects.len() + 1);
+    }
+    assert!(history.undo(&mut current));
+    assert!(history.undo(&mut current));
+    assert_eq!(current.objects.len(), 1);
+    assert!(history.redo(&mut current));
+    assert_eq!(current.objects.len(), 2);
+
+    // a new edit drops what could be redone
+    history.record(&current);
+    current = level(10);
+    assert!(!history.can_redo());
+    assert!(history.undo(&mut current));
+    assert_eq!(current.objects.len(), 2);
+  }
+
+  #[test]
+  fn undo_history_is_bounded() {
+    let mut history = UndoHistory {
+      max: 2,
+      ..default()
+    };
+    let mut current = level(0);
+    for _ in 0..5 {
+      history.record(&current);
+      current = level(current.objects.len() + 1);
+    }
+    assert!(history.undo(&mut current));
+    assert!(history.undo(&mut current));
+    assert!(!history.undo(&mut current));
+    assert_eq!(current.objects.len(), 3);
+  }
+
+  #[test]
+  fn snapping_rounds_to_the_grid() {
+    assert_eq!(snap(1.26, 0.5), 1.5);
+    assert_eq!(snap(-0.74, 0.5), -0.5);
+    assert_eq!(snap(0.3, 0.0), 0.3);
+  }
+}
@@ -1,86 +1,127 @@
-use avian3d::{math::*, prelude::*};
 use bevy::prelude::*;
+use shared::level::{Level, LevelLoader, LevelSpawner, player_object};
+use shared::state::{GameState, PlayState, cleanup_game};
 
-use crate::GameState;
+use crate::game_states::loading::GameAssets;
 use crate::systems::camera::{camera_follow, pan_orbit_camera, spawn_camera};
-use crate::systems::controller::{CharacterControllerBundle, PlayerMovementPlugin};
+use crate::systems::camera_shake::{
+  CameraShake, apply_camera_shake, receive_camera_shake, remove_camera_shake, shake_on_impact,
+  shake_on_landing,
+};
+use crate::systems::checkpoint::CheckpointEffectsPlugin;
+use crate::systems::cinematic::{
+  CameraSequence, CameraSequenceLoader, PlayCameraSequence, play_camera_sequence,
+  start_camera_sequence,
+};
+use crate::systems::controller::PlayerMovementPlugin;
+use crate::systems::free_camera::{
+  CameraMode, FreeFlySettings, enter_free_fly, exit_free_fly, free_fly_camera, toggle_free_fly,
+};
+use crate::systems::level::{
+  SelectedLevel, load_level_list, prefab_registry, reload_level, spawn_saved_level,
+};
+use crate::systems::local_players::{
+  InputDevices, LocalPlayer, LocalPlayers, assign_gamepads, set_camera_viewports,
+};
+use crate::systems::lock_on::{draw_lock_on_marker, frame_lock_on_target, lock_on_input};
+use crate::systems::save_game::SaveId;
 
 pub struct GamePlugin;
 
 impl Plugin for GamePlugin {
   fn build(&self, app: &mut App) {
     app
-      .add_plugins(PlayerMovementPlugin)
-      .add_systems(OnEnter(GameState::Game), (setup, spawn_camera))
+      .add_plugins((PlayerMovementPlugin, CheckpointEffectsPlugin))
+      .add_event::<CameraShake>()
+      .add_event::<PlayCameraSequence>()
+      .init_asset::<CameraSequence>()
+      .init_asset_loader::<CameraSequenceLoader>()
+      .init_asset::<Level>()
+      .init_asset_loader::<LevelLoader>()
+      .insert_resource(prefab_registry())
+      .init_resource::<SelectedLevel>()
+      .add_systems(Startup, load_level_list)
+      .add_sub_state::<CameraMode>()
+      .init_resource::<FreeFlySettings>()
+      .add_systems(OnEnter(GameState::Game), (setup, spawn_camera).chain())
+      // not on restarts, loads of the same level or coming back from the editor
+      .add_systems(
+        OnTransition {
+          exited: GameState::Loading,
+          entered: GameState::Game,
+        },
+        play_intro,
+      )
+      .add_systems(
+        Update,
+        (
+          remove_camera_shake,
+          start_camera_sequence,
+          lock_on_input.run_if(in_state(CameraMode::Follow)),
+          camera_follow.run_if(in_state(CameraMode::Follow).or(in_state(CameraMode::Cinematic))),
+          (frame_lock_on_target, pan_orbit_camera)
+            .chain()
+            .run_if(in_state(CameraMode::Follow)),
+          play_camera_sequence.run_if(in_state(CameraMode::Cinematic)),
+          free_fly_camera.run_if(in_state(CameraMode::FreeFly)),
+          (shake_on_landing, shake_on_impact),
+          receive_camera_shake,
+          apply_camera_shake,
+          draw_lock_on_marker,
+        )
+          .chain()
+          .run_if(in_state(PlayState::Playing)),
+      )
+      .add_systems(Update, toggle_free_fly.run_if(in_state(PlayState::Playing)))
       .add_systems(
         Update,
-        (camera_follow.before(pan_orbit_camera), pan_orbit_camera),
+        (assign_gamepads, set_camera_viewports, reload_level).run_if(in_state(GameState::Game)),
       )
+      .add_systems(OnEnter(CameraMode::FreeFly), enter_free_fly)
+      .add_systems(OnExit(CameraMode::FreeFly), exit_free_fly)
       .add_systems(OnExit(GameState::Game), cleanup_game);
   }
 }
 
-#[derive(Component)]
-pub struct InGameEntity;
-
-#[derive(Component, Reflect)]
-pub struct Player;
+const PLAYER_COLORS: [Color; 4] = [
+  Color::srgb(124.0 / 255.0, 144.0 / 255.0, 1.0),
+  Color::srgb(1.0, 200.0 / 255.0, 80.0 / 255.0),
+  Color::srgb(120.0 / 255.0, 220.0 / 255.0, 120.0 / 255.0),
+  Color::srgb(220.0 / 255.0, 120.0 / 255.0, 220.0 / 255.0),
+];
 
 fn setup(
-  mut commands: Commands,
-  mut meshes: ResMut<Assets<Mesh>>,
-  mut materials: ResMut<Assets<StandardMaterial>>,
-  asset_server: Res<AssetServer>,
+  mut spawner: LevelSpawner,
+  game_assets: Res<GameAssets>,
+  levels: Res<Assets<Level>>,
+  local_players: Res<LocalPlayers>,
 ) {
-  let grass_material = materials.add(StandardMaterial {
-    base_color_texture: Some(asset_server.load("textures/grass.png")),
-    ..default()
-  });
-  // circular base
-  commands.spawn((
-    RigidBody::Static,
-    Collider::cylinder(20.0, 0.1),
-    Mesh3d(meshes.add(Cylinder::new(20.0, 0.1))),
-    MeshMaterial3d(grass_material),
-    Transform::from_rotation(Quat::from_rotation_y(-std::f32::consts::FRAC_PI_2)),
-    InGameEntity,
-  ));
-  // player
-  commands.spawn((
-    Mesh3d(meshes.add(Cuboid::new(1.0, 1.0, 1.0))),
-    MeshMaterial3d(materials.add(Color::srgb_u8(124, 144, 255))),
-    Transform::from_xyz(0.0, 0.55, 0.0),
-    Player,
-    InGameEntity,
-    CharacterControllerBundle::new(Collider::cuboid(1.0, 1.0, 1.0), Vector::NEG_Y * 5.81 * 2.0)
-      .with_movement(30.0, 0.92, 7.0, (30.0 as Scalar).to_radians()),
-  ));
-
-  //balls for fun
-  commands.spawn((
-    RigidBody::Dynamic,
-    Collider::sphere(0.2),
-    ColliderDensity(3.0),
-    SpeculativeMargin(5.0),
-    Mesh3d(meshes.add(Sphere::new(0.2))),
-    MeshMaterial3d(materials.add(Color::srgb_u8(255, 0, 0))),
-    Transform::from_xyz(0.0, 1.0, 0.0),
-    InGameEntity,
-  ));
-  // light
-  commands.spawn((
-    PointLight {
-      shadows_enabled: true,
-      ..default()
-    },
-    Transform::from_xyz(4.0, 8.0, 4.0),
-    InGameEntity,
-  ));
-  // and all entries provided by the crate:
+  let Some(level) = levels.get(&game_assets.level) else {
+    error!("The level is not loaded");
+    return;
+  };
+  spawn_saved_level(&mut spawner, level);
+  // players at the spawn points, the first one uses the keyboard and mouse
+  for index in 0..local_players.count {
+    let player = player_object(level.player_spawn_point(index));
+    if let Some(entity) = spawner.spawn_object(&player) {
+      let mut entity = spawner.commands.entity(entity);
+      let color = PLAYER_COLORS[index % PLAYER_COLORS.len()];
+      spawner.assets.draw_with(&mut entity, &player, color.into());
+      entity.insert((
+        LocalPlayer(index),
+        SaveId::Player(index),
+        InputDevices {
+          keyboard_mouse: index == 0,
+          gamepad: None,
+        },
+      ));
+    }
+  }
 }
 
-fn cleanup_game(mut commands: Commands, query: Query<Entity, With<InGameEntity>>) {
-  for entity in query.iter() {
-    commands.entity(entity).despawn_recursive();
+fn play_intro(game_assets: Res<GameAssets>, mut evw_play: EventWriter<PlayCameraSequence>) {
+  if let Some(intro) = game_assets.intro.clone() {
+    evw_play.send(PlayCameraSequence(intro));
   }
 }
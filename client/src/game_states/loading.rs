@@ -0,0 +1,299 @@
+use bevy::asset::{LoadState, UntypedAssetId};
+use bevy::prelude::*;
+use shared::level::Level;
+use shared::state::GameState;
+
+use crate::systems::cinematic::CameraSequence;
+use crate::systems::level::SelectedLevel;
+use crate::systems::menu::{
+  MenuItem, MenuSelection, activate_menu_item, highlight_menu_buttons, hover_menu_buttons,
+  menu_root, navigate_menu, spawn_menu_buttons, spawn_menu_title,
+};
+
+pub struct LoadingPlugin;
+
+impl Plugin for LoadingPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .add_systems(OnEnter(GameState::Loading), start_loading)
+      .add_systems(
+        Update,
+        update_loading
+          .run_if(in_state(GameState::Loading))
+          .run_if(not(resource_exists::<LoadingFailed>)),
+      )
+      .add_systems(
+        Update,
+        (
+          show_loading_error.run_if(resource_added::<LoadingFailed>),
+          hover_menu_buttons::<LoadingErrorButton>,
+          navigate_menu::<LoadingErrorButton>,
+          activate_menu_item::<LoadingErrorButton>.pipe(loading_error_action),
+          highlight_menu_buttons::<LoadingErrorButton>,
+        )
+          .chain()
+          .run_if(in_state(GameState::Loading))
+          .run_if(resource_exists::<LoadingFailed>),
+      )
+      .add_systems(OnExit(GameState::Loading), cleanup_loading);
+  }
+}
+
+/// Every asset the game needs, loaded before entering it.
+/// The level loads its textures along with it, its intro is loaded once the level is.
+#[derive(Resource)]
+pub struct GameAssets {
+  pub level: Handle<Level>,
+  pub intro: Option<Handle<CameraSequence>>,
+}
+
+impl GameAssets {
+  pub fn load(asset_server: &AssetServer, level: &str) -> Self {
+    GameAssets {
+      level: asset_server.load(level.to_string()),
+      intro: None,
+    }
+  }
+
+  /// Start loading what the level refers to, once it is loaded
+  pub fn load_dependencies(&mut self, asset_server: &AssetServer, levels: &Assets<Level>) {
+    if self.intro.is_none()
+      && let Some(intro) = levels
+        .get(&self.level)
+        .and_then(|level| level.intro.clone())
+    {
+      self.intro = Some(asset_server.load(intro));
+    }
+  }
+
+  /// The level, and once it is loaded, the assets it refers to.
+  /// Call `load_dependencies` first for its intro to be counted.
+  pub fn ids(&self, levels: &Assets<Level>) -> Vec<UntypedAssetId> {
+    let mut ids = vec![self.level.id().untyped()];
+    if let Some(level) = levels.get(&self.level) {
+      ids.extend(
+        level
+          .textures
+          .values()
+          .map(|texture| texture.id().untyped()),
+      );
+    }
+    ids.extend(self.intro.iter().map(|intro| intro.id().untyped()));
+    ids
+  }
+}
+
+/// How far along loading a set of assets is
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadProgress {
+  Loading {
+    loaded: usize,
+    total: usize,
+  },
+  Loaded,
+  /// Why the first failing asset could not be loaded
+  Failed(String),
+}
+
+impl LoadProgress {
+  /// Check the assets and their dependencies, any failure fails the whole set
+  pub fn of(asset_server: &AssetServer, ids: &[UntypedAssetId]) -> Self {
+    let mut loaded = 0;
+    for &id in ids {
+      if let Some(LoadState::Failed(err)) = asset_server.get_load_state(id) {
+        return LoadProgress::Failed(err.to_string());
+      }
+      if asset_server.is_loaded_with_dependencies(id) {
+        loaded += 1;
+      }
+    }
+    if loaded == ids.len() {
+      LoadProgress::Loaded
+    } else {
+      LoadProgress::Loading {
+        loaded,
+        total: ids.len(),
+      }
+    }
+  }
+}
+
+/// Everything spawned for the loading screen, despawned when leaving it
+#[derive(Component)]
+pub struct LoadingEntity;
+
+#[derive(Component)]
+struct LoadingBar;
+
+#[derive(Component)]
+struct LoadingText;
+
+/// Present once an asset failed to load, the error screen is shown instead of the progress
+#[derive(Resource)]
+pub struct LoadingFailed(pub String);
+
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadingErrorButton {
+  BackToMenu,
+}
+
+impl MenuItem for LoadingErrorButton {
+  const ALL: &'static [LoadingErrorButton] = &[LoadingErrorButton::BackToMenu];
+
+  fn label(self) -> &'static str {
+    match self {
+      LoadingErrorButton::BackToMenu => "Back to menu",
+    }
+  }
+}
+
+fn start_loading(
+  mut commands: Commands,
+  asset_server: Res<AssetServer>,
+  selected_level: Res<SelectedLevel>,
+) {
+  commands.insert_resource(GameAssets::load(&asset_server, &selected_level.0));
+  commands.spawn((Camera2d, LoadingEntity));
+  commands
+    .spawn((menu_root(), LoadingEntity))
+    .with_children(|parent| {
+      parent.spawn((
+        Text::new("Loading..."),
+        TextFont {
+          font_size: 32.0,
+          ..default()
+        },
+        LoadingText,
+      ));
+      parent
+        .spawn((
+          Node {
+            width: Val::Px(400.0),
+            height: Val::Px(24.0),
+            border: UiRect::all(Val::Px(2.0)),
+            ..default()
+          },
+          BorderColor(Color::WHITE),
+        ))
+        .with_child((
+          Node {
+            width: Val::Percent(0.0),
+            height: Val::Percent(100.0),
+            ..default()
+          },
+          BackgroundColor(Color::srgb(0.3, 0.3, 0.45)),
+          LoadingBar,
+        ));
+    });
+}
+
+fn update_loading(
+  mut commands: Commands,
+  asset_server: Res<AssetServer>,
+  mut game_assets: ResMut<GameAssets>,
+  levels: Res<Assets<Level>>,
+  mut next_state: ResMut<NextState<GameState>>,
+  mut bar_q: Query<&mut Node, With<LoadingBar>>,
+  mut text_q: Query<&mut Text, With<LoadingText>>,
+) {
+  game_assets.load_dependencies(&asset_server, &levels);
+  match LoadProgress::of(&asset_server, &game_assets.ids(&levels)) {
+    LoadProgress::Loading { loaded, total } => {
+      for mut bar in &mut bar_q {
+        bar.width = Val::Percent(100.0 * loaded as f32 / total as f32);
+      }
+      for mut text in &mut text_q {
+        text.0 = format!("Loading... {loaded}/{total}");
+      }
+    }
+    LoadProgress::Loaded => next_state.set(GameState::Game),
+    LoadProgress::Failed(err) => {
+      error!("Cannot load the game: {err}");
+      commands.insert_resource(LoadingFailed(err));
+    }
+  }
+}
+
+/// Replace the progress bar with the error and a way back
+fn show_loading_error(
+  mut commands: Commands,
+  failed: Res<LoadingFailed>,
+  mut selection: ResMut<MenuSelection>,
+  screen_q: Query<Entity, (With<LoadingEntity>, With<Node>)>,
+) {
+  for entity in &screen_q {
+    commands.entity(entity).despawn_recursive();
+  }
+  commands
+    .spawn((menu_root(), LoadingEntity))
+    .with_children(|parent| {
+      spawn_menu_title(parent, "Loading failed");
+      parent.spawn((
+        Text::new(failed.0.clone()),
+        TextFont {
+          font_size: 20.0,
+          ..default()
+        },
+        TextColor(Color::srgb(1.0, 0.4, 0.4)),
+      ));
+      spawn_menu_buttons::<LoadingErrorButton>(parent);
+    });
+  selection.0 = 0;
+}
+
+fn loading_error_action(
+  In(action): In<Option<LoadingErrorButton>>,
+  mut next_state: ResMut<NextState<GameState>>,
+) {
+  if let Some(LoadingErrorButton::BackToMenu) = action {
+    next_state.set(GameState::MainMenu);
+  }
+}
+
+fn cleanup_loading(mut commands: Commands, query: Query<Entity, With<LoadingEntity>>) {
+  for entity in &query {
+    commands.entity(entity).despawn_recursive();
+  }
+  commands.remove_resource::<LoadingFailed>();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::systems::cinematic::{CameraSequence, CameraSequenceLoader};
+
+  fn progress(path: &'static str) -> LoadProgress {
+    let mut app = App::new();
+    app
+      .add_plugins((MinimalPlugins, AssetPlugin::default()))
+      .init_asset::<CameraSequence>()
+      .init_asset_loader::<CameraSequenceLoader>();
+    let handle: Handle<CameraSequence> = app.world().resource::<AssetServer>().load(path);
+    let ids = [handle.id().untyped()];
+    for _ in 0..1000 {
+      app.update();
+      let progress = LoadProgress::of(app.world().resource::<AssetServer>(), &ids);
+      if !matches!(progress, LoadProgress::Loading { .. }) {
+        return progress;
+      }
+      std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    panic!("{path} never finished loading");
+  }
+
+  #[test]
+  fn existing_assets_finish_loading() {
+    assert_eq!(
+      progress("sequences/arena_intro.sequence.ron"),
+      LoadProgress::Loaded
+    );
+  }
+
+  #[test]
+  fn missing_assets_fail() {
+    assert!(matches!(
+      progress("sequences/missing.sequence.ron"),
+      LoadProgress::Failed(_)
+    ));
+  }
+}
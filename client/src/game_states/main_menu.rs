@@ -0,0 +1,134 @@
+use bevy::prelude::*;
+use shared::state::GameState;
+
+use crate::systems::level::LevelList;
+use crate::systems::lobby::{LobbyPanel, lobby_closed};
+use crate::systems::menu::{
+  MenuItem, MenuSelection, activate_menu_item, highlight_menu_buttons, hover_menu_buttons,
+  menu_root, navigate_menu, reset_menu_selection, spawn_menu_buttons, spawn_menu_title,
+};
+use crate::systems::user_settings::SettingsPanel;
+
+pub struct MainMenuPlugin;
+
+impl Plugin for MainMenuPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .init_resource::<MenuSelection>()
+      .add_systems(
+        OnEnter(GameState::MainMenu),
+        (reset_menu_selection, setup_main_menu),
+      )
+      .add_systems(
+        Update,
+        (
+          hover_menu_buttons::<MenuButton>,
+          (
+            navigate_menu::<MenuButton>,
+            activate_menu_item::<MenuButton>.pipe(main_menu_action),
+          )
+            .chain()
+            .run_if(lobby_closed),
+          highlight_menu_buttons::<MenuButton>,
+          show_selected_level,
+        )
+          .chain()
+          .run_if(in_state(GameState::MainMenu)),
+      )
+      .add_systems(OnExit(GameState::MainMenu), cleanup_main_menu);
+  }
+}
+
+/// Everything spawned for the main menu, despawned when leaving it
+#[derive(Component)]
+pub struct MainMenuEntity;
+
+/// What a main menu button does when activated
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuButton {
+  Play,
+  Online,
+  Level,
+  Settings,
+  Quit,
+}
+
+impl MenuItem for MenuButton {
+  const ALL: &'static [MenuButton] = &[
+    MenuButton::Play,
+    MenuButton::Online,
+    MenuButton::Level,
+    MenuButton::Settings,
+    MenuButton::Quit,
+  ];
+
+  fn label(self) -> &'static str {
+    match self {
+      MenuButton::Play => "Play",
+      MenuButton::Online => "Online",
+      MenuButton::Level => "Level",
+      MenuButton::Settings => "Settings",
+      MenuButton::Quit => "Quit",
+    }
+  }
+}
+
+fn setup_main_menu(mut commands: Commands) {
+  commands.spawn((Camera2d, MainMenuEntity));
+  commands
+    .spawn((menu_root(), MainMenuEntity))
+    .with_children(|parent| {
+      spawn_menu_title(parent, "Bevy Playground");
+      spawn_menu_buttons::<MenuButton>(parent);
+    });
+}
+
+fn cleanup_main_menu(mut commands: Commands, query: Query<Entity, With<MainMenuEntity>>) {
+  for entity in &query {
+    commands.entity(entity).despawn_recursive();
+  }
+}
+
+fn main_menu_action(
+  In(action): In<Option<MenuButton>>,
+  mut next_state: ResMut<NextState<GameState>>,
+  mut settings_panel: ResMut<SettingsPanel>,
+  mut lobby_panel: ResMut<LobbyPanel>,
+  mut level_list: LevelList,
+  mut evw_exit: EventWriter<AppExit>,
+) {
+  match action {
+    Some(MenuButton::Play) => next_state.set(GameState::Loading),
+    Some(MenuButton::Online) => lobby_panel.open = true,
+    Some(MenuButton::Level) => level_list.select_next(),
+    Some(MenuButton::Settings) => settings_panel.open = !settings_panel.open,
+    Some(MenuButton::Quit) => {
+      evw_exit.send(AppExit::Success);
+    }
+    None => {}
+  }
+}
+
+/// Show the name of the selected level on its button, levels load in the background
+fn show_selected_level(
+  level_list: LevelList,
+  buttons_q: Query<(&MenuButton, &Children)>,
+  mut text_q: Query<&mut Text>,
+) {
+  let label = match level_list.selected_name() {
+    Some(name) => format!("Level: {name}"),
+    None => "Level: ...".to_string(),
+  };
+  for (button, children) in &buttons_q {
+    if *button != MenuButton::Level {
+      continue;
+    }
+    for &child in children {
+      if let Ok(mut text) = text_q.get_mut(child)
+        && text.0 != label
+      {
+        text.0 = label.clone();
+      }
+    }
+  }
+}
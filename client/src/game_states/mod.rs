@@ -1 +1,6 @@
+pub mod editor;
 pub mod game;
+pub mod loading;
+pub mod main_menu;
+pub mod pause;
+pub mod photo_mode;
@@ -0,0 +1,357 @@
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use bevy::render::camera::ClearColorConfig;
+use bevy::window::{CursorGrabMode, PrimaryWindow};
+use shared::state::{GameState, PlayState};
+
+use crate::systems::free_camera::CameraMode;
+use crate::systems::menu::{
+  MenuItem, activate_menu_item, highlight_menu_buttons, hover_menu_buttons, menu_root,
+  navigate_menu, reset_menu_selection, spawn_menu_buttons_where, spawn_menu_title,
+};
+use crate::systems::network::{Server, playing_online};
+use crate::systems::save_game::{LoadGame, SaveGame, SaveSlots};
+
+pub struct PausePlugin;
+
+impl Plugin for PausePlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .init_resource::<SavedCursor>()
+      .add_systems(Update, toggle_pause.run_if(in_state(GameState::Game)))
+      .add_systems(
+        Update,
+        (
+          hover_menu_buttons::<PauseButton>,
+          navigate_menu::<PauseButton>,
+          activate_menu_item::<PauseButton>.pipe(pause_menu_action),
+          highlight_menu_buttons::<PauseButton>,
+          show_save_slot,
+        )
+          .chain()
+          .run_if(in_state(PlayState::Paused)),
+      )
+      .add_systems(
+        OnEnter(PlayState::Paused),
+        (reset_menu_selection, enter_pause),
+      )
+      .add_systems(OnExit(PlayState::Paused), exit_pause);
+  }
+}
+
+/// The pause overlay, despawned when resuming
+#[derive(Component)]
+pub struct PauseMenuEntity;
+
+/// What a pause menu button does when activated
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PauseButton {
+  Resume,
+  SaveSlot,
+  Save,
+  Load,
+  Restart,
+  QuitToMenu,
+}
+
+impl MenuItem for PauseButton {
+  const ALL: &'static [PauseButton] = &[
+    PauseButton::Resume,
+    PauseButton::SaveSlot,
+    PauseButton::Save,
+    PauseButton::Load,
+    PauseButton::Restart,
+    PauseButton::QuitToMenu,
+  ];
+
+  fn label(self) -> &'static str {
+    match self {
+      PauseButton::Resume => "Resume",
+      PauseButton::SaveSlot => "Slot",
+      PauseButton::Save => "Save",
+      PauseButton::Load => "Load",
+      PauseButton::Restart => "Restart",
+      PauseButton::QuitToMenu => "Quit to menu",
+    }
+  }
+}
+
+impl PauseButton {
+  /// Only changes this client, the server would undo it in an online game
+  fn offline_only(self) -> bool {
+    matches!(
+      self,
+      PauseButton::SaveSlot | PauseButton::Save | PauseButton::Load | PauseButton::Restart
+    )
+  }
+}
+
+/// How the cursor was set up before pausing, restored when resuming
+#[derive(Resource, Default)]
+struct SavedCursor(Option<(bool, CursorGrabMode)>);
+
+/// Escape or the gamepad's start button pause and resume
+fn toggle_pause(
+  kbd: Res<ButtonInput<KeyCode>>,
+  gamepads: Query<&Gamepad>,
+  state: Res<State<PlayState>>,
+  mut next_state: ResMut<NextState<PlayState>>,
+) {
+  let pressed = kbd.just_pressed(KeyCode::Escape)
+    || gamepads
+      .iter()
+      .any(|gamepad| gamepad.just_pressed(GamepadButton::Start));
+  if !pressed {
+    return;
+  }
+  // photo mode handles escape on its own
+  match state.get() {
+    PlayState::Playing => next_state.set(PlayState::Paused),
+    PlayState::Paused => next_state.set(PlayState::Playing),
+    PlayState::PhotoMode => {}
+  }
+}
+
+fn enter_pause(
+  mut commands: Commands,
+  mut physics_time: ResMut<Time<Physics>>,
+  mut windows: Query<&mut Window, With<PrimaryWindow>>,
+  mut saved_cursor: ResMut<SavedCursor>,
+  server: Option<Res<Server>>,
+) {
+  let online = playing_online(server);
+  physics_time.pause();
+  if let Ok(mut window) = windows.get_single_mut() {
+    let cursor = &mut window.cursor_options;
+    saved_cursor.0 = Some((cursor.visible, cursor.grab_mode));
+    cursor.visible = true;
+    cursor.grab_mode = CursorGrabMode::None;
+  }
+
+  // a camera of its own draws the overlay across every split-screen viewport
+  let camera = commands
+    .spawn((
+      Camera2d,
+      Camera {
+        order: 100,
+        clear_color: ClearColorConfig::None,
+        ..default()
+      },
+      PauseMenuEntity,
+    ))
+    .id();
+  commands
+    .spawn((
+      menu_root(),
+      BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+      TargetCamera(camera),
+      PauseMenuEntity,
+    ))
+    .with_children(|parent| {
+      spawn_menu_title(parent, "Paused");
+      spawn_menu_buttons_where(parent, |button: PauseButton| {
+        !(online && button.offline_only())
+      });
+    });
+}
+
+fn exit_pause(
+  mut commands: Commands,
+  mut physics_time: ResMut<Time<Physics>>,
+  mut windows: Query<&mut Window, With<PrimaryWindow>>,
+  mut saved_cursor: ResMut<SavedCursor>,
+  query: Query<Entity, With<PauseMenuEntity>>,
+) {
+  physics_time.unpause();
+  if let Some((visible, grab_mode)) = saved_cursor.0.take()
+    && let Ok(mut window) = windows.get_single_mut()
+  {
+    window.cursor_options.visible = visible;
+    window.cursor_options.grab_mode = grab_mode;
+  }
+  for entity in &query {
+    commands.entity(entity).despawn_recursive();
+  }
+}
+
+fn pause_menu_action(
+  In(action): In<Option<PauseButton>>,
+  mut commands: Commands,
+  mut next_play_state: ResMut<NextState<PlayState>>,
+  mut next_camera_mode: ResMut<NextState<CameraMode>>,
+  mut next_game_state: ResMut<NextState<GameState>>,
+  mut save_slots: ResMut<SaveSlots>,
+) {
+  match action {
+    Some(PauseButton::Resume) => next_play_state.set(PlayState::Playing),
+    Some(PauseButton::SaveSlot) => save_slots.select_next(),
+    Some(PauseButton::Save) => {
+      commands.send_event(SaveGame(save_slots.slot));
+    }
+    Some(PauseButton::Load) => {
+      commands.send_event(LoadGame(save_slots.slot));
+    }
+    Some(PauseButton::Restart) => {
+      commands.queue(restart_game);
+      next_play_state.set(PlayState::Playing);
+      next_camera_mode.set(CameraMode::Follow);
+    }
+    Some(PauseButton::QuitToMenu) => next_game_state.set(GameState::MainMenu),
+    None => {}
+  }
+}
+
+/// Show the selected save slot on its button
+fn show_save_slot(
+  save_slots: Res<SaveSlots>,
+  buttons_q: Query<(&PauseButton, &Children)>,
+  mut text_q: Query<&mut Text>,
+) {
+  let label = format!("Slot: {}", save_slots.slot + 1);
+  for (button, children) in &buttons_q {
+    if *button != PauseButton::SaveSlot {
+      continue;
+    }
+    for &child in children {
+      if let Ok(mut text) = text_q.get_mut(child)
+        && text.0 != label
+      {
+        text.0 = label.clone();
+      }
+    }
+  }
+}
+
+/// Tear the game down and set it up again.
+/// Entering the state we are already in does not run its schedules, so run them by hand.
+pub fn restart_game(world: &mut World) {
+  world.run_schedule(OnExit(GameState::Game));
+  world.run_schedule(OnEnter(GameState::Game));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::game_states::game::GamePlugin;
+  use crate::game_states::loading::GameAssets;
+  use crate::systems::local_players::LocalPlayers;
+  use crate::systems::menu::MenuSelection;
+  use crate::systems::network::tests::playing_server;
+  use crate::systems::save_game::SaveSlots;
+  use bevy::state::app::StatesPlugin;
+  use shared::HeadlessPlugins;
+  use shared::level::Level;
+  use shared::state::InGameEntity;
+
+  #[test]
+  fn restart_does_not_leak_entities() {
+    let mut app = App::new();
+    app
+      .add_plugins((
+        MinimalPlugins,
+        AssetPlugin::default(),
+        StatesPlugin,
+        TransformPlugin,
+        HierarchyPlugin,
+        bevy::input::InputPlugin,
+        bevy::scene::ScenePlugin,
+        PhysicsPlugins::default(),
+      ))
+      // the gizmos are never drawn, but the lock-on marker asks for them
+      .init_asset::<bevy::render::render_resource::Shader>()
+      .add_plugins((bevy::gizmos::GizmoPlugin, GamePlugin))
+      .init_asset::<Mesh>()
+      .init_asset::<StandardMaterial>()
+      .init_asset::<Image>()
+      .insert_resource(LocalPlayers { count: 2 })
+      .init_state::<GameState>()
+      .add_sub_state::<PlayState>();
+    let game_assets = GameAssets::load(
+      app.world().resource::<AssetServer>(),
+      "levels/arena.level.ron",
+    );
+    let level = game_assets.level.clone();
+    app.insert_resource(game_assets);
+    for _ in 0..1000 {
+      if app.world().resource::<Assets<Level>>().contains(&level) {
+        break;
+      }
+      app.update();
+      std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert!(
+      app.world().resource::<Assets<Level>>().contains(&level),
+      "the level never finished loading"
+    );
+    app
+      .world_mut()
+      .resource_mut::<NextState<GameState>>()
+      .set(GameState::Game);
+    app.update();
+    app
+      .world_mut()
+      .resource_mut::<NextState<PlayState>>()
+      .set(PlayState::Paused);
+    app.update();
+
+    let count = |app: &mut App| {
+      let world = app.world_mut();
+      (
+        world.query::<Entity>().iter(world).count(),
+        world
+          .query_filtered::<Entity, With<InGameEntity>>()
+          .iter(world)
+          .count(),
+      )
+    };
+    let before = count(&mut app);
+    assert!(before.1 > 0);
+    for _ in 0..3 {
+      restart_game(app.world_mut());
+      app.update();
+    }
+    assert_eq!(count(&mut app), before);
+  }
+
+  #[test]
+  fn online_games_cannot_be_saved_loaded_or_restarted() {
+    let mut app = App::new();
+    app
+      .add_plugins((HeadlessPlugins, PausePlugin))
+      // pressed by hand, without the input plugin clearing it before the menu sees it
+      .init_resource::<ButtonInput<KeyCode>>()
+      .init_resource::<MenuSelection>()
+      .insert_resource(SaveSlots::from_args(Vec::new()))
+      .insert_resource(playing_server())
+      .init_state::<GameState>()
+      .add_sub_state::<PlayState>()
+      .add_sub_state::<CameraMode>();
+    app
+      .world_mut()
+      .resource_mut::<NextState<GameState>>()
+      .set(GameState::Game);
+    app.update();
+    app
+      .world_mut()
+      .resource_mut::<NextState<PlayState>>()
+      .set(PlayState::Paused);
+    app.update();
+
+    let world = app.world_mut();
+    let buttons: Vec<PauseButton> = world.query::<&PauseButton>().iter(world).copied().collect();
+    assert!(buttons.contains(&PauseButton::Resume));
+    for button in [PauseButton::Save, PauseButton::Load, PauseButton::Restart] {
+      assert!(!buttons.contains(&button), "{button:?}");
+    }
+
+    // the selection skips the buttons left out
+    world
+      .resource_mut::<ButtonInput<KeyCode>>()
+      .press(KeyCode::ArrowDown);
+    app.update();
+    assert_eq!(
+      PauseButton::ALL[app.world().resource::<MenuSelection>().0],
+      PauseButton::QuitToMenu
+    );
+  }
+}
@@ -0,0 +1,391 @@
+use avian3d::prelude::*;
+use bevy::dev_tools::fps_overlay::FpsOverlayConfig;
+use bevy::input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel};
+use bevy::prelude::*;
+use bevy::render::camera::RenderTarget;
+use bevy::render::view::screenshot::{Screenshot, save_to_disk};
+use bevy::window::WindowRef;
+use shared::state::{GameState, PlayState};
+use std::f32::consts::FRAC_PI_2;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::systems::camera::PanOrbitState;
+use crate::systems::free_camera::FreeFlySettings;
+use crate::systems::local_players::{InputDevices, PlayerCamera, uses_keyboard_mouse};
+
+pub struct PhotoModePlugin;
+
+impl Plugin for PhotoModePlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .init_resource::<PhotoModeSettings>()
+      .init_resource::<HudVisible>()
+      .add_systems(Update, toggle_photo_mode.run_if(in_state(GameState::Game)))
+      .add_systems(
+        Update,
+        (photo_camera, toggle_hud, take_photo).run_if(in_state(PlayState::PhotoMode)),
+      )
+      .add_systems(Update, sync_fps_overlay)
+      .add_systems(OnEnter(PlayState::PhotoMode), enter_photo_mode)
+      .add_systems(OnExit(PlayState::PhotoMode), exit_photo_mode);
+  }
+}
+
+/// The configuration of photo mode
+#[derive(Resource)]
+pub struct PhotoModeSettings {
+  /// Key to enter and leave photo mode
+  pub toggle_key: KeyCode,
+  /// Key to take a screenshot
+  pub capture_key: KeyCode,
+  /// Key to show or hide the HUD
+  pub hud_key: KeyCode,
+  /// Directory screenshots are written to, created if missing
+  pub screenshot_dir: PathBuf,
+  /// What screenshots capture, the primary window unless rendering somewhere else
+  pub target: RenderTarget,
+  /// Radians per second of roll while holding Q or E
+  pub roll_speed: f32,
+  /// Radians of field of view per scroll line
+  pub fov_sensitivity: f32,
+  /// Narrowest field of view in radians
+  pub min_fov: f32,
+  /// Widest field of view in radians
+  pub max_fov: f32,
+}
+
+impl Default for PhotoModeSettings {
+  fn default() -> Self {
+    PhotoModeSettings {
+      toggle_key: KeyCode::KeyP,
+      capture_key: KeyCode::F12,
+      hud_key: KeyCode::KeyH,
+      screenshot_dir: PathBuf::from("screenshots"),
+      target: RenderTarget::Window(WindowRef::Primary),
+      roll_speed: 45.0f32.to_radians(),
+      fov_sensitivity: 2.0f32.to_radians(),
+      min_fov: 10.0f32.to_radians(),
+      max_fov: 120.0f32.to_radians(),
+    }
+  }
+}
+
+/// Whether the FPS overlay and the inspector are shown
+#[derive(Resource)]
+pub struct HudVisible(pub bool);
+
+impl Default for HudVisible {
+  fn default() -> Self {
+    HudVisible(true)
+  }
+}
+
+pub fn hud_visible(hud: Res<HudVisible>) -> bool {
+  hud.0
+}
+
+/// The detached camera of photo mode, with what to restore when leaving it
+#[derive(Component)]
+pub struct PhotoCamera {
+  pub yaw: f32,
+  pub pitch: f32,
+  pub roll: f32,
+  saved_transform: Transform,
+  saved_fov: f32,
+}
+
+fn toggle_photo_mode(
+  kbd: Res<ButtonInput<KeyCode>>,
+  settings: Res<PhotoModeSettings>,
+  state: Res<State<PlayState>>,
+  mut next_state: ResMut<NextState<PlayState>>,
+) {
+  match state.get() {
+    PlayState::Playing if kbd.just_pressed(settings.toggle_key) => {
+      next_state.set(PlayState::PhotoMode)
+    }
+    PlayState::PhotoMode
+      if kbd.just_pressed(settings.toggle_key) || kbd.just_pressed(KeyCode::Escape) =>
+    {
+      next_state.set(PlayState::Playing)
+    }
+    _ => {}
+  }
+}
+
+fn enter_photo_mode(
+  mut commands: Commands,
+  mut physics_time: ResMut<Time<Physics>>,
+  mut hud: ResMut<HudVisible>,
+  camera_q: Query<(Entity, &Transform, &Projection, Option<&PlayerCamera>), With<PanOrbitState>>,
+  devices_q: Query<&InputDevices>,
+) {
+  physics_time.pause();
+  hud.0 = false;
+  // the camera of the keyboard and mouse player becomes the photo camera
+  for (entity, transform, projection, player_camera) in &camera_q {
+    if !uses_keyboard_mouse(player_camera, &devices_q) {
+      continue;
+    }
+    let Projection::Perspective(perspective) = projection else {
+      continue;
+    };
+    let (yaw, pitch, roll) = transform.rotation.to_euler(EulerRot::YXZ);
+    commands.entity(entity).insert(PhotoCamera {
+      yaw,
+      pitch,
+      roll,
+      saved_transform: *transform,
+      saved_fov: perspective.fov,
+    });
+  }
+}
+
+fn exit_photo_mode(
+  mut commands: Commands,
+  mut physics_time: ResMut<Time<Physics>>,
+  mut hud: ResMut<HudVisible>,
+  mut camera_q: Query<(Entity, &PhotoCamera, &mut Transform, &mut Projection)>,
+) {
+  physics_time.unpause();
+  hud.0 = true;
+  for (entity, photo_camera, mut transform, mut projection) in &mut camera_q {
+    *transform = photo_camera.saved_transform;
+    if let Projection::Perspective(perspective) = projection.as_mut() {
+      perspective.fov = photo_camera.saved_fov;
+    }
+    commands.entity(entity).remove::<PhotoCamera>();
+  }
+}
+
+fn photo_camera(
+  time: Res<Time<Real>>,
+  kbd: Res<ButtonInput<KeyCode>>,
+  fly_settings: Res<FreeFlySettings>,
+  settings: Res<PhotoModeSettings>,
+  mut evr_motion: EventReader<MouseMotion>,
+  mut evr_scroll: EventReader<MouseWheel>,
+  mut camera_q: Query<(&mut PhotoCamera, &mut Transform, &mut Projection)>,
+) {
+  // virtual time keeps running, but real time is what the user feels
+  let delta = time.delta_secs();
+  let motion: Vec2 = evr_motion.read().map(|ev| ev.delta).sum();
+  let scroll: f32 = evr_scroll
+    .read()
+    .map(|ev| match ev.unit {
+      MouseScrollUnit::Line => ev.y,
+      // 1 "line" == 16 "pixels of motion"
+      MouseScrollUnit::Pixel => ev.y / 16.0,
+    })
+    .sum();
+
+  let mut direction = Vec3::ZERO;
+  if kbd.pressed(KeyCode::KeyW) {
+    direction += Vec3::NEG_Z;
+  }
+  if kbd.pressed(KeyCode::KeyS) {
+    direction += Vec3::Z;
+  }
+  if kbd.pressed(KeyCode::KeyA) {
+    direction += Vec3::NEG_X;
+  }
+  if kbd.pressed(KeyCode::KeyD) {
+    direction += Vec3::X;
+  }
+  if kbd.pressed(KeyCode::Space) {
+    direction += Vec3::Y;
+  }
+  if kbd.pressed(KeyCode::ControlLeft) {
+    direction += Vec3::NEG_Y;
+  }
+  let mut roll = 0.0;
+  if kbd.pressed(KeyCode::KeyQ) {
+    roll += 1.0;
+  }
+  if kbd.pressed(KeyCode::KeyE) {
+    roll -= 1.0;
+  }
+  let mut speed = fly_settings.speed;
+  if kbd.pressed(fly_settings.boost_key) {
+    speed *= fly_settings.boost;
+  }
+
+  for (mut photo_camera, mut transform, mut projection) in &mut camera_q {
+    photo_camera.yaw -= motion.x * fly_settings.look_sensitivity;
+    photo_camera.pitch = (photo_camera.pitch - motion.y * fly_settings.look_sensitivity)
+      .clamp(-FRAC_PI_2 + 0.01, FRAC_PI_2 - 0.01);
+    photo_camera.roll += roll * settings.roll_speed * delta;
+    if kbd.just_pressed(KeyCode::KeyR) {
+      photo_camera.roll = 0.0;
+    }
+    transform.rotation = Quat::from_euler(
+      EulerRot::YXZ,
+      photo_camera.yaw,
+      photo_camera.pitch,
+      photo_camera.roll,
+    );
+
+    // move along the ground plane of the view, up and down stay vertical
+    let yaw_rotation = Quat::from_rotation_y(photo_camera.yaw);
+    let velocity = (yaw_rotation * Vec3::new(direction.x, 0.0, direction.z)
+      + Vec3::Y * direction.y)
+      .normalize_or_zero();
+    transform.translation += velocity * speed * delta;
+
+    if let Projection::Perspective(perspective) = projection.as_mut() {
+      if kbd.just_pressed(KeyCode::KeyR) {
+        perspective.fov = photo_camera.saved_fov;
+      }
+      // scrolling up zooms in
+      perspective.fov = (perspective.fov - scroll * settings.fov_sensitivity)
+        .clamp(settings.min_fov, settings.max_fov);
+    }
+  }
+}
+
+fn toggle_hud(
+  kbd: Res<ButtonInput<KeyCode>>,
+  settings: Res<PhotoModeSettings>,
+  mut hud: ResMut<HudVisible>,
+) {
+  if kbd.just_pressed(settings.hud_key) {
+    hud.0 = !hud.0;
+  }
+}
+
+fn sync_fps_overlay(hud: Res<HudVisible>, mut overlay: ResMut<FpsOverlayConfig>) {
+  if hud.is_changed() && overlay.enabled != hud.0 {
+    overlay.enabled = hud.0;
+  }
+}
+
+fn take_photo(
+  mut commands: Commands,
+  kbd: Res<ButtonInput<KeyCode>>,
+  settings: Res<PhotoModeSettings>,
+) {
+  if kbd.just_pressed(settings.capture_key) {
+    capture_photo(&mut commands, &settings, SystemTime::now());
+  }
+}
+
+/// Capture the settings' target into a timestamped file of the screenshot directory
+pub fn capture_photo(
+  commands: &mut Commands,
+  settings: &PhotoModeSettings,
+  now: SystemTime,
+) -> PathBuf {
+  if let Err(err) = std::fs::create_dir_all(&settings.screenshot_dir) {
+    error!(
+      "Cannot create screenshot directory {}: {err}",
+      settings.screenshot_dir.display()
+    );
+  }
+  let path = settings.screenshot_dir.join(photo_file_name(now));
+  commands
+    .spawn(Screenshot(settings.target.clone()))
+    .observe(save_to_disk(path.clone()));
+  path
+}
+
+/// A file name like `photo_2025-01-31_18-04-59_042.png`, in UTC
+pub fn photo_file_name(time: SystemTime) -> String {
+  let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let secs = since_epoch.as_secs() as i64;
+  let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+  let secs_of_day = secs.rem_euclid(86_400);
+  format!(
+    "photo_{year:04}-{month:02}-{day:02}_{:02}-{:02}-{:02}_{:03}.png",
+    secs_of_day / 3600,
+    secs_of_day / 60 % 60,
+    secs_of_day % 60,
+    since_epoch.subsec_millis(),
+  )
+}
+
+// Year, month and day of a number of days since 1970-01-01,
+// from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z.rem_euclid(146_097);
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = yoe + era * 400 + i64::from(month <= 2);
+  (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bevy::ecs::system::RunSystemOnce;
+  use bevy::render::render_asset::RenderAssetUsages;
+  use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+  use bevy::render::view::screenshot::ScreenshotCaptured;
+  use std::time::Duration;
+
+  #[test]
+  fn photo_file_names_are_timestamped() {
+    assert_eq!(
+      photo_file_name(UNIX_EPOCH),
+      "photo_1970-01-01_00-00-00_000.png"
+    );
+    assert_eq!(
+      photo_file_name(UNIX_EPOCH + Duration::from_millis(1_738_346_699_042)),
+      "photo_2025-01-31_18-04-59_042.png"
+    );
+    assert_eq!(
+      photo_file_name(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+      "photo_2000-02-29_00-00-00_000.png"
+    );
+  }
+
+  #[test]
+  fn photo_of_render_target_image_is_saved() {
+    let dir = std::env::temp_dir().join(format!("photo_mode_test_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let target = Handle::<Image>::default();
+
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins);
+    app.insert_resource(PhotoModeSettings {
+      screenshot_dir: dir.clone(),
+      target: RenderTarget::Image(target.clone()),
+      ..default()
+    });
+
+    let path = app
+      .world_mut()
+      .run_system_once(|mut commands: Commands, settings: Res<PhotoModeSettings>| {
+        capture_photo(&mut commands, &settings, UNIX_EPOCH)
+      })
+      .unwrap();
+    assert_eq!(path, dir.join("photo_1970-01-01_00-00-00_000.png"));
+
+    let world = app.world_mut();
+    let (entity, screenshot) = world.query::<(Entity, &Screenshot)>().single(world);
+    assert!(matches!(&screenshot.0, RenderTarget::Image(image) if *image == target));
+
+    // stand in for the render world, which delivers the captured image
+    let image = Image::new_fill(
+      Extent3d {
+        width: 4,
+        height: 4,
+        depth_or_array_layers: 1,
+      },
+      TextureDimension::D2,
+      &[255, 0, 0, 255],
+      TextureFormat::Rgba8UnormSrgb,
+      RenderAssetUsages::default(),
+    );
+    world.trigger_targets(ScreenshotCaptured(image), entity);
+    world.flush();
+
+    assert!(path.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
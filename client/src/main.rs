@@ -7,20 +7,25 @@ use bevy::{
 };
 
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
+use shared::state::{GameState, PlayState};
 
 mod game_states;
 mod systems;
+use game_states::editor::EditorPlugin;
 use game_states::game::GamePlugin;
+use game_states::loading::LoadingPlugin;
+use game_states::main_menu::MainMenuPlugin;
+use game_states::pause::PausePlugin;
+use game_states::photo_mode::{PhotoModePlugin, hud_visible};
+use systems::lobby::LobbyPlugin;
+use systems::local_players::LocalPlayers;
+use systems::network::NetworkPlugin;
+use systems::save_game::SaveGamePlugin;
+use systems::user_settings::UserSettingsPlugin;
 
-#[derive(Clone, Copy, Default, Eq, PartialEq, Debug, Hash, States)]
-pub enum GameState {
-  #[default]
-  Game,
-}
 struct OverlayColor;
 
 impl OverlayColor {
-  const RED: Color = Color::srgb(1.0, 0.0, 0.0);
   const GREEN: Color = Color::srgb(0.0, 1.0, 0.0);
 }
 
@@ -37,7 +42,9 @@ fn main() {
       }),
       ..default()
     }))
+    .insert_resource(LocalPlayers::from_args(std::env::args()))
     .init_state::<GameState>()
+    .add_sub_state::<PlayState>()
     .add_plugins((
       FpsOverlayPlugin {
         config: FpsOverlayConfig {
@@ -54,9 +61,18 @@ fn main() {
           enabled: true,
         },
       },
-      WorldInspectorPlugin::new(),
+      WorldInspectorPlugin::new().run_if(hud_visible),
       PhysicsPlugins::default(),
+      MainMenuPlugin,
+      LoadingPlugin,
       GamePlugin,
+      EditorPlugin,
+      PausePlugin,
+      PhotoModePlugin,
+      UserSettingsPlugin,
+      SaveGamePlugin,
+      NetworkPlugin,
+      LobbyPlugin,
     ))
     .run();
 }
@@ -1,6 +1,10 @@
-use crate::game_states::game::{InGameEntity, Player};
+use crate::systems::camera_shake::CameraTrauma;
+use crate::systems::local_players::{InputDevices, LocalPlayer, PlayerCamera, uses_keyboard_mouse};
+use crate::systems::lock_on::LockOn;
 use bevy::input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel};
 use bevy::prelude::*;
+use shared::controller::Player;
+use shared::state::InGameEntity;
 use std::f32::consts::{FRAC_PI_2, PI, TAU};
 
 // Bundle to spawn our custom camera easily
@@ -30,16 +34,24 @@ pub struct PanOrbitSettings {
   pub pan_sensitivity: f32,
   /// Radians per pixel of mouse motion
   pub orbit_sensitivity: f32,
+  /// Radians per second with the right stick pushed all the way
+  pub stick_orbit_sensitivity: f32,
   /// Exponent per pixel of mouse motion
   pub zoom_sensitivity: f32,
   /// Key to hold for panning
   pub pan_key: Option<KeyCode>,
   /// Key to hold for orbiting
   pub orbit_key: Option<KeyCode>,
-  /// Max yaw ()
+  /// Lowest pitch in radians (negative looks down on the center)
   pub min_pitch: f32,
-  /// Min yaw
+  /// Highest pitch in radians
   pub max_pitch: f32,
+  /// Yaw limits in radians as `(min, max)`, `None` orbits freely
+  pub yaw_limits: Option<(f32, f32)>,
+  /// Closest the camera can zoom to the center
+  pub min_radius: f32,
+  /// Furthest the camera can zoom away from the center
+  pub max_radius: f32,
   /// Key to hold for zooming
   pub zoom_key: Option<KeyCode>,
   /// What action is bound to the scroll wheel?
@@ -48,6 +60,12 @@ pub struct PanOrbitSettings {
   pub scroll_line_sensitivity: f32,
   /// For devices with smooth scrolling, like touchpads
   pub scroll_pixel_sensitivity: f32,
+  /// Reverse the horizontal orbit direction
+  pub invert_x: bool,
+  /// Reverse the vertical orbit direction
+  pub invert_y: bool,
+  /// Vertical field of view in radians
+  pub fov: f32,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -75,6 +93,7 @@ impl Default for PanOrbitSettings {
       pan_enabled: false,
       pan_sensitivity: 0.001,                 // 1000 pixels per world unit
       orbit_sensitivity: 0.1f32.to_radians(), // 0.1 degree per pixel
+      stick_orbit_sensitivity: PI,            // half a turn per second
       zoom_sensitivity: 0.01,
       pan_key: Some(KeyCode::ControlLeft),
       orbit_key: Some(KeyCode::AltLeft),
@@ -82,64 +101,171 @@ impl Default for PanOrbitSettings {
       scroll_action: Some(PanOrbitAction::Zoom),
       scroll_line_sensitivity: 16.0, // 1 "line" == 16 "pixels of motion"
       scroll_pixel_sensitivity: 1.0,
-      // pitch is in radians, final number = PI / max or min
-      max_pitch: 2.0,
-      min_pitch: 180.0,
+      min_pitch: -FRAC_PI_2,
+      max_pitch: 1.0f32.to_radians(),
+      yaw_limits: None,
+      min_radius: 3.5,
+      max_radius: 30.0,
+      invert_x: false,
+      invert_y: false,
+      fov: PerspectiveProjection::default().fov,
     }
   }
 }
 
-pub fn spawn_camera(mut commands: Commands) {
-  let mut camera = PanOrbitCameraBundle::default();
-  // Position our camera using our component,
-  // not Transform (it would get overwritten)
-  camera.state.center = Vec3::ZERO;
-  camera.state.radius = 10.0;
-  camera.state.pitch = -15.0f32.to_radians();
-  camera.state.yaw = 0.0f32.to_radians();
-  camera.state.upside_down = false;
-  commands.spawn((camera, InGameEntity));
+impl PanOrbitSettings {
+  pub fn clamp_pitch(&self, pitch: f32) -> f32 {
+    pitch.clamp(self.min_pitch, self.max_pitch)
+  }
+
+  /// Clamp the yaw to the limits, or wrap it around if there are none
+  pub fn clamp_yaw(&self, yaw: f32) -> f32 {
+    match self.yaw_limits {
+      Some((min, max)) => yaw.clamp(min, max),
+      None => wrap_angle(yaw),
+    }
+  }
+
+  pub fn clamp_radius(&self, radius: f32) -> f32 {
+    radius.clamp(self.min_radius, self.max_radius)
+  }
+
+  /// Signs to multiply the orbit input by, following the invert options
+  pub fn orbit_inversion(&self) -> Vec2 {
+    let sign = |invert: bool| if invert { -1.0 } else { 1.0 };
+    Vec2::new(sign(self.invert_x), sign(self.invert_y))
+  }
 }
 
+impl PanOrbitState {
+  /// Multiply the radius by the exponential of `zoom`, keeping it within the settings' range
+  pub fn zoom(&mut self, zoom: f32, settings: &PanOrbitSettings) {
+    // in order for zoom to feel intuitive,
+    // everything needs to be exponential
+    // (done via multiplication)
+    // not linear
+    // (done via addition)
+    self.radius = settings.clamp_radius(self.radius * (-zoom).exp());
+  }
+
+  /// Add `delta` (x = yaw, y = pitch) to the orbit angles, wrapping and clamping them
+  pub fn orbit(&mut self, delta: Vec2, settings: &PanOrbitSettings) {
+    self.yaw = settings.clamp_yaw(self.yaw + delta.x);
+    // negative pitch looks down on the center from above
+    self.pitch = settings.clamp_pitch(self.pitch + delta.y);
+  }
+
+  /// The camera transform for the current center, angles and radius
+  pub fn transform(&self) -> Transform {
+    // YXZ Euler Rotation performs yaw/pitch/roll.
+    let mut transform =
+      Transform::from_rotation(Quat::from_euler(EulerRot::YXZ, self.yaw, self.pitch, 0.0));
+    // To position the camera, get the backward direction vector
+    // and place the camera at the desired radius from the center.
+    transform.translation = self.center + transform.back() * self.radius;
+    transform
+  }
+}
+
+/// Wrap an angle around, to stay between +- 180 degrees
+pub fn wrap_angle(angle: f32) -> f32 {
+  let angle = angle % TAU;
+  if angle > PI {
+    angle - TAU
+  } else if angle < -PI {
+    angle + TAU
+  } else {
+    angle
+  }
+}
+
+pub fn spawn_camera(mut commands: Commands, players_q: Query<(Entity, &LocalPlayer)>) {
+  for (player, local_player) in &players_q {
+    let mut camera = PanOrbitCameraBundle::default();
+    // Position our camera using our component,
+    // not Transform (it would get overwritten)
+    camera.state.center = Vec3::ZERO;
+    camera.state.radius = 10.0;
+    camera.state.pitch = -15.0f32.to_radians();
+    camera.state.yaw = 0.0f32.to_radians();
+    camera.state.upside_down = false;
+    commands.spawn((
+      camera,
+      // every camera renders its own viewport, in a fixed order
+      Camera {
+        order: local_player.0 as isize,
+        ..default()
+      },
+      PlayerCamera(player),
+      LockOn::default(),
+      CameraTrauma::default(),
+      InGameEntity,
+    ));
+  }
+}
+
+#[allow(clippy::type_complexity)]
 pub fn pan_orbit_camera(
+  time: Res<Time>,
   kbd: Res<ButtonInput<KeyCode>>,
   mut evr_motion: EventReader<MouseMotion>,
   mut evr_scroll: EventReader<MouseWheel>,
-  mut q_camera: Query<(&PanOrbitSettings, &mut PanOrbitState, &mut Transform)>,
+  gamepads: Query<&Gamepad>,
+  devices_q: Query<&InputDevices>,
+  mut q_camera: Query<(
+    &PanOrbitSettings,
+    &mut PanOrbitState,
+    &mut Transform,
+    Option<&LockOn>,
+    Option<&PlayerCamera>,
+  )>,
 ) {
   // First, accumulate the total amount of
   // mouse motion and scroll, from all pending events:
-  let mut total_motion: Vec2 = evr_motion.read().map(|ev| ev.delta).sum();
+  let mut mouse_motion: Vec2 = evr_motion.read().map(|ev| ev.delta).sum();
 
   // Reverse Y (Bevy's Worldspace coordinate system is Y-Up,
   // but events are in window/ui coordinates, which are Y-Down)
-  total_motion.y = -total_motion.y;
+  mouse_motion.y = -mouse_motion.y;
 
-  let mut total_scroll_lines = Vec2::ZERO;
-  let mut total_scroll_pixels = Vec2::ZERO;
+  let mut mouse_scroll_lines = Vec2::ZERO;
+  let mut mouse_scroll_pixels = Vec2::ZERO;
   for ev in evr_scroll.read() {
     match ev.unit {
       MouseScrollUnit::Line => {
-        total_scroll_lines.x += ev.x;
-        total_scroll_lines.y -= ev.y;
+        mouse_scroll_lines.x += ev.x;
+        mouse_scroll_lines.y -= ev.y;
       }
       MouseScrollUnit::Pixel => {
-        total_scroll_pixels.x += ev.x;
-        total_scroll_pixels.y -= ev.y;
+        mouse_scroll_pixels.x += ev.x;
+        mouse_scroll_pixels.y -= ev.y;
       }
     }
   }
 
-  for (settings, mut state, mut transform) in &mut q_camera {
+  for (settings, mut state, mut transform, lock_on, player_camera) in &mut q_camera {
+    // The mouse and keyboard only drive the camera of the player using them
+    let keyboard_mouse = uses_keyboard_mouse(player_camera, &devices_q);
+    let (total_motion, total_scroll_lines, total_scroll_pixels) = if keyboard_mouse {
+      (mouse_motion, mouse_scroll_lines, mouse_scroll_pixels)
+    } else {
+      (Vec2::ZERO, Vec2::ZERO, Vec2::ZERO)
+    };
+    let pressed = |key: KeyCode| keyboard_mouse && kbd.pressed(key);
+    let just_pressed = |key: KeyCode| keyboard_mouse && kbd.just_pressed(key);
+
+    // Gamepad players orbit with the right stick instead
+    let stick = player_camera
+      .and_then(|player_camera| devices_q.get(player_camera.0).ok())
+      .and_then(|devices| devices.gamepad)
+      .and_then(|gamepad| gamepads.get(gamepad).ok())
+      .map_or(Vec2::ZERO, Gamepad::right_stick);
+
     // Check how much of each thing we need to apply.
     // Accumulate values from motion and scroll,
     // based on our configuration settings.
     let mut total_pan = Vec2::ZERO;
-    if settings
-      .pan_key
-      .map(|key| kbd.pressed(key))
-      .unwrap_or(false)
-    {
+    if settings.pan_key.map(pressed).unwrap_or(false) {
       total_pan -= total_motion * settings.pan_sensitivity;
     }
     if settings.scroll_action == Some(PanOrbitAction::Pan) {
@@ -154,6 +280,8 @@ pub fn pan_orbit_camera(
     // }
 
     total_orbit -= total_motion * settings.orbit_sensitivity;
+    total_orbit -= stick * settings.stick_orbit_sensitivity * time.delta_secs();
+    total_orbit *= settings.orbit_inversion();
 
     if settings.scroll_action == Some(PanOrbitAction::Orbit) {
       total_orbit -=
@@ -163,11 +291,7 @@ pub fn pan_orbit_camera(
     }
 
     let mut total_zoom = Vec2::ZERO;
-    if settings
-      .zoom_key
-      .map(|key| kbd.pressed(key))
-      .unwrap_or(false)
-    {
+    if settings.zoom_key.map(pressed).unwrap_or(false) {
       total_zoom -= total_motion * settings.zoom_sensitivity;
     }
     if settings.scroll_action == Some(PanOrbitAction::Zoom) {
@@ -179,11 +303,7 @@ pub fn pan_orbit_camera(
 
     // Upon starting a new orbit maneuver (key is just pressed),
     // check if we are starting it upside-down
-    if settings
-      .orbit_key
-      .map(|key| kbd.just_pressed(key))
-      .unwrap_or(false)
-    {
+    if settings.orbit_key.map(just_pressed).unwrap_or(false) {
       state.upside_down = state.pitch < -FRAC_PI_2 || state.pitch > FRAC_PI_2;
     }
 
@@ -192,6 +312,11 @@ pub fn pan_orbit_camera(
       total_orbit.x = -total_orbit.x;
     }
 
+    // While locked on, the yaw follows the target instead of the mouse
+    if lock_on.is_some_and(LockOn::is_locked) {
+      total_orbit.x = 0.0;
+    }
+
     // Now we can actually do the things!
 
     let mut any = false;
@@ -199,39 +324,13 @@ pub fn pan_orbit_camera(
     // To ZOOM, we need to multiply our radius.
     if total_zoom != Vec2::ZERO {
       any = true;
-      // in order for zoom to feel intuitive,
-      // everything needs to be exponential
-      // (done via multiplication)
-      // not linear
-      // (done via addition)
-
-      // so we compute the exponential of our
-      // accumulated value and multiply by that
-      state.radius *= (-total_zoom.y).exp();
-      // clamp the radius to a reasonable range
-      state.radius = state.radius.clamp(3.5, 30.0);
+      state.zoom(total_zoom.y, settings);
     }
 
     // To ORBIT, we change our pitch and yaw values
     if total_orbit != Vec2::ZERO {
       any = true;
-
-      state.yaw += total_orbit.x;
-      state.pitch += total_orbit.y;
-      // wrap around, to stay between +- 180 degrees
-      if state.yaw > PI {
-        state.yaw -= TAU; // 2 * PI
-      }
-      if state.yaw < -PI {
-        state.yaw += TAU; // 2 * PI
-      }
-      // down is positive, up is negative (in radians)
-      if state.pitch < -PI / settings.max_pitch {
-        state.pitch = -PI / settings.max_pitch;
-      }
-      if state.pitch > PI / settings.min_pitch {
-        state.pitch = PI / settings.min_pitch;
-      }
+      state.orbit(total_orbit, settings);
     }
 
     // To PAN, we can get the UP and RIGHT direction
@@ -250,43 +349,136 @@ pub fn pan_orbit_camera(
     // controller was just added and thus we are running
     // for the first time and need to initialize)
     if any || state.is_added() {
-      // YXZ Euler Rotation performs yaw/pitch/roll.
-      transform.rotation = Quat::from_euler(EulerRot::YXZ, state.yaw, state.pitch, 0.0);
-      // To position the camera, get the backward direction vector
-      // and place the camera at the desired radius from the center.
-      transform.translation = state.center + transform.back() * state.radius;
+      *transform = state.transform();
     }
   }
 }
 
 pub fn camera_follow(
-  mut player_q: Query<&mut Transform, With<Player>>, // Player query (mutable)
-  mut pan_orbit_q: Query<&mut PanOrbitState>,
-  mut camera_q: Query<&mut Transform, (With<Camera>, Without<Player>)>, // Camera query (immutable, excluding Player)
+  player_q: Query<&Transform, (With<Player>, Without<Camera>)>,
+  mut camera_q: Query<(&PlayerCamera, &mut PanOrbitState, &mut Transform), With<Camera>>,
 ) {
-  let player_tfm = player_q
-    .get_single_mut()
-    .expect("Error: Could not find a single player.");
-  let mut pan_orbit_state = pan_orbit_q
-    .get_single_mut()
-    .expect("Error: Could not find a single camera.");
-  let mut camera_tfm = camera_q
-    .get_single_mut()
-    .expect("Error: Could not find a single camera.");
-
-  // Get the rotation of the camera, and ignore pitch (up/down)
-  let yaw = camera_tfm.rotation.to_euler(EulerRot::YXZ).0; // Extract yaw (rotation around Y-axis)
-
-  // Compute the forward and right directions from the camera (ignoring vertical angle)
-  let right = Quat::from_rotation_y(yaw).mul_vec3(Vec3::X).normalize();
-
-  // Update the camera's center to follow the player add right to off center player
-  pan_orbit_state.center = player_tfm.translation + right * 1.5;
-  camera_tfm.rotation = Quat::from_euler(
-    EulerRot::YXZ,
-    pan_orbit_state.yaw,
-    pan_orbit_state.pitch,
-    0.0,
-  );
-  camera_tfm.translation = pan_orbit_state.center + camera_tfm.back() * pan_orbit_state.radius;
+  for (player_camera, mut pan_orbit_state, mut camera_tfm) in &mut camera_q {
+    // Nothing to follow, e.g. while the player is despawned
+    let Ok(player_tfm) = player_q.get(player_camera.0) else {
+      continue;
+    };
+
+    // Get the rotation of the camera, and ignore pitch (up/down)
+    let yaw = camera_tfm.rotation.to_euler(EulerRot::YXZ).0; // Extract yaw (rotation around Y-axis)
+
+    // Compute the forward and right directions from the camera (ignoring vertical angle)
+    let right = Quat::from_rotation_y(yaw).mul_vec3(Vec3::X).normalize();
+
+    // Update the camera's center to follow the player add right to off center player
+    pan_orbit_state.center = player_tfm.translation + right * 1.5;
+    *camera_tfm = pan_orbit_state.transform();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const EPSILON: f32 = 1e-5;
+
+  #[test]
+  fn wrap_angle_stays_within_half_turn() {
+    assert!((wrap_angle(PI + 0.5) - (-PI + 0.5)).abs() < EPSILON);
+    assert!((wrap_angle(-PI - 0.5) - (PI - 0.5)).abs() < EPSILON);
+    assert!((wrap_angle(3.0 * TAU + 0.25) - 0.25).abs() < EPSILON);
+    assert_eq!(wrap_angle(PI), PI);
+    assert_eq!(wrap_angle(0.3), 0.3);
+  }
+
+  #[test]
+  fn orbit_clamps_pitch_to_settings() {
+    let settings = PanOrbitSettings {
+      min_pitch: -60.0f32.to_radians(),
+      max_pitch: 10.0f32.to_radians(),
+      ..default()
+    };
+    let mut state = PanOrbitState::default();
+
+    state.orbit(Vec2::new(0.0, -2.0), &settings);
+    assert_eq!(state.pitch, settings.min_pitch);
+
+    state.orbit(Vec2::new(0.0, 4.0), &settings);
+    assert_eq!(state.pitch, settings.max_pitch);
+  }
+
+  #[test]
+  fn orbit_wraps_free_yaw() {
+    let settings = PanOrbitSettings::default();
+    let mut state = PanOrbitState {
+      yaw: PI - 0.1,
+      ..default()
+    };
+
+    state.orbit(Vec2::new(0.2, 0.0), &settings);
+    assert!((state.yaw - (-PI + 0.1)).abs() < EPSILON);
+  }
+
+  #[test]
+  fn orbit_clamps_limited_yaw() {
+    let settings = PanOrbitSettings {
+      yaw_limits: Some((-FRAC_PI_2, FRAC_PI_2)),
+      ..default()
+    };
+    let mut state = PanOrbitState::default();
+
+    state.orbit(Vec2::new(3.0, 0.0), &settings);
+    assert_eq!(state.yaw, FRAC_PI_2);
+
+    state.orbit(Vec2::new(-5.0, 0.0), &settings);
+    assert_eq!(state.yaw, -FRAC_PI_2);
+  }
+
+  #[test]
+  fn zoom_is_exponential_and_clamped() {
+    let settings = PanOrbitSettings {
+      min_radius: 2.0,
+      max_radius: 20.0,
+      ..default()
+    };
+    let mut state = PanOrbitState {
+      radius: 10.0,
+      ..default()
+    };
+
+    state.zoom(-(2.0f32.ln()), &settings);
+    assert!((state.radius - 20.0).abs() < EPSILON);
+
+    state.zoom(100.0, &settings);
+    assert_eq!(state.radius, settings.min_radius);
+
+    state.zoom(-100.0, &settings);
+    assert_eq!(state.radius, settings.max_radius);
+  }
+
+  #[test]
+  fn transform_places_camera_behind_center() {
+    let state = PanOrbitState {
+      center: Vec3::new(1.0, 2.0, 3.0),
+      radius: 5.0,
+      ..default()
+    };
+
+    let transform = state.transform();
+    assert!(transform.translation.distance(Vec3::new(1.0, 2.0, 8.0)) < EPSILON);
+    assert!(transform.forward().dot(Vec3::NEG_Z) > 1.0 - EPSILON);
+  }
+
+  #[test]
+  fn transform_pitch_down_raises_camera() {
+    let state = PanOrbitState {
+      radius: 10.0,
+      pitch: -30.0f32.to_radians(),
+      ..default()
+    };
+
+    let transform = state.transform();
+    assert!((transform.translation.y - 5.0).abs() < 1e-4);
+    assert!((transform.translation.length() - 10.0).abs() < 1e-4);
+  }
 }
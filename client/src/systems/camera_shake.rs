@@ -0,0 +1,229 @@
+use crate::systems::local_players::PlayerCamera;
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use shared::controller::{Grounded, Player};
+
+/// Send to add trauma to every shaking camera, e.g. for explosions
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CameraShake {
+  /// Trauma to add, the total is capped to 1.0
+  pub trauma: f32,
+  /// Only shake the camera of this player, `None` shakes every camera
+  pub player: Option<Entity>,
+}
+
+/// Trauma-based shake of a camera.
+/// The shake grows with the square of the trauma, which decays over time.
+/// The offset is applied on top of the transform computed from `PanOrbitState`
+/// and removed again before the camera systems run, so the state is never touched.
+#[derive(Component)]
+pub struct CameraTrauma {
+  /// Current trauma, between 0.0 and 1.0
+  pub trauma: f32,
+  /// Trauma lost per second
+  pub decay: f32,
+  /// Largest offset in world units along each local axis
+  pub max_translation: Vec3,
+  /// Largest yaw, pitch and roll offsets in radians
+  pub max_rotation: Vec3,
+  /// How fast the noise driving the shake changes
+  pub frequency: f32,
+  // time fed to the noise, only advances while shaking
+  time: f32,
+  // offset applied in the last frame, to be removed in the next one
+  applied: Transform,
+}
+
+impl Default for CameraTrauma {
+  fn default() -> Self {
+    CameraTrauma {
+      trauma: 0.0,
+      decay: 1.2,
+      max_translation: Vec3::new(0.3, 0.3, 0.1),
+      max_rotation: Vec3::new(2.0, 2.0, 4.0).map(f32::to_radians),
+      frequency: 18.0,
+      time: 0.0,
+      applied: Transform::IDENTITY,
+    }
+  }
+}
+
+impl CameraTrauma {
+  pub fn add_trauma(&mut self, trauma: f32) {
+    self.trauma = (self.trauma + trauma).clamp(0.0, 1.0);
+  }
+
+  /// The offset to apply for the current trauma and time
+  pub fn offset(&self) -> Transform {
+    let shake = self.trauma * self.trauma;
+    let t = self.time * self.frequency;
+    let channel = |seed: u32| noise(seed, t) * shake;
+
+    let translation = Vec3::new(channel(0), channel(1), channel(2)) * self.max_translation;
+    let rotation = Vec3::new(channel(3), channel(4), channel(5)) * self.max_rotation;
+    Transform {
+      translation,
+      rotation: Quat::from_euler(EulerRot::YXZ, rotation.x, rotation.y, rotation.z),
+      ..default()
+    }
+  }
+}
+
+/// Smooth 1D gradient noise between -1.0 and 1.0, a different curve for every seed
+pub fn noise(seed: u32, t: f32) -> f32 {
+  let gradient = |cell: i32| {
+    let mut h = (cell as u32) ^ seed.wrapping_mul(0x9e37_79b9);
+    h = (h ^ (h >> 16)).wrapping_mul(0x7feb_352d);
+    h = (h ^ (h >> 15)).wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+    (h as f32 / u32::MAX as f32) * 2.0 - 1.0
+  };
+  let cell = t.floor();
+  let frac = t - cell;
+  let a = gradient(cell as i32) * frac;
+  let b = gradient(cell as i32 + 1) * (frac - 1.0);
+  // quintic fade, the curve and its slope are continuous across cells
+  let fade = frac * frac * frac * (frac * (frac * 6.0 - 15.0) + 10.0);
+  // gradient noise peaks at 0.5, scale it up to cover -1.0..1.0
+  ((a + (b - a) * fade) * 2.0).clamp(-1.0, 1.0)
+}
+
+/// Shake the camera when the player collides with this entity
+#[derive(Component)]
+pub struct ShakeOnImpact {
+  pub trauma: f32,
+}
+
+/// Undo the offset of the last frame, so the camera systems see the unshaken transform
+pub fn remove_camera_shake(mut camera_q: Query<(&mut CameraTrauma, &mut Transform)>) {
+  for (mut trauma, mut transform) in &mut camera_q {
+    if trauma.applied != Transform::IDENTITY {
+      let offset = transform.rotation * trauma.applied.translation;
+      transform.translation -= offset;
+      transform.rotation *= trauma.applied.rotation.inverse();
+      trauma.applied = Transform::IDENTITY;
+    }
+  }
+}
+
+pub fn receive_camera_shake(
+  mut evr_shake: EventReader<CameraShake>,
+  mut camera_q: Query<(&mut CameraTrauma, Option<&PlayerCamera>)>,
+) {
+  for ev in evr_shake.read() {
+    for (mut trauma, player_camera) in &mut camera_q {
+      let following = player_camera.map(|player_camera| player_camera.0);
+      if ev.player.is_none() || ev.player == following {
+        trauma.add_trauma(ev.trauma);
+      }
+    }
+  }
+}
+
+pub fn apply_camera_shake(
+  time: Res<Time>,
+  mut camera_q: Query<(&mut CameraTrauma, &mut Transform)>,
+) {
+  for (mut trauma, mut transform) in &mut camera_q {
+    if trauma.trauma <= 0.0 {
+      continue;
+    }
+    trauma.time += time.delta_secs();
+    let offset = trauma.offset();
+    let decay = trauma.decay * time.delta_secs();
+    trauma.trauma = (trauma.trauma - decay).max(0.0);
+
+    // offsets are in camera space, so the shake looks the same from every angle
+    transform.rotation *= offset.rotation;
+    let translation = transform.rotation * offset.translation;
+    transform.translation += translation;
+    trauma.applied = offset;
+  }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn shake_on_landing(
+  mut evw_shake: EventWriter<CameraShake>,
+  player_q: Query<(Entity, &LinearVelocity), (With<Player>, Added<Grounded>)>,
+) {
+  // falls slower than this don't shake, faster ones shake up to full trauma
+  const MIN_SPEED: f32 = 8.0;
+  const MAX_SPEED: f32 = 25.0;
+
+  for (player, velocity) in &player_q {
+    let speed = -velocity.y;
+    if speed > MIN_SPEED {
+      evw_shake.send(CameraShake {
+        trauma: ((speed - MIN_SPEED) / (MAX_SPEED - MIN_SPEED)).clamp(0.1, 1.0),
+        player: Some(player),
+      });
+    }
+  }
+}
+
+pub fn shake_on_impact(
+  mut evr_collisions: EventReader<CollisionStarted>,
+  mut evw_shake: EventWriter<CameraShake>,
+  player_q: Query<(), With<Player>>,
+  impact_q: Query<&ShakeOnImpact>,
+) {
+  for CollisionStarted(a, b) in evr_collisions.read() {
+    let (player, other) = if player_q.contains(*a) {
+      (a, b)
+    } else if player_q.contains(*b) {
+      (b, a)
+    } else {
+      continue;
+    };
+    if let Ok(impact) = impact_q.get(*other) {
+      evw_shake.send(CameraShake {
+        trauma: impact.trauma,
+        player: Some(*player),
+      });
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn trauma_is_capped() {
+    let mut trauma = CameraTrauma::default();
+    trauma.add_trauma(0.6);
+    trauma.add_trauma(0.6);
+    assert_eq!(trauma.trauma, 1.0);
+    trauma.add_trauma(-3.0);
+    assert_eq!(trauma.trauma, 0.0);
+  }
+
+  #[test]
+  fn shake_grows_with_the_square_of_trauma() {
+    let mut trauma = CameraTrauma {
+      time: 0.37,
+      ..default()
+    };
+    assert_eq!(trauma.offset(), Transform::IDENTITY);
+
+    trauma.trauma = 0.5;
+    let half = trauma.offset().translation;
+    trauma.trauma = 1.0;
+    let full = trauma.offset().translation;
+    assert!(full != Vec3::ZERO);
+    assert!((full / 4.0).distance(half) < 1e-6);
+    assert!(full.abs().cmple(trauma.max_translation).all());
+  }
+
+  #[test]
+  fn noise_is_bounded_and_smooth() {
+    for step in 0..1000 {
+      let t = step as f32 * 0.01;
+      let value = noise(7, t);
+      assert!((-1.0..=1.0).contains(&value));
+      assert!((noise(7, t + 0.001) - value).abs() < 0.05);
+      assert_eq!(noise(7, t), value);
+    }
+    assert!(noise(1, 0.5) != noise(2, 0.5));
+  }
+}
@@ -0,0 +1,190 @@
+use bevy::prelude::*;
+use shared::checkpoint::{CheckpointActivated, CheckpointPlugin, Respawn, RespawnPoint};
+use shared::controller::Player;
+use shared::state::PlayState;
+use std::f32::consts::TAU;
+
+use crate::systems::camera::{PanOrbitSettings, PanOrbitState};
+use crate::systems::local_players::{InputDevices, PlayerCamera};
+use crate::systems::network::playing_online;
+
+/// The shared checkpoints and respawning, with what players see and press of them
+pub struct CheckpointEffectsPlugin;
+
+impl Plugin for CheckpointEffectsPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .add_plugins(CheckpointPlugin)
+      .init_resource::<CheckpointSettings>()
+      .add_systems(
+        Update,
+        (
+          // the server does not know of it, falling is the only way to respawn online
+          respawn_input.run_if(not(playing_online)),
+          reset_respawned_cameras,
+          flash_activated_checkpoints,
+          draw_checkpoint_flash,
+        )
+          .chain()
+          .run_if(in_state(PlayState::Playing)),
+      );
+  }
+}
+
+#[derive(Resource)]
+pub struct CheckpointSettings {
+  /// Key for keyboard and mouse players to respawn
+  pub respawn_key: KeyCode,
+  /// Gamepad button for gamepad players to respawn
+  pub respawn_button: GamepadButton,
+  /// Color of activated checkpoints
+  pub active_color: Color,
+}
+
+impl Default for CheckpointSettings {
+  fn default() -> Self {
+    CheckpointSettings {
+      respawn_key: KeyCode::KeyR,
+      respawn_button: GamepadButton::Select,
+      active_color: Color::srgb(0.2, 1.0, 0.4),
+    }
+  }
+}
+
+/// Ring shown around a checkpoint for a moment after it is activated
+#[derive(Component)]
+struct CheckpointFlash(Timer);
+
+fn flash_activated_checkpoints(
+  mut commands: Commands,
+  mut evr_activated: EventReader<CheckpointActivated>,
+  settings: Res<CheckpointSettings>,
+  mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+  for &CheckpointActivated { checkpoint, .. } in evr_activated.read() {
+    let Some(mut checkpoint) = commands.get_entity(checkpoint) else {
+      continue;
+    };
+    // level materials are shared, so activated checkpoints get one of their own
+    checkpoint.insert((
+      MeshMaterial3d(materials.add(StandardMaterial {
+        base_color: settings.active_color,
+        emissive: settings.active_color.to_linear() * 2.0,
+        ..default()
+      })),
+      CheckpointFlash(Timer::from_seconds(0.6, TimerMode::Once)),
+    ));
+  }
+}
+
+fn respawn_input(
+  kbd: Res<ButtonInput<KeyCode>>,
+  settings: Res<CheckpointSettings>,
+  gamepads: Query<&Gamepad>,
+  player_q: Query<(Entity, &InputDevices), With<Player>>,
+  mut evw_respawn: EventWriter<Respawn>,
+) {
+  for (player, devices) in &player_q {
+    let pressed = (devices.keyboard_mouse && kbd.just_pressed(settings.respawn_key))
+      || devices
+        .gamepad
+        .and_then(|gamepad| gamepads.get(gamepad).ok())
+        .is_some_and(|gamepad| gamepad.just_pressed(settings.respawn_button));
+    if pressed {
+      evw_respawn.send(Respawn { player });
+    }
+  }
+}
+
+/// Put the cameras of respawned players behind them
+pub fn reset_respawned_cameras(
+  mut evr_respawn: EventReader<Respawn>,
+  player_q: Query<&RespawnPoint, With<Player>>,
+  mut camera_q: Query<(
+    &PlayerCamera,
+    &PanOrbitSettings,
+    &mut PanOrbitState,
+    &mut Transform,
+  )>,
+) {
+  for &Respawn { player } in evr_respawn.read() {
+    let Ok(respawn_point) = player_q.get(player) else {
+      continue;
+    };
+    for (player_camera, settings, mut state, mut camera_tfm) in &mut camera_q {
+      if player_camera.0 != player {
+        continue;
+      }
+      let (yaw, _, _) = respawn_point.0.rotation.to_euler(EulerRot::YXZ);
+      state.yaw = settings.clamp_yaw(yaw);
+      state.pitch = settings.clamp_pitch(PanOrbitState::default().pitch);
+      state.center = respawn_point.0.translation;
+      *camera_tfm = state.transform();
+    }
+  }
+}
+
+fn draw_checkpoint_flash(
+  mut commands: Commands,
+  time: Res<Time>,
+  mut gizmos: Gizmos,
+  settings: Res<CheckpointSettings>,
+  mut flash_q: Query<(Entity, &GlobalTransform, &mut CheckpointFlash)>,
+) {
+  for (entity, transform, mut flash) in &mut flash_q {
+    flash.0.tick(time.delta());
+    if flash.0.finished() {
+      commands.entity(entity).remove::<CheckpointFlash>();
+      continue;
+    }
+    let t = flash.0.fraction();
+    gizmos.circle(
+      Isometry3d::new(transform.translation(), Quat::from_rotation_x(TAU / 4.0)),
+      0.5 + 2.0 * t,
+      settings.active_color.with_alpha(1.0 - t),
+    );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use shared::checkpoint::Checkpoint;
+
+  #[test]
+  fn respawn_puts_the_camera_behind_the_player() {
+    let mut app = App::new();
+    app
+      .add_event::<Respawn>()
+      .add_systems(Update, reset_respawned_cameras);
+    let spawn = Checkpoint::new(
+      &Transform::from_xyz(3.0, 2.0, -9.0).with_rotation(Quat::from_rotation_y(1.0)),
+    )
+    .spawn;
+    let player = app.world_mut().spawn((Player, RespawnPoint(spawn))).id();
+    let camera = app
+      .world_mut()
+      .spawn((
+        PlayerCamera(player),
+        PanOrbitSettings::default(),
+        PanOrbitState {
+          yaw: -2.5,
+          center: Vec3::new(0.0, -30.0, 40.0),
+          ..default()
+        },
+        Transform::default(),
+      ))
+      .id();
+
+    app.world_mut().send_event(Respawn { player });
+    app.update();
+
+    // behind the player, looking the way the checkpoint faces
+    let world = app.world();
+    let state = world.get::<PanOrbitState>(camera).unwrap();
+    assert!((state.yaw - 1.0).abs() < 1e-5);
+    assert_eq!(state.center, spawn.translation);
+    let camera_tfm = world.get::<Transform>(camera).unwrap();
+    assert!(camera_tfm.forward().dot(*spawn.forward()) > 0.9);
+  }
+}
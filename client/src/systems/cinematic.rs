@@ -0,0 +1,383 @@
+use crate::systems::free_camera::CameraMode;
+use bevy::asset::{AssetLoader, LoadContext, io::Reader};
+use bevy::prelude::*;
+use serde::Deserialize;
+use thiserror::Error;
+
+/// A keyframed camera path, loaded from `*.sequence.ron` files.
+/// Positions and look-at targets follow Catmull-Rom splines through their keys,
+/// the field of view is interpolated between its keys.
+#[derive(Asset, TypePath, Debug, Deserialize)]
+pub struct CameraSequence {
+  /// Keys for the camera position
+  pub position: Vec<Key<Vec3>>,
+  /// Keys for the point the camera looks at
+  pub look_at: Vec<Key<Vec3>>,
+  /// Keys for the vertical field of view, in degrees
+  #[serde(default)]
+  pub fov: Vec<Key<f32>>,
+  /// Seconds to blend back to the follow camera once the sequence is over
+  #[serde(default = "default_blend_out")]
+  pub blend_out: f32,
+}
+
+/// A value at a point in time of a `CameraSequence`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Key<T> {
+  /// Seconds since the start of the sequence
+  pub time: f32,
+  pub value: T,
+  /// Easing from this key to the next one
+  #[serde(default = "default_ease")]
+  pub ease: EaseFunction,
+}
+
+fn default_blend_out() -> f32 {
+  1.0
+}
+
+fn default_ease() -> EaseFunction {
+  EaseFunction::Linear
+}
+
+/// Where the camera is and what it looks at, at one point of a sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraPose {
+  pub translation: Vec3,
+  pub look_at: Vec3,
+  /// Vertical field of view in radians, `None` keeps the camera's own
+  pub fov: Option<f32>,
+}
+
+impl CameraSequence {
+  /// Seconds until the last key of any track
+  pub fn duration(&self) -> f32 {
+    [
+      self.position.last().map(|key| key.time),
+      self.look_at.last().map(|key| key.time),
+      self.fov.last().map(|key| key.time),
+    ]
+    .into_iter()
+    .flatten()
+    .fold(0.0, f32::max)
+  }
+
+  /// The pose `time` seconds into the sequence, `None` if a spline has no keys
+  pub fn sample(&self, time: f32) -> Option<CameraPose> {
+    Some(CameraPose {
+      translation: sample_spline(&self.position, time)?,
+      look_at: sample_spline(&self.look_at, time)?,
+      fov: sample_linear(&self.fov, time).map(f32::to_radians),
+    })
+  }
+}
+
+impl CameraPose {
+  pub fn transform(&self) -> Transform {
+    Transform::from_translation(self.translation).looking_at(self.look_at, Vec3::Y)
+  }
+}
+
+// The key to start from and the eased progress towards the next one
+fn segment<T>(keys: &[Key<T>], time: f32) -> Option<(usize, f32)> {
+  let last = keys.len().checked_sub(1)?;
+  let next = keys.partition_point(|key| key.time <= time);
+  if next == 0 {
+    return Some((0, 0.0));
+  }
+  if next > last {
+    return Some((last, 0.0));
+  }
+  let (from, to) = (&keys[next - 1], &keys[next]);
+  let span = to.time - from.time;
+  let progress = if span > 0.0 {
+    (time - from.time) / span
+  } else {
+    1.0
+  };
+  let eased = EasingCurve::new(0.0, 1.0, from.ease).sample_clamped(progress);
+  Some((next - 1, eased))
+}
+
+/// Sample a Catmull-Rom spline passing through every key, the ends are clamped
+pub fn sample_spline(keys: &[Key<Vec3>], time: f32) -> Option<Vec3> {
+  let (i, t) = segment(keys, time)?;
+  let point = |index: isize| {
+    let index = index.clamp(0, keys.len() as isize - 1) as usize;
+    keys[index].value
+  };
+  let i = i as isize;
+  Some(catmull_rom(
+    point(i - 1),
+    point(i),
+    point(i + 1),
+    point(i + 2),
+    t,
+  ))
+}
+
+/// Sample a value interpolated linearly (after easing) between the keys
+pub fn sample_linear(keys: &[Key<f32>], time: f32) -> Option<f32> {
+  let (i, t) = segment(keys, time)?;
+  let from = keys[i].value;
+  let to = keys.get(i + 1).map_or(from, |key| key.value);
+  Some(from + (to - from) * t)
+}
+
+/// The point at `t` between `p1` and `p2` on a uniform Catmull-Rom spline
+pub fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3 {
+  let t2 = t * t;
+  let t3 = t2 * t;
+  0.5
+    * ((2.0 * p1)
+      + (p2 - p0) * t
+      + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+      + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+#[derive(Default)]
+pub struct CameraSequenceLoader;
+
+#[derive(Debug, Error)]
+pub enum CameraSequenceLoaderError {
+  #[error("could not read camera sequence: {0}")]
+  Io(#[from] std::io::Error),
+  #[error("could not parse camera sequence: {0}")]
+  Ron(#[from] ron::error::SpannedError),
+  #[error("camera sequence has no {0} keys")]
+  EmptyTrack(&'static str),
+}
+
+impl CameraSequence {
+  /// Parse a sequence, it needs keys for where the camera is and what it looks at
+  pub fn from_ron(bytes: &[u8]) -> Result<Self, CameraSequenceLoaderError> {
+    let sequence: CameraSequence = ron::de::from_bytes(bytes)?;
+    if sequence.position.is_empty() {
+      return Err(CameraSequenceLoaderError::EmptyTrack("position"));
+    }
+    if sequence.look_at.is_empty() {
+      return Err(CameraSequenceLoaderError::EmptyTrack("look_at"));
+    }
+    Ok(sequence)
+  }
+}
+
+impl AssetLoader for CameraSequenceLoader {
+  type Asset = CameraSequence;
+  type Settings = ();
+  type Error = CameraSequenceLoaderError;
+
+  async fn load(
+    &self,
+    reader: &mut dyn Reader,
+    _settings: &(),
+    _load_context: &mut LoadContext<'_>,
+  ) -> Result<Self::Asset, Self::Error> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).await?;
+    CameraSequence::from_ron(&bytes)
+  }
+
+  fn extensions(&self) -> &[&str] {
+    &["sequence.ron"]
+  }
+}
+
+/// Send to play a sequence on the follow camera, e.g. when entering a game state
+#[derive(Event, Debug, Clone)]
+pub struct PlayCameraSequence(pub Handle<CameraSequence>);
+
+/// Drives the camera along a sequence, present while it plays and blends out
+#[derive(Component)]
+pub struct CameraSequencePlayer {
+  pub sequence: Handle<CameraSequence>,
+  /// Key to skip straight to blending back
+  pub skip_key: KeyCode,
+  elapsed: f32,
+  // pose and progress of the blend back, once the sequence is over
+  blend: Option<(CameraPose, f32)>,
+  // field of view from before the sequence, restored when blending back
+  original_fov: f32,
+}
+
+pub fn start_camera_sequence(
+  mut commands: Commands,
+  mut evr_play: EventReader<PlayCameraSequence>,
+  mut next_mode: ResMut<NextState<CameraMode>>,
+  camera_q: Query<(Entity, &Projection), With<Camera3d>>,
+) {
+  let Some(PlayCameraSequence(sequence)) = evr_play.read().last() else {
+    return;
+  };
+  for (entity, projection) in &camera_q {
+    let original_fov = match projection {
+      Projection::Perspective(perspective) => perspective.fov,
+      Projection::Orthographic(_) => PerspectiveProjection::default().fov,
+    };
+    commands.entity(entity).insert(CameraSequencePlayer {
+      sequence: sequence.clone(),
+      skip_key: KeyCode::Enter,
+      elapsed: 0.0,
+      blend: None,
+      original_fov,
+    });
+  }
+  next_mode.set(CameraMode::Cinematic);
+}
+
+/// Runs after `camera_follow`, so the transform it finds is where the follow camera wants to be
+pub fn play_camera_sequence(
+  mut commands: Commands,
+  time: Res<Time>,
+  kbd: Res<ButtonInput<KeyCode>>,
+  asset_server: Res<AssetServer>,
+  sequences: Res<Assets<CameraSequence>>,
+  mut next_mode: ResMut<NextState<CameraMode>>,
+  mut camera_q: Query<(
+    Entity,
+    &mut CameraSequencePlayer,
+    &mut Transform,
+    &mut Projection,
+  )>,
+) {
+  for (entity, mut player, mut transform, mut projection) in &mut camera_q {
+    let Some(sequence) = sequences.get(&player.sequence) else {
+      // hand the camera back right away if the sequence will never load
+      if asset_server.load_state(&player.sequence).is_failed() {
+        commands.entity(entity).remove::<CameraSequencePlayer>();
+        next_mode.set(CameraMode::Follow);
+      }
+      continue;
+    };
+    player.elapsed += time.delta_secs();
+    let Projection::Perspective(perspective) = projection.as_mut() else {
+      continue;
+    };
+
+    let duration = sequence.duration();
+    if player.blend.is_none() && (player.elapsed >= duration || kbd.just_pressed(player.skip_key)) {
+      let Some(pose) = sequence.sample(player.elapsed.min(duration)) else {
+        // nothing to blend back from
+        commands.entity(entity).remove::<CameraSequencePlayer>();
+        next_mode.set(CameraMode::Follow);
+        continue;
+      };
+      player.blend = Some((pose, 0.0));
+    }
+
+    match player.blend {
+      None => {
+        if let Some(pose) = sequence.sample(player.elapsed) {
+          *transform = pose.transform();
+          if let Some(fov) = pose.fov {
+            perspective.fov = fov;
+          }
+        }
+      }
+      Some((from, progress)) => {
+        let progress = if sequence.blend_out > 0.0 {
+          progress + time.delta_secs() / sequence.blend_out
+        } else {
+          1.0
+        };
+        let t = EasingCurve::new(0.0, 1.0, EaseFunction::SineInOut).sample_clamped(progress);
+        let from_tfm = from.transform();
+        let from_fov = from.fov.unwrap_or(player.original_fov);
+        transform.translation = from_tfm.translation.lerp(transform.translation, t);
+        transform.rotation = from_tfm.rotation.slerp(transform.rotation, t);
+        perspective.fov = from_fov + (player.original_fov - from_fov) * t;
+        player.blend = Some((from, progress));
+
+        if progress >= 1.0 {
+          commands.entity(entity).remove::<CameraSequencePlayer>();
+          next_mode.set(CameraMode::Follow);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn key<T>(time: f32, value: T) -> Key<T> {
+    Key {
+      time,
+      value,
+      ease: EaseFunction::Linear,
+    }
+  }
+
+  #[test]
+  fn catmull_rom_passes_through_control_points() {
+    let points = [Vec3::ZERO, Vec3::X, Vec3::new(2.0, 1.0, 0.0), Vec3::Y * 3.0];
+    assert_eq!(
+      catmull_rom(points[0], points[1], points[2], points[3], 0.0),
+      points[1]
+    );
+    assert!(
+      catmull_rom(points[0], points[1], points[2], points[3], 1.0).distance(points[2]) < 1e-5
+    );
+  }
+
+  #[test]
+  fn spline_hits_keys_and_clamps_ends() {
+    let keys = [
+      key(0.0, Vec3::ZERO),
+      key(1.0, Vec3::X),
+      key(3.0, Vec3::new(1.0, 0.0, 4.0)),
+    ];
+    assert_eq!(sample_spline(&keys, -1.0), Some(Vec3::ZERO));
+    assert_eq!(sample_spline(&keys, 1.0), Some(Vec3::X));
+    assert_eq!(sample_spline(&keys, 10.0), Some(Vec3::new(1.0, 0.0, 4.0)));
+    assert_eq!(sample_spline(&[], 0.0), None);
+  }
+
+  #[test]
+  fn linear_keys_are_interpolated_with_easing() {
+    let mut keys = [key(0.0, 40.0), key(2.0, 60.0)];
+    assert_eq!(sample_linear(&keys, 1.0), Some(50.0));
+
+    keys[0].ease = EaseFunction::QuadraticIn;
+    assert_eq!(sample_linear(&keys, 1.0), Some(45.0));
+    assert_eq!(sample_linear(&keys, 5.0), Some(60.0));
+  }
+
+  #[test]
+  fn duration_is_the_last_key_of_any_track() {
+    let sequence = CameraSequence {
+      position: vec![key(0.0, Vec3::ZERO), key(4.0, Vec3::X)],
+      look_at: vec![key(0.0, Vec3::ZERO)],
+      fov: vec![key(0.0, 45.0), key(6.0, 60.0)],
+      blend_out: 1.0,
+    };
+    assert_eq!(sequence.duration(), 6.0);
+    assert_eq!(
+      sequence.sample(6.0).unwrap().fov,
+      Some(60.0f32.to_radians())
+    );
+  }
+
+  #[test]
+  fn arena_intro_parses() {
+    let sequence = CameraSequence::from_ron(include_bytes!(
+      "../../assets/sequences/arena_intro.sequence.ron"
+    ))
+    .unwrap();
+    assert!(sequence.duration() > 0.0);
+    assert!(sequence.sample(0.0).is_some());
+  }
+
+  #[test]
+  fn sequences_without_a_path_are_rejected() {
+    let error =
+      CameraSequence::from_ron(b"(position: [], look_at: [(time: 0.0, value: (0.0, 0.0, 0.0))])")
+        .unwrap_err();
+    assert_eq!(error.to_string(), "camera sequence has no position keys");
+    let error =
+      CameraSequence::from_ron(b"(position: [(time: 0.0, value: (0.0, 0.0, 0.0))], look_at: [])")
+        .unwrap_err();
+    assert_eq!(error.to_string(), "camera sequence has no look_at keys");
+  }
+}
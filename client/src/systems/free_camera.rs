@@ -0,0 +1,184 @@
+use crate::systems::local_players::{InputDevices, PlayerCamera, uses_keyboard_mouse};
+use bevy::input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel};
+use bevy::prelude::*;
+use shared::state::GameState;
+use std::f32::consts::FRAC_PI_2;
+
+/// Which controller drives the camera while in game
+#[derive(SubStates, Clone, Copy, Default, Eq, PartialEq, Debug, Hash)]
+#[source(GameState = GameState::Game)]
+pub enum CameraMode {
+  /// The pan-orbit camera follows the player
+  #[default]
+  Follow,
+  /// A debug camera flies freely through the level, the player gets no input
+  FreeFly,
+  /// A camera sequence plays, the player gets no input
+  Cinematic,
+}
+
+/// The configuration of the free-fly camera
+#[derive(Resource)]
+pub struct FreeFlySettings {
+  /// Key to switch between the follow and free-fly cameras
+  pub toggle_key: KeyCode,
+  /// World units per second
+  pub speed: f32,
+  /// Slowest speed reachable by scrolling
+  pub min_speed: f32,
+  /// Fastest speed reachable by scrolling
+  pub max_speed: f32,
+  /// Speed multiplier while holding the boost key
+  pub boost: f32,
+  /// Key to hold for boosting
+  pub boost_key: KeyCode,
+  /// Radians per pixel of mouse motion
+  pub look_sensitivity: f32,
+  /// Exponent per scroll line
+  pub scroll_sensitivity: f32,
+}
+
+impl Default for FreeFlySettings {
+  fn default() -> Self {
+    FreeFlySettings {
+      toggle_key: KeyCode::F1,
+      speed: 8.0,
+      min_speed: 0.5,
+      max_speed: 200.0,
+      boost: 4.0,
+      boost_key: KeyCode::ShiftLeft,
+      look_sensitivity: 0.1f32.to_radians(), // 0.1 degree per pixel
+      scroll_sensitivity: 0.1,
+    }
+  }
+}
+
+/// The look angles of the free-fly camera, only present while flying
+#[derive(Component)]
+pub struct FreeFlyState {
+  pub yaw: f32,
+  pub pitch: f32,
+}
+
+pub fn toggle_free_fly(
+  kbd: Res<ButtonInput<KeyCode>>,
+  settings: Res<FreeFlySettings>,
+  mode: Res<State<CameraMode>>,
+  mut next_mode: ResMut<NextState<CameraMode>>,
+) {
+  if kbd.just_pressed(settings.toggle_key) {
+    match mode.get() {
+      CameraMode::Follow => next_mode.set(CameraMode::FreeFly),
+      CameraMode::FreeFly => next_mode.set(CameraMode::Follow),
+      CameraMode::Cinematic => {}
+    }
+  }
+}
+
+/// Start flying from wherever the follow camera of the keyboard and mouse player currently is
+pub fn enter_free_fly(
+  mut commands: Commands,
+  camera_q: Query<(Entity, &Transform, Option<&PlayerCamera>), With<Camera3d>>,
+  devices_q: Query<&InputDevices>,
+) {
+  for (entity, transform, player_camera) in &camera_q {
+    if !uses_keyboard_mouse(player_camera, &devices_q) {
+      continue;
+    }
+    let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+    commands.entity(entity).insert(FreeFlyState { yaw, pitch });
+  }
+}
+
+/// Hand the camera back, the follow systems recompute its transform from `PanOrbitState`
+pub fn exit_free_fly(mut commands: Commands, camera_q: Query<Entity, With<FreeFlyState>>) {
+  for entity in &camera_q {
+    commands.entity(entity).remove::<FreeFlyState>();
+  }
+}
+
+pub fn free_fly_camera(
+  time: Res<Time>,
+  kbd: Res<ButtonInput<KeyCode>>,
+  mut settings: ResMut<FreeFlySettings>,
+  mut evr_motion: EventReader<MouseMotion>,
+  mut evr_scroll: EventReader<MouseWheel>,
+  mut camera_q: Query<(&mut FreeFlyState, &mut Transform)>,
+) {
+  let motion: Vec2 = evr_motion.read().map(|ev| ev.delta).sum();
+  let scroll: f32 = evr_scroll
+    .read()
+    .map(|ev| match ev.unit {
+      MouseScrollUnit::Line => ev.y,
+      // 1 "line" == 16 "pixels of motion"
+      MouseScrollUnit::Pixel => ev.y / 16.0,
+    })
+    .sum();
+
+  // scrolling scales the speed exponentially, like zooming the orbit camera
+  if scroll != 0.0 {
+    settings.speed = (settings.speed * (scroll * settings.scroll_sensitivity).exp())
+      .clamp(settings.min_speed, settings.max_speed);
+  }
+
+  let direction = fly_direction(&kbd);
+  let mut speed = settings.speed;
+  if kbd.pressed(settings.boost_key) {
+    speed *= settings.boost;
+  }
+
+  for (mut state, mut transform) in &mut camera_q {
+    fly(
+      &mut state,
+      &mut transform,
+      motion * settings.look_sensitivity,
+      direction,
+      speed * time.delta_secs(),
+    );
+  }
+}
+
+/// Where WASD, E/Space and Q/Ctrl point, in camera space
+pub fn fly_direction(kbd: &ButtonInput<KeyCode>) -> Vec3 {
+  let mut direction = Vec3::ZERO;
+  if kbd.pressed(KeyCode::KeyW) {
+    direction += Vec3::NEG_Z;
+  }
+  if kbd.pressed(KeyCode::KeyS) {
+    direction += Vec3::Z;
+  }
+  if kbd.pressed(KeyCode::KeyA) {
+    direction += Vec3::NEG_X;
+  }
+  if kbd.pressed(KeyCode::KeyD) {
+    direction += Vec3::X;
+  }
+  if kbd.pressed(KeyCode::KeyE) || kbd.pressed(KeyCode::Space) {
+    direction += Vec3::Y;
+  }
+  if kbd.pressed(KeyCode::KeyQ) || kbd.pressed(KeyCode::ControlLeft) {
+    direction += Vec3::NEG_Y;
+  }
+  direction
+}
+
+/// Turn by `look` radians (x = yaw, y = pitch) and move `distance` towards `direction`,
+/// given in camera space
+pub fn fly(
+  state: &mut FreeFlyState,
+  transform: &mut Transform,
+  look: Vec2,
+  direction: Vec3,
+  distance: f32,
+) {
+  state.yaw -= look.x;
+  // stop just short of straight up or down, to keep the yaw meaningful
+  state.pitch = (state.pitch - look.y).clamp(-FRAC_PI_2 + 0.01, FRAC_PI_2 - 0.01);
+  transform.rotation = Quat::from_euler(EulerRot::YXZ, state.yaw, state.pitch, 0.0);
+
+  // fly where we look, up and down stay vertical
+  let velocity = (transform.rotation * Vec3::new(direction.x, 0.0, direction.z)
+    + Vec3::Y * direction.y)
+    .normalize_or_zero();
+  transform.translation += velocity * distance;
+}
@@ -0,0 +1,253 @@
+use bevy::asset::LoadedFolder;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use shared::level::{Level, LevelEntity, LevelSpawner, PrefabRegistry, dynamic_prop};
+use shared::state::GameState;
+
+use crate::game_states::loading::GameAssets;
+use crate::systems::camera_shake::ShakeOnImpact;
+use crate::systems::lock_on::Targetable;
+use crate::systems::save_game::SaveId;
+
+/// The simulation prefabs, with what the game adds on top of them
+pub fn prefab_registry() -> PrefabRegistry {
+  let mut registry = PrefabRegistry::default();
+  registry
+    .register("prop", |entity, object| {
+      dynamic_prop(entity, object);
+      entity.insert(ShakeOnImpact { trauma: 0.3 });
+    })
+    .register("target", |entity, object| {
+      dynamic_prop(entity, object);
+      entity.insert((ShakeOnImpact { trauma: 0.3 }, Targetable));
+    });
+  registry
+}
+
+/// Spawn the objects of a level with the `SaveId`s saves find them by
+pub fn spawn_saved_level(spawner: &mut LevelSpawner, level: &Level) {
+  for (index, entity) in spawner.spawn_level(level).into_iter().enumerate() {
+    if let Some(entity) = entity {
+      spawner
+        .commands
+        .entity(entity)
+        .insert(SaveId::Object(index));
+    }
+  }
+}
+
+/// Respawn the level when its file changes, players and cameras stay where they are.
+/// Files are only watched with the `dev` feature.
+pub fn reload_level(
+  mut spawner: LevelSpawner,
+  mut evr_level: EventReader<AssetEvent<Level>>,
+  state: Res<State<GameState>>,
+  game_assets: Res<GameAssets>,
+  levels: Res<Assets<Level>>,
+  level_q: Query<Entity, With<LevelEntity>>,
+) {
+  // the game just spawned the level as it is, with what the editor changed meanwhile
+  if state.is_changed() {
+    evr_level.clear();
+    return;
+  }
+  if !evr_level
+    .read()
+    .any(|ev| ev.is_modified(&game_assets.level))
+  {
+    return;
+  }
+  let Some(level) = levels.get(&game_assets.level) else {
+    return;
+  };
+  info!("Reloading level {:?}", level.name);
+  for entity in &level_q {
+    spawner.commands.entity(entity).despawn_recursive();
+  }
+  spawn_saved_level(&mut spawner, level);
+}
+
+/// Load every level in `assets/levels`, for the level list
+pub fn load_level_list(mut commands: Commands, asset_server: Res<AssetServer>) {
+  commands.insert_resource(LevelFolder(asset_server.load_folder("levels")));
+}
+
+/// The loaded `assets/levels` folder
+#[derive(Resource)]
+pub struct LevelFolder(pub Handle<LoadedFolder>);
+
+/// The level played next, as an asset path
+#[derive(Resource)]
+pub struct SelectedLevel(pub String);
+
+impl Default for SelectedLevel {
+  fn default() -> Self {
+    SelectedLevel("levels/arena.level.ron".to_string())
+  }
+}
+
+/// Every level in `assets/levels`, for picking one from a list
+#[derive(SystemParam)]
+pub struct LevelList<'w> {
+  folder: Option<Res<'w, LevelFolder>>,
+  folders: Res<'w, Assets<LoadedFolder>>,
+  levels: Res<'w, Assets<Level>>,
+  asset_server: Res<'w, AssetServer>,
+  selected: ResMut<'w, SelectedLevel>,
+}
+
+impl LevelList<'_> {
+  /// Paths and names of the loaded levels, sorted by path
+  pub fn levels(&self) -> Vec<(String, String)> {
+    let Some(folder) = self
+      .folder
+      .as_ref()
+      .and_then(|folder| self.folders.get(&folder.0))
+    else {
+      return Vec::new();
+    };
+    let mut list: Vec<_> = folder
+      .handles
+      .iter()
+      .filter_map(|handle| {
+        let handle = handle.clone().try_typed::<Level>().ok()?;
+        let path = self.asset_server.get_path(&handle)?.to_string();
+        Some((path, self.levels.get(&handle)?.name.clone()))
+      })
+      .collect();
+    list.sort();
+    list
+  }
+
+  /// Name of the selected level, once it is loaded
+  pub fn selected_name(&self) -> Option<String> {
+    self
+      .levels()
+      .into_iter()
+      .find(|(path, _)| *path == self.selected.0)
+      .map(|(_, name)| name)
+  }
+
+  /// Select the level after the selected one, wrapping around
+  pub fn select_next(&mut self) {
+    let levels = self.levels();
+    if levels.is_empty() {
+      return;
+    }
+    let next = levels
+      .iter()
+      .position(|(path, _)| *path == self.selected.0)
+      .map_or(0, |index| (index + 1) % levels.len());
+    self.selected.0 = levels[next].0.clone();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::systems::save_game::{SavedComponents, read_save, write_save};
+  use avian3d::prelude::RigidBody;
+  use shared::HeadlessPlugins;
+
+  fn parse(source: &str) -> Level {
+    ron::de::from_str(source).unwrap()
+  }
+
+  #[test]
+  fn bundled_levels_parse_with_known_prefabs() {
+    let registry = prefab_registry();
+    for source in [
+      include_str!("../../assets/levels/arena.level.ron"),
+      include_str!("../../assets/levels/playground.level.ron"),
+    ] {
+      let level = parse(source);
+      assert!(!level.name.is_empty());
+      assert!(!level.spawn_points.is_empty());
+      for object in &level.objects {
+        assert!(registry.get(&object.prefab).is_some(), "{}", object.prefab);
+        if let Some(material) = &object.material {
+          assert!(level.materials.contains_key(material), "{material}");
+        }
+      }
+    }
+  }
+
+  /// A game of the playground level, without the level spawned, the level's handle
+  fn game() -> (App, Handle<Level>) {
+    let mut app = App::new();
+    app
+      .add_plugins(HeadlessPlugins)
+      .register_type::<SaveId>()
+      .init_asset::<Level>()
+      .insert_resource(prefab_registry())
+      .init_resource::<SavedComponents>()
+      .init_state::<GameState>()
+      .add_systems(Update, reload_level.run_if(in_state(GameState::Game)));
+    let level = parse(include_str!("../../assets/levels/playground.level.ron"));
+    let level = app.world_mut().resource_mut::<Assets<Level>>().add(level);
+    app.insert_resource(GameAssets {
+      level: level.clone(),
+      intro: None,
+    });
+    set_state(&mut app, GameState::Game);
+    (app, level)
+  }
+
+  fn set_state(app: &mut App, state: GameState) {
+    app
+      .world_mut()
+      .resource_mut::<NextState<GameState>>()
+      .set(state);
+    app.update();
+  }
+
+  /// Modify the level as its file changing or the editor would, the event is sent at the end
+  /// of the next update
+  fn modify(app: &mut App, level: &Handle<Level>) {
+    app
+      .world_mut()
+      .resource_mut::<Assets<Level>>()
+      .get_mut(level);
+    app.update();
+  }
+
+  fn level_entities(app: &mut App) -> usize {
+    let world = app.world_mut();
+    world
+      .query_filtered::<Entity, With<LevelEntity>>()
+      .iter(world)
+      .count()
+  }
+
+  #[test]
+  fn edits_made_outside_the_game_do_not_respawn_the_level() {
+    let (mut app, level) = game();
+    set_state(&mut app, GameState::Editor);
+    modify(&mut app, &level);
+    set_state(&mut app, GameState::Game);
+    app.update();
+    assert_eq!(level_entities(&mut app), 0);
+
+    modify(&mut app, &level);
+    app.update();
+    assert!(level_entities(&mut app) > 0);
+  }
+
+  #[test]
+  fn reloaded_levels_are_saved() {
+    let (mut app, level) = game();
+    modify(&mut app, &level);
+    app.update();
+
+    let world = app.world_mut();
+    let props = world
+      .query::<&RigidBody>()
+      .iter(world)
+      .filter(|rigid_body| rigid_body.is_dynamic())
+      .count();
+    assert!(props > 0);
+    let contents = write_save(world, "levels/playground.level.ron").unwrap();
+    let save = read_save(&contents, &world.resource::<AppTypeRegistry>().read()).unwrap();
+    assert_eq!(save.scene.entities.len(), props);
+  }
+}
@@ -0,0 +1,225 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::bevy_egui::{EguiContexts, egui};
+use shared::net::{ClientState, DEFAULT_PORT, LobbyRequest, MAX_ROOM_NAME};
+use shared::state::GameState;
+
+use crate::systems::network::{Server, ServerNotice, resolve};
+
+/// The server browser of the main menu: connect to a server, pick or open a room,
+/// and get ready. Everyone in the room enters the game once they all are.
+pub struct LobbyPlugin;
+
+impl Plugin for LobbyPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .insert_resource(LobbyPanel::from_args(std::env::args()))
+      .add_systems(Startup, open_when_connecting)
+      .add_systems(
+        Update,
+        (show_notices, lobby_panel)
+          .chain()
+          .run_if(in_state(GameState::MainMenu)),
+      );
+  }
+}
+
+/// Whether the lobby is shown, with what is being typed into it
+#[derive(Resource, Debug)]
+pub struct LobbyPanel {
+  pub open: bool,
+  /// Where to connect, a host name or an IP with a port
+  pub address: String,
+  pub room_name: String,
+  pub max_players: u8,
+  /// The latest thing the server or the connection had to say
+  pub notice: Option<String>,
+}
+
+impl LobbyPanel {
+  /// Connects to the `--connect <address>` argument, or a server on this machine
+  pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+    let address = args
+      .into_iter()
+      .skip_while(|arg| arg != "--connect")
+      .nth(1)
+      .unwrap_or_else(|| format!("127.0.0.1:{DEFAULT_PORT}"));
+    LobbyPanel {
+      open: false,
+      address,
+      room_name: "My room".to_string(),
+      max_players: 4,
+      notice: None,
+    }
+  }
+}
+
+/// The main menu keeps its keys to itself while the lobby is open
+pub fn lobby_closed(panel: Res<LobbyPanel>) -> bool {
+  !panel.open
+}
+
+fn open_when_connecting(server: Option<Res<Server>>, mut panel: ResMut<LobbyPanel>) {
+  panel.open = server.is_some();
+}
+
+fn show_notices(mut evr_notice: EventReader<ServerNotice>, mut panel: ResMut<LobbyPanel>) {
+  if let Some(notice) = evr_notice.read().last() {
+    panel.notice = Some(notice.0.clone());
+  }
+}
+
+fn lobby_panel(
+  mut commands: Commands,
+  mut contexts: EguiContexts,
+  mut panel: ResMut<LobbyPanel>,
+  server: Option<ResMut<Server>>,
+  kbd: Res<ButtonInput<KeyCode>>,
+) {
+  if !panel.open {
+    return;
+  }
+  if kbd.just_pressed(KeyCode::Escape) {
+    panel.open = false;
+    return;
+  }
+  let mut open = true;
+  let panel = &mut *panel;
+  egui::Window::new("Online")
+    .open(&mut open)
+    .resizable(false)
+    .show(contexts.ctx_mut(), |ui| {
+      match server {
+        None => connect_form(ui, &mut commands, panel),
+        Some(mut server) => match (server.client.state(), server.room.clone()) {
+          (ClientState::Connected { client }, Some(room)) => {
+            ui.heading(&room.name);
+            for member in &room.members {
+              ui.horizontal(|ui| {
+                let mut label = format!("Player {}", member.client);
+                if member.client == room.host {
+                  label.push_str(" (host)");
+                }
+                if member.client == client {
+                  label.push_str(" (you)");
+                }
+                ui.label(label);
+                ui.label(if member.ready { "ready" } else { "not ready" });
+                if client == room.host && member.client != client {
+                  if ui.button("Kick").clicked() {
+                    server.send_lobby(LobbyRequest::Kick(member.client));
+                  }
+                  if ui.button("Ban").clicked() {
+                    server.send_lobby(LobbyRequest::Ban(member.client));
+                  }
+                }
+              });
+            }
+            ui.label(format!(
+              "{}/{} players",
+              room.members.len(),
+              room.max_players
+            ));
+            ui.separator();
+            ui.horizontal(|ui| {
+              let mut ready = room.member(client).is_some_and(|member| member.ready);
+              if ui.checkbox(&mut ready, "Ready").changed() {
+                server.send_lobby(LobbyRequest::SetReady(ready));
+              }
+              if ui.button("Leave room").clicked() {
+                server.send_lobby(LobbyRequest::LeaveRoom);
+              }
+            });
+          }
+          (ClientState::Connected { .. }, None) => {
+            if server.rooms.is_empty() {
+              ui.label("No rooms yet");
+            }
+            let mut join = None;
+            egui::Grid::new("rooms").striped(true).show(ui, |ui| {
+              for room in &server.rooms {
+                ui.label(&room.name);
+                ui.label(format!("{}/{}", room.players, room.max_players));
+                if room.playing {
+                  ui.label("playing");
+                } else if ui.button("Join").clicked() {
+                  join = Some(room.id);
+                }
+                ui.end_row();
+              }
+            });
+            if let Some(room) = join {
+              server.send_lobby(LobbyRequest::JoinRoom(room));
+            }
+            ui.separator();
+            ui.horizontal(|ui| {
+              ui.add(
+                egui::TextEdit::singleline(&mut panel.room_name)
+                  .char_limit(MAX_ROOM_NAME)
+                  .desired_width(160.0),
+              );
+              ui.add(egui::Slider::new(&mut panel.max_players, 1..=16).text("players"));
+              if ui.button("Create room").clicked() {
+                server.send_lobby(LobbyRequest::CreateRoom {
+                  name: panel.room_name.clone(),
+                  max_players: panel.max_players,
+                });
+              }
+            });
+            if ui.button("Disconnect").clicked() {
+              server.client.disconnect();
+              commands.remove_resource::<Server>();
+            }
+          }
+          _ => {
+            ui.label(format!("Connecting to {}...", server.client.server()));
+            if ui.button("Cancel").clicked() {
+              commands.remove_resource::<Server>();
+            }
+          }
+        },
+      }
+      if let Some(notice) = &panel.notice {
+        ui.separator();
+        ui.label(notice);
+      }
+    });
+  panel.open &= open;
+}
+
+fn connect_form(ui: &mut egui::Ui, commands: &mut Commands, panel: &mut LobbyPanel) {
+  ui.horizontal(|ui| {
+    ui.label("Server");
+    ui.text_edit_singleline(&mut panel.address);
+    if ui.button("Connect").clicked() {
+      let Some(address) = resolve(panel.address.trim()) else {
+        panel.notice = Some(format!("Invalid server address {:?}", panel.address));
+        return;
+      };
+      match Server::connect(address) {
+        Ok(server) => {
+          panel.notice = None;
+          commands.insert_resource(server);
+        }
+        Err(error) => panel.notice = Some(format!("Could not connect to {address}: {error}")),
+      }
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn the_lobby_connects_to_the_connect_argument() {
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+      LobbyPanel::from_args(args(&["client", "--connect", "example.com:5000"])).address,
+      "example.com:5000"
+    );
+    assert_eq!(
+      LobbyPanel::from_args(args(&["client"])).address,
+      format!("127.0.0.1:{DEFAULT_PORT}")
+    );
+  }
+}
@@ -0,0 +1,206 @@
+use bevy::prelude::*;
+use bevy::render::camera::Viewport;
+use bevy::window::PrimaryWindow;
+
+pub const MAX_LOCAL_PLAYERS: usize = 4;
+
+/// How many players share this screen, each gets a camera and a section of the window
+#[derive(Resource)]
+pub struct LocalPlayers {
+  pub count: usize,
+}
+
+impl Default for LocalPlayers {
+  fn default() -> Self {
+    LocalPlayers { count: 1 }
+  }
+}
+
+impl LocalPlayers {
+  /// Read the player count from a `--players <count>` argument, one player by default
+  pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+    let count = args
+      .into_iter()
+      .skip_while(|arg| arg != "--players")
+      .nth(1)
+      .and_then(|count| count.parse().ok())
+      .unwrap_or(1);
+    LocalPlayers {
+      count: count.clamp(1, MAX_LOCAL_PLAYERS),
+    }
+  }
+}
+
+/// Index of a player on this screen
+#[derive(Component, Reflect, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalPlayer(pub usize);
+
+/// The devices a local player reads input from
+#[derive(Component, Reflect, Default, Debug)]
+pub struct InputDevices {
+  /// The keyboard and mouse, only ever given to one player
+  pub keyboard_mouse: bool,
+  /// The gamepad entity, assigned in order of connection
+  pub gamepad: Option<Entity>,
+}
+
+/// Relates a camera to the player it follows and takes input for
+#[derive(Component, Clone, Copy, Debug)]
+pub struct PlayerCamera(pub Entity);
+
+/// Whether the camera is driven by the keyboard and mouse,
+/// cameras without a player always are
+pub fn uses_keyboard_mouse(
+  player_camera: Option<&PlayerCamera>,
+  devices_q: &Query<&InputDevices>,
+) -> bool {
+  player_camera
+    .and_then(|player_camera| devices_q.get(player_camera.0).ok())
+    .is_none_or(|devices| devices.keyboard_mouse)
+}
+
+/// Hand connected gamepads to players without one, in player order,
+/// and take them back from players once they disconnect
+pub fn assign_gamepads(
+  gamepads: Query<Entity, With<Gamepad>>,
+  mut players_q: Query<(&LocalPlayer, &mut InputDevices)>,
+) {
+  let mut players: Vec<_> = players_q.iter_mut().collect();
+  players.sort_by_key(|(local_player, _)| local_player.0);
+
+  for (_, devices) in &mut players {
+    if devices
+      .gamepad
+      .is_some_and(|gamepad| !gamepads.contains(gamepad))
+    {
+      devices.gamepad = None;
+    }
+  }
+
+  // with a single player, the keyboard and a gamepad can be used side by side
+  let single = players.len() == 1;
+  let mut free = gamepads
+    .iter()
+    .filter(|gamepad| !players.iter().any(|(_, d)| d.gamepad == Some(*gamepad)))
+    .collect::<Vec<_>>()
+    .into_iter();
+  for (_, devices) in &mut players {
+    if devices.gamepad.is_none() && (single || !devices.keyboard_mouse) {
+      devices.gamepad = free.next();
+    }
+  }
+}
+
+/// The position and size of a player's section of the window
+pub fn viewport_rect(index: usize, count: usize, window_size: UVec2) -> (UVec2, UVec2) {
+  let columns = if count > 1 { 2 } else { 1 };
+  let rows = if count > 2 { 2 } else { 1 };
+  let size = window_size / UVec2::new(columns, rows);
+  let cell = UVec2::new(index as u32 % columns, index as u32 / columns);
+  (cell * size, size)
+}
+
+pub fn set_camera_viewports(
+  windows: Query<&Window, With<PrimaryWindow>>,
+  local_players: Res<LocalPlayers>,
+  players_q: Query<&LocalPlayer>,
+  mut camera_q: Query<(&PlayerCamera, &mut Camera)>,
+) {
+  let Ok(window) = windows.get_single() else {
+    return;
+  };
+  for (player_camera, mut camera) in &mut camera_q {
+    let Ok(local_player) = players_q.get(player_camera.0) else {
+      continue;
+    };
+    let rect = (local_players.count > 1)
+      .then(|| viewport_rect(local_player.0, local_players.count, window.physical_size()));
+    let current = camera
+      .viewport
+      .as_ref()
+      .map(|viewport| (viewport.physical_position, viewport.physical_size));
+    // only touch the camera when the layout changed, e.g. on resize
+    if current == rect {
+      continue;
+    }
+    camera.viewport = rect.map(|(physical_position, physical_size)| Viewport {
+      physical_position,
+      physical_size,
+      ..default()
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bevy::ecs::system::RunSystemOnce;
+
+  #[test]
+  fn viewports_split_the_window() {
+    let window = UVec2::new(1600, 900);
+    assert_eq!(viewport_rect(0, 1, window), (UVec2::ZERO, window));
+    assert_eq!(
+      viewport_rect(1, 2, window),
+      (UVec2::new(800, 0), UVec2::new(800, 900))
+    );
+    assert_eq!(
+      viewport_rect(2, 3, window),
+      (UVec2::new(0, 450), UVec2::new(800, 450))
+    );
+    assert_eq!(
+      viewport_rect(3, 4, window),
+      (UVec2::new(800, 450), UVec2::new(800, 450))
+    );
+  }
+
+  #[test]
+  fn gamepads_go_to_players_without_a_keyboard() {
+    let mut world = World::new();
+    let players = [0, 1, 2].map(|index| {
+      world
+        .spawn((
+          LocalPlayer(index),
+          InputDevices {
+            keyboard_mouse: index == 0,
+            gamepad: None,
+          },
+        ))
+        .id()
+    });
+    let gamepad = world.spawn(Gamepad::default()).id();
+    let gamepad_of = |world: &World, player| world.get::<InputDevices>(player).unwrap().gamepad;
+
+    world.run_system_once(assign_gamepads).unwrap();
+    assert_eq!(gamepad_of(&world, players[0]), None);
+    assert_eq!(gamepad_of(&world, players[1]), Some(gamepad));
+    assert_eq!(gamepad_of(&world, players[2]), None);
+
+    // a second gamepad goes to the next player, and a disconnected one is taken back
+    let second = world.spawn(Gamepad::default()).id();
+    world.despawn(gamepad);
+    world.run_system_once(assign_gamepads).unwrap();
+    assert_eq!(gamepad_of(&world, players[1]), Some(second));
+    assert_eq!(gamepad_of(&world, players[2]), None);
+  }
+
+  #[test]
+  fn a_single_player_uses_the_keyboard_and_a_gamepad() {
+    let mut world = World::new();
+    let player = world
+      .spawn((
+        LocalPlayer(0),
+        InputDevices {
+          keyboard_mouse: true,
+          gamepad: None,
+        },
+      ))
+      .id();
+    let gamepad = world.spawn(Gamepad::default()).id();
+    world.run_system_once(assign_gamepads).unwrap();
+    assert_eq!(
+      world.get::<InputDevices>(player).unwrap().gamepad,
+      Some(gamepad)
+    );
+  }
+}
@@ -0,0 +1,317 @@
+use crate::systems::camera::{PanOrbitSettings, PanOrbitState, wrap_angle};
+use crate::systems::local_players::{InputDevices, PlayerCamera};
+use bevy::prelude::*;
+use shared::controller::Player;
+use std::f32::consts::TAU;
+
+/// A marker component for entities the camera can lock on to.
+#[derive(Component, Reflect)]
+pub struct Targetable;
+
+/// The lock-on state and configuration of a camera
+#[derive(Component)]
+pub struct LockOn {
+  /// The entity currently locked on to, if any
+  pub target: Option<Entity>,
+  /// Key to toggle the lock-on
+  pub toggle_key: KeyCode,
+  /// Key to cycle to the next target (hold shift to cycle backwards)
+  pub cycle_key: KeyCode,
+  /// Gamepad button to toggle the lock-on
+  pub toggle_button: GamepadButton,
+  /// Half-angle in radians of the cone in front of the camera searched for targets
+  pub cone_angle: f32,
+  /// Furthest a target can be from the player
+  pub max_distance: f32,
+  /// How far the right stick needs to be pushed to flick to another target
+  pub flick_threshold: f32,
+  /// Distance kept around the player and the target when framing them
+  pub framing_margin: f32,
+  /// How quickly the camera turns and zooms to frame the target
+  pub framing_speed: f32,
+  // radius the camera had before locking on, restored when unlocking
+  unlocked_radius: Option<f32>,
+  // whether the right stick is still held from the last flick
+  stick_flicked: bool,
+}
+
+impl Default for LockOn {
+  fn default() -> Self {
+    LockOn {
+      target: None,
+      toggle_key: KeyCode::KeyQ,
+      cycle_key: KeyCode::Tab,
+      toggle_button: GamepadButton::RightThumb,
+      cone_angle: 35.0f32.to_radians(),
+      max_distance: 25.0,
+      flick_threshold: 0.8,
+      framing_margin: 6.0,
+      framing_speed: 8.0,
+      unlocked_radius: None,
+      stick_flicked: false,
+    }
+  }
+}
+
+impl LockOn {
+  pub fn is_locked(&self) -> bool {
+    self.target.is_some()
+  }
+
+  /// The nearest candidate to the player inside the cone in front of the camera
+  pub fn acquire(
+    &self,
+    camera_tfm: &Transform,
+    player_pos: Vec3,
+    candidates: impl IntoIterator<Item = (Entity, Vec3)>,
+  ) -> Option<Entity> {
+    let forward = camera_tfm.forward();
+    candidates
+      .into_iter()
+      .filter(|(_, pos)| pos.distance(player_pos) <= self.max_distance)
+      .filter(|(_, pos)| {
+        let to_candidate = *pos - camera_tfm.translation;
+        to_candidate != Vec3::ZERO && forward.angle_between(to_candidate) <= self.cone_angle
+      })
+      .min_by(|(_, a), (_, b)| {
+        a.distance_squared(player_pos)
+          .total_cmp(&b.distance_squared(player_pos))
+      })
+      .map(|(entity, _)| entity)
+  }
+
+  /// The candidate next to the current target, going around the player
+  /// to the right for a positive `direction` and to the left otherwise
+  pub fn cycle(
+    &self,
+    player_pos: Vec3,
+    current: (Entity, Vec3),
+    candidates: impl IntoIterator<Item = (Entity, Vec3)>,
+    direction: f32,
+  ) -> Option<Entity> {
+    let forward = horizontal(current.1 - player_pos)?;
+    let right = Vec3::new(-forward.z, 0.0, forward.x);
+    candidates
+      .into_iter()
+      .filter(|(entity, pos)| *entity != current.0 && pos.distance(player_pos) <= self.max_distance)
+      .filter_map(|(entity, pos)| {
+        let dir = horizontal(pos - player_pos)?;
+        let angle = dir.dot(right).atan2(dir.dot(forward)) * direction.signum();
+        Some((entity, angle.rem_euclid(TAU)))
+      })
+      .min_by(|(_, a), (_, b)| a.total_cmp(b))
+      .map(|(entity, _)| entity)
+  }
+}
+
+// The direction of `v` on the ground plane, if it has one
+fn horizontal(v: Vec3) -> Option<Vec3> {
+  Vec3::new(v.x, 0.0, v.z).try_normalize()
+}
+
+/// The yaw that makes the camera look along `direction`
+pub fn yaw_towards(direction: Vec3) -> f32 {
+  (-direction.x).atan2(-direction.z)
+}
+
+/// The forward direction the player should move along while locked on, if any
+pub fn lock_on_forward(player_pos: Vec3, target_pos: Vec3) -> Option<Vec3> {
+  horizontal(target_pos - player_pos)
+}
+
+pub fn lock_on_input(
+  kbd: Res<ButtonInput<KeyCode>>,
+  gamepads: Query<&Gamepad>,
+  mut camera_q: Query<(&PlayerCamera, &Transform, &mut LockOn, &mut PanOrbitState), With<Camera>>,
+  player_q: Query<(&Transform, &InputDevices), With<Player>>,
+  targets_q: Query<(Entity, &Transform), With<Targetable>>,
+) {
+  let candidates = || {
+    targets_q
+      .iter()
+      .map(|(entity, tfm)| (entity, tfm.translation))
+  };
+
+  for (player_camera, camera_tfm, mut lock_on, mut state) in &mut camera_q {
+    let Ok((player_tfm, devices)) = player_q.get(player_camera.0) else {
+      continue;
+    };
+    let player_pos = player_tfm.translation;
+    let gamepad = devices
+      .gamepad
+      .and_then(|gamepad| gamepads.get(gamepad).ok());
+
+    let toggle = (devices.keyboard_mouse && kbd.just_pressed(lock_on.toggle_key))
+      || gamepad.is_some_and(|gamepad| gamepad.just_pressed(lock_on.toggle_button));
+
+    // a flick only counts once the stick went back to rest in between
+    let stick_x = gamepad.map_or(0.0, |gamepad| gamepad.right_stick().x);
+    let mut cycle = 0.0;
+    if stick_x.abs() >= lock_on.flick_threshold {
+      if !lock_on.stick_flicked {
+        cycle = stick_x.signum();
+      }
+      lock_on.stick_flicked = true;
+    } else {
+      lock_on.stick_flicked = false;
+    }
+    if devices.keyboard_mouse && kbd.just_pressed(lock_on.cycle_key) {
+      cycle = if kbd.pressed(KeyCode::ShiftLeft) {
+        -1.0
+      } else {
+        1.0
+      };
+    }
+
+    // drop targets that were despawned or got out of range
+    let current = lock_on
+      .target
+      .and_then(|target| targets_q.get(target).ok())
+      .map(|(entity, tfm)| (entity, tfm.translation))
+      .filter(|(_, pos)| pos.distance(player_pos) <= lock_on.max_distance);
+
+    let target = match current {
+      Some(_) if toggle => None,
+      Some(current) if cycle != 0.0 => lock_on
+        .cycle(player_pos, current, candidates(), cycle)
+        .or(Some(current.0)),
+      Some(current) => Some(current.0),
+      None if toggle => lock_on.acquire(camera_tfm, player_pos, candidates()),
+      None => None,
+    };
+
+    match (lock_on.is_locked(), target.is_some()) {
+      (false, true) => lock_on.unlocked_radius = Some(state.radius),
+      (true, false) => {
+        if let Some(radius) = lock_on.unlocked_radius.take() {
+          state.radius = radius;
+        }
+      }
+      _ => {}
+    }
+    lock_on.target = target;
+  }
+}
+
+pub fn frame_lock_on_target(
+  time: Res<Time>,
+  mut camera_q: Query<
+    (
+      &PlayerCamera,
+      &LockOn,
+      &PanOrbitSettings,
+      &mut PanOrbitState,
+      &mut Transform,
+    ),
+    With<Camera>,
+  >,
+  player_q: Query<&Transform, (With<Player>, Without<Camera>)>,
+  targets_q: Query<&Transform, (With<Targetable>, Without<Camera>)>,
+) {
+  // exponential smoothing, independent of the frame rate
+  let blend = |speed: f32| 1.0 - (-speed * time.delta_secs()).exp();
+
+  for (player_camera, lock_on, settings, mut state, mut transform) in &mut camera_q {
+    let Ok(player_tfm) = player_q.get(player_camera.0) else {
+      continue;
+    };
+    let Some(target_tfm) = lock_on.target.and_then(|target| targets_q.get(target).ok()) else {
+      continue;
+    };
+    let to_target = target_tfm.translation - player_tfm.translation;
+
+    // keep both in view by looking over the player at the target
+    state.center = player_tfm.translation + to_target * 0.5;
+    if let Some(direction) = horizontal(to_target) {
+      let yaw_offset = wrap_angle(yaw_towards(direction) - state.yaw);
+      state.yaw = settings.clamp_yaw(state.yaw + yaw_offset * blend(lock_on.framing_speed));
+    }
+    let radius = settings.clamp_radius(to_target.length() * 0.5 + lock_on.framing_margin);
+    state.radius += (radius - state.radius) * blend(lock_on.framing_speed);
+
+    *transform = state.transform();
+  }
+}
+
+pub fn draw_lock_on_marker(
+  mut gizmos: Gizmos,
+  camera_q: Query<(&LockOn, &Transform), With<Camera>>,
+  targets_q: Query<&Transform, (With<Targetable>, Without<Camera>)>,
+) {
+  for (lock_on, camera_tfm) in &camera_q {
+    if let Some(target_tfm) = lock_on.target.and_then(|target| targets_q.get(target).ok()) {
+      gizmos.circle(
+        Isometry3d::new(target_tfm.translation, camera_tfm.rotation),
+        0.5,
+        Color::srgb(1.0, 0.8, 0.0),
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::f32::consts::FRAC_PI_2;
+
+  const EPSILON: f32 = 1e-5;
+
+  // a camera behind the player at the origin, looking down -Z
+  fn camera() -> Transform {
+    Transform::from_xyz(0.0, 2.0, 5.0).looking_to(Vec3::NEG_Z, Vec3::Y)
+  }
+
+  #[test]
+  fn acquire_picks_the_nearest_target_in_the_cone() {
+    let lock_on = LockOn::default();
+    let [near, far, behind, aside, away] = [0, 1, 2, 3, 4].map(Entity::from_raw);
+    let candidates = [
+      (far, Vec3::new(0.0, 2.0, -12.0)),
+      (near, Vec3::new(1.0, 2.0, -6.0)),
+      (behind, Vec3::new(0.0, 2.0, 8.0)),
+      (aside, Vec3::new(10.0, 2.0, 3.0)),
+      (away, Vec3::new(0.0, 2.0, -40.0)),
+    ];
+    assert_eq!(
+      lock_on.acquire(&camera(), Vec3::ZERO, candidates),
+      Some(near)
+    );
+    assert_eq!(
+      lock_on.acquire(&camera(), Vec3::ZERO, candidates[2..].iter().copied()),
+      None
+    );
+  }
+
+  #[test]
+  fn cycle_goes_around_the_player() {
+    let lock_on = LockOn::default();
+    let [ahead, right, left, behind] = [0, 1, 2, 3].map(Entity::from_raw);
+    let candidates = [
+      (ahead, Vec3::NEG_Z * 5.0),
+      (right, Vec3::new(5.0, 0.0, -5.0)),
+      (left, Vec3::new(-5.0, 0.0, -1.0)),
+      (behind, Vec3::Z * 5.0),
+    ];
+    let current = candidates[0];
+    assert_eq!(
+      lock_on.cycle(Vec3::ZERO, current, candidates, 1.0),
+      Some(right)
+    );
+    assert_eq!(
+      lock_on.cycle(Vec3::ZERO, current, candidates, -1.0),
+      Some(left)
+    );
+    assert_eq!(lock_on.cycle(Vec3::ZERO, current, [current], 1.0), None);
+  }
+
+  #[test]
+  fn lock_on_directions_stay_on_the_ground() {
+    assert!(yaw_towards(Vec3::NEG_Z).abs() < EPSILON);
+    assert!((yaw_towards(Vec3::NEG_X) - FRAC_PI_2).abs() < EPSILON);
+    assert_eq!(
+      lock_on_forward(Vec3::ZERO, Vec3::new(3.0, 5.0, 0.0)),
+      Some(Vec3::X)
+    );
+    assert_eq!(lock_on_forward(Vec3::ZERO, Vec3::Y * 2.0), None);
+  }
+}
@@ -0,0 +1,159 @@
+use bevy::prelude::*;
+
+/// The buttons of a menu, spawned from top to bottom in the order of `ALL`
+pub trait MenuItem: Component + Copy + Eq {
+  const ALL: &'static [Self];
+
+  fn label(self) -> &'static str;
+}
+
+/// Index of the button selected with the keyboard, a gamepad or the mouse.
+/// Only one menu is shown at a time, so they all share it.
+#[derive(Resource, Default)]
+pub struct MenuSelection(pub usize);
+
+const BUTTON_COLOR: Color = Color::srgb(0.15, 0.15, 0.15);
+const SELECTED_COLOR: Color = Color::srgb(0.3, 0.3, 0.45);
+
+/// A full screen column, centering the title and buttons spawned into it
+pub fn menu_root() -> Node {
+  Node {
+    width: Val::Percent(100.0),
+    height: Val::Percent(100.0),
+    flex_direction: FlexDirection::Column,
+    align_items: AlignItems::Center,
+    justify_content: JustifyContent::Center,
+    row_gap: Val::Px(16.0),
+    ..default()
+  }
+}
+
+pub fn spawn_menu_title(parent: &mut ChildBuilder, title: &str) {
+  parent.spawn((
+    Text::new(title),
+    TextFont {
+      font_size: 64.0,
+      ..default()
+    },
+    Node {
+      margin: UiRect::bottom(Val::Px(32.0)),
+      ..default()
+    },
+  ));
+}
+
+pub fn spawn_menu_buttons<T: MenuItem>(parent: &mut ChildBuilder) {
+  spawn_menu_buttons_where::<T>(parent, |_| true);
+}
+
+/// The buttons of the items `shown` allows, the others are skipped when navigating
+pub fn spawn_menu_buttons_where<T: MenuItem>(parent: &mut ChildBuilder, shown: impl Fn(T) -> bool) {
+  for &item in T::ALL.iter().filter(|&&item| shown(item)) {
+    parent
+      .spawn((
+        Button,
+        item,
+        Node {
+          width: Val::Px(240.0),
+          height: Val::Px(56.0),
+          justify_content: JustifyContent::Center,
+          align_items: AlignItems::Center,
+          ..default()
+        },
+        BackgroundColor(BUTTON_COLOR),
+      ))
+      .with_child((
+        Text::new(item.label()),
+        TextFont {
+          font_size: 32.0,
+          ..default()
+        },
+      ));
+  }
+}
+
+/// Select the first button, for when a menu opens
+pub fn reset_menu_selection(mut selection: ResMut<MenuSelection>) {
+  selection.0 = 0;
+}
+
+/// The mouse selects whatever button it hovers
+pub fn hover_menu_buttons<T: MenuItem>(
+  buttons_q: Query<(&Interaction, &T), Changed<Interaction>>,
+  mut selection: ResMut<MenuSelection>,
+) {
+  for (interaction, item) in &buttons_q {
+    if *interaction != Interaction::None
+      && let Some(index) = T::ALL.iter().position(|other| other == item)
+    {
+      selection.0 = index;
+    }
+  }
+}
+
+/// Arrow keys, W/S and the d-pad move the selection to the next button shown, wrapping
+/// around at the ends
+pub fn navigate_menu<T: MenuItem>(
+  kbd: Res<ButtonInput<KeyCode>>,
+  gamepads: Query<&Gamepad>,
+  buttons_q: Query<&T>,
+  mut selection: ResMut<MenuSelection>,
+) {
+  let pressed = |keys: [KeyCode; 2], button: GamepadButton| {
+    kbd.any_just_pressed(keys) || gamepads.iter().any(|gamepad| gamepad.just_pressed(button))
+  };
+  let count = T::ALL.len();
+  let step = if pressed([KeyCode::ArrowUp, KeyCode::KeyW], GamepadButton::DPadUp) {
+    count - 1
+  } else if pressed([KeyCode::ArrowDown, KeyCode::KeyS], GamepadButton::DPadDown) {
+    1
+  } else {
+    return;
+  };
+  let mut index = selection.0;
+  for _ in 0..count {
+    index = (index + step) % count;
+    if buttons_q.iter().any(|item| *item == T::ALL[index]) {
+      selection.0 = index;
+      return;
+    }
+  }
+}
+
+/// The selected item, if it was activated this frame, pipe it into whatever handles it
+pub fn activate_menu_item<T: MenuItem>(
+  kbd: Res<ButtonInput<KeyCode>>,
+  gamepads: Query<&Gamepad>,
+  buttons_q: Query<&Interaction, (Changed<Interaction>, With<T>)>,
+  shown_q: Query<&T>,
+  selection: Res<MenuSelection>,
+) -> Option<T> {
+  let activated = kbd.any_just_pressed([KeyCode::Enter, KeyCode::Space])
+    || gamepads
+      .iter()
+      .any(|gamepad| gamepad.just_pressed(GamepadButton::South))
+    || buttons_q
+      .iter()
+      .any(|interaction| *interaction == Interaction::Pressed);
+  activated
+    .then(|| T::ALL.get(selection.0).copied())
+    .flatten()
+    .filter(|item| shown_q.iter().any(|shown| shown == item))
+}
+
+pub fn highlight_menu_buttons<T: MenuItem>(
+  selection: Res<MenuSelection>,
+  mut buttons_q: Query<(&T, &mut BackgroundColor)>,
+) {
+  let selected = T::ALL.get(selection.0);
+  for (item, mut color) in &mut buttons_q {
+    let target = if selected == Some(item) {
+      SELECTED_COLOR
+    } else {
+      BUTTON_COLOR
+    };
+    if color.0 != target {
+      color.0 = target;
+    }
+  }
+}
@@ -1,2 +1,16 @@
 pub mod camera;
+pub mod camera_shake;
+pub mod checkpoint;
+pub mod cinematic;
 pub mod controller;
+pub mod free_camera;
+pub mod level;
+pub mod lobby;
+pub mod local_players;
+pub mod lock_on;
+pub mod menu;
+pub mod network;
+pub mod network_conditions;
+pub mod network_stats;
+pub mod save_game;
+pub mod user_settings;
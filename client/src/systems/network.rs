@@ -0,0 +1,552 @@
+use avian3d::prelude::*;
+use bevy::diagnostic::Diagnostics;
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use shared::controller::MovementAction;
+use shared::interpolation::{InterpolationClock, InterpolationDelay, Pose, SnapshotBuffer};
+use shared::level::{LevelSpawner, ObjectIndex, player_object};
+use shared::net::{
+  ClientMessage, ClientNetEvent, ClientState, DeltaSnapshot, INPUT_REDUNDANCY, LinkConditions,
+  LobbyRequest, NetClient, NetEntity, RoomInfo, RoomState, ServerMessage, Snapshot, TickInput,
+};
+use shared::prediction::{
+  AuthoritativeState, ControllerState, PredictionError, PredictionHistory, PredictionPlugin,
+  reconcile, resimulating,
+};
+use shared::state::{GameState, InGameEntity};
+use shared::tick::TICK_RATE;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::systems::level::SelectedLevel;
+use crate::systems::local_players::LocalPlayer;
+use crate::systems::network_conditions::NetworkConditionsPlugin;
+use crate::systems::network_stats::{NetworkStatsPlugin, SNAPSHOT_SIZE};
+
+const REMOTE_PLAYER_COLOR: Color = Color::srgb(0.8, 0.8, 0.8);
+
+/// Plays on a server, connected to from the lobby or with `--connect <address>`.
+/// The local player is predicted, remote players and props are interpolated between snapshots.
+pub struct NetworkPlugin;
+
+impl Plugin for NetworkPlugin {
+  fn build(&self, app: &mut App) {
+    if let Some(address) = server_address(std::env::args()) {
+      match Server::connect(address) {
+        Ok(server) => {
+          app.insert_resource(server);
+        }
+        Err(error) => error!("Could not connect to {address}: {error}"),
+      }
+    }
+    app
+      .add_plugins((
+        PredictionPlugin,
+        NetworkStatsPlugin,
+        NetworkConditionsPlugin,
+      ))
+      .insert_resource(interpolation_delay(std::env::args()))
+      .add_event::<ServerNotice>()
+      .add_systems(
+        PreUpdate,
+        (
+          receive_messages,
+          (
+            start_predicting,
+            start_interpolating,
+            sync_remote_players,
+            apply_authoritative_state,
+          )
+            .chain()
+            .run_if(in_state(GameState::Game))
+            .run_if(playing_online),
+        )
+          .chain()
+          .before(reconcile)
+          .run_if(resource_exists::<Server>),
+      )
+      .add_systems(
+        Update,
+        interpolate_remote_entities
+          .run_if(in_state(GameState::Game))
+          .run_if(playing_online),
+      )
+      .add_systems(
+        FixedPostUpdate,
+        // once per tick, not once per tick replayed after a correction
+        send_inputs.run_if(playing_online).run_if(not(resimulating)),
+      )
+      .add_systems(
+        OnEnter(GameState::MainMenu),
+        (
+          leave_game.run_if(resource_exists::<Server>),
+          restore_tick_rate,
+        ),
+      )
+      .add_systems(Last, flush_packets.run_if(resource_exists::<Server>));
+  }
+}
+
+/// Whether the game is the one of this client's room on the server. Games started from the
+/// menu while connected are offline, nothing of the server moves them.
+pub fn playing_online(server: Option<Res<Server>>) -> bool {
+  server.is_some_and(|server| server.room.as_ref().is_some_and(|room| room.playing))
+}
+
+/// The address of a `--connect <address>` argument, a host name or an IP with a port
+pub fn server_address(args: impl IntoIterator<Item = String>) -> Option<SocketAddr> {
+  let address = args
+    .into_iter()
+    .skip_while(|arg| arg != "--connect")
+    .nth(1)?;
+  resolve(&address)
+}
+
+/// The first address a host name or an IP with a port resolves to
+pub fn resolve(address: &str) -> Option<SocketAddr> {
+  match address.to_socket_addrs() {
+    Ok(mut addresses) => addresses.next(),
+    Err(error) => {
+      error!("Invalid server address {address:?}: {error}");
+      None
+    }
+  }
+}
+
+/// An `--interpolation-delay <milliseconds>` argument, or the default delay
+pub fn interpolation_delay(args: impl IntoIterator<Item = String>) -> InterpolationDelay {
+  args
+    .into_iter()
+    .skip_while(|arg| arg != "--interpolation-delay")
+    .nth(1)
+    .and_then(|delay| delay.parse().ok())
+    .map(|delay| InterpolationDelay(Duration::from_millis(delay)))
+    .unwrap_or_default()
+}
+
+/// The server being played on
+#[derive(Resource)]
+pub struct Server {
+  pub client: NetClient,
+  /// Other clients, their players are spawned in the game
+  pub remote_players: HashSet<u64>,
+  /// The latest snapshots, to interpolate and to decode the next ones against
+  pub snapshots: SnapshotBuffer,
+  clock: InterpolationClock,
+  /// The newest snapshot, until the predicted player is corrected with it
+  pending: Option<Snapshot>,
+  /// Rooms of the lobby
+  pub rooms: Vec<RoomInfo>,
+  /// The room this client is in
+  pub room: Option<RoomState>,
+}
+
+impl Server {
+  /// Start connecting, through the bad network of the command line if there is one
+  pub fn connect(address: SocketAddr) -> io::Result<Self> {
+    let mut client = NetClient::connect(address)?;
+    info!("Connecting to {address}");
+    let conditions = LinkConditions::from_args(std::env::args());
+    if !conditions.is_perfect() {
+      info!("Simulating {conditions:?}");
+    }
+    client.set_conditions(conditions);
+    Ok(Server {
+      client,
+      remote_players: HashSet::new(),
+      snapshots: SnapshotBuffer::default(),
+      clock: InterpolationClock::default(),
+      pending: None,
+      rooms: Vec::new(),
+      room: None,
+    })
+  }
+
+  pub fn send_lobby(&mut self, request: LobbyRequest) {
+    self.client.send(&ClientMessage::Lobby(request));
+  }
+
+  /// Forget the game, for the next one
+  fn reset_game(&mut self) {
+    self.remote_players.clear();
+    self.snapshots = SnapshotBuffer::default();
+    self.clock = InterpolationClock::default();
+    self.pending = None;
+  }
+
+  /// Predict and interpolate at the rate the server ticks at
+  fn set_tick_rate(&mut self, tick_rate: u32, fixed_time: &mut Time<Fixed>) {
+    let tick_rate = tick_rate.max(1) as f64;
+    fixed_time.set_timestep_hz(tick_rate);
+    self.snapshots.set_tick_rate(tick_rate);
+    self.clock.set_tick_rate(tick_rate);
+  }
+
+  /// The id the server gave this client, once connected
+  pub fn id(&self) -> Option<u64> {
+    match self.client.state() {
+      ClientState::Connected { client } => Some(client),
+      _ => None,
+    }
+  }
+
+  /// Decode a snapshot against the baseline it refers to, returns its size on the wire
+  fn receive_snapshot(&mut self, message: &DeltaSnapshot) -> Option<usize> {
+    let baseline = match message.baseline {
+      Some(tick) => match self.snapshots.get(tick) {
+        Some(baseline) => Some(baseline),
+        None => {
+          debug!(
+            "Dropping snapshot {}, its baseline {tick} is gone",
+            message.tick
+          );
+          return None;
+        }
+      },
+      None => None,
+    };
+    let snapshot = match message.decode(baseline) {
+      Ok(snapshot) => snapshot,
+      Err(error) => {
+        debug!("Dropping snapshot {}: {error}", message.tick);
+        return None;
+      }
+    };
+    if self
+      .snapshots
+      .latest()
+      .is_none_or(|latest| snapshot.tick > latest.tick)
+    {
+      self.pending = Some(snapshot.clone());
+    }
+    self.snapshots.push(snapshot);
+    Some(message.data.len())
+  }
+}
+
+/// The player of another client
+#[derive(Component, Debug)]
+pub struct RemotePlayer(pub u64);
+
+/// Moved along the snapshots of the server rather than simulated
+#[derive(Component, Debug)]
+pub struct Interpolated;
+
+/// Something the server or the connection wants the player to know
+#[derive(Event, Debug, Clone)]
+pub struct ServerNotice(pub String);
+
+#[allow(clippy::too_many_arguments)]
+fn receive_messages(
+  mut commands: Commands,
+  mut server: ResMut<Server>,
+  mut selected_level: ResMut<SelectedLevel>,
+  mut fixed_time: ResMut<Time<Fixed>>,
+  state: Res<State<GameState>>,
+  mut next_state: ResMut<NextState<GameState>>,
+  mut diagnostics: Diagnostics,
+  mut evw_notice: EventWriter<ServerNotice>,
+) {
+  for event in server.client.receive(Instant::now()) {
+    match event {
+      ClientNetEvent::Connected(id) => info!("Connected as client {id}"),
+      ClientNetEvent::Message(ServerMessage::Welcome {
+        level,
+        tick,
+        tick_rate,
+      }) => {
+        info!("Joining {level} at tick {tick}, {tick_rate} ticks per second");
+        server.set_tick_rate(tick_rate, &mut fixed_time);
+        selected_level.0 = level;
+        next_state.set(GameState::Loading);
+      }
+      ClientNetEvent::Message(ServerMessage::Spawn(NetEntity::Player(id))) => {
+        if Some(id) != server.id() {
+          server.remote_players.insert(id);
+        }
+      }
+      ClientNetEvent::Message(ServerMessage::Despawn(NetEntity::Player(id))) => {
+        server.remote_players.remove(&id);
+      }
+      // objects are part of the level
+      ClientNetEvent::Message(ServerMessage::Spawn(NetEntity::Object(_)))
+      | ClientNetEvent::Message(ServerMessage::Despawn(NetEntity::Object(_))) => {}
+      ClientNetEvent::Message(ServerMessage::Snapshot(snapshot)) => {
+        if let Some(size) = server.receive_snapshot(&snapshot) {
+          diagnostics.add_measurement(&SNAPSHOT_SIZE, || size as f64);
+        }
+      }
+      ClientNetEvent::Message(ServerMessage::Rooms(rooms)) => server.rooms = rooms,
+      ClientNetEvent::Message(ServerMessage::Room(room)) => {
+        // kicked out of the game, its room closed or its game ended
+        if room.as_ref().is_none_or(|room| !room.playing)
+          && server.room.as_ref().is_some_and(|room| room.playing)
+        {
+          server.reset_game();
+          if *state.get() != GameState::MainMenu {
+            next_state.set(GameState::MainMenu);
+          }
+        }
+        server.room = room;
+      }
+      ClientNetEvent::Message(ServerMessage::TickRate(tick_rate)) => {
+        info!("The server now runs {tick_rate} ticks per second");
+        server.set_tick_rate(tick_rate, &mut fixed_time);
+      }
+      ClientNetEvent::Message(ServerMessage::Notice(notice)) => {
+        info!("{notice}");
+        evw_notice.send(ServerNotice(notice));
+      }
+      ClientNetEvent::Disconnected(reason) => {
+        warn!("Disconnected from the server: {reason:?}");
+        evw_notice.send(ServerNotice(format!("Disconnected: {reason:?}")));
+        commands.remove_resource::<Server>();
+        if *state.get() != GameState::MainMenu {
+          next_state.set(GameState::MainMenu);
+        }
+      }
+    }
+  }
+}
+
+/// Only the first local player plays online, it is predicted
+fn start_predicting(
+  mut commands: Commands,
+  player_q: Query<(Entity, &LocalPlayer), Without<PredictionHistory>>,
+) {
+  for (entity, local_player) in &player_q {
+    if local_player.0 == 0 {
+      commands
+        .entity(entity)
+        .insert((PredictionHistory::default(), PredictionError::default()));
+    }
+  }
+}
+
+/// Props only move on the server, they are kinematic here so players still bump into them
+fn start_interpolating(
+  mut commands: Commands,
+  object_q: Query<(Entity, &RigidBody), Added<ObjectIndex>>,
+) {
+  for (entity, rigid_body) in &object_q {
+    if rigid_body.is_dynamic() {
+      commands.entity(entity).insert((
+        RigidBody::Kinematic,
+        LinearVelocity::ZERO,
+        AngularVelocity::ZERO,
+        Interpolated,
+      ));
+    }
+  }
+}
+
+fn sync_remote_players(
+  mut spawner: LevelSpawner,
+  server: Res<Server>,
+  player_q: Query<(Entity, &RemotePlayer)>,
+) {
+  let mut spawned = HashSet::new();
+  for (entity, remote_player) in &player_q {
+    if server.remote_players.contains(&remote_player.0) {
+      spawned.insert(remote_player.0);
+    } else {
+      spawner.commands.entity(entity).despawn_recursive();
+    }
+  }
+  for &id in server.remote_players.difference(&spawned) {
+    // placed by the snapshots
+    let player = player_object(Vec3::ZERO);
+    let mut entity = spawner.commands.spawn((
+      player.transform(),
+      RigidBody::Kinematic,
+      player.shape.collider(),
+      RemotePlayer(id),
+      Interpolated,
+      InGameEntity,
+    ));
+    spawner
+      .assets
+      .draw_with(&mut entity, &player, REMOTE_PLAYER_COLOR.into());
+  }
+}
+
+fn apply_authoritative_state(
+  mut server: ResMut<Server>,
+  predicted_q: Query<Entity, With<PredictionHistory>>,
+  mut evw_authoritative: EventWriter<AuthoritativeState>,
+) {
+  let own_id = server.id();
+  let Some(snapshot) = server.pending.take() else {
+    return;
+  };
+  let (Ok(entity), Some(player)) = (
+    predicted_q.get_single(),
+    snapshot
+      .players
+      .iter()
+      .find(|player| Some(player.client) == own_id),
+  ) else {
+    return;
+  };
+  evw_authoritative.send(AuthoritativeState {
+    entity,
+    tick: player.last_input_tick,
+    state: ControllerState {
+      translation: player.translation,
+      velocity: player.velocity,
+      grounded: player.grounded,
+    },
+  });
+}
+
+// Kinematic bodies follow their transform, physics picks it up as their position
+#[allow(clippy::type_complexity)]
+fn interpolate_remote_entities(
+  time: Res<Time<Real>>,
+  delay: Res<InterpolationDelay>,
+  mut server: ResMut<Server>,
+  mut interpolated_q: Query<
+    (&mut Transform, Option<&RemotePlayer>, Option<&ObjectIndex>),
+    With<Interpolated>,
+  >,
+) {
+  let Some(latest) = server.snapshots.latest().map(|snapshot| snapshot.tick) else {
+    return;
+  };
+  let tick = server.clock.advance(time.delta(), latest, delay.0);
+  for (mut transform, remote_player, object_index) in &mut interpolated_q {
+    if let Some(remote_player) = remote_player {
+      let pose = server.snapshots.sample(tick, |snapshot| {
+        let player = snapshot
+          .players
+          .iter()
+          .find(|player| player.client == remote_player.0)?;
+        Some(Pose {
+          translation: player.translation,
+          rotation: Quat::IDENTITY,
+          velocity: player.velocity,
+        })
+      });
+      if let Some(pose) = pose {
+        transform.translation = pose.translation;
+      }
+    } else if let Some(object_index) = object_index {
+      let pose = server.snapshots.sample(tick, |snapshot| {
+        let object = snapshot
+          .objects
+          .iter()
+          .find(|object| object.index as usize == object_index.0)?;
+        Some(Pose {
+          translation: object.translation,
+          rotation: object.rotation,
+          velocity: object.velocity,
+        })
+      });
+      if let Some(pose) = pose {
+        transform.translation = pose.translation;
+        transform.rotation = pose.rotation;
+      }
+    }
+  }
+}
+
+/// The inputs of the last few ticks go out after every tick
+fn send_inputs(mut server: ResMut<Server>, player_q: Query<&PredictionHistory>) {
+  let Ok(history) = player_q.get_single() else {
+    return;
+  };
+  if server.id().is_none() {
+    return;
+  }
+  let mut inputs: Vec<_> = history
+    .ticks()
+    .rev()
+    .take(INPUT_REDUNDANCY)
+    .map(|predicted| {
+      let mut actions = vec![MovementAction::Move(predicted.input.movement)];
+      if predicted.input.jump {
+        actions.push(MovementAction::Jump);
+      }
+      TickInput {
+        tick: predicted.tick,
+        actions,
+      }
+    })
+    .collect();
+  if inputs.is_empty() {
+    return;
+  }
+  inputs.reverse();
+  let snapshot_ack = server.snapshots.latest().map(|snapshot| snapshot.tick);
+  server.client.send(&ClientMessage::Inputs {
+    inputs,
+    snapshot_ack,
+  });
+}
+
+/// Back in the menu, leave the room whose game this was
+fn leave_game(mut server: ResMut<Server>) {
+  if server.room.as_ref().is_some_and(|room| room.playing) {
+    server.send_lobby(LobbyRequest::LeaveRoom);
+    server.reset_game();
+  }
+}
+
+/// Offline games tick at the default rate, whatever the last server's was
+fn restore_tick_rate(mut fixed_time: ResMut<Time<Fixed>>) {
+  fixed_time.set_timestep_hz(TICK_RATE);
+}
+
+fn flush_packets(mut server: ResMut<Server>) {
+  server.client.flush(Instant::now());
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  /// A server whose room is playing, over a link to nowhere
+  pub fn playing_server() -> Server {
+    let mut server = Server::connect(([127, 0, 0, 1], 9).into()).unwrap();
+    server.room = Some(RoomState {
+      id: 1,
+      name: "room".to_string(),
+      host: 1,
+      max_players: 2,
+      members: Vec::new(),
+      playing: true,
+    });
+    server
+  }
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+  }
+
+  #[test]
+  fn server_address_comes_from_the_connect_argument() {
+    assert_eq!(
+      server_address(args(&["client", "--connect", "127.0.0.1:5000"])),
+      Some(([127, 0, 0, 1], 5000).into())
+    );
+    assert_eq!(server_address(args(&["client", "--players", "2"])), None);
+    assert_eq!(server_address(args(&["client", "--connect"])), None);
+    assert_eq!(
+      server_address(args(&["client", "--connect", "no port"])),
+      None
+    );
+  }
+
+  #[test]
+  fn interpolation_delay_comes_from_its_argument() {
+    assert_eq!(
+      interpolation_delay(args(&["client", "--interpolation-delay", "250"])),
+      InterpolationDelay(Duration::from_millis(250))
+    );
+    assert_eq!(
+      interpolation_delay(args(&["client", "--interpolation-delay", "soon"])),
+      InterpolationDelay::default()
+    );
+  }
+}
@@ -0,0 +1,97 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::bevy_egui::{EguiContexts, egui};
+use shared::net::LinkConditions;
+use std::time::Duration;
+
+use crate::systems::network::Server;
+
+/// A panel to simulate a bad network between the client and the server, on top of
+/// the `--latency`, `--jitter`, `--loss`, `--duplicate` and `--reorder` arguments
+pub struct NetworkConditionsPlugin;
+
+impl Plugin for NetworkConditionsPlugin {
+  fn build(&self, app: &mut App) {
+    app.init_resource::<NetworkConditionsPanel>().add_systems(
+      Update,
+      (toggle_conditions_panel, conditions_panel)
+        .chain()
+        .run_if(resource_exists::<Server>),
+    );
+  }
+}
+
+/// Whether the network conditions panel is open
+#[derive(Resource)]
+pub struct NetworkConditionsPanel {
+  pub open: bool,
+  /// Key to open and close the panel
+  pub toggle_key: KeyCode,
+}
+
+impl Default for NetworkConditionsPanel {
+  fn default() -> Self {
+    NetworkConditionsPanel {
+      open: false,
+      toggle_key: KeyCode::F3,
+    }
+  }
+}
+
+fn toggle_conditions_panel(
+  kbd: Res<ButtonInput<KeyCode>>,
+  mut panel: ResMut<NetworkConditionsPanel>,
+) {
+  if kbd.just_pressed(panel.toggle_key) {
+    panel.open = !panel.open;
+  }
+}
+
+fn conditions_panel(
+  mut contexts: EguiContexts,
+  mut panel: ResMut<NetworkConditionsPanel>,
+  mut server: ResMut<Server>,
+) {
+  if !panel.open {
+    return;
+  }
+  let conditions = server.client.conditions();
+  // edited in milliseconds and percents
+  let shown = [
+    conditions.latency.as_secs_f32() * 1000.0,
+    conditions.jitter.as_secs_f32() * 1000.0,
+    conditions.loss * 100.0,
+    conditions.duplication * 100.0,
+    conditions.reordering * 100.0,
+  ];
+  let [
+    mut latency,
+    mut jitter,
+    mut loss,
+    mut duplication,
+    mut reordering,
+  ] = shown;
+  let mut reset = false;
+  egui::Window::new("Network conditions")
+    .open(&mut panel.open)
+    .resizable(false)
+    .show(contexts.ctx_mut(), |ui| {
+      ui.label("Each way, on top of the real network");
+      ui.add(egui::Slider::new(&mut latency, 0.0..=500.0).text("Latency (ms)"));
+      ui.add(egui::Slider::new(&mut jitter, 0.0..=100.0).text("Jitter (ms)"));
+      ui.add(egui::Slider::new(&mut loss, 0.0..=50.0).text("Loss (%)"));
+      ui.add(egui::Slider::new(&mut duplication, 0.0..=50.0).text("Duplication (%)"));
+      ui.add(egui::Slider::new(&mut reordering, 0.0..=50.0).text("Reordering (%)"));
+      reset = ui.button("Perfect network").clicked();
+    });
+  if reset {
+    server.client.set_conditions(LinkConditions::default());
+  } else if [latency, jitter, loss, duplication, reordering] != shown {
+    server.client.set_conditions(LinkConditions {
+      latency: Duration::from_secs_f32(latency / 1000.0),
+      jitter: Duration::from_secs_f32(jitter / 1000.0),
+      loss: loss / 100.0,
+      duplication: duplication / 100.0,
+      reordering: reordering / 100.0,
+    });
+  }
+}
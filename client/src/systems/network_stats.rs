@@ -0,0 +1,114 @@
+use bevy::diagnostic::{
+  Diagnostic, DiagnosticPath, Diagnostics, DiagnosticsStore, RegisterDiagnostic,
+};
+use bevy::prelude::*;
+use shared::net::NetStats;
+
+use crate::game_states::photo_mode::HudVisible;
+use crate::systems::network::Server;
+
+pub const BYTES_RECEIVED: DiagnosticPath =
+  DiagnosticPath::const_new("net/bytes_received_per_second");
+pub const BYTES_SENT: DiagnosticPath = DiagnosticPath::const_new("net/bytes_sent_per_second");
+/// Size of the snapshots as received, after delta compression
+pub const SNAPSHOT_SIZE: DiagnosticPath = DiagnosticPath::const_new("net/snapshot_bytes");
+pub const RTT: DiagnosticPath = DiagnosticPath::const_new("net/rtt");
+
+const OVERLAY_COLOR: Color = Color::srgb(0.0, 1.0, 0.0);
+
+/// Bandwidth, snapshot size and round trip time, as diagnostics and under the FPS overlay
+pub struct NetworkStatsPlugin;
+
+impl Plugin for NetworkStatsPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .register_diagnostic(Diagnostic::new(BYTES_RECEIVED).with_suffix(" B/s"))
+      .register_diagnostic(Diagnostic::new(BYTES_SENT).with_suffix(" B/s"))
+      .register_diagnostic(Diagnostic::new(SNAPSHOT_SIZE).with_suffix(" B"))
+      .register_diagnostic(Diagnostic::new(RTT).with_suffix(" ms"))
+      .add_systems(Startup, spawn_overlay)
+      .add_systems(Last, measure_traffic.run_if(resource_exists::<Server>))
+      .add_systems(Update, update_overlay);
+  }
+}
+
+#[derive(Component)]
+struct NetworkStatsText;
+
+fn spawn_overlay(mut commands: Commands) {
+  commands.spawn((
+    Text::default(),
+    TextFont {
+      font_size: 20.0,
+      ..default()
+    },
+    TextColor(OVERLAY_COLOR),
+    Node {
+      position_type: PositionType::Absolute,
+      // below the FPS overlay
+      top: Val::Px(50.0),
+      ..default()
+    },
+    GlobalZIndex(i32::MAX - 32),
+    NetworkStatsText,
+  ));
+}
+
+fn measure_traffic(
+  time: Res<Time<Real>>,
+  server: Res<Server>,
+  mut previous: Local<Option<NetStats>>,
+  mut diagnostics: Diagnostics,
+) {
+  // a new connection counts from zero again
+  if server.is_added() {
+    *previous = None;
+  }
+  let stats = server.client.stats();
+  let seconds = time.delta_secs_f64();
+  if let Some(previous) = *previous
+    && seconds > 0.0
+  {
+    let received = stats.bytes_received - previous.bytes_received;
+    let sent = stats.bytes_sent - previous.bytes_sent;
+    diagnostics.add_measurement(&BYTES_RECEIVED, || received as f64 / seconds);
+    diagnostics.add_measurement(&BYTES_SENT, || sent as f64 / seconds);
+  }
+  *previous = Some(stats);
+  if let Some(rtt) = server.client.rtt() {
+    diagnostics.add_measurement(&RTT, || rtt.as_secs_f64() * 1000.0);
+  }
+}
+
+fn update_overlay(
+  hud: Res<HudVisible>,
+  server: Option<Res<Server>>,
+  store: Res<DiagnosticsStore>,
+  mut text_q: Query<(&mut Text, &mut Visibility), With<NetworkStatsText>>,
+) {
+  let Ok((mut text, mut visibility)) = text_q.get_single_mut() else {
+    return;
+  };
+  let visible = hud.0 && server.is_some();
+  visibility.set_if_neq(if visible {
+    Visibility::Inherited
+  } else {
+    Visibility::Hidden
+  });
+  if !visible {
+    return;
+  }
+  let value = |path| {
+    store
+      .get(path)
+      .and_then(|diagnostic| diagnostic.smoothed())
+      .unwrap_or_default()
+  };
+  text.0 = format!(
+    "in {:.1} kB/s  out {:.1} kB/s  snapshot {:.0} B  rtt {:.0} ms",
+    value(&BYTES_RECEIVED) / 1000.0,
+    value(&BYTES_SENT) / 1000.0,
+    value(&SNAPSHOT_SIZE),
+    value(&RTT),
+  );
+}
@@ -0,0 +1,500 @@
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use bevy::reflect::TypeRegistry;
+use bevy::scene::serde::SceneDeserializer;
+use bevy::scene::{DynamicEntity, SceneFilter};
+use serde::de::DeserializeSeed;
+use serde::{Deserialize, Serialize};
+use shared::checkpoint::RespawnPoint;
+use shared::controller::Grounded;
+use shared::state::{GameState, PlayState};
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::game_states::loading::GameAssets;
+use crate::game_states::pause::restart_game;
+use crate::systems::free_camera::CameraMode;
+use crate::systems::level::SelectedLevel;
+use crate::systems::network::playing_online;
+
+pub struct SaveGamePlugin;
+
+impl Plugin for SaveGamePlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .register_type::<SaveId>()
+      .insert_resource(SaveSlots::from_args(std::env::args()))
+      .init_resource::<SavedComponents>()
+      .add_event::<SaveGame>()
+      .add_event::<LoadGame>()
+      .add_systems(
+        Update,
+        quick_save
+          .run_if(in_state(PlayState::Playing))
+          .run_if(not(playing_online)),
+      )
+      .add_systems(
+        Update,
+        (
+          save_game,
+          load_game,
+          apply_pending_load.run_if(resource_exists::<PendingLoad>),
+        )
+          .chain()
+          .run_if(in_state(GameState::Game)),
+      );
+  }
+}
+
+/// The version of the save files written by this build
+pub const SAVE_VERSION: u32 = 1;
+
+/// Upgrades a save file from the version at its index + 1 to the next one
+type Migration = fn(&mut SaveHeader, &mut String);
+
+/// One per version before `SAVE_VERSION`, none yet
+const MIGRATIONS: [Migration; (SAVE_VERSION - 1) as usize] = [];
+
+/// Identifies a saved entity across runs, entities are respawned with the level on load
+#[derive(Component, Reflect, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[reflect(Component)]
+pub enum SaveId {
+  /// A local player, by index
+  Player(usize),
+  /// An object of the level, by index in `Level::objects`
+  Object(usize),
+}
+
+/// The first line of a save file, the rest is a scene of the saved components
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SaveHeader {
+  pub version: u32,
+  /// Asset path of the level the save was made in
+  pub level: String,
+}
+
+#[derive(Debug, Error)]
+pub enum SaveError {
+  #[error("could not access the save file: {0}")]
+  Io(#[from] std::io::Error),
+  #[error("could not write the save: {0}")]
+  Serialize(#[from] ron::Error),
+  #[error("could not parse the save: {0}")]
+  Parse(#[from] ron::error::SpannedError),
+  #[error("the save file is empty")]
+  Empty,
+  #[error("unknown save version {0}, the save may be from a newer version of the game")]
+  UnknownVersion(u32),
+  #[error("the game is not running a level")]
+  NoLevel,
+}
+
+/// A save read back from disk, migrated to the current version
+pub struct LoadedSave {
+  pub header: SaveHeader,
+  pub scene: DynamicScene,
+}
+
+/// The reflected components written to saves, others are set up by the level on load.
+/// Gameplay state such as health or inventories opts in with `allow`.
+#[derive(Resource)]
+pub struct SavedComponents(Vec<TypeId>);
+
+impl Default for SavedComponents {
+  fn default() -> Self {
+    let mut saved = SavedComponents(Vec::new());
+    saved
+      .allow::<SaveId>()
+      .allow::<Transform>()
+      .allow::<LinearVelocity>()
+      .allow::<AngularVelocity>()
+      .allow::<Grounded>()
+      .allow::<RespawnPoint>();
+    saved
+  }
+}
+
+impl SavedComponents {
+  pub fn allow<T: Component>(&mut self) -> &mut Self {
+    self.0.push(TypeId::of::<T>());
+    self
+  }
+
+  fn filter(&self) -> SceneFilter {
+    self.0.iter().fold(SceneFilter::deny_all(), |filter, &id| {
+      filter.allow_by_id(id)
+    })
+  }
+}
+
+/// Where save slots are kept, and the slot saving and loading use
+#[derive(Resource, Debug, Clone)]
+pub struct SaveSlots {
+  pub dir: PathBuf,
+  pub slot: usize,
+  pub count: usize,
+}
+
+impl SaveSlots {
+  /// A `--saves <dir>` argument, or `saves` in the platform data directory
+  pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+    let dir = args
+      .into_iter()
+      .skip_while(|arg| arg != "--saves")
+      .nth(1)
+      .map(PathBuf::from)
+      .unwrap_or_else(|| {
+        dirs::data_dir()
+          .unwrap_or_default()
+          .join("bevy_playground")
+          .join("saves")
+      });
+    SaveSlots {
+      dir,
+      slot: 0,
+      count: 3,
+    }
+  }
+
+  pub fn path(&self, slot: usize) -> PathBuf {
+    self.dir.join(format!("slot{}.ron", slot + 1))
+  }
+
+  pub fn select_next(&mut self) {
+    self.slot = (self.slot + 1) % self.count;
+  }
+}
+
+/// Save the game to a slot
+#[derive(Event, Debug, Clone, Copy)]
+pub struct SaveGame(pub usize);
+
+/// Restart the level saved in a slot and restore the save on top of it
+#[derive(Event, Debug, Clone, Copy)]
+pub struct LoadGame(pub usize);
+
+/// A save waiting for its level to be spawned
+#[derive(Resource)]
+struct PendingLoad(DynamicScene);
+
+/// Serialize the saved components of every entity with a `SaveId` that can move
+pub fn write_save(world: &mut World, level: &str) -> Result<String, SaveError> {
+  let entities: Vec<Entity> = world
+    .query_filtered::<(Entity, Option<&RigidBody>), With<SaveId>>()
+    .iter(world)
+    .filter(|(_, rigid_body)| !rigid_body.is_some_and(RigidBody::is_static))
+    .map(|(entity, _)| entity)
+    .collect();
+  let scene = DynamicSceneBuilder::from_world(world)
+    .with_component_filter(world.resource::<SavedComponents>().filter())
+    .extract_entities(entities.into_iter())
+    .build();
+  let header = SaveHeader {
+    version: SAVE_VERSION,
+    level: level.to_string(),
+  };
+  let registry = world.resource::<AppTypeRegistry>().read();
+  Ok(format!(
+    "{}\n{}",
+    ron::to_string(&header)?,
+    scene.serialize(&registry)?
+  ))
+}
+
+/// Parse a save file, migrating it from older versions
+pub fn read_save(contents: &str, registry: &TypeRegistry) -> Result<LoadedSave, SaveError> {
+  let (header, scene) = contents.split_once('\n').ok_or(SaveError::Empty)?;
+  let mut header: SaveHeader = ron::from_str(header)?;
+  let mut scene = scene.to_string();
+  migrate(&mut header, &mut scene, &MIGRATIONS)?;
+  let scene = SceneDeserializer {
+    type_registry: registry,
+  }
+  .deserialize(&mut ron::Deserializer::from_str(&scene)?)?;
+  Ok(LoadedSave { header, scene })
+}
+
+/// Bring a save up to the version after the last of `migrations`
+fn migrate(
+  header: &mut SaveHeader,
+  scene: &mut String,
+  migrations: &[Migration],
+) -> Result<(), SaveError> {
+  let current = migrations.len() as u32 + 1;
+  if header.version == 0 || header.version > current {
+    return Err(SaveError::UnknownVersion(header.version));
+  }
+  while header.version < current {
+    migrations[header.version as usize - 1](header, scene);
+    header.version += 1;
+  }
+  Ok(())
+}
+
+/// Restore saved components onto the entities with the same `SaveId`.
+/// Saved components the entity lacks are inserted, and saved kinds of component missing
+/// from the save are removed, so markers like `Grounded` come back as they were.
+pub fn apply_save(world: &mut World, scene: &DynamicScene) {
+  let entities: HashMap<SaveId, Entity> = world
+    .query::<(Entity, &SaveId)>()
+    .iter(world)
+    .map(|(entity, id)| (*id, entity))
+    .collect();
+  let saved = world.resource::<SavedComponents>().0.clone();
+  let registry = world.resource::<AppTypeRegistry>().clone();
+  let registry = registry.read();
+  for saved_entity in &scene.entities {
+    let Some(id) = save_id(saved_entity) else {
+      continue;
+    };
+    let Some(mut entity) = entities
+      .get(&id)
+      .and_then(|&entity| world.get_entity_mut(entity).ok())
+    else {
+      warn!("Nothing to restore {id:?} onto, skipping it");
+      continue;
+    };
+    for &type_id in &saved {
+      let Some(reflect_component) = registry
+        .get(type_id)
+        .and_then(|registration| registration.data::<ReflectComponent>())
+      else {
+        continue;
+      };
+      let component = saved_entity.components.iter().find(|component| {
+        component
+          .get_represented_type_info()
+          .is_some_and(|info| info.type_id() == type_id)
+      });
+      match component {
+        Some(component) => reflect_component.apply_or_insert(&mut entity, &**component, &registry),
+        None => reflect_component.remove(&mut entity),
+      }
+    }
+  }
+}
+
+fn save_id(entity: &DynamicEntity) -> Option<SaveId> {
+  entity
+    .components
+    .iter()
+    .find_map(|component| SaveId::from_reflect(&**component))
+}
+
+/// F5 saves to the selected slot and F9 loads it, offline only as the server would undo
+/// a load
+fn quick_save(
+  kbd: Res<ButtonInput<KeyCode>>,
+  slots: Res<SaveSlots>,
+  mut evw_save: EventWriter<SaveGame>,
+  mut evw_load: EventWriter<LoadGame>,
+) {
+  if kbd.just_pressed(KeyCode::F5) {
+    evw_save.send(SaveGame(slots.slot));
+  }
+  if kbd.just_pressed(KeyCode::F9) {
+    evw_load.send(LoadGame(slots.slot));
+  }
+}
+
+fn save_game(mut commands: Commands, mut evr_save: EventReader<SaveGame>) {
+  for &SaveGame(slot) in evr_save.read() {
+    commands.queue(move |world: &mut World| {
+      let path = world.resource::<SaveSlots>().path(slot);
+      match save_to(world, &path) {
+        Ok(()) => info!("Saved the game to {}", path.display()),
+        Err(err) => error!("Cannot save the game to {}: {err}", path.display()),
+      }
+    });
+  }
+}
+
+fn save_to(world: &mut World, path: &std::path::Path) -> Result<(), SaveError> {
+  let level = world
+    .resource::<AssetServer>()
+    .get_path(&world.resource::<GameAssets>().level)
+    .ok_or(SaveError::NoLevel)?
+    .to_string();
+  let contents = write_save(world, &level)?;
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir)?;
+  }
+  std::fs::write(path, contents)?;
+  Ok(())
+}
+
+fn load_game(mut commands: Commands, mut evr_load: EventReader<LoadGame>) {
+  for &LoadGame(slot) in evr_load.read() {
+    commands.queue(move |world: &mut World| {
+      let path = world.resource::<SaveSlots>().path(slot);
+      let save = std::fs::read_to_string(&path)
+        .map_err(SaveError::from)
+        .and_then(|contents| read_save(&contents, &world.resource::<AppTypeRegistry>().read()));
+      match save {
+        Ok(save) => load(world, save),
+        Err(err) => error!("Cannot load the game from {}: {err}", path.display()),
+      }
+    });
+  }
+}
+
+/// Respawn the level of the save, the save is applied once it is spawned
+fn load(world: &mut World, save: LoadedSave) {
+  let current_level = world
+    .resource::<AssetServer>()
+    .get_path(&world.resource::<GameAssets>().level)
+    .map(|path| path.to_string());
+  world.insert_resource(PendingLoad(save.scene));
+  if current_level.as_deref() == Some(save.header.level.as_str()) {
+    restart_game(world);
+    world
+      .resource_mut::<NextState<PlayState>>()
+      .set(PlayState::Playing);
+    world
+      .resource_mut::<NextState<CameraMode>>()
+      .set(CameraMode::Follow);
+  } else {
+    world.resource_mut::<SelectedLevel>().0 = save.header.level;
+    world
+      .resource_mut::<NextState<GameState>>()
+      .set(GameState::Loading);
+  }
+}
+
+fn apply_pending_load(world: &mut World) {
+  if let Some(PendingLoad(scene)) = world.remove_resource::<PendingLoad>() {
+    apply_save(world, &scene);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn world() -> World {
+    let mut app = App::new();
+    app
+      .register_type::<SaveId>()
+      .register_type::<Transform>()
+      .register_type::<LinearVelocity>()
+      .register_type::<AngularVelocity>()
+      .register_type::<Grounded>()
+      .register_type::<RespawnPoint>()
+      .init_resource::<SavedComponents>();
+    std::mem::take(app.world_mut())
+  }
+
+  fn read(world: &World, contents: &str) -> Result<LoadedSave, SaveError> {
+    read_save(contents, &world.resource::<AppTypeRegistry>().read())
+  }
+
+  #[test]
+  fn saves_round_trip() {
+    let mut world = world();
+    let player = world
+      .spawn((
+        SaveId::Player(0),
+        Transform::from_xyz(1.0, 2.0, 3.0),
+        LinearVelocity(Vec3::new(0.0, -4.0, 1.0)),
+        Grounded,
+      ))
+      .id();
+    let prop = world
+      .spawn((
+        SaveId::Object(3),
+        Transform::from_xyz(-2.0, 0.5, 0.0).with_rotation(Quat::from_rotation_y(1.0)),
+        AngularVelocity(Vec3::Y),
+      ))
+      .id();
+    let contents = write_save(&mut world, "levels/arena.level.ron").unwrap();
+
+    // the level respawns in its initial state before the save is applied
+    *world.get_mut::<Transform>(player).unwrap() = Transform::default();
+    *world.get_mut::<LinearVelocity>(player).unwrap() = LinearVelocity::ZERO;
+    world.entity_mut(player).remove::<Grounded>();
+    world.entity_mut(prop).insert(Grounded);
+    *world.get_mut::<AngularVelocity>(prop).unwrap() = AngularVelocity::ZERO;
+
+    let save = read(&world, &contents).unwrap();
+    assert_eq!(save.header.version, SAVE_VERSION);
+    assert_eq!(save.header.level, "levels/arena.level.ron");
+    apply_save(&mut world, &save.scene);
+
+    assert_eq!(
+      world.get::<Transform>(player).unwrap().translation,
+      Vec3::new(1.0, 2.0, 3.0)
+    );
+    assert_eq!(
+      world.get::<LinearVelocity>(player).unwrap().0,
+      Vec3::new(0.0, -4.0, 1.0)
+    );
+    assert!(world.get::<Grounded>(player).is_some());
+    assert_eq!(
+      *world.get::<Transform>(prop).unwrap(),
+      Transform::from_xyz(-2.0, 0.5, 0.0).with_rotation(Quat::from_rotation_y(1.0))
+    );
+    assert_eq!(world.get::<AngularVelocity>(prop).unwrap().0, Vec3::Y);
+    assert!(world.get::<Grounded>(prop).is_none());
+  }
+
+  #[test]
+  fn static_objects_are_not_saved() {
+    let mut world = world();
+    world.spawn((SaveId::Object(0), Transform::default(), RigidBody::Static));
+    world.spawn((SaveId::Object(1), Transform::default(), RigidBody::Dynamic));
+    let contents = write_save(&mut world, "levels/arena.level.ron").unwrap();
+    let save = read(&world, &contents).unwrap();
+    let ids: Vec<_> = save.scene.entities.iter().filter_map(save_id).collect();
+    assert_eq!(ids, [SaveId::Object(1)]);
+  }
+
+  #[test]
+  fn older_saves_are_migrated_in_order() {
+    // a made up history: version 2 renamed the arena, version 3 moved levels to a folder
+    let migrations: [Migration; 2] = [
+      |header, scene| {
+        header.level = header.level.replace("arena", "colosseum");
+        scene.push_str(" 2");
+      },
+      |header, scene| {
+        header.level = format!("levels/{}", header.level);
+        scene.push_str(" 3");
+      },
+    ];
+    let mut header = SaveHeader {
+      version: 1,
+      level: "arena.level.ron".to_string(),
+    };
+    let mut scene = "scene".to_string();
+    migrate(&mut header, &mut scene, &migrations).unwrap();
+    assert_eq!(
+      header,
+      SaveHeader {
+        version: 3,
+        level: "levels/colosseum.level.ron".to_string(),
+      }
+    );
+    assert_eq!(scene, "scene 2 3");
+
+    header.version = 4;
+    assert!(matches!(
+      migrate(&mut header, &mut scene, &migrations),
+      Err(SaveError::UnknownVersion(4))
+    ));
+  }
+
+  #[test]
+  fn newer_saves_are_rejected() {
+    let world = world();
+    let contents = format!(
+      "(version:{},level:\"\")\n(resources:{{}},entities:{{}})",
+      SAVE_VERSION + 1
+    );
+    assert!(matches!(
+      read(&world, &contents),
+      Err(SaveError::UnknownVersion(_))
+    ));
+  }
+}
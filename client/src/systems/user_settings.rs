@@ -0,0 +1,357 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::bevy_egui::{EguiContexts, egui};
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::systems::camera::PanOrbitSettings;
+
+pub struct UserSettingsPlugin;
+
+impl Plugin for UserSettingsPlugin {
+  fn build(&self, app: &mut App) {
+    let path = UserSettingsPath::from_args(std::env::args());
+    let settings = CameraUserSettings::load(&path.0);
+    app
+      .insert_resource(path)
+      .insert_resource(settings)
+      .init_resource::<SettingsPanel>()
+      .add_systems(
+        Update,
+        (toggle_settings_panel, settings_panel, apply_camera_settings).chain(),
+      )
+      // after everything that may quit, for the last change to be saved on exit
+      .add_systems(Last, save_camera_settings);
+  }
+}
+
+/// Where the user settings are read from and written to
+#[derive(Resource, Debug, Clone)]
+pub struct UserSettingsPath(pub PathBuf);
+
+impl UserSettingsPath {
+  /// A `--settings <path>` argument, or `settings.ron` in the platform config directory
+  pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+    let path = args
+      .into_iter()
+      .skip_while(|arg| arg != "--settings")
+      .nth(1)
+      .map(PathBuf::from)
+      .unwrap_or_else(|| {
+        dirs::config_dir()
+          .unwrap_or_default()
+          .join("bevy_playground")
+          .join("settings.ron")
+      });
+    UserSettingsPath(path)
+  }
+}
+
+/// The camera settings a user can change, saved between runs.
+/// Missing fields fall back to `PanOrbitSettings::default`.
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraUserSettings {
+  /// Degrees per pixel of mouse motion
+  pub orbit_sensitivity: f32,
+  /// Degrees per second with the right stick pushed all the way
+  pub stick_orbit_sensitivity: f32,
+  /// Exponent per pixel of mouse motion
+  pub zoom_sensitivity: f32,
+  pub invert_x: bool,
+  pub invert_y: bool,
+  /// Vertical field of view in degrees
+  pub fov: f32,
+}
+
+/// What the settings can be set to, in the panel and in the file
+const ORBIT_SENSITIVITY: RangeInclusive<f32> = 0.01..=1.0;
+const STICK_ORBIT_SENSITIVITY: RangeInclusive<f32> = 30.0..=720.0;
+const ZOOM_SENSITIVITY: RangeInclusive<f32> = 0.001..=0.1;
+const FOV: RangeInclusive<f32> = 30.0..=120.0;
+
+impl Default for CameraUserSettings {
+  fn default() -> Self {
+    let defaults = PanOrbitSettings::default();
+    CameraUserSettings {
+      orbit_sensitivity: defaults.orbit_sensitivity.to_degrees(),
+      stick_orbit_sensitivity: defaults.stick_orbit_sensitivity.to_degrees(),
+      zoom_sensitivity: defaults.zoom_sensitivity,
+      invert_x: defaults.invert_x,
+      invert_y: defaults.invert_y,
+      fov: defaults.fov.to_degrees(),
+    }
+  }
+}
+
+#[derive(Debug, Error)]
+pub enum SettingsError {
+  #[error("could not write the settings file: {0}")]
+  Io(#[from] std::io::Error),
+  #[error("could not serialize the settings: {0}")]
+  Serialize(#[from] ron::Error),
+}
+
+impl CameraUserSettings {
+  /// Read the settings file, falling back to the defaults if it is missing or broken.
+  /// Values out of the range of their slider are brought back into it.
+  pub fn load(path: &Path) -> Self {
+    match std::fs::read_to_string(path) {
+      Ok(contents) => ron::from_str::<CameraUserSettings>(&contents)
+        .map(CameraUserSettings::clamped)
+        .unwrap_or_else(|err| {
+          warn!("Ignoring broken settings file {}: {err}", path.display());
+          default()
+        }),
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => default(),
+      Err(err) => {
+        warn!("Cannot read settings file {}: {err}", path.display());
+        default()
+      }
+    }
+  }
+
+  /// Every value within the range of its slider, the default for values that are not numbers
+  pub fn clamped(self) -> Self {
+    let defaults = CameraUserSettings::default();
+    let clamp = |value: f32, range: RangeInclusive<f32>, default: f32| {
+      if value.is_finite() {
+        value.clamp(*range.start(), *range.end())
+      } else {
+        default
+      }
+    };
+    CameraUserSettings {
+      orbit_sensitivity: clamp(
+        self.orbit_sensitivity,
+        ORBIT_SENSITIVITY,
+        defaults.orbit_sensitivity,
+      ),
+      stick_orbit_sensitivity: clamp(
+        self.stick_orbit_sensitivity,
+        STICK_ORBIT_SENSITIVITY,
+        defaults.stick_orbit_sensitivity,
+      ),
+      zoom_sensitivity: clamp(
+        self.zoom_sensitivity,
+        ZOOM_SENSITIVITY,
+        defaults.zoom_sensitivity,
+      ),
+      fov: clamp(self.fov, FOV, defaults.fov),
+      ..self
+    }
+  }
+
+  pub fn save(&self, path: &Path) -> Result<(), SettingsError> {
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir)?;
+    }
+    let contents = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+    std::fs::write(path, contents)?;
+    Ok(())
+  }
+
+  /// Copy the user's choices over a camera's settings
+  pub fn apply(&self, settings: &mut PanOrbitSettings) {
+    settings.orbit_sensitivity = self.orbit_sensitivity.to_radians();
+    settings.stick_orbit_sensitivity = self.stick_orbit_sensitivity.to_radians();
+    settings.zoom_sensitivity = self.zoom_sensitivity;
+    settings.invert_x = self.invert_x;
+    settings.invert_y = self.invert_y;
+    settings.fov = self.fov.to_radians();
+  }
+}
+
+/// Whether the camera settings panel is open
+#[derive(Resource)]
+pub struct SettingsPanel {
+  pub open: bool,
+  /// Key to open and close the panel
+  pub toggle_key: KeyCode,
+}
+
+impl Default for SettingsPanel {
+  fn default() -> Self {
+    SettingsPanel {
+      open: false,
+      toggle_key: KeyCode::F2,
+    }
+  }
+}
+
+fn toggle_settings_panel(kbd: Res<ButtonInput<KeyCode>>, mut panel: ResMut<SettingsPanel>) {
+  if kbd.just_pressed(panel.toggle_key) {
+    panel.open = !panel.open;
+  }
+}
+
+fn settings_panel(
+  mut contexts: EguiContexts,
+  mut panel: ResMut<SettingsPanel>,
+  mut settings: ResMut<CameraUserSettings>,
+) {
+  if !panel.open {
+    return;
+  }
+  let mut edited = settings.clone();
+  egui::Window::new("Camera settings")
+    .open(&mut panel.open)
+    .resizable(false)
+    .show(contexts.ctx_mut(), |ui| {
+      ui.add(
+        egui::Slider::new(&mut edited.orbit_sensitivity, ORBIT_SENSITIVITY)
+          .text("Mouse orbit (°/px)"),
+      );
+      ui.add(
+        egui::Slider::new(&mut edited.stick_orbit_sensitivity, STICK_ORBIT_SENSITIVITY)
+          .text("Stick orbit (°/s)"),
+      );
+      ui.add(egui::Slider::new(&mut edited.zoom_sensitivity, ZOOM_SENSITIVITY).text("Zoom"));
+      ui.checkbox(&mut edited.invert_x, "Invert X");
+      ui.checkbox(&mut edited.invert_y, "Invert Y");
+      ui.add(egui::Slider::new(&mut edited.fov, FOV).text("Field of view (°)"));
+      if ui.button("Reset to defaults").clicked() {
+        edited = default();
+      }
+    });
+  // only mark the settings as changed when something actually changed
+  if edited != *settings {
+    *settings = edited;
+  }
+}
+
+fn apply_camera_settings(
+  user_settings: Res<CameraUserSettings>,
+  mut camera_q: Query<(&mut PanOrbitSettings, &mut Projection)>,
+) {
+  // new cameras pick up the settings as well, e.g. when a game starts
+  for (mut settings, mut projection) in &mut camera_q {
+    if !user_settings.is_changed() && !settings.is_added() {
+      continue;
+    }
+    user_settings.apply(&mut settings);
+    if let Projection::Perspective(perspective) = projection.as_mut() {
+      perspective.fov = settings.fov;
+    }
+  }
+}
+
+fn save_camera_settings(
+  time: Res<Time<Real>>,
+  path: Res<UserSettingsPath>,
+  settings: Res<CameraUserSettings>,
+  mut evr_exit: EventReader<AppExit>,
+  mut dirty_since: Local<Option<f32>>,
+) {
+  // wait for the sliders to settle instead of writing the file every frame
+  const DELAY: f32 = 0.5;
+
+  let now = time.elapsed_secs();
+  if settings.is_changed() && !settings.is_added() {
+    *dirty_since = Some(now);
+  }
+  let exiting = evr_exit.read().count() > 0;
+  if dirty_since.is_some_and(|since| exiting || now - since >= DELAY) {
+    *dirty_since = None;
+    if let Err(err) = settings.save(&path.0) {
+      error!("Cannot save settings to {}: {err}", path.0.display());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn settings_path_can_be_overridden() {
+    let args = ["client", "--settings", "/tmp/camera.ron"].map(String::from);
+    assert_eq!(
+      UserSettingsPath::from_args(args).0,
+      PathBuf::from("/tmp/camera.ron")
+    );
+    let default_path = UserSettingsPath::from_args(["client".to_string()]).0;
+    assert!(default_path.ends_with("bevy_playground/settings.ron"));
+  }
+
+  #[test]
+  fn missing_fields_fall_back_to_defaults() {
+    let settings: CameraUserSettings = ron::from_str("(invert_y: true, fov: 70.0)").unwrap();
+    assert!(settings.invert_y);
+    assert_eq!(settings.fov, 70.0);
+    assert_eq!(
+      settings.orbit_sensitivity,
+      CameraUserSettings::default().orbit_sensitivity
+    );
+  }
+
+  #[test]
+  fn settings_round_trip_through_the_file() {
+    let path = std::env::temp_dir()
+      .join(format!("bevy_playground_test_{}", std::process::id()))
+      .join("settings.ron");
+    let settings = CameraUserSettings {
+      invert_x: true,
+      zoom_sensitivity: 0.05,
+      ..default()
+    };
+    settings.save(&path).unwrap();
+    assert_eq!(CameraUserSettings::load(&path), settings);
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn pending_changes_are_saved_on_exit() {
+    let path = std::env::temp_dir()
+      .join(format!("bevy_playground_exit_test_{}", std::process::id()))
+      .join("settings.ron");
+    let mut app = App::new();
+    app
+      .init_resource::<Time<Real>>()
+      .insert_resource(UserSettingsPath(path.clone()))
+      .init_resource::<CameraUserSettings>()
+      .add_systems(Last, save_camera_settings);
+    app.update();
+    app.world_mut().resource_mut::<CameraUserSettings>().fov = 70.0;
+    app.world_mut().send_event(AppExit::Success);
+    app.update();
+    let saved = CameraUserSettings::load(&path);
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    assert_eq!(saved.fov, 70.0);
+  }
+
+  #[test]
+  fn loaded_values_stay_within_the_sliders() {
+    let path = std::env::temp_dir()
+      .join(format!("bevy_playground_clamp_test_{}", std::process::id()))
+      .join("settings.ron");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(
+      &path,
+      "(fov: 0.0, orbit_sensitivity: -2.0, zoom_sensitivity: NaN, stick_orbit_sensitivity: inf)",
+    )
+    .unwrap();
+    let settings = CameraUserSettings::load(&path);
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    let defaults = CameraUserSettings::default();
+    assert_eq!(settings.fov, 30.0);
+    assert_eq!(settings.orbit_sensitivity, 0.01);
+    assert_eq!(settings.zoom_sensitivity, defaults.zoom_sensitivity);
+    assert_eq!(
+      settings.stick_orbit_sensitivity,
+      defaults.stick_orbit_sensitivity
+    );
+    assert_eq!(defaults.clone().clamped(), defaults);
+  }
+
+  #[test]
+  fn defaults_match_the_camera_defaults() {
+    let mut settings = PanOrbitSettings::default();
+    CameraUserSettings::default().apply(&mut settings);
+    let defaults = PanOrbitSettings::default();
+    assert!((settings.orbit_sensitivity - defaults.orbit_sensitivity).abs() < 1e-6);
+    assert!((settings.fov - defaults.fov).abs() < 1e-6);
+    assert_eq!(settings.orbit_inversion(), Vec2::ONE);
+  }
+}
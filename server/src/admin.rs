@@ -0,0 +1,492 @@
+//! Console commands to run the server: typed into its terminal, or sent by tools to its
+//! admin socket, a TCP port of localhost taking one command per line.
+
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use serde_json::{Value, json};
+use shared::console::{CommandError, ConsoleCommand, ConsoleCommands, ConsolePlugin, run_command};
+use shared::controller::Grounded;
+use shared::level::ObjectIndex;
+use shared::tick::{Tick, ticks_per_second};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crate::config::MAX_TICK_RATE;
+use crate::lobby::Lobby;
+use crate::network::{self, Network};
+use crate::simulation::{self, Clients, RemotePlayer, ServerLevel, ServerState};
+
+/// Takes console commands from the terminal and the admin socket, and runs them between
+/// frames. The commands are in the `ConsoleCommands` registry.
+pub struct AdminPlugin {
+  /// Read commands from standard input
+  pub stdin: bool,
+  /// Localhost port of the admin socket, none without one. 0 picks a free port.
+  pub port: Option<u16>,
+}
+
+impl Plugin for AdminPlugin {
+  fn build(&self, app: &mut App) {
+    let (sender, receiver) = mpsc::channel();
+    app.add_plugins(ConsolePlugin);
+    let mut commands = app.world_mut().resource_mut::<ConsoleCommands>();
+    for command in COMMANDS {
+      commands.add(command);
+    }
+    if self.stdin {
+      let sender = sender.clone();
+      thread::spawn(move || serve(io::stdin().lock(), io::stdout(), sender));
+    }
+    if let Some(port) = self.port {
+      match TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+        Ok(listener) => {
+          let address = listener
+            .local_addr()
+            .expect("a bound socket has an address");
+          info!("Admin socket on {address}");
+          app.insert_resource(AdminSocket(address));
+          thread::spawn(move || accept_admins(listener, sender));
+        }
+        Err(error) => error!("Could not open the admin socket on port {port}: {error}"),
+      }
+    }
+    app
+      .insert_resource(AdminRequests(Mutex::new(receiver)))
+      .add_systems(Update, run_admin_commands);
+  }
+}
+
+/// Where the admin socket listens
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct AdminSocket(pub SocketAddr);
+
+/// A command line, and where its output goes
+struct AdminRequest {
+  line: String,
+  reply: Sender<String>,
+}
+
+#[derive(Resource)]
+struct AdminRequests(Mutex<Receiver<AdminRequest>>);
+
+/// Answer the commands of `input` one at a time. Every answer ends with an empty line.
+fn serve(input: impl BufRead, mut output: impl Write, requests: Sender<AdminRequest>) {
+  for line in input.lines() {
+    let Ok(line) = line else {
+      return;
+    };
+    if line.trim().is_empty() {
+      continue;
+    }
+    let (reply, answer) = mpsc::channel();
+    if requests.send(AdminRequest { line, reply }).is_err() {
+      return;
+    }
+    // the server is gone without an answer
+    let Ok(answer) = answer.recv() else {
+      return;
+    };
+    if writeln!(output, "{answer}\n")
+      .and_then(|_| output.flush())
+      .is_err()
+    {
+      return;
+    }
+  }
+}
+
+fn accept_admins(listener: TcpListener, requests: Sender<AdminRequest>) {
+  for stream in listener.incoming() {
+    let Ok(stream) = stream else {
+      continue;
+    };
+    // the socket is bound to localhost, this is only to be sure
+    if !stream
+      .peer_addr()
+      .is_ok_and(|address| address.ip().is_loopback())
+    {
+      continue;
+    }
+    let Ok(input) = stream.try_clone() else {
+      continue;
+    };
+    let requests = requests.clone();
+    thread::spawn(move || serve(BufReader::new(input), stream, requests));
+  }
+}
+
+fn run_admin_commands(world: &mut World) {
+  let requests = world
+    .resource::<AdminRequests>()
+    .0
+    .lock()
+    .expect("the admin requests are never poisoned")
+    .try_iter()
+    .collect::<Vec<_>>();
+  for request in requests {
+    info!("Admin command: {}", request.line);
+    let answer = run_command(world, &request.line).unwrap_or_else(|error| error.to_string());
+    // whoever asked may have gone already
+    let _ = request.reply.send(answer);
+  }
+}
+
+/// The commands of the server, for its consoles and maybe one day the client's
+pub const COMMANDS: [ConsoleCommand; 6] = [PLAYERS, KICK, LEVEL, TICK_RATE, SPAWN, DUMP];
+
+const PLAYERS: ConsoleCommand = ConsoleCommand {
+  name: "players",
+  arguments: "",
+  help: "List the connected clients, and the players no one controls",
+  run: |world, _| {
+    let server = &world.resource::<Network>().0;
+    let lobby = world.resource::<Lobby>();
+    let mut clients = server.clients().collect::<Vec<_>>();
+    clients.sort();
+    let mut lines = clients
+      .iter()
+      .map(|&client| {
+        let address = server
+          .address(client)
+          .map_or_else(String::new, |address| address.to_string());
+        let room = match lobby.room_of(client) {
+          Some(room) if room.playing => format!("playing in {:?}", room.name),
+          Some(room) => format!("waiting in {:?}", room.name),
+          None => "in the lobby".to_string(),
+        };
+        let rtt = server
+          .rtt(client)
+          .map_or_else(String::new, |rtt| format!(", {}ms", rtt.as_millis()));
+        format!("{client}  {address}  {room}{rtt}")
+      })
+      .collect::<Vec<_>>();
+    let mut idle = world
+      .resource::<Clients>()
+      .0
+      .keys()
+      .map(|client| client.0)
+      .filter(|client| !clients.contains(client))
+      .collect::<Vec<_>>();
+    idle.sort();
+    lines.extend(idle.iter().map(|client| format!("{client}  idle player")));
+    if lines.is_empty() {
+      return Ok("No players".to_string());
+    }
+    Ok(lines.join("\n"))
+  },
+};
+
+const KICK: ConsoleCommand = ConsoleCommand {
+  name: "kick",
+  arguments: "<client>",
+  help: "Disconnect a client, or remove an idle player",
+  run: |world, arguments| {
+    let client = KICK.argument(arguments, 0)?;
+    if network::kick(world, client) {
+      Ok(format!("Kicked {client}"))
+    } else {
+      Err(CommandError::Failed(format!("There is no client {client}")))
+    }
+  },
+};
+
+const LEVEL: ConsoleCommand = ConsoleCommand {
+  name: "level",
+  arguments: "<path>",
+  help: "Run another level, the game in progress ends",
+  run: |world, arguments| {
+    let level: String = LEVEL.argument(arguments, 0)?;
+    let players = network::stop_game(world, &format!("The server is changing level to {level}"));
+    simulation::load_level(world, &level);
+    Ok(format!(
+      "Loading {level}, {players} players are back in their room"
+    ))
+  },
+};
+
+const TICK_RATE: ConsoleCommand = ConsoleCommand {
+  name: "tick-rate",
+  arguments: "<hz>",
+  help: "Simulate this many ticks per second",
+  run: |world, arguments| {
+    let tick_rate: u32 = TICK_RATE.argument(arguments, 0)?;
+    if !(1..=MAX_TICK_RATE).contains(&tick_rate) {
+      return Err(CommandError::Failed(format!(
+        "The tick rate is 1 to {MAX_TICK_RATE}"
+      )));
+    }
+    network::set_tick_rate(world, tick_rate);
+    Ok(format!("Ticking {tick_rate} times per second"))
+  },
+};
+
+const SPAWN: ConsoleCommand = ConsoleCommand {
+  name: "spawn",
+  arguments: "[x y z]",
+  help: "Spawn an idle player, at the next spawn point or a position",
+  run: |world, arguments| {
+    let translation = if arguments.is_empty() {
+      None
+    } else {
+      let translation = Vec3::new(
+        SPAWN.argument(arguments, 0)?,
+        SPAWN.argument(arguments, 1)?,
+        SPAWN.argument(arguments, 2)?,
+      );
+      // `nan`, `inf` and numbers too large for an f32 parse too
+      if !translation.is_finite() {
+        return Err(CommandError::Failed(format!(
+          "The position {translation} is not finite"
+        )));
+      }
+      Some(translation)
+    };
+    let client = network::spawn_idle_player(world, translation)
+      .ok_or_else(|| CommandError::Failed("No level is running".to_string()))?;
+    Ok(format!("Spawned idle player {client}"))
+  },
+};
+
+const DUMP: ConsoleCommand = ConsoleCommand {
+  name: "dump",
+  arguments: "",
+  help: "Print the state of the world as JSON",
+  run: |world, arguments| {
+    // the admin socket takes anyone on localhost, it does not write files for them
+    if !arguments.is_empty() {
+      return Err(CommandError::Usage(DUMP.usage()));
+    }
+    serde_json::to_string_pretty(&world_json(world))
+      .map_err(|error| CommandError::Failed(error.to_string()))
+  },
+};
+
+#[allow(clippy::type_complexity)]
+fn world_json(world: &mut World) -> Value {
+  let players = world
+    .query::<(&RemotePlayer, &Transform, &LinearVelocity, Has<Grounded>)>()
+    .iter(world)
+    .map(|(player, transform, velocity, grounded)| {
+      json!({
+        "client": player.client.0,
+        "last_input_tick": player.last_input_tick,
+        "translation": transform.translation.to_array(),
+        "velocity": velocity.0.to_array(),
+        "grounded": grounded,
+      })
+    })
+    .collect::<Vec<_>>();
+  let mut objects = world
+    .query::<(
+      &ObjectIndex,
+      &RigidBody,
+      &Transform,
+      Option<&LinearVelocity>,
+    )>()
+    .iter(world)
+    .map(|(index, rigid_body, transform, velocity)| {
+      (
+        index.0,
+        json!({
+          "index": index.0,
+          "body": format!("{rigid_body:?}"),
+          "translation": transform.translation.to_array(),
+          "rotation": transform.rotation.to_array(),
+          "velocity": velocity.map(|velocity| velocity.0.to_array()),
+        }),
+      )
+    })
+    .collect::<Vec<_>>();
+  objects.sort_by_key(|(index, _)| *index);
+  let rooms = world
+    .resource::<Lobby>()
+    .rooms()
+    .iter()
+    .map(|room| {
+      json!({
+        "id": room.id,
+        "name": room.name,
+        "host": room.host,
+        "max_players": room.max_players,
+        "playing": room.playing,
+        "members": room.members.iter().map(|member| json!({
+          "client": member.client,
+          "ready": member.ready,
+        })).collect::<Vec<_>>(),
+        "banned": room.banned.iter().collect::<Vec<_>>(),
+      })
+    })
+    .collect::<Vec<_>>();
+  let level = world
+    .get_resource::<ServerLevel>()
+    .and_then(|level| world.resource::<AssetServer>().get_path(&level.0))
+    .map(|path| path.to_string());
+  json!({
+    "level": level,
+    "state": format!("{:?}", world.resource::<State<ServerState>>().get()),
+    "tick": world.resource::<Tick>().0,
+    "tick_rate": ticks_per_second(world.resource::<Time<Fixed>>()),
+    "clients": world.resource::<Network>().0.clients().count(),
+    "players": players,
+    "objects": objects.into_iter().map(|(_, object)| object).collect::<Vec<_>>(),
+    "rooms": rooms,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lobby::MAX_ROOM_PLAYERS;
+  use crate::network::NetworkPlugin;
+  use crate::simulation::tests::server;
+  use shared::net::LinkConditions;
+  use std::io::Read;
+  use std::net::TcpStream;
+  use std::time::Duration;
+
+  fn admin_server() -> App {
+    let mut app = server();
+    app.add_plugins((
+      NetworkPlugin {
+        address: ([127, 0, 0, 1], 0).into(),
+        max_clients: 16,
+        room_size: MAX_ROOM_PLAYERS,
+        conditions: LinkConditions::default(),
+      },
+      AdminPlugin {
+        stdin: false,
+        port: Some(0),
+      },
+    ));
+    app
+  }
+
+  #[test]
+  fn commands_run_the_server() {
+    let mut app = admin_server();
+    let mut run = |line: &str| run_command(app.world_mut(), line);
+
+    assert_eq!(run("players"), Ok("No players".to_string()));
+    let spawned = run("spawn 1 2 3").unwrap();
+    let client = spawned.rsplit(' ').next().unwrap().to_string();
+    assert_eq!(
+      run("spawn 1 2"),
+      Err(CommandError::Usage("spawn [x y z]".into()))
+    );
+    for position in ["nan 0 0", "0 inf 0", "0 0 1e39"] {
+      assert!(matches!(
+        run(&format!("spawn {position}")),
+        Err(CommandError::Failed(_))
+      ));
+    }
+    app.update();
+    let mut run = |line: &str| run_command(app.world_mut(), line);
+    assert_eq!(run("players"), Ok(format!("{client}  idle player")));
+
+    let dump: Value = serde_json::from_str(&run("dump").unwrap()).unwrap();
+    assert_eq!(dump["players"][0]["client"].to_string(), client);
+    assert_eq!(dump["level"], "levels/arena.level.ron");
+    assert!(!dump["objects"].as_array().unwrap().is_empty());
+    assert_eq!(
+      run("dump /tmp/world.json"),
+      Err(CommandError::Usage("dump".into()))
+    );
+
+    assert_eq!(
+      run("tick-rate 500"),
+      Err(CommandError::Failed("The tick rate is 1 to 240".into()))
+    );
+    run("tick-rate 30").unwrap();
+    assert_eq!(ticks_per_second(app.world().resource::<Time<Fixed>>()), 30);
+
+    let mut run = |line: &str| run_command(app.world_mut(), line);
+    assert_eq!(
+      run(&format!("kick {client}")),
+      Ok(format!("Kicked {client}"))
+    );
+    assert_eq!(
+      run("kick 999"),
+      Err(CommandError::Failed("There is no client 999".into()))
+    );
+    app.update();
+    assert_eq!(
+      run_command(app.world_mut(), "players"),
+      Ok("No players".to_string())
+    );
+  }
+
+  #[test]
+  fn levels_change_between_games() {
+    let mut app = admin_server();
+    run_command(app.world_mut(), "spawn").unwrap();
+    app.update();
+    run_command(app.world_mut(), "level levels/arena.level.ron").unwrap();
+    app.update();
+    assert_eq!(
+      *app.world().resource::<State<ServerState>>(),
+      ServerState::Loading
+    );
+    for _ in 0..100 {
+      app.update();
+    }
+    assert_eq!(
+      *app.world().resource::<State<ServerState>>(),
+      ServerState::Running
+    );
+    assert!(app.world().resource::<Clients>().0.is_empty());
+    let objects = app
+      .world_mut()
+      .query::<&ObjectIndex>()
+      .iter(app.world())
+      .count();
+    assert!(objects > 0);
+  }
+
+  #[test]
+  fn levels_failing_to_load_give_way_to_the_previous_one() {
+    let mut app = admin_server();
+    for _ in 0..100 {
+      app.update();
+    }
+    run_command(app.world_mut(), "level levels/missing.level.ron").unwrap();
+    for _ in 0..100 {
+      app.update();
+    }
+    assert_eq!(
+      *app.world().resource::<State<ServerState>>(),
+      ServerState::Running
+    );
+    let dump = run_command(app.world_mut(), "dump").unwrap();
+    let dump: Value = serde_json::from_str(&dump).unwrap();
+    assert_eq!(dump["level"], "levels/arena.level.ron");
+  }
+
+  #[test]
+  fn the_admin_socket_answers_commands() {
+    let mut app = admin_server();
+    let address = app.world().resource::<AdminSocket>().0;
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+      .set_read_timeout(Some(Duration::from_millis(5)))
+      .unwrap();
+    stream.write_all(b"players\nfly\n").unwrap();
+    let mut answers = String::new();
+    for _ in 0..500 {
+      app.update();
+      let mut buffer = [0; 256];
+      if let Ok(read) = stream.read(&mut buffer) {
+        answers.push_str(std::str::from_utf8(&buffer[..read]).unwrap());
+      }
+      if answers.matches("\n\n").count() == 2 {
+        break;
+      }
+    }
+    assert_eq!(
+      answers,
+      "No players\n\nUnknown command \"fly\", try help\n\n"
+    );
+  }
+}
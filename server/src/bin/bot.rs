@@ -0,0 +1,27 @@
+use std::process::ExitCode;
+
+use server::bot::{BotCommand, USAGE, run};
+
+fn main() -> ExitCode {
+  let config = match BotCommand::from_args(std::env::args()) {
+    Ok(BotCommand::Run(config)) => config,
+    Ok(BotCommand::Help) => {
+      println!("{USAGE}");
+      return ExitCode::SUCCESS;
+    }
+    Err(error) => {
+      eprintln!("{error}");
+      return ExitCode::from(2);
+    }
+  };
+  match run(&config) {
+    Ok(report) => {
+      println!("{report}");
+      ExitCode::SUCCESS
+    }
+    Err(error) => {
+      eprintln!("{error}");
+      ExitCode::FAILURE
+    }
+  }
+}
@@ -0,0 +1,1053 @@
+//! Headless clients playing on a server over loopback, to find out how many players it
+//! handles. The bots gather in one room and move by a script or at random, a few of them
+//! predicting their player as the game does. The report gives the update times of the
+//! server, the traffic, and how far the predictions were from the server.
+
+use bevy::ecs::system::RunSystemOnce;
+use bevy::log::Level as LogLevel;
+use bevy::prelude::*;
+use shared::checkpoint::CheckpointPlugin;
+use shared::controller::{MovementAction, MovementPlugin, PlayerInput};
+use shared::interpolation::SnapshotBuffer;
+use shared::level::{Level, LevelLoader, LevelSpawner, PrefabRegistry, player_object};
+use shared::net::conditioner::Random;
+use shared::net::{
+  CONDITION_ARGUMENTS, ClientMessage, ClientNetEvent, ClientState, INPUT_REDUNDANCY,
+  LinkConditions, LobbyRequest, NetClient, NetStats, PlayerSnapshot, ServerMessage, TickInput,
+};
+use shared::prediction::{
+  AuthoritativeState, ControllerState, PredictionError, PredictionHistory, PredictionPlugin,
+  TOLERANCE,
+};
+use shared::stepped::{set_tick_rate, stepped_app};
+use shared::tick::Tick;
+use shared::tick::TickPlugin;
+use std::collections::VecDeque;
+use std::f64::consts::TAU;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::config::{ConfigError, MAX_PLAYERS, ServerConfig, parse, parse_condition};
+use crate::network::Network;
+use crate::server_app;
+
+/// The room the bots gather in
+const ROOM: &str = "Bots";
+/// Bots not all in the game by then give up
+const JOIN_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub const USAGE: &str = "\
+Usage: bot [options]
+
+Options:
+  --bots <n>            Clients to connect, 1 to 64 [default: 8]
+  --duration <s>        Seconds to play once every bot is in the game [default: 30]
+  --script <path>       Play the moves of a script in a loop, rather than random ones
+  --seed <n>            Seed of the random moves [default: 1]
+  --predicting <n>      Bots predicting their player, to measure prediction errors [default: 4]
+  --connect <address>   Play on a running server, rather than one started here,
+                        with a --room-size of at least the bots
+  --tick-rate <hz>      Ticks per second of the server started here [default: 60]
+  --level <path>        Level of the server started here [default: levels/arena.level.ron]
+  --assets <path>       Assets of the server started here and of the predicting bots
+                        [default: ../client/assets]
+  --help                Print this and exit
+
+Simulated network, between the bots and the server:
+  --latency <ms>  --jitter <ms>  --loss <%>  --duplicate <%>  --reorder <%>
+
+Scripts have one move per line, played in a loop, # starts a comment:
+  move <x> <y> <ticks>  Walk in a direction, forward is 0 1
+  jump                  Jump, keeping the direction
+  wait <ticks>          Stand still";
+
+/// What the command line asks for
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum BotCommand {
+  Run(BotConfig),
+  Help,
+}
+
+impl BotCommand {
+  /// Parse arguments as `std::env::args` gives them, the program first,
+  /// reading the `--script` file if there is one
+  pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+    let mut config = BotConfig::default();
+    let mut seed = 1;
+    let mut script = None;
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+      if arg == "--help" || arg == "-h" {
+        return Ok(BotCommand::Help);
+      }
+      let mut value = || {
+        args
+          .next()
+          .ok_or_else(|| ConfigError::MissingValue(arg.clone()))
+      };
+      match arg.as_str() {
+        "--bots" => config.bots = parse("bot count", &value()?, "a number of bots")?,
+        "--duration" => {
+          config.duration = Duration::from_secs(parse("duration", &value()?, "seconds")?)
+        }
+        "--script" => script = Some(PathBuf::from(value()?)),
+        "--seed" => seed = parse("seed", &value()?, "a number")?,
+        "--predicting" => {
+          config.predicting = parse("predicting bot count", &value()?, "a number of bots")?
+        }
+        "--connect" => {
+          config.connect = Some(parse(
+            "server address",
+            &value()?,
+            "an IP address and a port",
+          )?)
+        }
+        "--tick-rate" => {
+          config.server.tick_rate = parse("tick rate", &value()?, "ticks per second")?
+        }
+        "--level" => config.server.level = value()?,
+        "--assets" => config.server.assets = value()?,
+        _ if CONDITION_ARGUMENTS.contains(&arg.as_str()) => {
+          parse_condition(&mut config.conditions, &arg, value()?)?
+        }
+        _ => return Err(ConfigError::UnknownArgument(arg)),
+      }
+    }
+
+    config.moves = match script {
+      Some(path) => Moves::Script(read_script(&path)?),
+      None => Moves::Random { seed },
+    };
+    if !(1..=MAX_PLAYERS).contains(&config.bots) {
+      return Err(ConfigError::InvalidValue {
+        setting: "bot count",
+        value: config.bots.to_string(),
+        expected: "1 to 64 bots",
+      });
+    }
+    config.predicting = config.predicting.min(config.bots);
+    // the bots play together, in one room
+    config.server.max_players = config.bots;
+    config.server.room_size = config.bots as u8;
+    config.server.validate()?;
+    Ok(BotCommand::Run(config))
+  }
+}
+
+/// How the bots play
+#[derive(Debug, Clone, PartialEq)]
+pub struct BotConfig {
+  pub bots: usize,
+  /// How long to play once every bot is in the game
+  pub duration: Duration,
+  pub moves: Moves,
+  /// Bots simulating their player as the game does, the first ones
+  pub predicting: usize,
+  /// A running server, none to start one in this process
+  pub connect: Option<SocketAddr>,
+  /// The server started here, on a free port of localhost
+  pub server: ServerConfig,
+  /// The network between the bots and the server, both ways
+  pub conditions: LinkConditions,
+}
+
+impl Default for BotConfig {
+  fn default() -> Self {
+    BotConfig {
+      bots: 8,
+      duration: Duration::from_secs(30),
+      moves: Moves::Random { seed: 1 },
+      predicting: 4,
+      connect: None,
+      server: ServerConfig {
+        bind: Ipv4Addr::LOCALHOST.into(),
+        port: 0,
+        max_players: 8,
+        // the bots have their own report
+        log_level: LogLevel::WARN,
+        ..default()
+      },
+      conditions: LinkConditions::default(),
+    }
+  }
+}
+
+/// Where the inputs of the bots come from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Moves {
+  /// Each bot walks its own way, from `seed` and its index
+  Random { seed: u64 },
+  /// Every bot plays the same moves in a loop
+  Script(Vec<ScriptMove>),
+}
+
+/// A line of a script
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScriptMove {
+  Move { direction: Vec2, ticks: u32 },
+  Jump,
+  Wait(u32),
+}
+
+fn read_script(path: &Path) -> Result<Vec<ScriptMove>, ConfigError> {
+  let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+    path: path.to_path_buf(),
+    source,
+  })?;
+  parse_script(&text)
+}
+
+pub fn parse_script(text: &str) -> Result<Vec<ScriptMove>, ConfigError> {
+  let mut moves = Vec::new();
+  for line in text.lines() {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if line.is_empty() {
+      continue;
+    }
+    let invalid = || ConfigError::InvalidValue {
+      setting: "script line",
+      value: line.to_string(),
+      expected: "move <x> <y> <ticks>, jump or wait <ticks>",
+    };
+    let coordinate = |value: &str| {
+      value
+        .parse::<f32>()
+        .ok()
+        .filter(|value| value.is_finite())
+        .ok_or_else(invalid)
+    };
+    let ticks = |ticks: &str| {
+      ticks
+        .parse::<u32>()
+        .ok()
+        .filter(|ticks| *ticks > 0)
+        .ok_or_else(invalid)
+    };
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    moves.push(match words.as_slice() {
+      ["move", x, y, duration] => ScriptMove::Move {
+        direction: Vec2::new(coordinate(x)?, coordinate(y)?).clamp_length_max(1.0),
+        ticks: ticks(duration)?,
+      },
+      ["jump"] => ScriptMove::Jump,
+      ["wait", duration] => ScriptMove::Wait(ticks(duration)?),
+      _ => return Err(invalid()),
+    });
+  }
+  if moves.is_empty() {
+    return Err(ConfigError::InvalidValue {
+      setting: "script",
+      value: text.to_string(),
+      expected: "at least one move",
+    });
+  }
+  Ok(moves)
+}
+
+/// The input of a bot, tick after tick
+struct Mover {
+  /// Played in a loop, random moves without one
+  script: Vec<ScriptMove>,
+  next: usize,
+  random: Random,
+  /// Ticks left of the current move
+  left: u32,
+  direction: Vec2,
+  jump: bool,
+}
+
+impl Mover {
+  fn new(moves: &Moves, bot: usize) -> Self {
+    let (script, seed) = match moves {
+      Moves::Random { seed } => (Vec::new(), seed.wrapping_add(bot as u64)),
+      Moves::Script(script) => (script.clone(), 0),
+    };
+    Mover {
+      script,
+      next: 0,
+      random: Random::new(seed),
+      left: 0,
+      direction: Vec2::ZERO,
+      jump: false,
+    }
+  }
+
+  fn next(&mut self) -> PlayerInput {
+    if self.script.is_empty() {
+      if self.left == 0 {
+        // 30 to 120 ticks one way, or standing still
+        self.left = 30 + (self.random.unit() * 90.0) as u32;
+        self.direction = if self.random.chance(0.2) {
+          Vec2::ZERO
+        } else {
+          Vec2::from_angle((self.random.unit() * TAU) as f32)
+        };
+      }
+      self.jump = self.random.chance(1.0 / 90.0);
+    }
+    while self.left == 0 {
+      match self.script[self.next % self.script.len()] {
+        ScriptMove::Move { direction, ticks } => {
+          self.direction = direction;
+          self.left = ticks;
+        }
+        ScriptMove::Jump => {
+          self.jump = true;
+          self.left = 1;
+        }
+        ScriptMove::Wait(ticks) => {
+          self.direction = Vec2::ZERO;
+          self.left = ticks;
+        }
+      }
+      self.next += 1;
+    }
+    self.left -= 1;
+    PlayerInput {
+      movement: self.direction,
+      jump: std::mem::take(&mut self.jump),
+    }
+  }
+}
+
+/// The player of a bot simulated as the game client does, to compare with the server
+struct Predictor {
+  app: App,
+  level: Handle<Level>,
+  player: Option<Entity>,
+}
+
+impl Predictor {
+  fn new(assets: &str, level: &str) -> Self {
+    let mut app = stepped_app(assets);
+    app
+      .add_plugins((
+        MovementPlugin::default(),
+        CheckpointPlugin,
+        TickPlugin,
+        PredictionPlugin,
+      ))
+      .init_asset::<Level>()
+      .register_asset_loader(LevelLoader { textures: false })
+      .init_resource::<PrefabRegistry>();
+    let level = app
+      .world()
+      .resource::<AssetServer>()
+      .load(level.to_string());
+    Predictor {
+      app,
+      level,
+      player: None,
+    }
+  }
+
+  /// One tick on every update, at the rate of the server
+  fn set_tick_rate(&mut self, tick_rate: u32) {
+    set_tick_rate(&mut self.app, tick_rate as f64);
+  }
+
+  /// The level could not be loaded, so there is nothing to predict
+  fn failed(&self) -> bool {
+    let asset_server = self.app.world().resource::<AssetServer>();
+    asset_server.load_state(&self.level).is_failed()
+  }
+
+  /// Spawn the level, and the player where the server has it, once the level is loaded
+  fn start(&mut self, translation: Vec3) {
+    let level = self.level.clone();
+    let spawned = self.app.world_mut().run_system_once(
+      move |mut spawner: LevelSpawner, levels: Res<Assets<Level>>| {
+        spawner.spawn_level(levels.get(&level)?);
+        let player = spawner.spawn_object(&player_object(translation))?;
+        spawner
+          .commands
+          .entity(player)
+          .insert((PredictionHistory::default(), PredictionError::default()));
+        Some(player)
+      },
+    );
+    self.player = spawned.ok().flatten();
+  }
+
+  /// Run a tick with `input`, returns the tick once the player is predicted
+  fn step(&mut self, input: PlayerInput) -> Option<u64> {
+    let player = self.player?;
+    if let Some(mut current) = self.app.world_mut().get_mut::<PlayerInput>(player) {
+      *current = input;
+    }
+    self.app.update();
+    let history = self.app.world().get::<PredictionHistory>(player)?;
+    history.ticks().last().map(|predicted| predicted.tick)
+  }
+
+  /// How far the prediction of `tick` was from the server's state
+  fn error(&self, tick: u64, translation: Vec3) -> Option<f32> {
+    let history = self.app.world().get::<PredictionHistory>(self.player?)?;
+    let predicted = history.ticks().find(|predicted| predicted.tick == tick)?;
+    Some(predicted.state?.translation.distance(translation))
+  }
+
+  fn correct(&mut self, player: &PlayerSnapshot) {
+    let Some(entity) = self.player else {
+      return;
+    };
+    self.app.world_mut().send_event(AuthoritativeState {
+      entity,
+      tick: player.last_input_tick,
+      state: ControllerState {
+        translation: player.translation,
+        velocity: player.velocity,
+        grounded: player.grounded,
+      },
+    });
+  }
+}
+
+/// What the bots measured while playing
+#[derive(Default)]
+struct BotStats {
+  snapshots: usize,
+  snapshot_bytes: usize,
+  /// Distances between predicted and confirmed positions
+  prediction_errors: Vec<f64>,
+  inputs_sent: usize,
+}
+
+struct Bot {
+  index: usize,
+  client: NetClient,
+  mover: Mover,
+  /// Predicts its player in the level of the server, once welcomed
+  predicting: bool,
+  assets: String,
+  predictor: Option<Predictor>,
+  snapshots: SnapshotBuffer,
+  /// The latest inputs, sent again with every new one
+  inputs: VecDeque<TickInput>,
+  /// Tick of the latest input, when not predicting
+  tick: u64,
+  /// Created or asked to join the room
+  joined: bool,
+  ready: bool,
+  playing: bool,
+  /// Latest input tick the server confirmed
+  confirmed: u64,
+}
+
+impl Bot {
+  fn connect(address: SocketAddr, index: usize, config: &BotConfig) -> io::Result<Self> {
+    let mut client = NetClient::connect(address)?;
+    client.set_conditions(config.conditions);
+    Ok(Bot {
+      index,
+      client,
+      mover: Mover::new(&config.moves, index),
+      predicting: index < config.predicting,
+      assets: config.server.assets.clone(),
+      predictor: None,
+      snapshots: SnapshotBuffer::default(),
+      inputs: VecDeque::new(),
+      tick: 0,
+      joined: false,
+      ready: false,
+      playing: false,
+      confirmed: 0,
+    })
+  }
+
+  /// Playing, and predicting if it does
+  fn in_game(&self) -> bool {
+    self.playing
+      && self
+        .predictor
+        .as_ref()
+        .is_none_or(|predictor| predictor.player.is_some())
+  }
+
+  fn receive(&mut self, bots: usize, tick_rate: &mut u32, stats: &mut BotStats) {
+    for event in self.client.receive(Instant::now()) {
+      match event {
+        ClientNetEvent::Connected(_) if self.index == 0 => {
+          self
+            .client
+            .send(&ClientMessage::Lobby(LobbyRequest::CreateRoom {
+              name: ROOM.to_string(),
+              max_players: bots as u8,
+            }));
+          self.joined = true;
+        }
+        ClientNetEvent::Message(ServerMessage::Rooms(rooms)) if !self.joined => {
+          if let Some(room) = rooms.iter().find(|room| room.name == ROOM && !room.playing) {
+            self
+              .client
+              .send(&ClientMessage::Lobby(LobbyRequest::JoinRoom(room.id)));
+            self.joined = true;
+          }
+        }
+        // ready once everyone is in, or the room would start without the others
+        ClientNetEvent::Message(ServerMessage::Room(Some(room)))
+          if !self.ready && room.members.len() == bots =>
+        {
+          self
+            .client
+            .send(&ClientMessage::Lobby(LobbyRequest::SetReady(true)));
+          self.ready = true;
+        }
+        ClientNetEvent::Message(ServerMessage::Welcome {
+          level,
+          tick_rate: rate,
+          ..
+        }) => {
+          self.playing = true;
+          *tick_rate = rate;
+          if self.predicting {
+            // ticks start over with the new level
+            let mut predictor = Predictor::new(&self.assets, &level);
+            predictor.set_tick_rate(rate);
+            self.predictor = Some(predictor);
+            self.inputs.clear();
+            self.confirmed = 0;
+          }
+        }
+        ClientNetEvent::Message(ServerMessage::TickRate(rate)) => {
+          *tick_rate = rate;
+          if let Some(predictor) = &mut self.predictor {
+            predictor.set_tick_rate(rate);
+          }
+        }
+        ClientNetEvent::Message(ServerMessage::Snapshot(delta)) => {
+          let baseline = match delta.baseline {
+            Some(tick) => match self.snapshots.get(tick) {
+              Some(baseline) => Some(baseline),
+              None => continue,
+            },
+            None => None,
+          };
+          let Ok(snapshot) = delta.decode(baseline) else {
+            continue;
+          };
+          stats.snapshots += 1;
+          stats.snapshot_bytes += delta.data.len();
+          let ClientState::Connected { client } = self.client.state() else {
+            continue;
+          };
+          if let Some(player) = snapshot
+            .players
+            .iter()
+            .find(|player| player.client == client)
+          {
+            self.confirm(player, stats);
+          }
+          self.snapshots.push(snapshot);
+        }
+        ClientNetEvent::Message(ServerMessage::Notice(notice)) => {
+          debug!("Bot {}: {notice}", self.index);
+        }
+        ClientNetEvent::Disconnected(reason) => {
+          warn!("Bot {} disconnected: {reason:?}", self.index);
+          self.playing = false;
+        }
+        _ => {}
+      }
+    }
+  }
+
+  /// Compare the server's state of the player with the prediction, and correct it
+  fn confirm(&mut self, player: &PlayerSnapshot, stats: &mut BotStats) {
+    let Some(predictor) = &mut self.predictor else {
+      return;
+    };
+    if predictor.player.is_none() {
+      predictor.start(player.translation);
+      return;
+    }
+    if player.last_input_tick <= self.confirmed {
+      return;
+    }
+    self.confirmed = player.last_input_tick;
+    if let Some(error) = predictor.error(player.last_input_tick, player.translation) {
+      stats.prediction_errors.push(error as f64);
+    }
+    predictor.correct(player);
+  }
+
+  /// Play a tick, and send its input with the previous ones
+  fn step(&mut self, stats: &mut BotStats) {
+    if let Some(predictor) = &self.predictor
+      && predictor.failed()
+    {
+      warn!(
+        "Bot {} cannot load the level, it stops predicting",
+        self.index
+      );
+      self.predicting = false;
+      self.predictor = None;
+    }
+    if !self.in_game() {
+      // the level loads meanwhile
+      if let Some(predictor) = &mut self.predictor {
+        predictor.app.update();
+      }
+      return;
+    }
+    let input = self.mover.next();
+    let tick = match &mut self.predictor {
+      Some(predictor) => match predictor.step(input) {
+        Some(tick) => tick,
+        None => return,
+      },
+      None => {
+        self.tick += 1;
+        self.tick
+      }
+    };
+    let mut actions = vec![MovementAction::Move(input.movement)];
+    if input.jump {
+      actions.push(MovementAction::Jump);
+    }
+    if self.inputs.len() == INPUT_REDUNDANCY {
+      self.inputs.pop_front();
+    }
+    self.inputs.push_back(TickInput { tick, actions });
+    self.client.send(&ClientMessage::Inputs {
+      inputs: self.inputs.iter().cloned().collect(),
+      snapshot_ack: self.snapshots.latest().map(|snapshot| snapshot.tick),
+    });
+    stats.inputs_sent += 1;
+  }
+}
+
+/// What the server started for the bots measured, for the report
+#[derive(Debug, Default, Clone)]
+struct ServerSamples {
+  update_times: Vec<Duration>,
+  tick: u64,
+  stats: NetStats,
+}
+
+#[derive(Resource)]
+struct ServerProbe {
+  samples: Arc<Mutex<ServerSamples>>,
+  stop: Arc<AtomicBool>,
+  update_start: Instant,
+}
+
+/// The server started for the bots, running on its own thread
+struct LocalServer {
+  address: SocketAddr,
+  samples: Arc<Mutex<ServerSamples>>,
+  stop: Arc<AtomicBool>,
+  thread: JoinHandle<()>,
+}
+
+impl LocalServer {
+  fn start(config: &ServerConfig) -> Option<Self> {
+    let samples = Arc::new(Mutex::new(ServerSamples::default()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = mpsc::channel();
+    let probe = ServerProbe {
+      samples: samples.clone(),
+      stop: stop.clone(),
+      update_start: Instant::now(),
+    };
+    let config = config.clone();
+    let thread = thread::spawn(move || {
+      let mut app = server_app(&config);
+      let address = app
+        .world()
+        .get_resource::<Network>()
+        .and_then(|network| network.0.local_addr().ok());
+      let _ = sender.send(address);
+      if address.is_some() {
+        app
+          .insert_resource(probe)
+          .add_systems(First, start_update)
+          .add_systems(Last, end_update)
+          .run();
+      }
+    });
+    let address = receiver.recv().ok().flatten()?;
+    Some(LocalServer {
+      address,
+      samples,
+      stop,
+      thread,
+    })
+  }
+
+  fn samples(&self) -> ServerSamples {
+    self
+      .samples
+      .lock()
+      .expect("the server never panics")
+      .clone()
+  }
+
+  fn stop(self) {
+    self.stop.store(true, Ordering::Relaxed);
+    let _ = self.thread.join();
+  }
+}
+
+fn start_update(mut probe: ResMut<ServerProbe>, mut evw_exit: EventWriter<AppExit>) {
+  probe.update_start = Instant::now();
+  if probe.stop.load(Ordering::Relaxed) {
+    evw_exit.send(AppExit::Success);
+  }
+}
+
+fn end_update(probe: Res<ServerProbe>, tick: Res<Tick>, network: Res<Network>) {
+  let mut samples = probe.samples.lock().expect("the bots never panic");
+  samples.update_times.push(probe.update_start.elapsed());
+  samples.tick = tick.0;
+  samples.stats = network.0.stats();
+}
+
+#[derive(Debug, Error)]
+pub enum BotError {
+  #[error("Could not start a server on localhost")]
+  Server,
+  #[error("Could not connect: {0}")]
+  Connect(#[from] io::Error),
+  #[error("Only {playing} of {bots} bots entered the game, is the server full or busy?")]
+  Timeout { playing: usize, bots: usize },
+}
+
+/// Mean and extremes of measurements
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Summary {
+  pub count: usize,
+  pub mean: f64,
+  pub p99: f64,
+  pub max: f64,
+}
+
+impl Summary {
+  pub fn of(samples: &[f64]) -> Self {
+    if samples.is_empty() {
+      return Summary::default();
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    Summary {
+      count: sorted.len(),
+      mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+      p99: sorted[(sorted.len() - 1) * 99 / 100],
+      max: sorted[sorted.len() - 1],
+    }
+  }
+}
+
+/// What a run of the bots found
+#[derive(Debug, Clone)]
+pub struct Report {
+  pub bots: usize,
+  /// How long they played
+  pub duration: Duration,
+  /// Of the server
+  pub tick_rate: u32,
+  /// How often the bots sent inputs, below the tick rate when they cannot keep up
+  pub input_rate: f64,
+  /// Update times of the server in milliseconds, none when it runs elsewhere
+  pub server_updates: Option<Summary>,
+  /// Ticks the server ran per second
+  pub server_tick_rate: Option<f64>,
+  pub server_traffic: Option<NetStats>,
+  /// Of every bot together
+  pub bot_traffic: NetStats,
+  pub snapshot_size: f64,
+  pub predicting: usize,
+  /// Distances in meters between predicted and confirmed positions
+  pub prediction_errors: Summary,
+  /// Share of the confirmed ticks that had to be corrected
+  pub mispredictions: f64,
+}
+
+impl fmt::Display for Report {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let seconds = self.duration.as_secs_f64();
+    let rate = |bytes: u64| format!("{:.1} kB/s", bytes as f64 / seconds / 1000.0);
+    writeln!(
+      f,
+      "{} bots played {seconds:.1}s at {} ticks per second, sending {:.1} inputs per second each",
+      self.bots, self.tick_rate, self.input_rate
+    )?;
+    match (self.server_updates, self.server_tick_rate) {
+      (Some(updates), Some(tick_rate)) => writeln!(
+        f,
+        "Server updates: mean {:.2}ms, p99 {:.2}ms, max {:.2}ms, of {:.2}ms per tick, \
+         {tick_rate:.1} ticks per second",
+        updates.mean,
+        updates.p99,
+        updates.max,
+        1000.0 / self.tick_rate as f64
+      )?,
+      _ => writeln!(f, "Server updates: unknown, the server runs elsewhere")?,
+    }
+    if let Some(traffic) = self.server_traffic {
+      writeln!(
+        f,
+        "Server traffic: {} out, {} in",
+        rate(traffic.bytes_sent),
+        rate(traffic.bytes_received)
+      )?;
+    }
+    let bots = self.bots as u64;
+    writeln!(
+      f,
+      "Bot traffic: {} in, {} out per bot, snapshots of {:.0} bytes",
+      rate(self.bot_traffic.bytes_received / bots),
+      rate(self.bot_traffic.bytes_sent / bots),
+      self.snapshot_size
+    )?;
+    let errors = self.prediction_errors;
+    write!(
+      f,
+      "Prediction errors of {} bots over {} ticks: mean {:.4}m, p99 {:.4}m, max {:.4}m, \
+       {:.1}% corrected",
+      self.predicting,
+      errors.count,
+      errors.mean,
+      errors.p99,
+      errors.max,
+      self.mispredictions * 100.0
+    )
+  }
+}
+
+fn traffic_since(now: NetStats, then: NetStats) -> NetStats {
+  NetStats {
+    packets_sent: now.packets_sent - then.packets_sent,
+    bytes_sent: now.bytes_sent - then.bytes_sent,
+    packets_received: now.packets_received - then.packets_received,
+    bytes_received: now.bytes_received - then.bytes_received,
+  }
+}
+
+fn bot_traffic(bots: &[Bot]) -> NetStats {
+  bots
+    .iter()
+    .map(|bot| bot.client.stats())
+    .fold(NetStats::default(), |total, stats| NetStats {
+      packets_sent: total.packets_sent + stats.packets_sent,
+      bytes_sent: total.bytes_sent + stats.bytes_sent,
+      packets_received: total.packets_received + stats.packets_received,
+      bytes_received: total.bytes_received + stats.bytes_received,
+    })
+}
+
+/// Connect the bots, wait for all of them to be in the game, then play and measure
+pub fn run(config: &BotConfig) -> Result<Report, BotError> {
+  let server = match config.connect {
+    Some(_) => None,
+    None => Some(LocalServer::start(&config.server).ok_or(BotError::Server)?),
+  };
+  let address = config
+    .connect
+    .or(server.as_ref().map(|server| server.address))
+    .ok_or(BotError::Server)?;
+  let result = play(config, address, server.as_ref());
+  if let Some(server) = server {
+    server.stop();
+  }
+  result
+}
+
+fn play(
+  config: &BotConfig,
+  address: SocketAddr,
+  server: Option<&LocalServer>,
+) -> Result<Report, BotError> {
+  let mut bots = (0..config.bots)
+    .map(|index| Bot::connect(address, index, config))
+    .collect::<io::Result<Vec<_>>>()?;
+  let mut tick_rate = config.server.tick_rate;
+  let mut stats = BotStats::default();
+  let frame = |bots: &mut [Bot], tick_rate: &mut u32, stats: &mut BotStats| {
+    for bot in bots {
+      bot.receive(config.bots, tick_rate, stats);
+      bot.step(stats);
+      bot.client.flush(Instant::now());
+    }
+  };
+
+  let joining = Instant::now();
+  while !bots.iter().all(Bot::in_game) {
+    if joining.elapsed() > JOIN_TIMEOUT {
+      return Err(BotError::Timeout {
+        playing: bots.iter().filter(|bot| bot.in_game()).count(),
+        bots: bots.len(),
+      });
+    }
+    frame(&mut bots, &mut tick_rate, &mut stats);
+    thread::sleep(Duration::from_millis(1));
+  }
+  info!("{} bots in the game", bots.len());
+
+  // only the game is measured
+  let mut stats = BotStats::default();
+  let server_start = server.map(|server| {
+    let mut samples = server.samples.lock().expect("the server never panics");
+    samples.update_times.clear();
+    samples.clone()
+  });
+  let bots_start = bot_traffic(&bots);
+  let start = Instant::now();
+  let mut next = start;
+  while start.elapsed() < config.duration {
+    frame(&mut bots, &mut tick_rate, &mut stats);
+    next += Duration::from_secs_f64(1.0 / tick_rate.max(1) as f64);
+    let now = Instant::now();
+    if next > now {
+      thread::sleep(next - now);
+    } else if now - next > Duration::from_secs(1) {
+      // too far behind to catch up
+      next = now;
+    }
+  }
+  let duration = start.elapsed();
+  let seconds = duration.as_secs_f64();
+
+  let server_end = server.map(LocalServer::samples);
+  let (server_updates, server_tick_rate, server_traffic) = match (server_start, server_end) {
+    (Some(start), Some(end)) => {
+      let update_times = end
+        .update_times
+        .iter()
+        .map(|time| time.as_secs_f64() * 1000.0)
+        .collect::<Vec<_>>();
+      (
+        Some(Summary::of(&update_times)),
+        Some((end.tick - start.tick) as f64 / seconds),
+        Some(traffic_since(end.stats, start.stats)),
+      )
+    }
+    _ => (None, None, None),
+  };
+  let prediction_errors = Summary::of(&stats.prediction_errors);
+  let corrected = stats
+    .prediction_errors
+    .iter()
+    .filter(|error| **error >= TOLERANCE as f64)
+    .count();
+  let report = Report {
+    bots: bots.len(),
+    duration,
+    tick_rate,
+    input_rate: stats.inputs_sent as f64 / bots.len() as f64 / seconds,
+    server_updates,
+    server_tick_rate,
+    server_traffic,
+    bot_traffic: traffic_since(bot_traffic(&bots), bots_start),
+    snapshot_size: stats.snapshot_bytes as f64 / stats.snapshots.max(1) as f64,
+    predicting: config.predicting,
+    prediction_errors,
+    mispredictions: corrected as f64 / prediction_errors.count.max(1) as f64,
+  };
+  for bot in &mut bots {
+    bot.client.disconnect();
+  }
+  Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn command(args: &str) -> Result<BotCommand, ConfigError> {
+    BotCommand::from_args(
+      std::iter::once("bot")
+        .chain(args.split_whitespace())
+        .map(str::to_string),
+    )
+  }
+
+  #[test]
+  fn arguments_set_up_the_bots() {
+    let Ok(BotCommand::Run(config)) =
+      command("--bots 20 --duration 5 --predicting 30 --tick-rate 30 --seed 7 --latency 50")
+    else {
+      panic!("invalid arguments");
+    };
+    assert_eq!(config.bots, 20);
+    assert_eq!(config.duration, Duration::from_secs(5));
+    assert_eq!(config.predicting, 20);
+    assert_eq!(config.moves, Moves::Random { seed: 7 });
+    assert_eq!(config.server.tick_rate, 30);
+    assert_eq!(config.server.max_players, 20);
+    assert_eq!(config.server.room_size, 20);
+    assert_eq!(config.conditions.latency, Duration::from_millis(50));
+    assert_eq!(config.connect, None);
+
+    let error = |args| command(args).unwrap_err().to_string();
+    assert_eq!(
+      error("--bots 65"),
+      "Invalid bot count \"65\", expected 1 to 64 bots"
+    );
+    assert_eq!(
+      error("--connect localhost"),
+      "Invalid server address \"localhost\", expected an IP address and a port"
+    );
+    assert_eq!(
+      error("--reorder -1"),
+      "Invalid reordering \"-1\", expected 0 to 100 percent"
+    );
+    assert_eq!(
+      error("--tick-rate 0").split(',').next(),
+      Some("Invalid tick rate \"0\"")
+    );
+  }
+
+  #[test]
+  fn scripts_play_in_a_loop() {
+    let script = parse_script(
+      "# a square\n\
+       move 2 0 2\n\
+       jump\n\
+       wait 1 # and breathe",
+    )
+    .unwrap();
+    assert_eq!(
+      script,
+      [
+        ScriptMove::Move {
+          direction: Vec2::X,
+          ticks: 2
+        },
+        ScriptMove::Jump,
+        ScriptMove::Wait(1)
+      ]
+    );
+    let mut mover = Mover::new(&Moves::Script(script), 3);
+    let inputs = (0..5).map(|_| mover.next()).collect::<Vec<_>>();
+    let moving = |x: f32, jump| PlayerInput {
+      movement: Vec2::new(x, 0.0),
+      jump,
+    };
+    assert_eq!(
+      inputs,
+      [
+        moving(1.0, false),
+        moving(1.0, false),
+        moving(1.0, true),
+        moving(0.0, false),
+        moving(1.0, false)
+      ]
+    );
+
+    let error = |text| parse_script(text).unwrap_err().to_string();
+    assert!(error("move 1 0").starts_with("Invalid script line \"move 1 0\""));
+    assert!(error("wait 0").starts_with("Invalid script line"));
+    assert!(error("move nan 0 5").starts_with("Invalid script line"));
+    assert!(error("move 0 inf 5").starts_with("Invalid script line"));
+    assert!(error("# nothing").ends_with("expected at least one move"));
+  }
+}
@@ -0,0 +1,392 @@
+//! How the server is set up: command line arguments, over a TOML file, over defaults.
+//! Parsing starts nothing, so it is tested without a server.
+
+use bevy::log::Level;
+use serde::Deserialize;
+use shared::net::{CONDITION_ARGUMENTS, DEFAULT_PORT, LinkConditions};
+use shared::stepped::WORKSPACE_ASSETS;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::lobby::MAX_ROOM_PLAYERS;
+
+/// Fastest simulation the server runs
+pub const MAX_TICK_RATE: u32 = 240;
+/// Most clients connected at once
+pub const MAX_PLAYERS: usize = 64;
+
+pub const USAGE: &str = "\
+Usage: server [options]
+
+Options:
+  --config <path>       TOML file with any of the settings below, overridden by the arguments
+  --bind <ip>           Address to listen on [default: 0.0.0.0]
+  --port <port>         UDP port to listen on [default: 5000]
+  --tick-rate <hz>      Simulation ticks per second, 1 to 240 [default: 60]
+  --max-players <n>     Clients connected at once, 1 to 64 [default: 16]
+  --room-size <n>       Players of a lobby room at most, 1 to 64 [default: 16]
+  --level <path>        Level to run, relative to the assets [default: levels/arena.level.ron]
+  --assets <path>       Asset directory, relative to the executable, or to the server crate
+                        when run by cargo [default: ../client/assets]
+  --log-level <level>   trace, debug, info, warn or error [default: info]
+  --admin-port <port>   Take console commands on this TCP port of localhost [default: none]
+  --help                Print this and exit
+
+Simulated network, for testing clients:
+  --latency <ms>  --jitter <ms>  --loss <%>  --duplicate <%>  --reorder <%>
+
+The config file uses the option names as keys, for example:
+  port = 5000
+  tick-rate = 30
+  level = \"levels/arena.level.ron\"";
+
+/// Why the server could not be set up
+#[derive(Debug, Error)]
+pub enum ConfigError {
+  #[error("Could not read {}: {source}", path.display())]
+  Io {
+    path: PathBuf,
+    source: std::io::Error,
+  },
+  #[error("Invalid config file {}: {message}", path.display())]
+  Toml { path: PathBuf, message: String },
+  #[error("{0} needs a value")]
+  MissingValue(String),
+  #[error("Invalid {setting} {value:?}, expected {expected}")]
+  InvalidValue {
+    setting: &'static str,
+    value: String,
+    expected: &'static str,
+  },
+  #[error("Unknown argument {0:?}, see --help")]
+  UnknownArgument(String),
+}
+
+/// What the command line asks for
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+  Run(ServerConfig),
+  Help,
+}
+
+impl Command {
+  /// Parse arguments as `std::env::args` gives them, the program first,
+  /// reading the `--config` file if there is one
+  pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+    let mut arguments = RawConfig::default();
+    let mut conditions = LinkConditions::default();
+    let mut config_file = None;
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+      if arg == "--help" || arg == "-h" {
+        return Ok(Command::Help);
+      }
+      let mut value = || {
+        args
+          .next()
+          .ok_or_else(|| ConfigError::MissingValue(arg.clone()))
+      };
+      match arg.as_str() {
+        "--config" => config_file = Some(PathBuf::from(value()?)),
+        "--bind" => arguments.bind = Some(parse("bind address", &value()?, "an IP address")?),
+        "--port" => arguments.port = Some(parse("port", &value()?, "a port number")?),
+        "--tick-rate" => {
+          arguments.tick_rate = Some(parse("tick rate", &value()?, "ticks per second")?)
+        }
+        "--max-players" => {
+          arguments.max_players = Some(parse("max players", &value()?, "a number of players")?)
+        }
+        "--room-size" => {
+          arguments.room_size = Some(parse("room size", &value()?, "a number of players")?)
+        }
+        "--level" => arguments.level = Some(value()?),
+        "--assets" => arguments.assets = Some(value()?),
+        "--log-level" => arguments.log_level = Some(value()?),
+        "--admin-port" => {
+          arguments.admin_port = Some(parse("admin port", &value()?, "a port number")?)
+        }
+        _ if CONDITION_ARGUMENTS.contains(&arg.as_str()) => {
+          parse_condition(&mut conditions, &arg, value()?)?
+        }
+        _ => return Err(ConfigError::UnknownArgument(arg)),
+      }
+    }
+
+    let mut config = match config_file {
+      Some(path) => ServerConfig::load(&path)?,
+      None => ServerConfig::default(),
+    };
+    config.apply(arguments)?;
+    config.conditions = conditions;
+    config.validate()?;
+    Ok(Command::Run(config))
+  }
+}
+
+/// Everything the server needs to start
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+  pub bind: IpAddr,
+  pub port: u16,
+  pub tick_rate: u32,
+  pub max_players: usize,
+  /// Most players of a lobby room, and so of a game
+  pub room_size: u8,
+  /// Level file to run, relative to the assets
+  pub level: String,
+  /// Directory of the assets, the client's in the workspace by default
+  pub assets: String,
+  pub log_level: Level,
+  /// Localhost port of the admin socket, none without one
+  pub admin_port: Option<u16>,
+  /// The simulated network towards every client, only set by arguments
+  pub conditions: LinkConditions,
+}
+
+impl Default for ServerConfig {
+  fn default() -> Self {
+    ServerConfig {
+      bind: Ipv4Addr::UNSPECIFIED.into(),
+      port: DEFAULT_PORT,
+      tick_rate: 60,
+      max_players: 16,
+      room_size: MAX_ROOM_PLAYERS,
+      level: "levels/arena.level.ron".to_string(),
+      assets: WORKSPACE_ASSETS.to_string(),
+      log_level: Level::INFO,
+      admin_port: None,
+      conditions: LinkConditions::default(),
+    }
+  }
+}
+
+impl ServerConfig {
+  /// The defaults with the settings of a TOML file on top
+  pub fn load(path: &Path) -> Result<Self, ConfigError> {
+    let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+      path: path.to_path_buf(),
+      source,
+    })?;
+    ServerConfig::from_toml(&text, path)
+  }
+
+  /// The defaults with the settings of the text of a config file on top
+  pub fn from_toml(text: &str, path: &Path) -> Result<Self, ConfigError> {
+    let raw = toml::from_str::<RawConfig>(text).map_err(|error| ConfigError::Toml {
+      path: path.to_path_buf(),
+      message: error.to_string().trim_end().to_string(),
+    })?;
+    let mut config = ServerConfig::default();
+    config.apply(raw)?;
+    config.validate()?;
+    Ok(config)
+  }
+
+  pub fn address(&self) -> SocketAddr {
+    (self.bind, self.port).into()
+  }
+
+  fn apply(&mut self, raw: RawConfig) -> Result<(), ConfigError> {
+    self.bind = raw.bind.unwrap_or(self.bind);
+    self.port = raw.port.unwrap_or(self.port);
+    self.tick_rate = raw.tick_rate.unwrap_or(self.tick_rate);
+    self.max_players = raw.max_players.unwrap_or(self.max_players);
+    self.room_size = raw.room_size.unwrap_or(self.room_size);
+    self.level = raw.level.unwrap_or_else(|| self.level.clone());
+    self.assets = raw.assets.unwrap_or_else(|| self.assets.clone());
+    self.admin_port = raw.admin_port.or(self.admin_port);
+    if let Some(level) = raw.log_level {
+      self.log_level = parse("log level", &level, "trace, debug, info, warn or error")?;
+    }
+    Ok(())
+  }
+
+  pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+    if !(1..=MAX_TICK_RATE).contains(&self.tick_rate) {
+      return Err(ConfigError::InvalidValue {
+        setting: "tick rate",
+        value: self.tick_rate.to_string(),
+        expected: "1 to 240 ticks per second",
+      });
+    }
+    if !(1..=MAX_PLAYERS).contains(&self.max_players) {
+      return Err(ConfigError::InvalidValue {
+        setting: "max players",
+        value: self.max_players.to_string(),
+        expected: "1 to 64 players",
+      });
+    }
+    if !(1..=MAX_PLAYERS).contains(&(self.room_size as usize)) {
+      return Err(ConfigError::InvalidValue {
+        setting: "room size",
+        value: self.room_size.to_string(),
+        expected: "1 to 64 players",
+      });
+    }
+    if self.level.trim().is_empty() {
+      return Err(ConfigError::InvalidValue {
+        setting: "level",
+        value: self.level.clone(),
+        expected: "the path of a level file",
+      });
+    }
+    if self.assets.trim().is_empty() {
+      return Err(ConfigError::InvalidValue {
+        setting: "assets",
+        value: self.assets.clone(),
+        expected: "the path of a directory",
+      });
+    }
+    Ok(())
+  }
+}
+
+/// Settings given by a file or the arguments, the others are left as they are
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RawConfig {
+  bind: Option<IpAddr>,
+  port: Option<u16>,
+  tick_rate: Option<u32>,
+  max_players: Option<usize>,
+  room_size: Option<u8>,
+  level: Option<String>,
+  assets: Option<String>,
+  log_level: Option<String>,
+  admin_port: Option<u16>,
+}
+
+pub(crate) fn parse<T: FromStr>(
+  setting: &'static str,
+  value: &str,
+  expected: &'static str,
+) -> Result<T, ConfigError> {
+  value.trim().parse().map_err(|_| ConfigError::InvalidValue {
+    setting,
+    value: value.to_string(),
+    expected,
+  })
+}
+
+/// Set a condition of the simulated network from one of the `CONDITION_ARGUMENTS`
+pub(crate) fn parse_condition(
+  conditions: &mut LinkConditions,
+  argument: &str,
+  value: String,
+) -> Result<(), ConfigError> {
+  conditions
+    .set_argument(argument, &value)
+    .map_err(|error| ConfigError::InvalidValue {
+      setting: error.setting,
+      value,
+      expected: error.expected,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  fn command(args: &str) -> Result<Command, ConfigError> {
+    Command::from_args(
+      std::iter::once("server")
+        .chain(args.split_whitespace())
+        .map(str::to_string),
+    )
+  }
+
+  fn config(args: &str) -> ServerConfig {
+    match command(args) {
+      Ok(Command::Run(config)) => config,
+      other => panic!("{other:?}"),
+    }
+  }
+
+  #[test]
+  fn arguments_override_the_defaults() {
+    assert_eq!(config(""), ServerConfig::default());
+    let config = config(
+      "--port 6000 --bind 127.0.0.1 --tick-rate 30 --max-players 4 --level levels/test.level.ron \
+       --log-level debug --latency 100 --admin-port 5001 --room-size 32 --assets /srv/assets",
+    );
+    assert_eq!(config.address(), "127.0.0.1:6000".parse().unwrap());
+    assert_eq!(config.tick_rate, 30);
+    assert_eq!(config.max_players, 4);
+    assert_eq!(config.room_size, 32);
+    assert_eq!(config.level, "levels/test.level.ron");
+    assert_eq!(config.assets, "/srv/assets");
+    assert_eq!(config.log_level, Level::DEBUG);
+    assert_eq!(config.admin_port, Some(5001));
+    assert_eq!(config.conditions.latency, Duration::from_millis(100));
+    assert!(matches!(command("--port 1 --help"), Ok(Command::Help)));
+  }
+
+  #[test]
+  fn bad_arguments_are_explained() {
+    let error = |args| command(args).unwrap_err().to_string();
+    assert_eq!(error("--port"), "--port needs a value");
+    assert_eq!(
+      error("--port 70000"),
+      "Invalid port \"70000\", expected a port number"
+    );
+    assert_eq!(
+      error("--tick-rate 0"),
+      "Invalid tick rate \"0\", expected 1 to 240 ticks per second"
+    );
+    assert_eq!(
+      error("--log-level loud"),
+      "Invalid log level \"loud\", expected trace, debug, info, warn or error"
+    );
+    assert_eq!(
+      error("--loss 150"),
+      "Invalid loss \"150\", expected 0 to 100 percent"
+    );
+    assert_eq!(
+      error("--jitter soon"),
+      "Invalid jitter \"soon\", expected 0 to 10000 milliseconds"
+    );
+    assert_eq!(error("--latency"), "--latency needs a value");
+    assert_eq!(
+      error("--verbose"),
+      "Unknown argument \"--verbose\", see --help"
+    );
+    assert!(error("--config missing.toml").starts_with("Could not read missing.toml"));
+  }
+
+  #[test]
+  fn config_files_are_validated() {
+    let from_toml = |text| ServerConfig::from_toml(text, Path::new("server.toml"));
+    let config = from_toml("port = 6000\ntick-rate = 30\nlog-level = \"warn\"").unwrap();
+    assert_eq!(config.port, 6000);
+    assert_eq!(config.tick_rate, 30);
+    assert_eq!(config.log_level, Level::WARN);
+    assert_eq!(config.level, ServerConfig::default().level);
+
+    let error = |text| from_toml(text).unwrap_err().to_string();
+    let unknown = error("prot = 6000");
+    assert!(unknown.starts_with("Invalid config file server.toml: "));
+    assert!(unknown.contains("unknown field `prot`"));
+    assert!(error("port = \"6000\"").contains("invalid type"));
+    assert_eq!(
+      error("max-players = 100"),
+      "Invalid max players \"100\", expected 1 to 64 players"
+    );
+    assert_eq!(
+      error("room-size = 65"),
+      "Invalid room size \"65\", expected 1 to 64 players"
+    );
+  }
+
+  #[test]
+  fn arguments_override_the_config_file() {
+    let path = std::env::temp_dir().join(format!("server-config-{}.toml", std::process::id()));
+    std::fs::write(&path, "port = 6000\nmax-players = 8").unwrap();
+    let config = config(&format!("--max-players 2 --config {}", path.display()));
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(config.port, 6000);
+    assert_eq!(config.max_players, 2);
+  }
+}
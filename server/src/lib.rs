@@ -0,0 +1,71 @@
+//! The authoritative game server, usable headless from tests and tools
+
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
+use shared::HeadlessPlugins;
+use std::time::Duration;
+
+use config::ServerConfig;
+use network::NetworkPlugin;
+use simulation::SimulationPlugin;
+
+pub mod admin;
+pub mod bot;
+pub mod config;
+pub mod lobby;
+pub mod network;
+pub mod simulation;
+
+/// The server as `config` sets it up, ready to run
+pub fn server_app(config: &ServerConfig) -> App {
+  let tick_rate = config.tick_rate as f64;
+  let mut app = App::new();
+  app
+    .add_plugins((
+      HeadlessPlugins
+        // the loop runs about as often as the simulation ticks
+        .set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+          1.0 / tick_rate,
+        )))
+        .set(AssetPlugin {
+          file_path: config.assets.clone(),
+          ..default()
+        }),
+      LogPlugin {
+        level: config.log_level,
+        ..default()
+      },
+    ))
+    .add_plugins((
+      SimulationPlugin {
+        level: config.level.clone(),
+      },
+      NetworkPlugin {
+        address: config.address(),
+        max_clients: config.max_players,
+        room_size: config.room_size,
+        conditions: config.conditions,
+      },
+    ))
+    // after the simulation, which ticks at the default rate
+    .insert_resource(Time::<Fixed>::from_hz(tick_rate));
+  app
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn the_server_ticks_at_the_configured_rate() {
+    let config = ServerConfig {
+      tick_rate: 30,
+      port: 0,
+      ..default()
+    };
+    let app = server_app(&config);
+    let time = app.world().resource::<Time<Fixed>>();
+    assert_eq!(shared::tick::ticks_per_second(time), 30);
+  }
+}
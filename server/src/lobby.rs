@@ -0,0 +1,415 @@
+//! Rooms clients gather in before playing. The server runs one level, so one room plays
+//! at a time: a room whose members are all ready starts once no other room is playing.
+
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use shared::net::{MAX_ROOM_NAME, MAX_ROOMS, RoomInfo, RoomMember, RoomState};
+use thiserror::Error;
+
+/// Rooms hold at most this many players, whatever their host asks for, unless the server
+/// is set up with another limit
+pub const MAX_ROOM_PLAYERS: u8 = 16;
+
+/// Why the lobby refused a request, told to the client who made it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum LobbyError {
+  #[error("There is no such room")]
+  NoSuchRoom,
+  #[error("The room is full")]
+  RoomFull,
+  #[error("You are banned from this room")]
+  Banned,
+  #[error("The game of this room already started")]
+  Playing,
+  #[error("Leave your room first")]
+  AlreadyInRoom,
+  #[error("You are not in a room")]
+  NotInRoom,
+  #[error("Only the host of the room can do that")]
+  NotHost,
+  #[error("There is no such member in the room")]
+  NoSuchMember,
+  #[error("Room names are 1 to {MAX_ROOM_NAME} bytes long")]
+  InvalidName,
+  #[error("The lobby is full, join a room instead")]
+  TooManyRooms,
+  #[error("Hosts cannot kick themselves, leave the room instead")]
+  KickedHost,
+}
+
+/// A room of the lobby
+#[derive(Debug)]
+pub struct Room {
+  pub id: u64,
+  pub name: String,
+  pub host: u64,
+  pub max_players: u8,
+  /// In the order they joined, the host first
+  pub members: Vec<RoomMember>,
+  /// Clients kicked for good
+  pub banned: HashSet<u64>,
+  pub playing: bool,
+}
+
+impl Room {
+  fn info(&self) -> RoomInfo {
+    RoomInfo {
+      id: self.id,
+      name: self.name.clone(),
+      players: self.members.len() as u8,
+      max_players: self.max_players,
+      playing: self.playing,
+    }
+  }
+
+  fn state(&self) -> RoomState {
+    RoomState {
+      id: self.id,
+      name: self.name.clone(),
+      host: self.host,
+      max_players: self.max_players,
+      members: self.members.clone(),
+      playing: self.playing,
+    }
+  }
+
+  pub fn clients(&self) -> impl Iterator<Item = u64> + '_ {
+    self.members.iter().map(|member| member.client)
+  }
+}
+
+/// A client out of its room
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Departure {
+  pub client: u64,
+  /// The client was in the game of the room, its player is to be removed
+  pub playing: bool,
+}
+
+/// Every room, and what changed since the clients were last told
+#[derive(Resource, Debug)]
+pub struct Lobby {
+  /// Most players of a room
+  max_room_players: u8,
+  rooms: Vec<Room>,
+  next_room: u64,
+  listing_changed: bool,
+  /// Clients whose room changed
+  changed: HashSet<u64>,
+}
+
+impl Default for Lobby {
+  fn default() -> Self {
+    Lobby::new(MAX_ROOM_PLAYERS)
+  }
+}
+
+impl Lobby {
+  /// An empty lobby whose rooms hold at most `max_room_players`
+  pub fn new(max_room_players: u8) -> Self {
+    Lobby {
+      max_room_players,
+      rooms: Vec::new(),
+      next_room: 0,
+      listing_changed: false,
+      changed: HashSet::new(),
+    }
+  }
+
+  pub fn rooms(&self) -> &[Room] {
+    &self.rooms
+  }
+
+  pub fn room_of(&self, client: u64) -> Option<&Room> {
+    self.room_index(client).map(|index| &self.rooms[index])
+  }
+
+  fn room_index(&self, client: u64) -> Option<usize> {
+    self
+      .rooms
+      .iter()
+      .position(|room| room.clients().any(|member| member == client))
+  }
+
+  fn touch(&mut self, room: &Room) {
+    self.listing_changed = true;
+    self.changed.extend(room.clients());
+  }
+
+  /// Open a room with `host` as its first member
+  pub fn create_room(&mut self, host: u64, name: &str, max_players: u8) -> Result<u64, LobbyError> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > MAX_ROOM_NAME {
+      return Err(LobbyError::InvalidName);
+    }
+    if self.room_of(host).is_some() {
+      return Err(LobbyError::AlreadyInRoom);
+    }
+    if self.rooms.len() >= MAX_ROOMS {
+      return Err(LobbyError::TooManyRooms);
+    }
+    self.next_room += 1;
+    let room = Room {
+      id: self.next_room,
+      name: name.to_string(),
+      host,
+      max_players: max_players.clamp(1, self.max_room_players),
+      members: vec![RoomMember {
+        client: host,
+        ready: false,
+      }],
+      banned: HashSet::new(),
+      playing: false,
+    };
+    self.touch(&room);
+    self.rooms.push(room);
+    Ok(self.next_room)
+  }
+
+  pub fn join(&mut self, client: u64, room: u64) -> Result<(), LobbyError> {
+    if self.room_of(client).is_some() {
+      return Err(LobbyError::AlreadyInRoom);
+    }
+    let room = self
+      .rooms
+      .iter_mut()
+      .find(|other| other.id == room)
+      .ok_or(LobbyError::NoSuchRoom)?;
+    if room.banned.contains(&client) {
+      return Err(LobbyError::Banned);
+    }
+    if room.playing {
+      return Err(LobbyError::Playing);
+    }
+    if room.members.len() >= room.max_players as usize {
+      return Err(LobbyError::RoomFull);
+    }
+    room.members.push(RoomMember {
+      client,
+      ready: false,
+    });
+    self.listing_changed = true;
+    self.changed.extend(room.clients());
+    Ok(())
+  }
+
+  pub fn set_ready(&mut self, client: u64, ready: bool) -> Result<(), LobbyError> {
+    let index = self.room_index(client).ok_or(LobbyError::NotInRoom)?;
+    let room = &mut self.rooms[index];
+    if room.playing {
+      return Err(LobbyError::Playing);
+    }
+    for member in &mut room.members {
+      if member.client == client {
+        member.ready = ready;
+      }
+    }
+    self.changed.extend(room.clients());
+    Ok(())
+  }
+
+  /// Take a client out of its room. Without its host the room closes and everyone leaves.
+  pub fn leave(&mut self, client: u64) -> Vec<Departure> {
+    let Some(index) = self.room_index(client) else {
+      return Vec::new();
+    };
+    self.listing_changed = true;
+    let room = &mut self.rooms[index];
+    let playing = room.playing;
+    self.changed.extend(room.clients());
+    if room.host == client {
+      let room = self.rooms.remove(index);
+      return room
+        .clients()
+        .map(|client| Departure { client, playing })
+        .collect();
+    }
+    room.members.retain(|member| member.client != client);
+    vec![Departure { client, playing }]
+  }
+
+  /// The host removes a member of their room, for good when banning
+  pub fn kick(&mut self, host: u64, client: u64, ban: bool) -> Result<Vec<Departure>, LobbyError> {
+    let index = self.room_index(host).ok_or(LobbyError::NotInRoom)?;
+    let room = &mut self.rooms[index];
+    if room.host != host {
+      return Err(LobbyError::NotHost);
+    }
+    if client == host {
+      return Err(LobbyError::KickedHost);
+    }
+    if !room.clients().any(|member| member == client) {
+      return Err(LobbyError::NoSuchMember);
+    }
+    if ban {
+      room.banned.insert(client);
+    }
+    Ok(self.leave(client))
+  }
+
+  /// Start the room whose members are all ready, unless a room is playing already
+  pub fn start_ready_room(&mut self) -> Option<&Room> {
+    if self.rooms.iter().any(|room| room.playing) {
+      return None;
+    }
+    let index = self
+      .rooms
+      .iter()
+      .position(|room| room.members.iter().all(|member| member.ready))?;
+    let room = &mut self.rooms[index];
+    room.playing = true;
+    self.listing_changed = true;
+    self.changed.extend(room.clients());
+    Some(room)
+  }
+
+  /// Send the members of the playing room back to it, not ready. Returns who was playing.
+  pub fn stop_game(&mut self) -> Vec<u64> {
+    let Some(room) = self.rooms.iter_mut().find(|room| room.playing) else {
+      return Vec::new();
+    };
+    room.playing = false;
+    for member in &mut room.members {
+      member.ready = false;
+    }
+    let clients = room.clients().collect::<Vec<_>>();
+    self.listing_changed = true;
+    self.changed.extend(clients.iter().copied());
+    clients
+  }
+
+  /// Every room, for the server browser
+  pub fn listing(&self) -> Vec<RoomInfo> {
+    self.rooms.iter().map(Room::info).collect()
+  }
+
+  /// The room of a client, as they see it
+  pub fn state(&self, client: u64) -> Option<RoomState> {
+    self.room_of(client).map(Room::state)
+  }
+
+  /// Whether the listing changed and whose room did, since the last call
+  pub fn take_changes(&mut self) -> (bool, Vec<u64>) {
+    let listing_changed = std::mem::take(&mut self.listing_changed);
+    (listing_changed, self.changed.drain().collect())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::MAX_PLAYERS;
+  use shared::net::ServerMessage;
+  use shared::net::connection::MAX_RELIABLE_SIZE;
+
+  #[test]
+  fn rooms_start_once_everyone_is_ready() {
+    let mut lobby = Lobby::default();
+    let room = lobby.create_room(1, " Friday night ", 2).unwrap();
+    assert_eq!(lobby.rooms()[0].name, "Friday night");
+    assert_eq!(
+      lobby.create_room(1, "Another", 2),
+      Err(LobbyError::AlreadyInRoom)
+    );
+    assert_eq!(lobby.create_room(2, "", 2), Err(LobbyError::InvalidName));
+    lobby.join(2, room).unwrap();
+    assert_eq!(lobby.join(3, room), Err(LobbyError::RoomFull));
+    assert_eq!(lobby.join(3, room + 1), Err(LobbyError::NoSuchRoom));
+    lobby.create_room(3, "Crowded", 200).unwrap();
+    assert_eq!(lobby.rooms()[1].max_players, MAX_ROOM_PLAYERS);
+    lobby.leave(3);
+    let mut larger = Lobby::new(32);
+    larger.create_room(1, "Crowded", 200).unwrap();
+    assert_eq!(larger.rooms()[0].max_players, 32);
+
+    lobby.set_ready(1, true).unwrap();
+    assert!(lobby.start_ready_room().is_none());
+    lobby.set_ready(2, true).unwrap();
+    let started = lobby.start_ready_room().unwrap();
+    assert_eq!(started.clients().collect::<Vec<_>>(), [1, 2]);
+    assert_eq!(lobby.set_ready(2, false), Err(LobbyError::Playing));
+
+    // one game at a time
+    let other = lobby.create_room(3, "Later", 4).unwrap();
+    lobby.set_ready(3, true).unwrap();
+    assert!(lobby.start_ready_room().is_none());
+    lobby.leave(1);
+    assert_eq!(lobby.start_ready_room().unwrap().id, other);
+    assert_eq!(lobby.stop_game(), [3]);
+    assert!(!lobby.rooms()[0].playing);
+    assert!(lobby.start_ready_room().is_none());
+  }
+
+  #[test]
+  fn the_listing_of_a_full_lobby_fits_in_a_message() {
+    // the longest ids
+    let mut lobby = Lobby {
+      next_room: u64::MAX - MAX_PLAYERS as u64,
+      ..default()
+    };
+    let name = "n".repeat(MAX_ROOM_NAME);
+    for client in 0..MAX_PLAYERS as u64 {
+      let created = lobby.create_room(client, &name, MAX_ROOM_PLAYERS);
+      if client < MAX_ROOMS as u64 {
+        created.unwrap();
+      } else {
+        assert_eq!(created, Err(LobbyError::TooManyRooms));
+      }
+    }
+    let listing = ServerMessage::Rooms(lobby.listing());
+    assert!(listing.encode().len() <= MAX_RELIABLE_SIZE);
+  }
+
+  #[test]
+  fn hosts_kick_and_ban_members() {
+    let mut lobby = Lobby::default();
+    let room = lobby.create_room(1, "Room", 4).unwrap();
+    lobby.join(2, room).unwrap();
+    lobby.join(3, room).unwrap();
+    lobby.take_changes();
+
+    assert_eq!(lobby.kick(2, 3, false), Err(LobbyError::NotHost));
+    assert_eq!(lobby.kick(1, 4, false), Err(LobbyError::NoSuchMember));
+    assert_eq!(lobby.kick(1, 1, true), Err(LobbyError::KickedHost));
+    let departures = lobby.kick(1, 2, false).unwrap();
+    assert_eq!(
+      departures,
+      [Departure {
+        client: 2,
+        playing: false
+      }]
+    );
+    let (listing_changed, mut changed) = lobby.take_changes();
+    changed.sort();
+    assert!(listing_changed);
+    assert_eq!(changed, [1, 2, 3]);
+    assert_eq!(lobby.state(2), None);
+    // kicked clients can come back, banned ones cannot
+    lobby.join(2, room).unwrap();
+    lobby.kick(1, 2, true).unwrap();
+    assert_eq!(lobby.join(2, room), Err(LobbyError::Banned));
+    assert_eq!(lobby.state(3).unwrap().members.len(), 2);
+  }
+
+  #[test]
+  fn rooms_close_when_their_host_leaves() {
+    let mut lobby = Lobby::default();
+    let room = lobby.create_room(1, "Room", 4).unwrap();
+    lobby.join(2, room).unwrap();
+    lobby.set_ready(1, true).unwrap();
+    lobby.set_ready(2, true).unwrap();
+    lobby.start_ready_room();
+
+    assert_eq!(
+      lobby.leave(2),
+      [Departure {
+        client: 2,
+        playing: true
+      }]
+    );
+    lobby.join(3, room).unwrap_err();
+    assert_eq!(lobby.leave(1).len(), 1);
+    assert!(lobby.rooms().is_empty());
+    assert!(lobby.leave(1).is_empty());
+  }
+}
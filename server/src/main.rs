@@ -1,3 +1,30 @@
-fn main() {
-  println!("Hello, world!");
+use bevy::app::TerminalCtrlCHandlerPlugin;
+use bevy::prelude::*;
+
+use server::admin::AdminPlugin;
+use server::config::{Command, USAGE};
+use server::server_app;
+
+fn main() -> AppExit {
+  let config = match Command::from_args(std::env::args()) {
+    Ok(Command::Run(config)) => config,
+    Ok(Command::Help) => {
+      println!("{USAGE}");
+      return AppExit::Success;
+    }
+    Err(error) => {
+      eprintln!("{error}");
+      return AppExit::from_code(2);
+    }
+  };
+  server_app(&config)
+    .add_plugins((
+      // SIGINT and SIGTERM exit the app, so clients are told
+      TerminalCtrlCHandlerPlugin,
+      AdminPlugin {
+        stdin: true,
+        port: config.admin_port,
+      },
+    ))
+    .run()
 }
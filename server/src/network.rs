@@ -0,0 +1,686 @@
+use bevy::ecs::system::SystemState;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use shared::level::{Level, LevelSpawner};
+use shared::net::{
+  ClientMessage, DeltaSnapshot, LinkConditions, LobbyRequest, NetEntity, NetServer, ObjectSnapshot,
+  PlayerSnapshot, ServerMessage, ServerNetEvent, Snapshot,
+};
+use shared::tick::{Tick, ticks_per_second};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::lobby::{Departure, Lobby, LobbyError};
+use crate::simulation::{
+  ClientEvent, ClientId, Clients, ServerLevel, ServerState, SpawnCounter, WorldState,
+  broadcast_world_state, receive_client_events, spawn_player,
+};
+
+/// Serves the simulation over UDP. Clients wait in the rooms of the `Lobby` until their
+/// room starts, then their packets become `ClientEvent`s and every `WorldState` goes out
+/// to them as a snapshot.
+pub struct NetworkPlugin {
+  pub address: SocketAddr,
+  /// Clients connected at once, in the lobby or playing
+  pub max_clients: usize,
+  /// Most players of a lobby room
+  pub room_size: u8,
+  /// A bad network to simulate, for testing clients
+  pub conditions: LinkConditions,
+}
+
+impl Plugin for NetworkPlugin {
+  fn build(&self, app: &mut App) {
+    match NetServer::bind(self.address) {
+      Ok(mut server) => {
+        if !self.conditions.is_perfect() {
+          info!("Simulating {:?}", self.conditions);
+        }
+        server.set_conditions(self.conditions);
+        server.max_clients = self.max_clients;
+        info!(
+          "Listening on {}",
+          server.local_addr().unwrap_or(self.address)
+        );
+        app
+          .insert_resource(Network(server))
+          .init_resource::<Baselines>()
+          .insert_resource(Lobby::new(self.room_size));
+      }
+      Err(error) => {
+        error!("Could not listen on {}: {error}", self.address);
+        app.add_systems(Startup, |mut evw_exit: EventWriter<AppExit>| {
+          evw_exit.send(AppExit::error());
+        });
+        return;
+      }
+    }
+    app
+      // clients wait in the socket until the level is running
+      .add_systems(
+        PreUpdate,
+        (receive_packets, start_games, send_lobby_changes)
+          .chain()
+          .before(receive_client_events)
+          .run_if(in_state(ServerState::Running)),
+      )
+      .add_systems(
+        FixedPostUpdate,
+        send_snapshots
+          .after(broadcast_world_state)
+          .run_if(in_state(ServerState::Running)),
+      )
+      .add_systems(Last, (disconnect_on_exit, flush_packets).chain());
+  }
+}
+
+/// Snapshots sent and not acked yet are kept this many ticks, about a second.
+/// A client acking none of them gets full snapshots.
+const BASELINE_HISTORY: usize = 64;
+
+/// The socket clients connect to
+#[derive(Resource)]
+pub struct Network(pub NetServer);
+
+/// What snapshots each client in the game can decode deltas against
+#[derive(Resource, Default)]
+pub struct Baselines(HashMap<u64, ClientBaselines>);
+
+#[derive(Default)]
+struct ClientBaselines {
+  /// Snapshots sent, as quantized as the client received them, oldest first
+  sent: VecDeque<Snapshot>,
+  /// Tick of the latest snapshot the client has
+  acked: Option<u64>,
+}
+
+impl ClientBaselines {
+  fn baseline(&self) -> Option<&Snapshot> {
+    let acked = self.acked?;
+    self.sent.iter().find(|snapshot| snapshot.tick == acked)
+  }
+
+  fn push(&mut self, snapshot: Snapshot) {
+    if self.sent.len() == BASELINE_HISTORY {
+      self.sent.pop_front();
+    }
+    self.sent.push_back(snapshot);
+  }
+}
+
+impl From<&WorldState> for Snapshot {
+  fn from(state: &WorldState) -> Self {
+    Snapshot {
+      tick: state.tick,
+      players: state
+        .players
+        .iter()
+        .map(|player| PlayerSnapshot {
+          client: player.client.0,
+          last_input_tick: player.last_input_tick,
+          translation: player.translation,
+          velocity: player.velocity,
+          grounded: player.grounded,
+        })
+        .collect(),
+      objects: state
+        .objects
+        .iter()
+        .map(|object| ObjectSnapshot {
+          index: object.index as u32,
+          translation: object.translation,
+          rotation: object.rotation,
+          velocity: object.velocity,
+        })
+        .collect(),
+    }
+  }
+}
+
+fn receive_packets(
+  mut network: ResMut<Network>,
+  mut baselines: ResMut<Baselines>,
+  mut lobby: ResMut<Lobby>,
+  mut evw_client: EventWriter<ClientEvent>,
+) {
+  let server = &mut network.0;
+  for event in server.receive(Instant::now()) {
+    match event {
+      ServerNetEvent::Connected(client) => {
+        server.send(client, &ServerMessage::Rooms(lobby.listing()));
+      }
+      ServerNetEvent::Message(
+        client,
+        ClientMessage::Inputs {
+          inputs,
+          snapshot_ack,
+        },
+      ) => {
+        // only players in the game have baselines
+        let Some(baselines) = baselines.0.get_mut(&client) else {
+          continue;
+        };
+        if snapshot_ack > baselines.acked {
+          baselines.acked = snapshot_ack;
+        }
+        evw_client.send_batch(inputs.into_iter().map(|input| ClientEvent::Input {
+          client: ClientId(client),
+          tick: input.tick,
+          actions: input.actions,
+        }));
+      }
+      ServerNetEvent::Message(client, ClientMessage::Lobby(request)) => {
+        match handle_lobby_request(server, &mut lobby, client, request) {
+          Ok(departures) => {
+            for departure in departures {
+              leave_game(server, &mut baselines, &mut evw_client, departure);
+            }
+          }
+          Err(error) => server.send(client, &ServerMessage::Notice(error.to_string())),
+        }
+      }
+      ServerNetEvent::Disconnected(client) => {
+        for departure in lobby.leave(client) {
+          leave_game(server, &mut baselines, &mut evw_client, departure);
+        }
+      }
+    }
+  }
+}
+
+/// Returns who left their room
+fn handle_lobby_request(
+  server: &mut NetServer,
+  lobby: &mut Lobby,
+  client: u64,
+  request: LobbyRequest,
+) -> Result<Vec<Departure>, LobbyError> {
+  match request {
+    LobbyRequest::CreateRoom { name, max_players } => {
+      let room = lobby.create_room(client, &name, max_players)?;
+      info!("Client {client} opened room {room} {name:?}");
+      Ok(Vec::new())
+    }
+    LobbyRequest::JoinRoom(room) => lobby.join(client, room).map(|_| Vec::new()),
+    LobbyRequest::LeaveRoom => Ok(lobby.leave(client)),
+    LobbyRequest::SetReady(ready) => lobby.set_ready(client, ready).map(|_| Vec::new()),
+    LobbyRequest::Kick(member) | LobbyRequest::Ban(member) => {
+      let ban = matches!(request, LobbyRequest::Ban(_));
+      let departures = lobby.kick(client, member, ban)?;
+      let notice = if ban {
+        "You were banned from the room"
+      } else {
+        "You were kicked from the room"
+      };
+      server.send(member, &ServerMessage::Notice(notice.to_string()));
+      Ok(departures)
+    }
+  }
+}
+
+/// Take the player of a client who left their room out of the game
+fn leave_game(
+  server: &mut NetServer,
+  baselines: &mut Baselines,
+  evw_client: &mut EventWriter<ClientEvent>,
+  departure: Departure,
+) {
+  if !departure.playing || baselines.0.remove(&departure.client).is_none() {
+    return;
+  }
+  let despawn = ServerMessage::Despawn(NetEntity::Player(departure.client));
+  for &other in baselines.0.keys() {
+    server.send(other, &despawn);
+  }
+  evw_client.send(ClientEvent::Disconnected(ClientId(departure.client)));
+}
+
+/// Every member of a room whose members are all ready enters the game at once
+#[allow(clippy::too_many_arguments)]
+fn start_games(
+  mut network: ResMut<Network>,
+  mut baselines: ResMut<Baselines>,
+  mut lobby: ResMut<Lobby>,
+  tick: Res<Tick>,
+  time: Res<Time<Fixed>>,
+  server_level: Res<ServerLevel>,
+  players: Res<Clients>,
+  asset_server: Res<AssetServer>,
+  mut evw_client: EventWriter<ClientEvent>,
+) {
+  let Some(room) = lobby.start_ready_room() else {
+    return;
+  };
+  let members: Vec<_> = room.clients().collect();
+  info!("Room {:?} starts with {} players", room.name, members.len());
+  let level = asset_server
+    .get_path(&server_level.0)
+    .map(|path| path.to_string())
+    .unwrap_or_default();
+  let server = &mut network.0;
+  for &client in &members {
+    server.send(
+      client,
+      &ServerMessage::Welcome {
+        level: level.clone(),
+        tick: tick.0,
+        tick_rate: ticks_per_second(&time),
+      },
+    );
+    // and the players spawned from the admin console
+    for &player in members
+      .iter()
+      .chain(players.0.keys().map(|client| &client.0))
+    {
+      server.send(client, &ServerMessage::Spawn(NetEntity::Player(player)));
+    }
+    baselines.0.insert(client, ClientBaselines::default());
+    evw_client.send(ClientEvent::Connected(ClientId(client)));
+  }
+}
+
+/// Drop a client from the server, or remove a player spawned without one.
+/// Returns whether there was such a client.
+pub fn kick(world: &mut World, client: u64) -> bool {
+  let mut state = SystemState::<(
+    ResMut<Network>,
+    ResMut<Baselines>,
+    ResMut<Lobby>,
+    Res<Clients>,
+    EventWriter<ClientEvent>,
+  )>::new(world);
+  let (mut network, mut baselines, mut lobby, players, mut evw_client) = state.get_mut(world);
+  let server = &mut network.0;
+  if server.clients().any(|other| other == client) {
+    server.send(
+      client,
+      &ServerMessage::Notice("You were kicked from the server".to_string()),
+    );
+    server.flush(Instant::now());
+    server.disconnect(client);
+    for departure in lobby.leave(client) {
+      leave_game(server, &mut baselines, &mut evw_client, departure);
+    }
+  } else if players.0.contains_key(&ClientId(client)) {
+    let despawn = ServerMessage::Despawn(NetEntity::Player(client));
+    for &other in baselines.0.keys() {
+      server.send(other, &despawn);
+    }
+    evw_client.send(ClientEvent::Disconnected(ClientId(client)));
+  } else {
+    return false;
+  }
+  state.apply(world);
+  true
+}
+
+/// Spawn a player no client controls, at `translation` or the next spawn point.
+/// Returns the id it goes by, none until a level runs.
+pub fn spawn_idle_player(world: &mut World, translation: Option<Vec3>) -> Option<u64> {
+  if *world.resource::<State<ServerState>>() != ServerState::Running {
+    return None;
+  }
+  let mut state = SystemState::<(
+    LevelSpawner,
+    ResMut<Network>,
+    ResMut<Clients>,
+    ResMut<SpawnCounter>,
+    Res<Baselines>,
+    Res<ServerLevel>,
+    Res<Assets<Level>>,
+  )>::new(world);
+  let (mut spawner, mut network, mut players, mut spawn_counter, baselines, server_level, levels) =
+    state.get_mut(world);
+  let client = network.0.reserve_client();
+  let translation =
+    translation.unwrap_or_else(|| spawn_counter.next_point(levels.get(&server_level.0)));
+  spawn_player(&mut spawner, &mut players, ClientId(client), translation)?;
+  for &other in baselines.0.keys() {
+    network
+      .0
+      .send(other, &ServerMessage::Spawn(NetEntity::Player(client)));
+  }
+  state.apply(world);
+  Some(client)
+}
+
+/// Send the players of the game back to their room, telling them why.
+/// Returns how many there were.
+pub fn stop_game(world: &mut World, reason: &str) -> usize {
+  let clients = world.resource_mut::<Lobby>().stop_game();
+  world.resource_mut::<Baselines>().0.clear();
+  let mut network = world.resource_mut::<Network>();
+  for &client in &clients {
+    network
+      .0
+      .send(client, &ServerMessage::Notice(reason.to_string()));
+  }
+  world.send_event_batch(
+    clients
+      .iter()
+      .map(|&client| ClientEvent::Disconnected(ClientId(client))),
+  );
+  clients.len()
+}
+
+/// Tick at another rate, the clients in the game follow
+pub fn set_tick_rate(world: &mut World, tick_rate: u32) {
+  world
+    .resource_mut::<Time<Fixed>>()
+    .set_timestep_hz(tick_rate as f64);
+  let clients = world
+    .resource::<Baselines>()
+    .0
+    .keys()
+    .copied()
+    .collect::<Vec<_>>();
+  let mut network = world.resource_mut::<Network>();
+  for client in clients {
+    network.0.send(client, &ServerMessage::TickRate(tick_rate));
+  }
+}
+
+/// Tell every client about the rooms that changed, and members about their room
+fn send_lobby_changes(mut network: ResMut<Network>, mut lobby: ResMut<Lobby>) {
+  let (listing_changed, changed) = lobby.take_changes();
+  if listing_changed {
+    network.0.broadcast(&ServerMessage::Rooms(lobby.listing()));
+  }
+  for client in changed {
+    network
+      .0
+      .send(client, &ServerMessage::Room(lobby.state(client)));
+  }
+}
+
+fn send_snapshots(
+  mut network: ResMut<Network>,
+  mut baselines: ResMut<Baselines>,
+  mut evr_state: EventReader<WorldState>,
+) {
+  // only the latest state matters
+  let Some(state) = evr_state.read().last() else {
+    return;
+  };
+  let snapshot = Snapshot::from(state);
+  let quantized = snapshot.quantized();
+  for (client, baselines) in &mut baselines.0 {
+    let delta = DeltaSnapshot::encode(&snapshot, baselines.baseline());
+    network.0.send(*client, &ServerMessage::Snapshot(delta));
+    baselines.push(quantized.clone());
+  }
+}
+
+/// Tell every client the server is going away, rather than have them time out
+fn disconnect_on_exit(mut network: ResMut<Network>, mut evr_exit: EventReader<AppExit>) {
+  if evr_exit.is_empty() {
+    return;
+  }
+  evr_exit.clear();
+  let clients = network.0.clients().collect::<Vec<_>>();
+  info!("Shutting down, disconnecting {} clients", clients.len());
+  network.0.broadcast(&ServerMessage::Notice(
+    "The server is shutting down".to_string(),
+  ));
+  network.0.flush(Instant::now());
+  for client in clients {
+    network.0.disconnect(client);
+  }
+}
+
+fn flush_packets(mut network: ResMut<Network>) {
+  network.0.flush(Instant::now());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lobby::MAX_ROOM_PLAYERS;
+  use crate::simulation::tests::server;
+  use shared::controller::MovementAction;
+  use shared::interpolation::SnapshotBuffer;
+  use shared::net::{ClientNetEvent, DisconnectReason, NetClient, RoomInfo, TickInput};
+  use std::time::Duration;
+
+  fn step(app: &mut App, client: &mut NetClient, received: &mut Vec<ClientNetEvent>) {
+    client.flush(Instant::now());
+    app.update();
+    std::thread::sleep(Duration::from_millis(1));
+    received.extend(client.receive(Instant::now()));
+  }
+
+  fn network_server() -> App {
+    let mut app = server();
+    app.add_plugins(NetworkPlugin {
+      address: ([127, 0, 0, 1], 0).into(),
+      max_clients: 16,
+      room_size: MAX_ROOM_PLAYERS,
+      conditions: LinkConditions::default(),
+    });
+    app
+  }
+
+  /// A client connected to the lobby, with its id and what it received so far
+  fn connect(app: &mut App) -> (NetClient, u64, Vec<ClientNetEvent>) {
+    let address = app.world().resource::<Network>().0.local_addr().unwrap();
+    let mut client = NetClient::connect(address).unwrap();
+    let mut received = Vec::new();
+    for _ in 0..500 {
+      step(app, &mut client, &mut received);
+      if let Some(&ClientNetEvent::Connected(id)) = received.first() {
+        return (client, id, received);
+      }
+    }
+    panic!("never connected: {received:?}");
+  }
+
+  fn messages(received: &[ClientNetEvent]) -> Vec<&ServerMessage> {
+    received
+      .iter()
+      .filter_map(|event| match event {
+        ClientNetEvent::Message(message) => Some(message),
+        _ => None,
+      })
+      .collect()
+  }
+
+  fn lobby(client: &mut NetClient, request: LobbyRequest) {
+    client.send(&ClientMessage::Lobby(request));
+  }
+
+  #[test]
+  fn clients_play_over_the_network() {
+    let mut app = network_server();
+    let (mut client, id, mut received) = connect(&mut app);
+    lobby(
+      &mut client,
+      LobbyRequest::CreateRoom {
+        name: "Solo".to_string(),
+        max_players: 1,
+      },
+    );
+    lobby(&mut client, LobbyRequest::SetReady(true));
+
+    // walk forward for a while, acking the snapshots as they come
+    let mut snapshots = SnapshotBuffer::default();
+    let mut deltas = 0;
+    for input_tick in 1..=80 {
+      client.send(&ClientMessage::Inputs {
+        inputs: vec![TickInput {
+          tick: input_tick,
+          actions: vec![MovementAction::Move(Vec2::Y)],
+        }],
+        snapshot_ack: snapshots.latest().map(|snapshot| snapshot.tick),
+      });
+      let from = received.len();
+      step(&mut app, &mut client, &mut received);
+      for event in &received[from..] {
+        if let ClientNetEvent::Message(ServerMessage::Snapshot(delta)) = event {
+          // always relative to one the client has
+          let baseline = delta.baseline.map(|tick| snapshots.get(tick).unwrap());
+          deltas += baseline.is_some() as usize;
+          snapshots.push(delta.decode(baseline).unwrap());
+        }
+      }
+    }
+    assert!(deltas > 30, "{deltas}");
+    let messages = messages(&received);
+    assert_eq!(*messages[0], ServerMessage::Rooms(Vec::new()));
+    let welcome = messages
+      .iter()
+      .position(|message| {
+        matches!(
+          message,
+          ServerMessage::Welcome { level, .. } if level == "levels/arena.level.ron"
+        )
+      })
+      .unwrap();
+    assert_eq!(
+      *messages[welcome + 1],
+      ServerMessage::Spawn(NetEntity::Player(id))
+    );
+    let first = messages
+      .iter()
+      .find_map(|message| match message {
+        ServerMessage::Snapshot(snapshot) => Some(snapshot),
+        _ => None,
+      })
+      .unwrap();
+    assert_eq!(first.baseline, None);
+    let last = snapshots.latest().unwrap();
+    assert!(last.tick > first.tick);
+    let player = last
+      .players
+      .iter()
+      .find(|player| player.client == id)
+      .unwrap();
+    assert!(player.last_input_tick > 30, "{}", player.last_input_tick);
+    assert!(player.velocity.z > 1.0, "{}", player.velocity);
+  }
+
+  #[test]
+  fn rooms_enter_the_game_together() {
+    let mut app = network_server();
+    let (mut host, host_id, mut host_received) = connect(&mut app);
+    let (mut guest, guest_id, mut guest_received) = connect(&mut app);
+    let run = |app: &mut App,
+               host: &mut NetClient,
+               guest: &mut NetClient,
+               host_received: &mut Vec<ClientNetEvent>,
+               guest_received: &mut Vec<ClientNetEvent>| {
+      for _ in 0..20 {
+        step(app, host, host_received);
+        step(app, guest, guest_received);
+      }
+    };
+
+    lobby(
+      &mut host,
+      LobbyRequest::CreateRoom {
+        name: "Duo".to_string(),
+        max_players: 2,
+      },
+    );
+    run(
+      &mut app,
+      &mut host,
+      &mut guest,
+      &mut host_received,
+      &mut guest_received,
+    );
+    let rooms = messages(&guest_received)
+      .into_iter()
+      .rev()
+      .find_map(|message| match message {
+        ServerMessage::Rooms(rooms) => Some(rooms.clone()),
+        _ => None,
+      })
+      .unwrap();
+    let [RoomInfo { id: room, .. }] = rooms[..] else {
+      panic!("{rooms:?}");
+    };
+
+    lobby(&mut guest, LobbyRequest::JoinRoom(room));
+    lobby(&mut guest, LobbyRequest::SetReady(true));
+    run(
+      &mut app,
+      &mut host,
+      &mut guest,
+      &mut host_received,
+      &mut guest_received,
+    );
+    let welcomed = |received: &[ClientNetEvent]| {
+      messages(received)
+        .iter()
+        .any(|message| matches!(message, ServerMessage::Welcome { .. }))
+    };
+    assert!(!welcomed(&guest_received));
+
+    lobby(&mut host, LobbyRequest::SetReady(true));
+    run(
+      &mut app,
+      &mut host,
+      &mut guest,
+      &mut host_received,
+      &mut guest_received,
+    );
+    assert!(welcomed(&host_received) && welcomed(&guest_received));
+    let world = app.world_mut();
+    let players = world
+      .query::<&crate::simulation::RemotePlayer>()
+      .iter(world)
+      .count();
+    assert_eq!(players, 2);
+
+    // the host throws the guest out of the game
+    lobby(&mut host, LobbyRequest::Ban(guest_id));
+    run(
+      &mut app,
+      &mut host,
+      &mut guest,
+      &mut host_received,
+      &mut guest_received,
+    );
+    let guest_messages = messages(&guest_received);
+    assert!(guest_messages.contains(&&ServerMessage::Notice(
+      "You were banned from the room".to_string()
+    )));
+    assert_eq!(
+      **guest_messages.last().unwrap(),
+      ServerMessage::Room(None),
+      "{guest_messages:?}"
+    );
+    assert!(
+      messages(&host_received).contains(&&ServerMessage::Despawn(NetEntity::Player(guest_id)))
+    );
+    let host_room = messages(&host_received)
+      .into_iter()
+      .rev()
+      .find_map(|message| match message {
+        ServerMessage::Room(room) => room.clone(),
+        _ => None,
+      })
+      .unwrap();
+    assert_eq!(host_room.host, host_id);
+    assert_eq!(host_room.members.len(), 1);
+  }
+
+  #[test]
+  fn clients_are_told_when_the_server_exits() {
+    let mut app = network_server();
+    let (mut client, _, mut received) = connect(&mut app);
+    app.world_mut().send_event(AppExit::Success);
+    for _ in 0..20 {
+      step(&mut app, &mut client, &mut received);
+    }
+    assert!(messages(&received).contains(&&ServerMessage::Notice(
+      "The server is shutting down".to_string()
+    )));
+    assert_eq!(
+      received.last(),
+      Some(&ClientNetEvent::Disconnected(DisconnectReason::Closed))
+    );
+    assert_eq!(app.world().resource::<Network>().0.clients().count(), 0);
+  }
+}
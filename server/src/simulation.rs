@@ -0,0 +1,484 @@
+use avian3d::prelude::*;
+use bevy::asset::LoadState;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use shared::checkpoint::CheckpointPlugin;
+use shared::controller::{Grounded, MovementAction, MovementPlugin, MovementSet, PlayerInput};
+use shared::level::{Level, LevelLoader, LevelSpawner, ObjectIndex, PrefabRegistry, player_object};
+use shared::state::InGameEntity;
+use shared::tick::{Tick, TickPlugin, ticks_per_second};
+
+use crate::config::MAX_PLAYERS;
+use std::collections::VecDeque;
+
+/// Inputs a client can be ahead of the server by, older ones are dropped to catch up
+const MAX_QUEUED_INPUTS: usize = 8;
+
+/// Runs a level and its players authoritatively, one simulation step per fixed tick,
+/// checkpoints and respawning included.
+/// Clients are heard through `ClientEvent`s and hear back through `WorldState`s.
+pub struct SimulationPlugin {
+  /// Asset path of the level to run
+  pub level: String,
+}
+
+impl Plugin for SimulationPlugin {
+  fn build(&self, app: &mut App) {
+    let level = self.level.clone();
+    app
+      .add_plugins((MovementPlugin::default(), CheckpointPlugin, TickPlugin))
+      .init_asset::<Level>()
+      .register_asset_loader(LevelLoader { textures: false })
+      .init_resource::<PrefabRegistry>()
+      .init_resource::<Clients>()
+      .init_resource::<SpawnCounter>()
+      .init_state::<ServerState>()
+      .add_event::<ClientEvent>()
+      .add_event::<WorldState>()
+      .add_systems(
+        Startup,
+        move |mut commands: Commands, asset_server: Res<AssetServer>| {
+          info!("Loading {level}");
+          commands.insert_resource(ServerLevel(asset_server.load(level.clone())));
+        },
+      )
+      .add_systems(
+        Update,
+        wait_for_level.run_if(in_state(ServerState::Loading)),
+      )
+      .add_systems(OnEnter(ServerState::Running), spawn_level)
+      // before the fixed ticks of the frame, so they see the latest inputs
+      .add_systems(
+        PreUpdate,
+        receive_client_events.run_if(in_state(ServerState::Running)),
+      )
+      .add_systems(
+        FixedUpdate,
+        apply_queued_inputs
+          .before(MovementSet)
+          .run_if(in_state(ServerState::Running)),
+      )
+      .add_systems(
+        FixedPostUpdate,
+        broadcast_world_state
+          .after(PhysicsSet::Sync)
+          .run_if(in_state(ServerState::Running)),
+      );
+  }
+}
+
+#[derive(States, Clone, Copy, Default, Eq, PartialEq, Debug, Hash)]
+pub enum ServerState {
+  /// Waiting for the level to load, clients cannot join yet
+  #[default]
+  Loading,
+  Running,
+}
+
+/// The level being run
+#[derive(Resource)]
+pub struct ServerLevel(pub Handle<Level>);
+
+/// The level run before the one loading, run again if that one fails to load
+#[derive(Resource)]
+struct PreviousLevel(Handle<Level>);
+
+/// Identifies a connected client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientId(pub u64);
+
+/// What clients tell the server
+#[derive(Event, Debug, Clone)]
+pub enum ClientEvent {
+  Connected(ClientId),
+  /// The movement actions of a client's player for one of its ticks
+  Input {
+    client: ClientId,
+    tick: u64,
+    actions: Vec<MovementAction>,
+  },
+  Disconnected(ClientId),
+}
+
+/// The player character of every connected client
+#[derive(Resource, Default)]
+pub struct Clients(pub HashMap<ClientId, Entity>);
+
+/// How many players were spawned in the level. Players take the spawn points in turn,
+/// so the point of one who left is not handed to the next while the others are still there.
+#[derive(Resource, Default)]
+pub struct SpawnCounter(pub usize);
+
+impl SpawnCounter {
+  /// Where the next player starts, the points come around again after `MAX_PLAYERS`
+  pub fn next_point(&mut self, level: Option<&Level>) -> Vec3 {
+    let index = self.0 % MAX_PLAYERS;
+    self.0 += 1;
+    level.map_or(Vec3::ZERO, |level| level.player_spawn_point(index))
+  }
+}
+
+/// A player character controlled by a client
+#[derive(Component, Debug)]
+pub struct RemotePlayer {
+  pub client: ClientId,
+  /// Last tick of the client whose input was applied
+  pub last_input_tick: u64,
+}
+
+/// Inputs of a client not applied yet, oldest first, one is applied on every tick.
+/// Without one the last input is applied again, so a late packet does not stop the player.
+#[derive(Component, Debug, Default)]
+pub struct InputQueue(pub VecDeque<(u64, PlayerInput)>);
+
+/// The state of everything that moves, sent to every client after each tick
+#[derive(Event, Debug, Clone, PartialEq)]
+pub struct WorldState {
+  pub tick: u64,
+  pub players: Vec<PlayerState>,
+  pub objects: Vec<ObjectState>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerState {
+  pub client: ClientId,
+  /// Last input tick of the client the state includes
+  pub last_input_tick: u64,
+  pub translation: Vec3,
+  pub velocity: Vec3,
+  pub grounded: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectState {
+  pub index: usize,
+  pub translation: Vec3,
+  pub rotation: Quat,
+  pub velocity: Vec3,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn wait_for_level(
+  mut commands: Commands,
+  mut server_level: ResMut<ServerLevel>,
+  previous_level: Option<Res<PreviousLevel>>,
+  asset_server: Res<AssetServer>,
+  levels: Res<Assets<Level>>,
+  time: Res<Time<Fixed>>,
+  mut next_state: ResMut<NextState<ServerState>>,
+  mut evw_exit: EventWriter<AppExit>,
+) {
+  if let LoadState::Failed(error) = asset_server.load_state(&server_level.0) {
+    error!("Could not load the level: {error}");
+    match previous_level {
+      Some(previous_level) => {
+        info!("Running the previous level again");
+        server_level.0 = previous_level.0.clone();
+        commands.remove_resource::<PreviousLevel>();
+      }
+      None => {
+        error!("No level to run, shutting down");
+        evw_exit.send(AppExit::error());
+      }
+    }
+    return;
+  }
+  if let Some(level) = levels.get(&server_level.0) {
+    commands.remove_resource::<PreviousLevel>();
+    info!(
+      "Running {:?} at {} ticks per second",
+      level.name,
+      ticks_per_second(&time)
+    );
+    next_state.set(ServerState::Running);
+  }
+}
+
+fn spawn_level(
+  mut spawner: LevelSpawner,
+  server_level: Res<ServerLevel>,
+  levels: Res<Assets<Level>>,
+) {
+  if let Some(level) = levels.get(&server_level.0) {
+    spawner.spawn_level(level);
+  }
+}
+
+pub fn receive_client_events(
+  mut spawner: LevelSpawner,
+  mut evr_client: EventReader<ClientEvent>,
+  mut clients: ResMut<Clients>,
+  mut spawn_counter: ResMut<SpawnCounter>,
+  server_level: Res<ServerLevel>,
+  levels: Res<Assets<Level>>,
+  mut player_q: Query<(&RemotePlayer, &PlayerInput, &mut InputQueue)>,
+) {
+  for event in evr_client.read() {
+    match event {
+      ClientEvent::Connected(client) => {
+        let spawn_point = spawn_counter.next_point(levels.get(&server_level.0));
+        if spawn_player(&mut spawner, &mut clients, *client, spawn_point).is_some() {
+          info!("Client {} joined", client.0);
+        }
+      }
+      ClientEvent::Input {
+        client,
+        tick,
+        actions,
+      } => {
+        let Some(Ok((player, current, mut queue))) = clients
+          .0
+          .get(client)
+          .map(|&entity| player_q.get_mut(entity))
+        else {
+          continue;
+        };
+        // inputs are sent more than once and can arrive out of order
+        let Err(position) = queue.0.binary_search_by_key(tick, |(queued, _)| *queued) else {
+          continue;
+        };
+        if *tick <= player.last_input_tick {
+          continue;
+        }
+        let mut input = PlayerInput {
+          movement: queue
+            .0
+            .back()
+            .map_or(current.movement, |(_, last)| last.movement),
+          jump: false,
+        };
+        for action in actions {
+          match *action {
+            MovementAction::Move(direction) => input.movement = direction.clamp_length_max(1.0),
+            MovementAction::Jump => input.jump = true,
+          }
+        }
+        queue.0.insert(position, (*tick, input));
+      }
+      ClientEvent::Disconnected(client) => {
+        if let Some(entity) = clients.0.remove(client) {
+          info!("Client {} left", client.0);
+          spawner.commands.entity(entity).despawn_recursive();
+        }
+      }
+    }
+  }
+}
+
+/// Spawn the character of a client, unless it has one already
+pub fn spawn_player(
+  spawner: &mut LevelSpawner,
+  clients: &mut Clients,
+  client: ClientId,
+  translation: Vec3,
+) -> Option<Entity> {
+  if clients.0.contains_key(&client) {
+    return None;
+  }
+  let entity = spawner.spawn_object(&player_object(translation))?;
+  spawner.commands.entity(entity).insert((
+    RemotePlayer {
+      client,
+      last_input_tick: 0,
+    },
+    InputQueue::default(),
+  ));
+  clients.0.insert(client, entity);
+  Some(entity)
+}
+
+/// Despawn the level and its players, and run another one once it is loaded. The previous
+/// level runs again if the new one cannot be loaded.
+pub fn load_level(world: &mut World, level: &str) {
+  info!("Loading {level}");
+  let entities = world
+    .query_filtered::<Entity, With<InGameEntity>>()
+    .iter(world)
+    .collect::<Vec<_>>();
+  for entity in entities {
+    if let Ok(entity) = world.get_entity_mut(entity) {
+      entity.despawn_recursive();
+    }
+  }
+  world.resource_mut::<Clients>().0.clear();
+  world.resource_mut::<SpawnCounter>().0 = 0;
+  // a level still loading or failed leaves nothing to go back to
+  if let Some(ServerLevel(previous)) = world.remove_resource::<ServerLevel>()
+    && world.resource::<Assets<Level>>().contains(&previous)
+  {
+    world.insert_resource(PreviousLevel(previous));
+  }
+  let handle = world.resource::<AssetServer>().load(level.to_string());
+  world.insert_resource(ServerLevel(handle));
+  world
+    .resource_mut::<NextState<ServerState>>()
+    .set(ServerState::Loading);
+}
+
+fn apply_queued_inputs(
+  mut player_q: Query<(&mut RemotePlayer, &mut PlayerInput, &mut InputQueue)>,
+) {
+  for (mut player, mut input, mut queue) in &mut player_q {
+    if queue.0.len() > MAX_QUEUED_INPUTS {
+      let behind = queue.0.len() - MAX_QUEUED_INPUTS;
+      debug!("Client {} is {behind} inputs ahead", player.client.0);
+      queue.0.drain(..behind);
+    }
+    if let Some((tick, queued)) = queue.0.pop_front() {
+      *input = queued;
+      player.last_input_tick = tick;
+    }
+  }
+}
+
+#[allow(clippy::type_complexity)]
+pub fn broadcast_world_state(
+  tick: Res<Tick>,
+  player_q: Query<(&RemotePlayer, &Transform, &LinearVelocity, Has<Grounded>)>,
+  object_q: Query<(&ObjectIndex, &RigidBody, &Transform, &LinearVelocity)>,
+  mut evw_state: EventWriter<WorldState>,
+) {
+  evw_state.send(WorldState {
+    tick: tick.0,
+    players: player_q
+      .iter()
+      .map(|(player, transform, velocity, grounded)| PlayerState {
+        client: player.client,
+        last_input_tick: player.last_input_tick,
+        translation: transform.translation,
+        velocity: velocity.0,
+        grounded,
+      })
+      .collect(),
+    objects: object_q
+      .iter()
+      .filter(|(_, rigid_body, _, _)| rigid_body.is_dynamic())
+      .map(|(index, _, transform, velocity)| ObjectState {
+        index: index.0,
+        translation: transform.translation,
+        rotation: transform.rotation,
+        velocity: velocity.0,
+      })
+      .collect(),
+  });
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use shared::stepped::{WORKSPACE_ASSETS, stepped_app, wait_for};
+
+  pub fn server() -> App {
+    let mut app = server_loading("levels/arena.level.ron");
+    let running = wait_for(&mut app, |world| {
+      *world.resource::<State<ServerState>>() == ServerState::Running
+    });
+    assert!(running, "the level never loaded");
+    app.update();
+    app
+  }
+
+  /// A server loading `level`, one tick per update
+  fn server_loading(level: &str) -> App {
+    let mut app = stepped_app(WORKSPACE_ASSETS);
+    app.add_plugins(SimulationPlugin {
+      level: level.to_string(),
+    });
+    app
+  }
+
+  pub fn latest_state(app: &App) -> WorldState {
+    let events = app.world().resource::<Events<WorldState>>();
+    events
+      .iter_current_update_events()
+      .last()
+      .cloned()
+      .expect("a state is broadcast every tick")
+  }
+
+  #[test]
+  fn inputs_move_players_authoritatively() {
+    let mut app = server();
+    let client = ClientId(7);
+    app.world_mut().send_event(ClientEvent::Connected(client));
+    // let the player land
+    for _ in 0..60 {
+      app.update();
+    }
+    let start = latest_state(&app).players[0].translation;
+
+    for tick in 1..=30 {
+      app.world_mut().send_event(ClientEvent::Input {
+        client,
+        tick,
+        actions: vec![MovementAction::Move(Vec2::new(0.0, 1.0))],
+      });
+      app.update();
+    }
+    let state = latest_state(&app);
+    assert_eq!(state.players.len(), 1);
+    let player = &state.players[0];
+    assert_eq!(player.client, client);
+    assert_eq!(player.last_input_tick, 30);
+    // `Move` is (x, z) in the world
+    assert!(
+      player.translation.z - start.z > 1.0,
+      "{start} -> {}",
+      player.translation
+    );
+    // the red sphere of the arena
+    assert_eq!(state.objects.len(), 1);
+
+    app
+      .world_mut()
+      .send_event(ClientEvent::Disconnected(client));
+    app.update();
+    assert!(latest_state(&app).players.is_empty());
+  }
+
+  #[test]
+  fn a_level_that_cannot_load_at_startup_stops_the_server() {
+    let mut app = server_loading("levels/missing.level.ron");
+    let exited = wait_for(&mut app, |world| {
+      !world.resource::<Events<AppExit>>().is_empty()
+    });
+    assert!(exited, "the server kept waiting for the level");
+    assert!(app.should_exit().unwrap().is_error());
+  }
+
+  #[test]
+  fn fallen_players_respawn_where_they_started() {
+    let mut app = server();
+    let client = ClientId(3);
+    app.world_mut().send_event(ClientEvent::Connected(client));
+    app.update();
+    let player = app.world().resource::<Clients>().0[&client];
+    let start = app.world().get::<Transform>(player).unwrap().translation;
+    app.world_mut().get_mut::<Position>(player).unwrap().0 = Vec3::new(0.0, -30.0, 0.0);
+    app.update();
+    app.update();
+    let translation = app.world().get::<Transform>(player).unwrap().translation;
+    assert!(
+      translation.distance(start) < 0.5,
+      "{start} -> {translation}"
+    );
+  }
+
+  #[test]
+  fn players_joining_after_a_departure_get_a_free_spawn_point() {
+    let mut app = server();
+    let spawn_point = |app: &App, client| {
+      let entity = app.world().resource::<Clients>().0[&client];
+      app.world().get::<Transform>(entity).unwrap().translation
+    };
+    let [first, second, third] = [1, 2, 3].map(ClientId);
+    app.world_mut().send_event(ClientEvent::Connected(first));
+    app.world_mut().send_event(ClientEvent::Connected(second));
+    app.update();
+    app.world_mut().send_event(ClientEvent::Disconnected(first));
+    app.update();
+    app.world_mut().send_event(ClientEvent::Connected(third));
+    app.update();
+    assert!(spawn_point(&app, third).distance(spawn_point(&app, second)) > 0.5);
+  }
+}
@@ -0,0 +1,25 @@
+//! Bots playing on a server started in the same process, over loopback, in real time
+
+use bevy::prelude::*;
+use server::bot::{BotConfig, run};
+use std::time::Duration;
+
+#[test]
+#[ignore = "plays on a real server for seconds of wall-clock time"]
+fn bots_play_on_a_local_server() {
+  let config = BotConfig {
+    bots: 3,
+    predicting: 1,
+    duration: Duration::from_secs(2),
+    ..default()
+  };
+  let report = run(&config).unwrap();
+  assert_eq!(report.bots, 3);
+  assert!(report.server_updates.unwrap().count > 10);
+  assert!(report.server_tick_rate.unwrap() > 10.0);
+  assert!(report.server_traffic.unwrap().bytes_sent > 0);
+  assert!(report.bot_traffic.bytes_received > 0);
+  assert!(report.input_rate > 10.0, "{}", report.input_rate);
+  assert!(report.prediction_errors.count > 10, "{report}");
+  assert!(report.to_string().starts_with("3 bots played"));
+}
@@ -0,0 +1,331 @@
+//! A predicting client and the server in one process, over a link with simulated latency
+
+use avian3d::prelude::*;
+use bevy::ecs::system::RunSystemOnce;
+use bevy::prelude::*;
+use server::simulation::{ClientEvent, ClientId, ServerState, SimulationPlugin, WorldState};
+use shared::checkpoint::CheckpointPlugin;
+use shared::controller::{MovementAction, MovementPlugin, PlayerInput};
+use shared::level::{Level, LevelLoader, LevelSpawner, PrefabRegistry, player_object};
+use shared::prediction::{
+  AuthoritativeState, ControllerState, PredictionError, PredictionHistory, PredictionPlugin,
+};
+use shared::stepped::{WORKSPACE_ASSETS, stepped_app, wait_for, wait_for_level};
+use shared::tick::{TICK_RATE, TickPlugin};
+use std::collections::VecDeque;
+
+const LEVEL: &str = "levels/arena.level.ron";
+/// One way, 100ms
+const LATENCY_TICKS: usize = 6;
+const CLIENT: ClientId = ClientId(1);
+
+fn server() -> App {
+  let mut app = stepped_app(WORKSPACE_ASSETS);
+  app.add_plugins(SimulationPlugin {
+    level: LEVEL.to_string(),
+  });
+  let running = wait_for(&mut app, |world| {
+    *world.resource::<State<ServerState>>() == ServerState::Running
+  });
+  assert!(running, "{LEVEL} never loaded");
+  app
+}
+
+/// A client predicting its player in the same level, returns the player
+fn client() -> (App, Entity) {
+  let mut app = stepped_app(WORKSPACE_ASSETS);
+  app
+    .add_plugins((
+      MovementPlugin::default(),
+      CheckpointPlugin,
+      TickPlugin,
+      PredictionPlugin,
+    ))
+    .init_asset::<Level>()
+    .register_asset_loader(LevelLoader { textures: false })
+    .init_resource::<PrefabRegistry>();
+  let level: Handle<Level> = app.world().resource::<AssetServer>().load(LEVEL);
+  assert!(wait_for_level(&mut app, &level), "{LEVEL} never loaded");
+  let player = app
+    .world_mut()
+    .run_system_once(
+      move |mut spawner: LevelSpawner, levels: Res<Assets<Level>>| {
+        let level = levels.get(&level).unwrap();
+        spawner.spawn_level(level);
+        let player = spawner
+          .spawn_object(&player_object(level.player_spawn_point(0)))
+          .unwrap();
+        spawner
+          .commands
+          .entity(player)
+          .insert((PredictionHistory::default(), PredictionError::default()));
+        player
+      },
+    )
+    .unwrap();
+  (app, player)
+}
+
+/// Messages in flight, delivered `LATENCY_TICKS` steps after they are sent
+struct Link<T> {
+  in_flight: VecDeque<(usize, T)>,
+}
+
+impl<T> Link<T> {
+  fn new() -> Self {
+    Link {
+      in_flight: VecDeque::new(),
+    }
+  }
+
+  fn send(&mut self, step: usize, message: T) {
+    self.in_flight.push_back((step + LATENCY_TICKS, message));
+  }
+
+  fn receive(&mut self, step: usize) -> Vec<T> {
+    let mut received = Vec::new();
+    while self.in_flight.front().is_some_and(|(at, _)| *at <= step) {
+      received.push(self.in_flight.pop_front().unwrap().1);
+    }
+    received
+  }
+}
+
+struct Harness {
+  server: App,
+  client: App,
+  player: Entity,
+  step: usize,
+  inputs: Link<ClientEvent>,
+  states: Link<WorldState>,
+}
+
+impl Harness {
+  fn new() -> Self {
+    let mut server = server();
+    let (client, player) = client();
+    server
+      .world_mut()
+      .send_event(ClientEvent::Connected(CLIENT));
+    Harness {
+      server,
+      client,
+      player,
+      step: 0,
+      inputs: Link::new(),
+      states: Link::new(),
+    }
+  }
+
+  /// One tick on both ends
+  fn step(&mut self) {
+    self.step += 1;
+    for state in self.states.receive(self.step) {
+      let player = state
+        .players
+        .iter()
+        .find(|player| player.client == CLIENT)
+        .unwrap();
+      self.client.world_mut().send_event(AuthoritativeState {
+        entity: self.player,
+        tick: player.last_input_tick,
+        state: ControllerState {
+          translation: player.translation,
+          velocity: player.velocity,
+          grounded: player.grounded,
+        },
+      });
+    }
+    self.client.update();
+    let history = self.client.world().get::<PredictionHistory>(self.player);
+    if let Some(predicted) = history.and_then(|history| history.ticks().last()) {
+      let mut actions = vec![MovementAction::Move(predicted.input.movement)];
+      if predicted.input.jump {
+        actions.push(MovementAction::Jump);
+      }
+      self.inputs.send(
+        self.step,
+        ClientEvent::Input {
+          client: CLIENT,
+          tick: predicted.tick,
+          actions,
+        },
+      );
+    }
+
+    for input in self.inputs.receive(self.step) {
+      self.server.world_mut().send_event(input);
+    }
+    self.server.update();
+    let state = self
+      .server
+      .world()
+      .resource::<Events<WorldState>>()
+      .iter_current_update_events()
+      .last()
+      .cloned()
+      .unwrap();
+    self.states.send(self.step, state);
+  }
+
+  fn run(&mut self, steps: usize) {
+    for _ in 0..steps {
+      self.step();
+    }
+  }
+
+  fn set_input(&mut self, movement: Vec2) {
+    self
+      .client
+      .world_mut()
+      .get_mut::<PlayerInput>(self.player)
+      .unwrap()
+      .movement = movement;
+  }
+
+  fn client_position(&self) -> Vec3 {
+    self.client.world().get::<Position>(self.player).unwrap().0
+  }
+
+  fn server_player(&mut self) -> Entity {
+    let world = self.server.world_mut();
+    world
+      .query_filtered::<Entity, With<server::simulation::RemotePlayer>>()
+      .single(world)
+  }
+
+  fn server_position(&mut self) -> Vec3 {
+    let player = self.server_player();
+    self.server.world().get::<Position>(player).unwrap().0
+  }
+}
+
+#[test]
+fn the_predicted_player_moves_without_waiting_for_the_server() {
+  let mut harness = Harness::new();
+  // land on both ends
+  harness.run(90);
+  let client_start = harness.client_position();
+  let server_start = harness.server_position();
+
+  harness.set_input(Vec2::Y);
+  harness.run(3);
+  assert!(
+    harness.client_position().z - client_start.z > 0.01,
+    "{client_start} -> {}",
+    harness.client_position()
+  );
+  // the input is still on its way
+  assert!(harness.server_position().distance(server_start) < 0.001);
+
+  // walk, stop, and let the last states arrive
+  harness.run(60);
+  harness.set_input(Vec2::ZERO);
+  harness.run(60);
+  let client = harness.client_position();
+  let server = harness.server_position();
+  assert!(
+    server.z - server_start.z > 1.0,
+    "{server_start} -> {server}"
+  );
+  assert!(client.distance(server) < 0.05, "{client} != {server}");
+  let error = harness
+    .client
+    .world()
+    .get::<PredictionError>(harness.player);
+  assert!(error.unwrap().0.length() < 0.01);
+}
+
+#[test]
+fn corrections_from_the_server_are_smoothed() {
+  let mut harness = Harness::new();
+  harness.run(90);
+
+  // something the client could not predict pushes the player on the server
+  let player = harness.server_player();
+  let pushed = harness.server_position() + Vec3::X;
+  harness
+    .server
+    .world_mut()
+    .get_mut::<Position>(player)
+    .unwrap()
+    .0 = pushed;
+
+  let mut last = harness.client_position();
+  let mut largest_step: f32 = 0.0;
+  for _ in 0..60 {
+    harness.step();
+    let position = harness.client_position();
+    largest_step = largest_step.max(position.distance(last));
+    last = position;
+  }
+  // 1.0 away, it moves over several ticks instead of all at once
+  assert!(largest_step < 0.5, "{largest_step}");
+  assert!(last.distance(harness.server_position()) < 0.05, "{last}");
+}
+
+#[test]
+fn corrections_replay_only_the_predicted_player() {
+  let mut harness = Harness::new();
+  harness.run(90);
+  // a body sliding along on its own, far from the player
+  let start = Vec3::new(50.0, 10.0, 50.0);
+  let body = harness
+    .client
+    .world_mut()
+    .spawn((
+      RigidBody::Kinematic,
+      Collider::sphere(0.5),
+      Transform::from_translation(start),
+      LinearVelocity(Vec3::X),
+    ))
+    .id();
+
+  let player = harness.server_player();
+  let pushed = harness.server_position() + Vec3::X;
+  harness
+    .server
+    .world_mut()
+    .get_mut::<Position>(player)
+    .unwrap()
+    .0 = pushed;
+  let steps = 60;
+  harness.run(steps);
+
+  // one tick of movement per step, none from the ticks replayed by the correction
+  let moved = harness.client.world().get::<Position>(body).unwrap().0 - start;
+  let expected = steps as f32 / TICK_RATE as f32;
+  assert!((moved.x - expected).abs() < 0.05, "{moved}");
+}
+
+#[test]
+fn pausing_stops_the_player_on_the_server() {
+  let mut harness = Harness::new();
+  harness.run(90);
+  harness.set_input(Vec2::Y);
+  harness.run(30);
+
+  // paused while walking, inputs keep going out for every tick
+  harness
+    .client
+    .world_mut()
+    .resource_mut::<Time<Physics>>()
+    .pause();
+  harness.run(60);
+  let stopped = harness.server_position();
+  harness.run(60);
+  let server = harness.server_position();
+  assert!(server.distance(stopped) < 0.01, "{stopped} -> {server}");
+
+  // back where the server has it once playing again
+  harness.set_input(Vec2::ZERO);
+  harness
+    .client
+    .world_mut()
+    .resource_mut::<Time<Physics>>()
+    .unpause();
+  harness.run(60);
+  let client = harness.client_position();
+  let server = harness.server_position();
+  assert!(client.distance(server) < 0.05, "{client} != {server}");
+}
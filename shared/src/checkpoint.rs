@@ -0,0 +1,159 @@
+use avian3d::prelude::*;
+use bevy::prelude::*;
+
+use crate::controller::{Grounded, MovementSet, Player, physics_running};
+
+/// Checkpoints and respawning, on every tick before the players move, so the server and
+/// predicting clients put fallen players back on the same tick
+pub struct CheckpointPlugin;
+
+impl Plugin for CheckpointPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .register_type::<RespawnPoint>()
+      .add_event::<Respawn>()
+      .add_event::<CheckpointActivated>()
+      .add_systems(
+        FixedUpdate,
+        (
+          activate_checkpoints,
+          respawn_fallen_players,
+          respawn_players,
+        )
+          .chain()
+          .before(MovementSet)
+          .run_if(physics_running),
+      );
+  }
+}
+
+/// Players falling below this height respawn
+pub const FALL_HEIGHT: f32 = -20.0;
+
+/// Respawn this high above a checkpoint, for the player to land on it
+const SPAWN_HEIGHT: f32 = 0.6;
+
+/// A trigger volume that moves the respawn point of players touching it
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Checkpoint {
+  /// Where players respawn, facing the way the checkpoint faces
+  pub spawn: Transform,
+}
+
+impl Checkpoint {
+  pub fn new(transform: &Transform) -> Self {
+    let (yaw, _, _) = transform.rotation.to_euler(EulerRot::YXZ);
+    Checkpoint {
+      spawn: Transform::from_translation(transform.translation + Vec3::Y * SPAWN_HEIGHT)
+        .with_rotation(Quat::from_rotation_y(yaw)),
+    }
+  }
+}
+
+/// Where a player respawns, their spawn point until they activate a checkpoint
+#[derive(Component, Reflect, Clone, Copy, Debug, PartialEq)]
+#[reflect(Component)]
+pub struct RespawnPoint(pub Transform);
+
+/// Put a player back at their respawn point
+#[derive(Event, Debug, Clone, Copy)]
+pub struct Respawn {
+  pub player: Entity,
+}
+
+/// A player moved their respawn point to a checkpoint
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CheckpointActivated {
+  pub player: Entity,
+  pub checkpoint: Entity,
+}
+
+fn activate_checkpoints(
+  mut evr_collisions: EventReader<CollisionStarted>,
+  checkpoint_q: Query<&Checkpoint>,
+  mut player_q: Query<&mut RespawnPoint, With<Player>>,
+  mut evw_activated: EventWriter<CheckpointActivated>,
+) {
+  for CollisionStarted(a, b) in evr_collisions.read() {
+    let (player, checkpoint) = if checkpoint_q.contains(*b) {
+      (a, b)
+    } else {
+      (b, a)
+    };
+    let (Ok(mut respawn_point), Ok(&Checkpoint { spawn })) =
+      (player_q.get_mut(*player), checkpoint_q.get(*checkpoint))
+    else {
+      continue;
+    };
+    if respawn_point.0 == spawn {
+      continue;
+    }
+    respawn_point.0 = spawn;
+    evw_activated.send(CheckpointActivated {
+      player: *player,
+      checkpoint: *checkpoint,
+    });
+  }
+}
+
+fn respawn_fallen_players(
+  player_q: Query<(Entity, &Transform), With<Player>>,
+  mut evw_respawn: EventWriter<Respawn>,
+) {
+  for (player, transform) in &player_q {
+    if transform.translation.y < FALL_HEIGHT {
+      evw_respawn.send(Respawn { player });
+    }
+  }
+}
+
+/// Move players to their respawn point, at rest
+pub fn respawn_players(
+  mut commands: Commands,
+  mut evr_respawn: EventReader<Respawn>,
+  mut player_q: Query<(&RespawnPoint, &mut Transform, &mut LinearVelocity), With<Player>>,
+) {
+  for &Respawn { player } in evr_respawn.read() {
+    let Ok((respawn_point, mut transform, mut velocity)) = player_q.get_mut(player) else {
+      continue;
+    };
+    *transform = respawn_point.0;
+    velocity.0 = Vec3::ZERO;
+    commands.entity(player).remove::<Grounded>();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn respawn_puts_players_back_at_rest() {
+    let mut app = App::new();
+    app
+      .add_event::<Respawn>()
+      .add_systems(Update, respawn_players);
+    let spawn = Checkpoint::new(
+      &Transform::from_xyz(3.0, 2.0, -9.0).with_rotation(Quat::from_rotation_y(1.0)),
+    )
+    .spawn;
+    let player = app
+      .world_mut()
+      .spawn((
+        Player,
+        RespawnPoint(spawn),
+        Transform::from_xyz(0.0, -30.0, 40.0),
+        LinearVelocity(Vec3::new(1.0, -20.0, 3.0)),
+        Grounded,
+      ))
+      .id();
+
+    app.world_mut().send_event(Respawn { player });
+    app.update();
+
+    let world = app.world();
+    assert_eq!(*world.get::<Transform>(player).unwrap(), spawn);
+    assert_eq!(world.get::<LinearVelocity>(player).unwrap().0, Vec3::ZERO);
+    assert!(world.get::<Grounded>(player).is_none());
+  }
+}
@@ -0,0 +1,172 @@
+//! Commands typed into a console and run against the world of the app. The server reads them
+//! from its terminal and its admin socket, a console of the client can run the same ones.
+
+use bevy::prelude::*;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// What a command prints, or why it did nothing
+pub type CommandResult = Result<String, CommandError>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CommandError {
+  #[error("Unknown command {0:?}, try help")]
+  Unknown(String),
+  #[error("Usage: {0}")]
+  Usage(String),
+  #[error("{0}")]
+  Failed(String),
+}
+
+/// A command of the registry
+#[derive(Clone, Copy)]
+pub struct ConsoleCommand {
+  pub name: &'static str,
+  /// What follows the name, `<required>` and `[optional]`
+  pub arguments: &'static str,
+  pub help: &'static str,
+  pub run: fn(&mut World, &[&str]) -> CommandResult,
+}
+
+impl ConsoleCommand {
+  pub fn usage(&self) -> String {
+    format!("{} {}", self.name, self.arguments)
+      .trim_end()
+      .to_string()
+  }
+
+  /// The argument at `index`, the usage of the command if it is missing or invalid
+  pub fn argument<T: FromStr>(&self, arguments: &[&str], index: usize) -> Result<T, CommandError> {
+    arguments
+      .get(index)
+      .and_then(|argument| argument.parse().ok())
+      .ok_or_else(|| CommandError::Usage(self.usage()))
+  }
+}
+
+/// Every command consoles can run, by name
+#[derive(Resource, Default)]
+pub struct ConsoleCommands(Vec<ConsoleCommand>);
+
+impl ConsoleCommands {
+  /// Register a command, replacing the one of the same name
+  pub fn add(&mut self, command: ConsoleCommand) -> &mut Self {
+    self.0.retain(|other| other.name != command.name);
+    self.0.push(command);
+    self.0.sort_by_key(|command| command.name);
+    self
+  }
+
+  pub fn get(&self, name: &str) -> Option<&ConsoleCommand> {
+    self.0.iter().find(|command| command.name == name)
+  }
+
+  /// In alphabetical order
+  pub fn iter(&self) -> impl Iterator<Item = &ConsoleCommand> {
+    self.0.iter()
+  }
+}
+
+/// Adds the registry, with a `help` command listing the others
+pub struct ConsolePlugin;
+
+impl Plugin for ConsolePlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .init_resource::<ConsoleCommands>()
+      .world_mut()
+      .resource_mut::<ConsoleCommands>()
+      .add(HELP);
+  }
+}
+
+const HELP: ConsoleCommand = ConsoleCommand {
+  name: "help",
+  arguments: "[command]",
+  help: "List the commands, or explain one",
+  run: |world, arguments| {
+    let commands = world.resource::<ConsoleCommands>();
+    if let Some(&name) = arguments.first() {
+      let command = commands
+        .get(name)
+        .ok_or_else(|| CommandError::Unknown(name.to_string()))?;
+      return Ok(format!("{}\n  {}", command.usage(), command.help));
+    }
+    let width = commands
+      .iter()
+      .map(|command| command.usage().len())
+      .max()
+      .unwrap_or(0);
+    Ok(
+      commands
+        .iter()
+        .map(|command| format!("{:width$}  {}", command.usage(), command.help))
+        .collect::<Vec<_>>()
+        .join("\n"),
+    )
+  },
+};
+
+/// Run a line typed into a console, its first word names the command
+pub fn run_command(world: &mut World, line: &str) -> CommandResult {
+  let words = line.split_whitespace().collect::<Vec<_>>();
+  let Some((&name, arguments)) = words.split_first() else {
+    return Ok(String::new());
+  };
+  let command = world
+    .get_resource::<ConsoleCommands>()
+    .and_then(|commands| commands.get(name))
+    .copied()
+    .ok_or_else(|| CommandError::Unknown(name.to_string()))?;
+  (command.run)(world, arguments)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Resource, Default)]
+  struct Counter(u32);
+
+  const ADD: ConsoleCommand = ConsoleCommand {
+    name: "add",
+    arguments: "<amount>",
+    help: "Add to the counter",
+    run: |world, arguments| {
+      let amount: u32 = ADD.argument(arguments, 0)?;
+      let mut counter = world.resource_mut::<Counter>();
+      counter.0 += amount;
+      Ok(format!("Counter at {}", counter.0))
+    },
+  };
+
+  #[test]
+  fn commands_run_by_name() {
+    let mut app = App::new();
+    app.add_plugins(ConsolePlugin).init_resource::<Counter>();
+    app.world_mut().resource_mut::<ConsoleCommands>().add(ADD);
+    let world = app.world_mut();
+
+    assert_eq!(
+      run_command(world, "  add 2 "),
+      Ok("Counter at 2".to_string())
+    );
+    assert_eq!(run_command(world, "add 3"), Ok("Counter at 5".to_string()));
+    assert_eq!(
+      run_command(world, "add three"),
+      Err(CommandError::Usage("add <amount>".to_string()))
+    );
+    assert_eq!(
+      run_command(world, "remove 1"),
+      Err(CommandError::Unknown("remove".to_string()))
+    );
+    assert_eq!(run_command(world, ""), Ok(String::new()));
+    assert_eq!(
+      run_command(world, "help add"),
+      Ok("add <amount>\n  Add to the counter".to_string())
+    );
+    let help = run_command(world, "help").unwrap();
+    assert_eq!(help.lines().count(), 2);
+    assert!(help.starts_with("add <amount>    Add to the counter"));
+  }
+}
@@ -0,0 +1,292 @@
+use avian3d::{math::*, prelude::*};
+use bevy::ecs::schedule::{InternedScheduleLabel, ScheduleLabel};
+use bevy::prelude::*;
+
+//largely https://github.com/Jondolf/avian/blob/main/crates/avian3d/examples/kinematic_character_3d/plugin.rs
+
+/// The character controller simulation, driven by `MovementEvent`s and `PlayerInput`s.
+/// It steps once per fixed tick by default, so the same inputs give the same movement.
+pub struct MovementPlugin {
+  schedule: InternedScheduleLabel,
+}
+
+impl MovementPlugin {
+  /// Run the movement systems in `schedule`, `FixedUpdate` for a fixed tick rate
+  pub fn new(schedule: impl ScheduleLabel) -> Self {
+    MovementPlugin {
+      schedule: schedule.intern(),
+    }
+  }
+}
+
+impl Default for MovementPlugin {
+  fn default() -> Self {
+    MovementPlugin::new(FixedUpdate)
+  }
+}
+
+impl Plugin for MovementPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .register_type::<Grounded>()
+      .add_event::<MovementEvent>()
+      .add_systems(
+        self.schedule,
+        (
+          apply_player_inputs,
+          update_grounded,
+          movement,
+          apply_gravity,
+          apply_movement_damping,
+        )
+          .chain()
+          .in_set(MovementSet)
+          .in_set(PhysicsSet::Prepare)
+          .run_if(physics_running),
+      );
+  }
+}
+
+/// The movement systems, `MovementEvent`s are sent before them
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MovementSet;
+
+// Movement runs on virtual time, so it has to stop by itself while physics is paused
+pub fn physics_running(time: Res<Time<Physics>>) -> bool {
+  !time.is_paused()
+}
+
+// Movement action
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MovementAction {
+  Move(Vector2),
+  Jump,
+}
+
+/// A movement action for one character controller
+#[derive(Event, Debug, Clone, Copy)]
+pub struct MovementEvent {
+  pub entity: Entity,
+  pub action: MovementAction,
+}
+
+/// The input of a player, turned into movement actions on every tick until it changes,
+/// however many frames or packets it took to arrive
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq)]
+pub struct PlayerInput {
+  pub movement: Vector2,
+  /// Jump on the next tick
+  pub jump: bool,
+}
+
+/// The gravitational acceleration used for a character controller.
+#[derive(Component, Reflect)]
+pub struct ControllerGravity(Vector);
+
+/// A marker component indicating that an entity is using a character controller.
+#[derive(Component, Reflect)]
+pub struct CharacterController;
+
+/// The character of a player, local or remote
+#[derive(Component, Reflect)]
+pub struct Player;
+
+/// A marker component indicating that an entity is on the ground.
+#[derive(Component, Reflect)]
+#[reflect(Component)]
+#[component(storage = "SparseSet")]
+pub struct Grounded;
+
+// Component for movement properties
+#[derive(Component, Reflect)]
+pub struct MovementAcceleration(pub f32);
+
+#[derive(Component, Reflect)]
+pub struct MovementDampingFactor(pub f32);
+
+#[derive(Component, Reflect)]
+pub struct JumpImpulse(pub f32);
+/// The maximum angle a slope can have for a character controller
+/// to be able to climb and jump. If the slope is steeper than this angle,
+/// the character will slide down.
+#[derive(Component, Reflect)]
+pub struct MaxSlopeAngle(Scalar);
+
+/// A bundle that contains the components needed for a basic
+/// kinematic character controller.
+#[derive(Bundle)]
+pub struct CharacterControllerBundle {
+  character_controller: CharacterController,
+  rigid_body: RigidBody,
+  collider: Collider,
+  ground_caster: ShapeCaster,
+  locked_axes: LockedAxes,
+  gravity: ControllerGravity,
+  movement: MovementBundle,
+}
+
+impl MovementBundle {
+  pub const fn new(
+    acceleration: Scalar,
+    damping: Scalar,
+    jump_impulse: Scalar,
+    max_slope_angle: Scalar,
+  ) -> Self {
+    Self {
+      acceleration: MovementAcceleration(acceleration),
+      damping: MovementDampingFactor(damping),
+      jump_impulse: JumpImpulse(jump_impulse),
+      max_slope_angle: MaxSlopeAngle(max_slope_angle),
+    }
+  }
+}
+
+impl Default for MovementBundle {
+  fn default() -> Self {
+    Self::new(30.0, 0.9, 7.0, PI * 0.45)
+  }
+}
+
+impl CharacterControllerBundle {
+  pub fn new(collider: Collider, gravity: Vector) -> Self {
+    // Create shape caster as a slightly smaller version of collider
+    let mut caster_shape = collider.clone();
+    caster_shape.set_scale(Vector::ONE * 0.99, 10);
+
+    Self {
+      character_controller: CharacterController,
+      rigid_body: RigidBody::Dynamic,
+      collider,
+      ground_caster: ShapeCaster::new(
+        caster_shape,
+        Vector::ZERO,
+        Quaternion::default(),
+        Dir3::NEG_Y,
+      )
+      .with_max_distance(0.2),
+      gravity: ControllerGravity(gravity),
+      locked_axes: LockedAxes::ROTATION_LOCKED,
+      movement: MovementBundle::default(),
+    }
+  }
+
+  pub fn with_movement(
+    mut self,
+    acceleration: Scalar,
+    damping: Scalar,
+    jump_impulse: Scalar,
+    max_slope_angle: Scalar,
+  ) -> Self {
+    self.movement = MovementBundle::new(acceleration, damping, jump_impulse, max_slope_angle);
+    self
+  }
+}
+
+/// A bundle that contains components for character movement.
+#[derive(Bundle)]
+pub struct MovementBundle {
+  acceleration: MovementAcceleration,
+  damping: MovementDampingFactor,
+  jump_impulse: JumpImpulse,
+  max_slope_angle: MaxSlopeAngle,
+}
+
+fn apply_player_inputs(
+  mut player_q: Query<(Entity, &mut PlayerInput)>,
+  mut evw_movement: EventWriter<MovementEvent>,
+) {
+  for (entity, mut input) in &mut player_q {
+    evw_movement.send(MovementEvent {
+      entity,
+      action: MovementAction::Move(input.movement),
+    });
+    if std::mem::take(&mut input.jump) {
+      evw_movement.send(MovementEvent {
+        entity,
+        action: MovementAction::Jump,
+      });
+    }
+  }
+}
+
+fn update_grounded(
+  mut commands: Commands,
+  mut query: Query<
+    (Entity, &ShapeHits, &Rotation, Option<&MaxSlopeAngle>),
+    With<CharacterController>,
+  >,
+) {
+  for (entity, hits, rotation, max_slope_angle) in &mut query {
+    // The character is grounded if the shape caster has a hit with a normal
+    // that isn't too steep.
+    let is_grounded = hits.iter().any(|hit| {
+      if let Some(angle) = max_slope_angle {
+        (rotation * -hit.normal2).angle_between(Vector::Y).abs() <= angle.0
+      } else {
+        true
+      }
+    });
+
+    if is_grounded {
+      commands.entity(entity).insert(Grounded);
+    } else {
+      commands.entity(entity).remove::<Grounded>();
+    }
+  }
+}
+
+// Movement system
+fn movement(
+  time: Res<Time>,
+  mut movement_event_reader: EventReader<MovementEvent>,
+  mut controllers: Query<(
+    &MovementAcceleration,
+    &JumpImpulse,
+    &mut LinearVelocity,
+    Has<Grounded>,
+  )>,
+) {
+  let delta_time = time.delta().as_secs_f32();
+
+  for event in movement_event_reader.read() {
+    let Ok((movement_acceleration, jump_impulse, mut linear_velocity, is_grounded)) =
+      controllers.get_mut(event.entity)
+    else {
+      continue;
+    };
+    match event.action {
+      MovementAction::Move(direction) => {
+        let movement_force = Vec3::new(direction.x, 0.0, direction.y) * movement_acceleration.0;
+        linear_velocity.x += movement_force.x * delta_time;
+        linear_velocity.z += movement_force.z * delta_time;
+      }
+      MovementAction::Jump => {
+        if is_grounded {
+          linear_velocity.y = jump_impulse.0;
+        }
+      }
+    }
+  }
+}
+
+// Apply damping to prevent infinite sliding
+fn apply_movement_damping(mut query: Query<(&MovementDampingFactor, &mut LinearVelocity)>) {
+  for (damping_factor, mut linear_velocity) in &mut query {
+    linear_velocity.x *= damping_factor.0;
+    linear_velocity.z *= damping_factor.0;
+  }
+}
+
+//apply gravity
+fn apply_gravity(
+  time: Res<Time>,
+  mut controllers: Query<(&ControllerGravity, &mut LinearVelocity)>,
+) {
+  // Precision is adjusted so that the example works with
+  // both the `f32` and `f64` features. Otherwise you don't need this.
+  let delta_time = time.delta_secs_f64().adjust_precision();
+
+  for (gravity, mut linear_velocity) in &mut controllers {
+    linear_velocity.0 += gravity.0 * delta_time;
+  }
+}
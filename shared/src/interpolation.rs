@@ -0,0 +1,265 @@
+//! Remote entities are shown a little in the past, between two snapshots of the server,
+//! so they move smoothly whatever the timing of the packets. Past the newest snapshot
+//! they carry on with their last velocity for a while.
+
+use bevy::prelude::*;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::net::Snapshot;
+use crate::tick::TICK_RATE;
+
+/// Snapshots kept, about a second. Older ones are neither interpolated nor used as baselines.
+pub const BUFFER_LENGTH: usize = 64;
+/// How far past the newest snapshot entities keep moving on their own
+pub const MAX_EXTRAPOLATION: Duration = Duration::from_millis(250);
+/// The clock jumps rather than catching up when it is further off than this
+const RESYNC: Duration = Duration::from_millis(500);
+/// How much faster or slower the clock can run to catch up
+const MAX_DRIFT: f64 = 0.1;
+
+/// How far behind the newest snapshot remote entities are shown.
+/// Long enough for the next snapshot to be there despite lost packets and jitter.
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct InterpolationDelay(pub Duration);
+
+impl Default for InterpolationDelay {
+  fn default() -> Self {
+    InterpolationDelay(Duration::from_millis(100))
+  }
+}
+
+/// Where an entity is at some point between snapshots
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pose {
+  pub translation: Vec3,
+  pub rotation: Quat,
+  pub velocity: Vec3,
+}
+
+/// The latest snapshots received, oldest first
+#[derive(Debug)]
+pub struct SnapshotBuffer {
+  snapshots: VecDeque<Snapshot>,
+  /// Ticks per second of the server the snapshots come from
+  tick_rate: f64,
+}
+
+impl Default for SnapshotBuffer {
+  fn default() -> Self {
+    SnapshotBuffer::new(TICK_RATE)
+  }
+}
+
+impl SnapshotBuffer {
+  pub fn new(tick_rate: f64) -> Self {
+    SnapshotBuffer {
+      snapshots: VecDeque::new(),
+      tick_rate,
+    }
+  }
+
+  /// The server ticks at another rate, the snapshots kept stay valid
+  pub fn set_tick_rate(&mut self, tick_rate: f64) {
+    self.tick_rate = tick_rate;
+  }
+
+  /// Keep `snapshot`, unless it is older than every one kept or already there
+  pub fn push(&mut self, snapshot: Snapshot) {
+    let position = match self
+      .snapshots
+      .binary_search_by_key(&snapshot.tick, |kept| kept.tick)
+    {
+      Ok(_) => return,
+      Err(position) => position,
+    };
+    if position == 0 && self.snapshots.len() == BUFFER_LENGTH {
+      return;
+    }
+    self.snapshots.insert(position, snapshot);
+    if self.snapshots.len() > BUFFER_LENGTH {
+      self.snapshots.pop_front();
+    }
+  }
+
+  pub fn get(&self, tick: u64) -> Option<&Snapshot> {
+    self
+      .snapshots
+      .binary_search_by_key(&tick, |kept| kept.tick)
+      .ok()
+      .map(|index| &self.snapshots[index])
+  }
+
+  pub fn latest(&self) -> Option<&Snapshot> {
+    self.snapshots.back()
+  }
+
+  /// The pose `pose_of` finds in the snapshots at the fractional `tick`: interpolated between
+  /// the snapshots around it, or extrapolated from the newest one
+  pub fn sample(&self, tick: f64, pose_of: impl Fn(&Snapshot) -> Option<Pose>) -> Option<Pose> {
+    let mut before = None;
+    for snapshot in &self.snapshots {
+      let Some(pose) = pose_of(snapshot) else {
+        continue;
+      };
+      if snapshot.tick as f64 > tick {
+        let Some((from_tick, from)) = before else {
+          // not there yet at `tick`, shown where it first was
+          return Some(pose);
+        };
+        let t = ((tick - from_tick) / (snapshot.tick as f64 - from_tick)) as f32;
+        return Some(interpolate(&from, &pose, t));
+      }
+      before = Some((snapshot.tick as f64, pose));
+    }
+    let (from_tick, mut pose) = before?;
+    let ahead = ((tick - from_tick) / self.tick_rate).min(MAX_EXTRAPOLATION.as_secs_f64());
+    pose.translation += pose.velocity * ahead as f32;
+    Some(pose)
+  }
+}
+
+fn interpolate(from: &Pose, to: &Pose, t: f32) -> Pose {
+  Pose {
+    translation: from.translation.lerp(to.translation, t),
+    rotation: from.rotation.slerp(to.rotation, t),
+    velocity: from.velocity.lerp(to.velocity, t),
+  }
+}
+
+/// The fractional server tick remote entities are shown at. It runs at the tick rate and
+/// speeds up or slows down a little to stay the interpolation delay behind the snapshots.
+#[derive(Debug, Clone, Copy)]
+pub struct InterpolationClock {
+  tick: Option<f64>,
+  tick_rate: f64,
+}
+
+impl Default for InterpolationClock {
+  fn default() -> Self {
+    InterpolationClock::new(TICK_RATE)
+  }
+}
+
+impl InterpolationClock {
+  pub fn new(tick_rate: f64) -> Self {
+    InterpolationClock {
+      tick: None,
+      tick_rate,
+    }
+  }
+
+  pub fn set_tick_rate(&mut self, tick_rate: f64) {
+    self.tick_rate = tick_rate;
+  }
+
+  pub fn tick(&self) -> Option<f64> {
+    self.tick
+  }
+
+  pub fn advance(&mut self, delta: Duration, latest_tick: u64, delay: Duration) -> f64 {
+    let target = latest_tick as f64 - delay.as_secs_f64() * self.tick_rate;
+    let ticks = delta.as_secs_f64() * self.tick_rate;
+    let resync_ticks = RESYNC.as_secs_f64() * self.tick_rate;
+    let tick = match self.tick {
+      Some(tick) if (target - tick).abs() < resync_ticks => {
+        let drift = ((target - tick) / resync_ticks).clamp(-MAX_DRIFT, MAX_DRIFT);
+        tick + ticks * (1.0 + drift)
+      }
+      _ => target,
+    };
+    self.tick = Some(tick);
+    tick
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::net::PlayerSnapshot;
+
+  fn snapshot(tick: u64, x: f32) -> Snapshot {
+    Snapshot {
+      tick,
+      players: vec![PlayerSnapshot {
+        client: 1,
+        last_input_tick: 0,
+        translation: Vec3::X * x,
+        velocity: Vec3::X * 6.0,
+        grounded: true,
+      }],
+      objects: Vec::new(),
+    }
+  }
+
+  fn player(snapshot: &Snapshot) -> Option<Pose> {
+    snapshot.players.first().map(|player| Pose {
+      translation: player.translation,
+      rotation: Quat::IDENTITY,
+      velocity: player.velocity,
+    })
+  }
+
+  #[test]
+  fn poses_are_interpolated_between_snapshots() {
+    let mut buffer = SnapshotBuffer::default();
+    buffer.push(snapshot(10, 0.0));
+    buffer.push(snapshot(14, 4.0));
+    // late and duplicated snapshots are sorted out
+    buffer.push(snapshot(12, 2.0));
+    buffer.push(snapshot(12, 2.0));
+    assert_eq!(buffer.latest().unwrap().tick, 14);
+    assert!(buffer.get(12).is_some());
+
+    let at = |tick| buffer.sample(tick, player).unwrap().translation.x;
+    assert_eq!(at(9.0), 0.0);
+    assert_eq!(at(11.0), 1.0);
+    assert_eq!(at(13.5), 3.5);
+  }
+
+  #[test]
+  fn poses_are_extrapolated_for_a_while_after_the_newest_snapshot() {
+    let mut buffer = SnapshotBuffer::default();
+    buffer.push(snapshot(10, 0.0));
+    // 6 m/s for 5 ticks
+    let x = buffer.sample(15.0, player).unwrap().translation.x;
+    assert!((x - 0.5).abs() < 1e-5, "{x}");
+    // then it stops
+    let far = buffer.sample(100.0, player).unwrap().translation.x;
+    assert!((far - 6.0 * MAX_EXTRAPOLATION.as_secs_f32()).abs() < 1e-5);
+    assert_eq!(SnapshotBuffer::default().sample(10.0, player), None);
+  }
+
+  #[test]
+  fn the_buffer_keeps_the_latest_snapshots() {
+    let mut buffer = SnapshotBuffer::default();
+    for tick in 0..BUFFER_LENGTH as u64 * 2 {
+      buffer.push(snapshot(tick, 0.0));
+    }
+    assert!(buffer.get(BUFFER_LENGTH as u64 - 1).is_none());
+    buffer.push(snapshot(0, 0.0));
+    assert!(buffer.get(0).is_none());
+    assert_eq!(buffer.snapshots.len(), BUFFER_LENGTH);
+  }
+
+  #[test]
+  fn the_clock_stays_behind_the_snapshots_by_the_delay() {
+    let delay = Duration::from_millis(100);
+    let frame = Duration::from_secs_f64(1.0 / 144.0);
+    let mut clock = InterpolationClock::default();
+    assert_eq!(clock.advance(frame, 100, delay), 94.0);
+
+    // snapshots come at the tick rate, frames at another rate, a little late
+    let mut latest = 100.0;
+    for _ in 0..600 {
+      latest += 60.0 / 144.0;
+      clock.advance(frame, latest as u64, delay);
+    }
+    let behind = latest - clock.tick().unwrap();
+    assert!((5.0..7.5).contains(&behind), "{behind}");
+
+    // a long stall resyncs it
+    let tick = clock.advance(frame, latest as u64 + 600, delay);
+    assert!((latest + 600.0 - tick - 6.0).abs() < 1.0);
+  }
+}
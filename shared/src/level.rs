@@ -0,0 +1,467 @@
+use avian3d::prelude::*;
+use bevy::asset::{AssetLoader, LoadContext, io::Reader};
+use bevy::ecs::system::{EntityCommands, SystemParam};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::checkpoint::{Checkpoint, RespawnPoint};
+use crate::controller::{CharacterControllerBundle, Player, PlayerInput};
+use crate::state::InGameEntity;
+
+#[cfg(not(feature = "render"))]
+mod headless;
+#[cfg(feature = "render")]
+mod render;
+#[cfg(not(feature = "render"))]
+pub use headless::{LevelAssets, LevelMaterials};
+#[cfg(feature = "render")]
+pub use render::{LevelAssets, LevelMaterials};
+
+/// A level, loaded from `*.level.ron` files.
+/// Every object is spawned through the prefab of the same name in the `PrefabRegistry`.
+#[derive(Asset, TypePath, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Level {
+  /// Shown in the level list
+  pub name: String,
+  /// Path of a camera sequence played when the level starts
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub intro: Option<String>,
+  /// Where local players start, in player order
+  #[serde(default)]
+  pub spawn_points: Vec<Vec3>,
+  /// Materials objects refer to by name
+  #[serde(default)]
+  pub materials: BTreeMap<String, LevelMaterial>,
+  #[serde(default)]
+  pub objects: Vec<LevelObject>,
+  #[serde(default)]
+  pub lights: Vec<LevelLight>,
+  /// Textures of the materials, loaded along with the level
+  #[cfg(feature = "render")]
+  #[serde(skip)]
+  pub textures: bevy::utils::HashMap<String, Handle<Image>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LevelMaterial {
+  /// sRGB base color, multiplied with the texture
+  #[serde(default = "default_color")]
+  pub color: (f32, f32, f32),
+  /// Path of the base color texture
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub texture: Option<String>,
+}
+
+/// A placed object, kept on the entity spawned for it
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+pub struct LevelObject {
+  /// Name of the prefab in the `PrefabRegistry`
+  pub prefab: String,
+  pub shape: Shape,
+  /// Name of a material of the level, plain white otherwise
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub material: Option<String>,
+  #[serde(default)]
+  pub translation: Vec3,
+  /// Rotation in degrees around the X, Y and Z axes, in that order
+  #[serde(default)]
+  pub rotation: Vec3,
+  #[serde(default = "default_scale")]
+  pub scale: Vec3,
+}
+
+/// The shape of an object, used for both its mesh and its collider
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Shape {
+  Cuboid(Vec3),
+  Sphere(f32),
+  Cylinder { radius: f32, height: f32 },
+  Capsule { radius: f32, length: f32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LevelLight {
+  Point {
+    position: Vec3,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    intensity: Option<f32>,
+    #[serde(default)]
+    shadows: bool,
+  },
+  Directional {
+    /// Direction the light shines towards
+    direction: Vec3,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    illuminance: Option<f32>,
+    #[serde(default)]
+    shadows: bool,
+  },
+}
+
+fn default_color() -> (f32, f32, f32) {
+  (1.0, 1.0, 1.0)
+}
+
+fn default_scale() -> Vec3 {
+  Vec3::ONE
+}
+
+impl Shape {
+  #[cfg(feature = "render")]
+  pub fn mesh(&self) -> Mesh {
+    match *self {
+      Shape::Cuboid(size) => Cuboid::from_size(size).into(),
+      Shape::Sphere(radius) => Sphere::new(radius).into(),
+      Shape::Cylinder { radius, height } => Cylinder::new(radius, height).into(),
+      Shape::Capsule { radius, length } => Capsule3d::new(radius, length).into(),
+    }
+  }
+
+  pub fn collider(&self) -> Collider {
+    match *self {
+      Shape::Cuboid(size) => Collider::cuboid(size.x, size.y, size.z),
+      Shape::Sphere(radius) => Collider::sphere(radius),
+      Shape::Cylinder { radius, height } => Collider::cylinder(radius, height),
+      Shape::Capsule { radius, length } => Collider::capsule(radius, length),
+    }
+  }
+}
+
+impl LevelObject {
+  pub fn transform(&self) -> Transform {
+    let rotation = self.rotation.map(f32::to_radians);
+    Transform {
+      translation: self.translation,
+      rotation: Quat::from_euler(EulerRot::XYZ, rotation.x, rotation.y, rotation.z),
+      scale: self.scale,
+    }
+  }
+
+  /// Write a transform back, e.g. after moving the object around
+  pub fn set_transform(&mut self, transform: &Transform) {
+    let (x, y, z) = transform.rotation.to_euler(EulerRot::XYZ);
+    self.translation = transform.translation;
+    self.rotation = Vec3::new(x, y, z).map(f32::to_degrees);
+    self.scale = transform.scale;
+  }
+}
+
+impl Level {
+  /// Where a player starts, next to the last spawn point if the level has too few
+  pub fn player_spawn_point(&self, index: usize) -> Vec3 {
+    match self.spawn_points.get(index) {
+      Some(point) => *point,
+      None => {
+        let last = self.spawn_points.last().copied().unwrap_or(Vec3::Y * 0.55);
+        last + Vec3::X * 2.0 * (index + 1 - self.spawn_points.len()) as f32
+      }
+    }
+  }
+}
+
+/// The object a player character is spawned from
+pub fn player_object(translation: Vec3) -> LevelObject {
+  LevelObject {
+    prefab: "player".to_string(),
+    shape: Shape::Cuboid(Vec3::ONE),
+    material: None,
+    translation,
+    rotation: Vec3::ZERO,
+    scale: Vec3::ONE,
+  }
+}
+
+/// Adds the components that make an object what it is, on top of its transform
+pub type Prefab = fn(&mut EntityCommands, &LevelObject);
+
+/// The prefabs levels can use, by name.
+/// The defaults only add what the simulation needs, rendering and gameplay add to them.
+#[derive(Resource)]
+pub struct PrefabRegistry {
+  prefabs: BTreeMap<String, Prefab>,
+}
+
+impl Default for PrefabRegistry {
+  fn default() -> Self {
+    let mut registry = PrefabRegistry {
+      prefabs: BTreeMap::new(),
+    };
+    registry
+      .register("static", static_body)
+      .register("prop", dynamic_prop)
+      .register("target", dynamic_prop)
+      .register("checkpoint", checkpoint)
+      .register("player", player_character);
+    registry
+  }
+}
+
+impl PrefabRegistry {
+  pub fn register(&mut self, name: impl Into<String>, prefab: Prefab) -> &mut Self {
+    self.prefabs.insert(name.into(), prefab);
+    self
+  }
+
+  pub fn get(&self, name: &str) -> Option<Prefab> {
+    self.prefabs.get(name).copied()
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.prefabs.keys().map(String::as_str)
+  }
+
+  /// Spawn an object with its transform and the components of its prefab,
+  /// `None` if the prefab is unknown
+  pub fn spawn<'a>(
+    &self,
+    commands: &'a mut Commands,
+    object: &LevelObject,
+  ) -> Option<EntityCommands<'a>> {
+    let Some(prefab) = self.get(&object.prefab) else {
+      warn!("Unknown prefab {:?}, skipping the object", object.prefab);
+      return None;
+    };
+    let mut entity = commands.spawn((object.transform(), object.clone()));
+    prefab(&mut entity, object);
+    Some(entity)
+  }
+}
+
+/// Level geometry, it never moves
+pub fn static_body(entity: &mut EntityCommands, object: &LevelObject) {
+  entity.insert((RigidBody::Static, object.shape.collider()));
+}
+
+/// A heavy object players can push around
+pub fn dynamic_prop(entity: &mut EntityCommands, object: &LevelObject) {
+  entity.insert((
+    RigidBody::Dynamic,
+    object.shape.collider(),
+    ColliderDensity(3.0),
+    SpeculativeMargin(5.0),
+  ));
+}
+
+/// A volume reporting what enters it without blocking anything
+pub fn trigger_volume(entity: &mut EntityCommands, object: &LevelObject) {
+  entity.insert((RigidBody::Static, object.shape.collider(), Sensor));
+}
+
+/// A volume moving the respawn point of the players touching it
+pub fn checkpoint(entity: &mut EntityCommands, object: &LevelObject) {
+  trigger_volume(entity, object);
+  entity.insert(Checkpoint::new(&object.transform()));
+}
+
+/// A character moved by the `PlayerInput` of whoever controls it, respawning where it starts
+pub fn player_character(entity: &mut EntityCommands, object: &LevelObject) {
+  entity.insert((
+    Player,
+    PlayerInput::default(),
+    RespawnPoint(object.transform()),
+    CharacterControllerBundle::new(object.shape.collider(), Vec3::NEG_Y * 5.81 * 2.0)
+      .with_movement(30.0, 0.92, 7.0, 30.0f32.to_radians()),
+  ));
+}
+
+/// Everything spawned from a level file, respawned when it is reloaded
+#[derive(Component)]
+pub struct LevelEntity;
+
+/// An object of the level, by index in `Level::objects`, the same on every machine
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectIndex(pub usize);
+
+/// Spawns levels and their objects through the prefab registry.
+/// Objects are drawn too when rendering is compiled in and the app has the assets for it.
+#[derive(SystemParam)]
+pub struct LevelSpawner<'w, 's> {
+  pub commands: Commands<'w, 's>,
+  pub registry: Res<'w, PrefabRegistry>,
+  pub assets: LevelAssets<'w>,
+}
+
+impl LevelSpawner<'_, '_> {
+  /// Spawn one object through its prefab, `None` if the prefab is unknown.
+  /// It is not drawn, `spawn_level` draws the objects of a level.
+  pub fn spawn_object(&mut self, object: &LevelObject) -> Option<Entity> {
+    let mut entity = self.registry.spawn(&mut self.commands, object)?;
+    entity.insert(InGameEntity);
+    Some(entity.id())
+  }
+
+  /// Spawn a stand-in for an object, only a static collider to pick it with.
+  /// Nothing moves on its own and no gameplay runs, for editing the level.
+  pub fn spawn_editable_object(&mut self, object: &LevelObject) -> Entity {
+    self
+      .commands
+      .spawn((
+        object.transform(),
+        object.clone(),
+        RigidBody::Static,
+        object.shape.collider(),
+        InGameEntity,
+      ))
+      .id()
+  }
+
+  /// Spawn the objects and lights of a level, players are spawned on their own.
+  /// Returns the objects in the order of `level.objects`, `None` for unknown prefabs.
+  pub fn spawn_level(&mut self, level: &Level) -> Vec<Option<Entity>> {
+    self.spawn_level_with(level, Self::spawn_object)
+  }
+
+  /// Spawn the level with stand-ins for its objects, in the order of `level.objects`
+  pub fn spawn_level_for_editing(&mut self, level: &Level) -> Vec<Entity> {
+    self
+      .spawn_level_with(level, |spawner, object| {
+        Some(spawner.spawn_editable_object(object))
+      })
+      .into_iter()
+      .flatten()
+      .collect()
+  }
+
+  fn spawn_level_with(
+    &mut self,
+    level: &Level,
+    mut spawn_object: impl FnMut(&mut Self, &LevelObject) -> Option<Entity>,
+  ) -> Vec<Option<Entity>> {
+    let level_materials = LevelMaterials::new(level, &mut self.assets);
+    let mut objects = Vec::with_capacity(level.objects.len());
+    for (index, object) in level.objects.iter().enumerate() {
+      let entity = spawn_object(self, object);
+      if let Some(entity) = entity {
+        let mut entity = self.commands.entity(entity);
+        entity.insert((LevelEntity, ObjectIndex(index)));
+        self.assets.draw(&mut entity, object, &level_materials);
+      }
+      objects.push(entity);
+    }
+    for light in &level.lights {
+      if let Some(mut entity) = self.assets.spawn_light(&mut self.commands, light) {
+        entity.insert((LevelEntity, InGameEntity));
+      }
+    }
+    objects
+  }
+}
+
+pub struct LevelLoader {
+  /// Load the textures of the materials, nothing draws them when running headless.
+  /// Textures are never loaded without the `render` feature.
+  pub textures: bool,
+}
+
+impl Default for LevelLoader {
+  fn default() -> Self {
+    LevelLoader { textures: true }
+  }
+}
+
+#[derive(Debug, Error)]
+pub enum LevelLoaderError {
+  #[error("could not read level: {0}")]
+  Io(#[from] std::io::Error),
+  #[error("could not parse level: {0}")]
+  Ron(#[from] ron::error::SpannedError),
+}
+
+impl AssetLoader for LevelLoader {
+  type Asset = Level;
+  type Settings = ();
+  type Error = LevelLoaderError;
+
+  async fn load(
+    &self,
+    reader: &mut dyn Reader,
+    _settings: &(),
+    load_context: &mut LoadContext<'_>,
+  ) -> Result<Self::Asset, Self::Error> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).await?;
+    let mut level: Level = ron::de::from_bytes(&bytes)?;
+    if self.textures {
+      load_textures(&mut level, load_context);
+    }
+    Ok(level)
+  }
+
+  fn extensions(&self) -> &[&str] {
+    &["level.ron"]
+  }
+}
+
+/// Load the textures of the materials as dependencies, so they are ready with the level
+#[cfg(feature = "render")]
+fn load_textures(level: &mut Level, load_context: &mut LoadContext) {
+  let textures = level.materials.values().filter_map(|m| m.texture.clone());
+  for path in textures {
+    let texture = load_context.load(&path);
+    level.textures.insert(path, texture);
+  }
+}
+
+#[cfg(not(feature = "render"))]
+fn load_textures(_level: &mut Level, _load_context: &mut LoadContext) {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(source: &str) -> Level {
+    ron::de::from_str(source).unwrap()
+  }
+
+  #[test]
+  fn object_transform_round_trips() {
+    let mut object = LevelObject {
+      prefab: "static".into(),
+      shape: Shape::Cuboid(Vec3::ONE),
+      material: None,
+      translation: Vec3::new(1.0, 2.0, 3.0),
+      rotation: Vec3::new(10.0, 20.0, 30.0),
+      scale: Vec3::splat(2.0),
+    };
+    let transform = object.transform();
+    object.set_transform(&transform);
+    assert!(object.rotation.distance(Vec3::new(10.0, 20.0, 30.0)) < 1e-3);
+    assert_eq!(object.scale, Vec3::splat(2.0));
+  }
+
+  #[test]
+  fn saved_levels_parse_back() {
+    let level = parse(include_str!(
+      "../../client/assets/levels/playground.level.ron"
+    ));
+    let saved = ron::ser::to_string_pretty(&level, ron::ser::PrettyConfig::default()).unwrap();
+    let parsed = parse(&saved);
+    assert_eq!(parsed.objects.len(), level.objects.len());
+    assert_eq!(parsed.materials.len(), level.materials.len());
+    assert_eq!(parsed.spawn_points, level.spawn_points);
+  }
+
+  #[test]
+  fn omitted_fields_have_defaults() {
+    let level = parse(r#"(name: "Empty", objects: [(prefab: "static", shape: Sphere(1.0))])"#);
+    assert_eq!(level.objects[0].scale, Vec3::ONE);
+    assert_eq!(level.objects[0].translation, Vec3::ZERO);
+    assert!(level.intro.is_none() && level.lights.is_empty());
+  }
+
+  #[test]
+  fn players_beyond_the_spawn_points_start_next_to_the_last() {
+    let level = Level {
+      spawn_points: vec![Vec3::ZERO, Vec3::Z],
+      ..default()
+    };
+    assert_eq!(level.player_spawn_point(1), Vec3::Z);
+    assert_eq!(level.player_spawn_point(3), Vec3::Z + Vec3::X * 4.0);
+    assert_eq!(
+      Level::default().player_spawn_point(0),
+      Vec3::new(2.0, 0.55, 0.0)
+    );
+  }
+}
@@ -0,0 +1,36 @@
+use bevy::ecs::system::{EntityCommands, SystemParam};
+use bevy::prelude::*;
+use std::marker::PhantomData;
+
+use super::{Level, LevelLight, LevelObject};
+
+/// Nothing is drawn without the `render` feature
+#[derive(SystemParam)]
+pub struct LevelAssets<'w> {
+  _headless: PhantomData<&'w ()>,
+}
+
+impl LevelAssets<'_> {
+  pub fn drawing(&self) -> bool {
+    false
+  }
+
+  pub fn draw(&mut self, _entity: &mut EntityCommands, _object: &LevelObject, _: &LevelMaterials) {}
+
+  pub fn spawn_light<'a>(
+    &self,
+    _commands: &'a mut Commands,
+    _light: &LevelLight,
+  ) -> Option<EntityCommands<'a>> {
+    None
+  }
+}
+
+#[derive(Default)]
+pub struct LevelMaterials;
+
+impl LevelMaterials {
+  pub fn new(_level: &Level, _assets: &mut LevelAssets) -> Self {
+    LevelMaterials
+  }
+}
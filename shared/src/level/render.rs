@@ -0,0 +1,132 @@
+use bevy::ecs::system::{EntityCommands, SystemParam};
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use super::{Level, LevelLight, LevelObject};
+
+/// The assets objects are drawn with, missing in headless apps
+#[derive(SystemParam)]
+pub struct LevelAssets<'w> {
+  pub meshes: Option<ResMut<'w, Assets<Mesh>>>,
+  pub materials: Option<ResMut<'w, Assets<StandardMaterial>>>,
+}
+
+impl LevelAssets<'_> {
+  /// Whether there is anything to draw with
+  pub fn drawing(&self) -> bool {
+    self.meshes.is_some() && self.materials.is_some()
+  }
+
+  /// Add the mesh of an object, with its material from the level or plain white
+  pub fn draw(
+    &mut self,
+    entity: &mut EntityCommands,
+    object: &LevelObject,
+    level_materials: &LevelMaterials,
+  ) {
+    let Some(materials) = self.materials.as_mut() else {
+      return;
+    };
+    let material = object
+      .material
+      .as_deref()
+      .and_then(|name| level_materials.0.get(name).cloned())
+      .unwrap_or_else(|| materials.add(Color::WHITE));
+    self.insert_mesh(entity, object, material);
+  }
+
+  /// Add the mesh of an object with a material of its own
+  pub fn draw_with(
+    &mut self,
+    entity: &mut EntityCommands,
+    object: &LevelObject,
+    material: StandardMaterial,
+  ) {
+    let Some(materials) = self.materials.as_mut() else {
+      return;
+    };
+    let material = materials.add(material);
+    self.insert_mesh(entity, object, material);
+  }
+
+  fn insert_mesh(
+    &mut self,
+    entity: &mut EntityCommands,
+    object: &LevelObject,
+    material: Handle<StandardMaterial>,
+  ) {
+    if let Some(meshes) = self.meshes.as_mut() {
+      entity.insert((
+        Mesh3d(meshes.add(object.shape.mesh())),
+        MeshMaterial3d(material),
+      ));
+    }
+  }
+
+  /// Spawn a light of the level, `None` when nothing is drawn
+  pub fn spawn_light<'a>(
+    &self,
+    commands: &'a mut Commands,
+    light: &LevelLight,
+  ) -> Option<EntityCommands<'a>> {
+    if !self.drawing() {
+      return None;
+    }
+    let entity = match *light {
+      LevelLight::Point {
+        position,
+        intensity,
+        shadows,
+      } => commands.spawn((
+        PointLight {
+          intensity: intensity.unwrap_or(PointLight::default().intensity),
+          shadows_enabled: shadows,
+          ..default()
+        },
+        Transform::from_translation(position),
+      )),
+      LevelLight::Directional {
+        direction,
+        illuminance,
+        shadows,
+      } => commands.spawn((
+        DirectionalLight {
+          illuminance: illuminance.unwrap_or(DirectionalLight::default().illuminance),
+          shadows_enabled: shadows,
+          ..default()
+        },
+        Transform::default().looking_to(direction, Vec3::Y),
+      )),
+    };
+    Some(entity)
+  }
+}
+
+/// Builds the materials of a level once, objects share them
+#[derive(Default)]
+pub struct LevelMaterials(HashMap<String, Handle<StandardMaterial>>);
+
+impl LevelMaterials {
+  pub fn new(level: &Level, assets: &mut LevelAssets) -> Self {
+    let Some(materials) = assets.materials.as_mut() else {
+      return LevelMaterials::default();
+    };
+    let handles = level
+      .materials
+      .iter()
+      .map(|(name, material)| {
+        let (r, g, b) = material.color;
+        let handle = materials.add(StandardMaterial {
+          base_color: Color::srgb(r, g, b),
+          base_color_texture: material
+            .texture
+            .as_ref()
+            .and_then(|path| level.textures.get(path).cloned()),
+          ..default()
+        });
+        (name.clone(), handle)
+      })
+      .collect();
+    LevelMaterials(handles)
+  }
+}
@@ -0,0 +1,49 @@
+//! Simulation code shared by the client and the server:
+//! the character controller, levels with their physics and checkpoints, the game states
+//! and the network protocol.
+//! Meshes, materials and lights of levels are only spawned with the `render` feature.
+
+use avian3d::prelude::*;
+use bevy::app::PluginGroupBuilder;
+use bevy::prelude::*;
+use bevy::scene::ScenePlugin;
+use bevy::state::app::StatesPlugin;
+
+pub mod checkpoint;
+pub mod console;
+pub mod controller;
+pub mod interpolation;
+pub mod level;
+pub mod net;
+pub mod prediction;
+pub mod state;
+pub mod stepped;
+pub mod tick;
+
+/// The engine plugins the simulation runs on without a window or a renderer,
+/// for the server and for tests
+pub struct HeadlessPlugins;
+
+impl PluginGroup for HeadlessPlugins {
+  fn build(self) -> PluginGroupBuilder {
+    MinimalPlugins
+      .build()
+      .add(AssetPlugin::default())
+      .add(StatesPlugin)
+      .add(TransformPlugin)
+      .add(HierarchyPlugin)
+      .add(ScenePlugin)
+      .add(MeshAssetsPlugin)
+      .add_group(PhysicsPlugins::default())
+  }
+}
+
+/// Colliders can be built from meshes when rendering is compiled in, even though none are loaded
+struct MeshAssetsPlugin;
+
+impl Plugin for MeshAssetsPlugin {
+  fn build(&self, _app: &mut App) {
+    #[cfg(feature = "render")]
+    _app.init_asset::<Mesh>();
+  }
+}
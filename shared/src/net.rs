@@ -0,0 +1,28 @@
+//! The wire protocol between clients and the server, over UDP.
+//!
+//! Clients connect with a handshake carrying the protocol version. Connected peers exchange
+//! `Payload` packets with sequence numbers and acks, carrying reliable-ordered messages
+//! (events like spawns) and unreliable ones (inputs and snapshots, fragmented when large).
+//! Snapshots are quantized and delta-compressed against the last one the client acked.
+//! Clients gather in the rooms of a lobby until every member is ready to play.
+//! Both ends can simulate a bad network, see `LinkConditions`.
+
+pub mod codec;
+pub mod conditioner;
+pub mod connection;
+pub mod lobby;
+pub mod protocol;
+pub mod snapshot;
+pub mod socket;
+
+pub use conditioner::{CONDITION_ARGUMENTS, InvalidCondition, LinkConditions};
+pub use connection::{Channel, RejectReason};
+pub use lobby::{LobbyRequest, MAX_ROOM_NAME, MAX_ROOMS, RoomInfo, RoomMember, RoomState};
+pub use protocol::{
+  ClientMessage, DEFAULT_PORT, INPUT_REDUNDANCY, NetEntity, PROTOCOL_VERSION, ServerMessage,
+  TickInput,
+};
+pub use snapshot::{DeltaSnapshot, ObjectSnapshot, PlayerSnapshot, Snapshot};
+pub use socket::{
+  ClientNetEvent, ClientState, DisconnectReason, NetClient, NetServer, NetStats, ServerNetEvent,
+};
@@ -0,0 +1,230 @@
+use bevy::math::{Quat, Vec2, Vec3};
+use thiserror::Error;
+
+/// Encodes values in little-endian order, as they go on the wire
+#[derive(Default)]
+pub struct Writer(Vec<u8>);
+
+impl Writer {
+  pub fn new() -> Self {
+    Writer::default()
+  }
+
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  pub fn finish(self) -> Vec<u8> {
+    self.0
+  }
+
+  pub fn u8(&mut self, value: u8) {
+    self.0.push(value);
+  }
+
+  pub fn bool(&mut self, value: bool) {
+    self.u8(value as u8);
+  }
+
+  pub fn u16(&mut self, value: u16) {
+    self.0.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn u32(&mut self, value: u32) {
+    self.0.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn u64(&mut self, value: u64) {
+    self.0.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn f32(&mut self, value: f32) {
+    self.0.extend_from_slice(&value.to_le_bytes());
+  }
+
+  /// Seven bits per byte, small values take a single byte
+  pub fn varint(&mut self, mut value: u64) {
+    while value >= 0x80 {
+      self.u8(value as u8 | 0x80);
+      value >>= 7;
+    }
+    self.u8(value as u8);
+  }
+
+  /// Zigzag encoded, small values of either sign take a single byte
+  pub fn signed(&mut self, value: i64) {
+    self.varint(((value << 1) ^ (value >> 63)) as u64);
+  }
+
+  pub fn vec2(&mut self, value: Vec2) {
+    self.f32(value.x);
+    self.f32(value.y);
+  }
+
+  pub fn vec3(&mut self, value: Vec3) {
+    self.f32(value.x);
+    self.f32(value.y);
+    self.f32(value.z);
+  }
+
+  pub fn quat(&mut self, value: Quat) {
+    for component in value.to_array() {
+      self.f32(component);
+    }
+  }
+
+  /// Bytes with their length in front, at most `u16::MAX` of them
+  pub fn bytes(&mut self, value: &[u8]) {
+    debug_assert!(value.len() <= u16::MAX as usize);
+    self.u16(value.len() as u16);
+    self.0.extend_from_slice(value);
+  }
+
+  pub fn str(&mut self, value: &str) {
+    self.bytes(value.as_bytes());
+  }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DecodeError {
+  #[error("the packet ended early")]
+  UnexpectedEnd,
+  #[error("unknown {0} tag {1}")]
+  UnknownTag(&'static str, u8),
+  #[error("a variable length integer is too long")]
+  InvalidVarint,
+  #[error("the text is not UTF-8")]
+  InvalidText,
+  #[error("not a packet of this game")]
+  ForeignPacket,
+  #[error("the {0} is out of range")]
+  OutOfRange(&'static str),
+}
+
+/// Decodes what a `Writer` encoded, in the same order
+pub struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+  pub fn new(bytes: &'a [u8]) -> Self {
+    Reader(bytes)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  fn take<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+    let (bytes, rest) = self
+      .0
+      .split_first_chunk::<N>()
+      .ok_or(DecodeError::UnexpectedEnd)?;
+    self.0 = rest;
+    Ok(*bytes)
+  }
+
+  pub fn u8(&mut self) -> Result<u8, DecodeError> {
+    Ok(self.take::<1>()?[0])
+  }
+
+  pub fn bool(&mut self) -> Result<bool, DecodeError> {
+    Ok(self.u8()? != 0)
+  }
+
+  pub fn u16(&mut self) -> Result<u16, DecodeError> {
+    Ok(u16::from_le_bytes(self.take()?))
+  }
+
+  pub fn u32(&mut self) -> Result<u32, DecodeError> {
+    Ok(u32::from_le_bytes(self.take()?))
+  }
+
+  pub fn u64(&mut self) -> Result<u64, DecodeError> {
+    Ok(u64::from_le_bytes(self.take()?))
+  }
+
+  pub fn f32(&mut self) -> Result<f32, DecodeError> {
+    Ok(f32::from_le_bytes(self.take()?))
+  }
+
+  pub fn varint(&mut self) -> Result<u64, DecodeError> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+      let byte = self.u8()?;
+      value |= ((byte & 0x7f) as u64) << shift;
+      if byte & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+    Err(DecodeError::InvalidVarint)
+  }
+
+  pub fn signed(&mut self) -> Result<i64, DecodeError> {
+    let value = self.varint()?;
+    Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+  }
+
+  pub fn vec2(&mut self) -> Result<Vec2, DecodeError> {
+    Ok(Vec2::new(self.f32()?, self.f32()?))
+  }
+
+  pub fn vec3(&mut self) -> Result<Vec3, DecodeError> {
+    Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+  }
+
+  pub fn quat(&mut self) -> Result<Quat, DecodeError> {
+    Ok(Quat::from_xyzw(
+      self.f32()?,
+      self.f32()?,
+      self.f32()?,
+      self.f32()?,
+    ))
+  }
+
+  pub fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+    let len = self.u16()? as usize;
+    if self.0.len() < len {
+      return Err(DecodeError::UnexpectedEnd);
+    }
+    let (bytes, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Ok(bytes)
+  }
+
+  pub fn str(&mut self) -> Result<&'a str, DecodeError> {
+    std::str::from_utf8(self.bytes()?).map_err(|_| DecodeError::InvalidText)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn varints_round_trip_in_few_bytes() {
+    let mut writer = Writer::new();
+    writer.varint(5);
+    assert_eq!(writer.len(), 1);
+    writer.signed(-3);
+    assert_eq!(writer.len(), 2);
+    writer.varint(u64::MAX);
+    writer.signed(i64::MIN);
+    writer.signed(300);
+
+    let bytes = writer.finish();
+    let mut reader = Reader::new(&bytes);
+    assert_eq!(reader.varint(), Ok(5));
+    assert_eq!(reader.signed(), Ok(-3));
+    assert_eq!(reader.varint(), Ok(u64::MAX));
+    assert_eq!(reader.signed(), Ok(i64::MIN));
+    assert_eq!(reader.signed(), Ok(300));
+    assert!(reader.is_empty());
+    assert_eq!(
+      Reader::new(&[0xff; 11]).varint(),
+      Err(DecodeError::InvalidVarint)
+    );
+  }
+}
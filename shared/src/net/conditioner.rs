@@ -0,0 +1,334 @@
+//! A bad network simulated in process, to test prediction and interpolation on one machine.
+//! Packets going through a `LinkConditioner` are delayed, lost, duplicated and reordered.
+
+use bevy::log::warn;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Extra delay of reordered packets, so packets sent after them overtake them
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// Longest latency or jitter the arguments can ask for, in milliseconds
+const MAX_DELAY_MS: f64 = 10_000.0;
+
+/// The arguments `LinkConditions::set_argument` takes, each followed by a number
+pub const CONDITION_ARGUMENTS: [&str; 5] = [
+  "--latency",
+  "--jitter",
+  "--loss",
+  "--duplicate",
+  "--reorder",
+];
+
+/// How the simulated network treats packets, in each direction
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkConditions {
+  /// Added to every packet
+  pub latency: Duration,
+  /// Packets are up to this much earlier or later than the latency
+  pub jitter: Duration,
+  /// Share of packets lost, from 0 to 1
+  pub loss: f32,
+  /// Share of packets arriving twice
+  pub duplication: f32,
+  /// Share of packets held back behind the ones sent after them
+  pub reordering: f32,
+}
+
+/// A value one of the `CONDITION_ARGUMENTS` does not take
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Invalid {setting}, expected {expected}")]
+pub struct InvalidCondition {
+  pub setting: &'static str,
+  pub expected: &'static str,
+}
+
+impl LinkConditions {
+  /// `--latency <ms>`, `--jitter <ms>`, `--loss <%>`, `--duplicate <%>` and `--reorder <%>`
+  /// arguments, a perfect network without them. Invalid values are ignored with a warning.
+  pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+    let mut conditions = LinkConditions::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+      if !CONDITION_ARGUMENTS.contains(&arg.as_str()) {
+        continue;
+      }
+      let value = args.next().unwrap_or_default();
+      if let Err(error) = conditions.set_argument(&arg, &value) {
+        warn!("Ignoring {arg} {value:?}, it takes {}", error.expected);
+      }
+    }
+    conditions
+  }
+
+  /// Set the condition one of the `CONDITION_ARGUMENTS` is for, from the value after it
+  pub fn set_argument(&mut self, argument: &str, value: &str) -> Result<(), InvalidCondition> {
+    const DELAY: &str = "0 to 10000 milliseconds";
+    const SHARE: &str = "0 to 100 percent";
+    let (setting, limit, expected) = match argument {
+      "--latency" => ("latency", MAX_DELAY_MS, DELAY),
+      "--jitter" => ("jitter", MAX_DELAY_MS, DELAY),
+      "--loss" => ("loss", 100.0, SHARE),
+      "--duplicate" => ("duplication", 100.0, SHARE),
+      "--reorder" => ("reordering", 100.0, SHARE),
+      _ => {
+        return Err(InvalidCondition {
+          setting: "network condition",
+          expected: "--latency, --jitter, --loss, --duplicate or --reorder",
+        });
+      }
+    };
+    let value = value
+      .trim()
+      .parse::<f64>()
+      .ok()
+      .filter(|value| (0.0..=limit).contains(value))
+      .ok_or(InvalidCondition { setting, expected })?;
+    let milliseconds = Duration::from_secs_f64(value / 1000.0);
+    let share = (value / 100.0) as f32;
+    match argument {
+      "--latency" => self.latency = milliseconds,
+      "--jitter" => self.jitter = milliseconds,
+      "--loss" => self.loss = share,
+      "--duplicate" => self.duplication = share,
+      _ => self.reordering = share,
+    }
+    Ok(())
+  }
+
+  /// Packets go through untouched
+  pub fn is_perfect(&self) -> bool {
+    *self == LinkConditions::default()
+  }
+}
+
+/// Holds packets back as the `LinkConditions` say, until they are due
+#[derive(Debug)]
+pub struct LinkConditioner {
+  pub conditions: LinkConditions,
+  random: Random,
+  /// Packets in flight, with when they are due
+  in_flight: Vec<(Instant, SocketAddr, Vec<u8>)>,
+}
+
+impl LinkConditioner {
+  pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+    LinkConditioner {
+      conditions,
+      random: Random::new(seed),
+      in_flight: Vec::new(),
+    }
+  }
+
+  /// Let a packet into the simulated network, it comes out of `due` zero, one or two times
+  pub fn push(&mut self, now: Instant, address: SocketAddr, bytes: Vec<u8>) {
+    if self.random.chance(self.conditions.loss) {
+      return;
+    }
+    if self.random.chance(self.conditions.duplication) {
+      let due = self.due_time(now);
+      self.in_flight.push((due, address, bytes.clone()));
+    }
+    let due = self.due_time(now);
+    self.in_flight.push((due, address, bytes));
+  }
+
+  fn due_time(&mut self, now: Instant) -> Instant {
+    let jitter = self.conditions.jitter.as_secs_f64() * (self.random.unit() * 2.0 - 1.0);
+    let mut delay = (self.conditions.latency.as_secs_f64() + jitter).max(0.0);
+    if self.random.chance(self.conditions.reordering) {
+      delay += REORDER_DELAY.as_secs_f64();
+    }
+    now + Duration::from_secs_f64(delay)
+  }
+
+  /// The packets that made it through by `now`, in the order they arrive
+  pub fn due(&mut self, now: Instant) -> Vec<(SocketAddr, Vec<u8>)> {
+    let (mut due, in_flight) = std::mem::take(&mut self.in_flight)
+      .into_iter()
+      .partition::<Vec<_>, _>(|(at, _, _)| *at <= now);
+    self.in_flight = in_flight;
+    // stable, packets due at once keep their order
+    due.sort_by_key(|(at, _, _)| *at);
+    due
+      .into_iter()
+      .map(|(_, address, bytes)| (address, bytes))
+      .collect()
+  }
+
+  /// Packets still in flight
+  pub fn len(&self) -> usize {
+    self.in_flight.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.in_flight.is_empty()
+  }
+}
+
+/// xorshift64*, plenty for rolling dice over packets, or anything else replayed from a seed
+#[derive(Debug)]
+pub struct Random(u64);
+
+impl Random {
+  pub fn new(seed: u64) -> Self {
+    Random(seed)
+  }
+
+  fn next(&mut self) -> u64 {
+    // zero would stay zero
+    let mut x = self.0.max(1);
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    self.0 = x;
+    x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+  }
+
+  /// Uniform in [0, 1)
+  pub fn unit(&mut self) -> f64 {
+    (self.next() >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  pub fn chance(&mut self, probability: f32) -> bool {
+    probability > 0.0 && self.unit() < probability as f64
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bevy::prelude::default;
+  use std::ops::Range;
+
+  const ADDRESS: ([u8; 4], u16) = ([127, 0, 0, 1], 5000);
+
+  fn send(conditioner: &mut LinkConditioner, now: Instant, packets: Range<u8>) {
+    for packet in packets {
+      conditioner.push(now, ADDRESS.into(), vec![packet]);
+    }
+  }
+
+  fn packets(due: Vec<(SocketAddr, Vec<u8>)>) -> Vec<u8> {
+    due.into_iter().map(|(_, bytes)| bytes[0]).collect()
+  }
+
+  #[test]
+  fn conditions_come_from_arguments() {
+    let args = ["server", "--latency", "80", "--jitter", "10", "--loss", "5"];
+    let conditions = LinkConditions::from_args(args.map(String::from));
+    assert_eq!(conditions.latency, Duration::from_millis(80));
+    assert_eq!(conditions.jitter, Duration::from_millis(10));
+    assert_eq!(conditions.loss, 0.05);
+    assert_eq!(conditions.duplication, 0.0);
+
+    let args = ["client", "--reorder", "50", "--duplicate", "lots"];
+    let conditions = LinkConditions::from_args(args.map(String::from));
+    assert_eq!(conditions.reordering, 0.5);
+    assert_eq!(conditions.duplication, 0.0);
+    assert!(LinkConditions::from_args(Vec::new()).is_perfect());
+  }
+
+  #[test]
+  fn values_out_of_range_are_ignored() {
+    let args = [
+      "--latency",
+      "inf",
+      "--jitter",
+      "-5",
+      "--loss",
+      "NaN",
+      "--duplicate",
+      "101",
+      "--reorder",
+      "-1",
+    ];
+    assert!(LinkConditions::from_args(args.map(String::from)).is_perfect());
+    let args = ["--latency", "1e300"];
+    assert!(LinkConditions::from_args(args.map(String::from)).is_perfect());
+    assert_eq!(
+      LinkConditions::default().set_argument("--loss", "150"),
+      Err(InvalidCondition {
+        setting: "loss",
+        expected: "0 to 100 percent"
+      })
+    );
+  }
+
+  #[test]
+  fn packets_arrive_after_the_latency() {
+    let start = Instant::now();
+    let mut conditioner = LinkConditioner::new(
+      LinkConditions {
+        latency: Duration::from_millis(100),
+        ..default()
+      },
+      1,
+    );
+    send(&mut conditioner, start, 0..3);
+    assert!(
+      conditioner
+        .due(start + Duration::from_millis(99))
+        .is_empty()
+    );
+    let due = conditioner.due(start + Duration::from_millis(100));
+    assert_eq!(packets(due), [0, 1, 2]);
+    assert!(conditioner.is_empty());
+  }
+
+  #[test]
+  fn packets_are_lost_duplicated_and_reordered() {
+    let start = Instant::now();
+    let later = start + Duration::from_secs(1);
+    let conditions = LinkConditions {
+      loss: 0.25,
+      ..default()
+    };
+    let mut conditioner = LinkConditioner::new(conditions, 2);
+    send(&mut conditioner, start, 0..200);
+    let received = conditioner.due(later).len();
+    assert!((120..180).contains(&received), "{received}");
+
+    let conditions = LinkConditions {
+      duplication: 0.5,
+      ..default()
+    };
+    let mut conditioner = LinkConditioner::new(conditions, 3);
+    send(&mut conditioner, start, 0..100);
+    let received = conditioner.due(later).len();
+    assert!((130..170).contains(&received), "{received}");
+
+    let conditions = LinkConditions {
+      reordering: 0.5,
+      ..default()
+    };
+    let mut conditioner = LinkConditioner::new(conditions, 4);
+    send(&mut conditioner, start, 0..10);
+    send(&mut conditioner, start + Duration::from_millis(10), 10..20);
+    let received = packets(conditioner.due(later));
+    assert_eq!(received.len(), 20);
+    assert!(received.windows(2).any(|pair| pair[0] > pair[1]));
+  }
+
+  #[test]
+  fn jitter_stays_within_its_bounds() {
+    let start = Instant::now();
+    let conditions = LinkConditions {
+      latency: Duration::from_millis(50),
+      jitter: Duration::from_millis(20),
+      ..default()
+    };
+    let mut conditioner = LinkConditioner::new(conditions, 5);
+    send(&mut conditioner, start, 0..100);
+    assert!(
+      conditioner
+        .due(start + Duration::from_millis(29))
+        .is_empty()
+    );
+    let early = conditioner.due(start + Duration::from_millis(50)).len();
+    assert!((20..80).contains(&early), "{early}");
+    conditioner.due(start + Duration::from_millis(70));
+    assert!(conditioner.is_empty());
+  }
+}
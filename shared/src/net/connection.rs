@@ -0,0 +1,613 @@
+use bevy::log::warn;
+use bevy::utils::HashMap;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::codec::{DecodeError, Reader, Writer};
+
+/// Starts every packet, anything else arriving on the socket is dropped
+const PROTOCOL_ID: u32 = 0x504c_4159;
+/// Largest datagram sent, small enough not to be split by the network
+pub const MAX_PACKET_SIZE: usize = 1200;
+/// Unreliable messages larger than this are sent in fragments
+pub const MAX_FRAGMENT_SIZE: usize = 1024;
+/// Reliable messages are never fragmented and have to fit in a packet on their own
+pub const MAX_RELIABLE_SIZE: usize = 1024;
+/// Reliable messages are sent again until acked, this long apart
+const RESEND_DELAY: Duration = Duration::from_millis(100);
+/// A peer nothing was heard from for this long is gone
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+/// Incomplete fragmented messages are dropped after this long
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Sent packets are remembered this long to be acked
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How a message travels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+  /// Resent until acked and delivered in the order it was sent
+  ReliableOrdered,
+  /// Sent once, may be lost, duplicated or arrive out of order; fragmented when large
+  Unreliable,
+}
+
+/// Why the server turned a client away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+  /// The client speaks another protocol version
+  VersionMismatch {
+    server: u16,
+  },
+  ServerFull,
+}
+
+/// The latest packet received from the peer and which of the 32 before it were too
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ack {
+  pub sequence: u16,
+  pub bits: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Unreliable {
+  Whole(Vec<u8>),
+  /// One part of a message too large for a packet
+  Fragment {
+    group: u16,
+    index: u8,
+    count: u8,
+    bytes: Vec<u8>,
+  },
+}
+
+/// A datagram of the protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+  ConnectRequest {
+    version: u16,
+  },
+  ConnectAccepted {
+    client: u64,
+  },
+  ConnectRejected(RejectReason),
+  /// Messages of a connection, with the acks of what it received
+  Payload {
+    sequence: u16,
+    ack: Option<Ack>,
+    /// Reliable messages with their ids
+    reliable: Vec<(u16, Vec<u8>)>,
+    unreliable: Option<Unreliable>,
+  },
+  Disconnect,
+}
+
+impl Packet {
+  pub fn encode(&self) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer.u32(PROTOCOL_ID);
+    match self {
+      Packet::ConnectRequest { version } => {
+        writer.u8(0);
+        writer.u16(*version);
+      }
+      Packet::ConnectAccepted { client } => {
+        writer.u8(1);
+        writer.u64(*client);
+      }
+      Packet::ConnectRejected(reason) => {
+        writer.u8(2);
+        match *reason {
+          RejectReason::VersionMismatch { server } => {
+            writer.u8(0);
+            writer.u16(server);
+          }
+          RejectReason::ServerFull => writer.u8(1),
+        }
+      }
+      Packet::Payload {
+        sequence,
+        ack,
+        reliable,
+        unreliable,
+      } => {
+        writer.u8(3);
+        writer.u16(*sequence);
+        match ack {
+          Some(ack) => {
+            writer.bool(true);
+            writer.u16(ack.sequence);
+            writer.u32(ack.bits);
+          }
+          None => writer.bool(false),
+        }
+        writer.u8(reliable.len() as u8);
+        for (id, bytes) in reliable {
+          writer.u16(*id);
+          writer.bytes(bytes);
+        }
+        match unreliable {
+          None => writer.u8(0),
+          Some(Unreliable::Whole(bytes)) => {
+            writer.u8(1);
+            writer.bytes(bytes);
+          }
+          Some(Unreliable::Fragment {
+            group,
+            index,
+            count,
+            bytes,
+          }) => {
+            writer.u8(2);
+            writer.u16(*group);
+            writer.u8(*index);
+            writer.u8(*count);
+            writer.bytes(bytes);
+          }
+        }
+      }
+      Packet::Disconnect => writer.u8(4),
+    }
+    writer.finish()
+  }
+
+  pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    if reader.u32()? != PROTOCOL_ID {
+      return Err(DecodeError::ForeignPacket);
+    }
+    match reader.u8()? {
+      0 => Ok(Packet::ConnectRequest {
+        version: reader.u16()?,
+      }),
+      1 => Ok(Packet::ConnectAccepted {
+        client: reader.u64()?,
+      }),
+      2 => match reader.u8()? {
+        0 => Ok(Packet::ConnectRejected(RejectReason::VersionMismatch {
+          server: reader.u16()?,
+        })),
+        1 => Ok(Packet::ConnectRejected(RejectReason::ServerFull)),
+        tag => Err(DecodeError::UnknownTag("reject reason", tag)),
+      },
+      3 => {
+        let sequence = reader.u16()?;
+        let ack = match reader.bool()? {
+          true => Some(Ack {
+            sequence: reader.u16()?,
+            bits: reader.u32()?,
+          }),
+          false => None,
+        };
+        let reliable = (0..reader.u8()?)
+          .map(|_| Ok((reader.u16()?, reader.bytes()?.to_vec())))
+          .collect::<Result<_, DecodeError>>()?;
+        let unreliable = match reader.u8()? {
+          0 => None,
+          1 => Some(Unreliable::Whole(reader.bytes()?.to_vec())),
+          2 => Some(Unreliable::Fragment {
+            group: reader.u16()?,
+            index: reader.u8()?,
+            count: reader.u8()?,
+            bytes: reader.bytes()?.to_vec(),
+          }),
+          tag => return Err(DecodeError::UnknownTag("unreliable", tag)),
+        };
+        Ok(Packet::Payload {
+          sequence,
+          ack,
+          reliable,
+          unreliable,
+        })
+      }
+      4 => Ok(Packet::Disconnect),
+      tag => Err(DecodeError::UnknownTag("packet", tag)),
+    }
+  }
+}
+
+/// Whether sequence number `a` comes after `b`, they wrap around
+pub fn sequence_newer(a: u16, b: u16) -> bool {
+  a != b && a.wrapping_sub(b) < 0x8000
+}
+
+struct SentPacket {
+  sequence: u16,
+  time: Instant,
+  /// Reliable messages in the packet, acked along with it
+  reliable: Vec<u16>,
+}
+
+struct OutgoingMessage {
+  id: u16,
+  bytes: Vec<u8>,
+  last_sent: Option<Instant>,
+}
+
+struct FragmentGroup {
+  first_received: Instant,
+  parts: Vec<Option<Vec<u8>>>,
+}
+
+/// The channels between two peers on top of `Payload` packets:
+/// sequence numbers and acks, reliable messages resent until acked and delivered in order,
+/// and unreliable messages split into fragments when large
+pub struct Connection {
+  sequence: u16,
+  received: Option<Ack>,
+  sent: VecDeque<SentPacket>,
+  next_reliable_id: u16,
+  reliable_out: VecDeque<OutgoingMessage>,
+  next_delivered_id: u16,
+  reliable_in: HashMap<u16, Vec<u8>>,
+  unreliable_out: Vec<Vec<u8>>,
+  next_group: u16,
+  fragments: HashMap<u16, FragmentGroup>,
+  delivered: Vec<(Channel, Vec<u8>)>,
+  last_received: Instant,
+  rtt: Option<Duration>,
+}
+
+impl Connection {
+  pub fn new(now: Instant) -> Self {
+    Connection {
+      sequence: 0,
+      received: None,
+      sent: VecDeque::new(),
+      next_reliable_id: 0,
+      reliable_out: VecDeque::new(),
+      next_delivered_id: 0,
+      reliable_in: HashMap::new(),
+      unreliable_out: Vec::new(),
+      next_group: 0,
+      fragments: HashMap::new(),
+      delivered: Vec::new(),
+      last_received: now,
+      rtt: None,
+    }
+  }
+
+  /// Queue a message, it goes out with the next `packets`
+  pub fn send(&mut self, channel: Channel, bytes: Vec<u8>) {
+    match channel {
+      Channel::ReliableOrdered => {
+        assert!(
+          bytes.len() <= MAX_RELIABLE_SIZE,
+          "reliable messages are not fragmented"
+        );
+        self.reliable_out.push_back(OutgoingMessage {
+          id: self.next_reliable_id,
+          bytes,
+          last_sent: None,
+        });
+        self.next_reliable_id = self.next_reliable_id.wrapping_add(1);
+      }
+      Channel::Unreliable => self.unreliable_out.push(bytes),
+    }
+  }
+
+  /// Messages received since the last call, reliable ones in the order they were sent
+  pub fn receive(&mut self) -> Vec<(Channel, Vec<u8>)> {
+    std::mem::take(&mut self.delivered)
+  }
+
+  /// Smoothed round trip time, once a packet was acked
+  pub fn rtt(&self) -> Option<Duration> {
+    self.rtt
+  }
+
+  pub fn timed_out(&self, now: Instant) -> bool {
+    now.duration_since(self.last_received) > TIMEOUT
+  }
+
+  /// Reliable messages sent and not acked yet
+  pub fn unacked(&self) -> usize {
+    self.reliable_out.len()
+  }
+
+  /// Handle a `Payload` packet from the peer
+  pub fn process(
+    &mut self,
+    sequence: u16,
+    ack: Option<Ack>,
+    reliable: Vec<(u16, Vec<u8>)>,
+    unreliable: Option<Unreliable>,
+    now: Instant,
+  ) {
+    self.last_received = now;
+    let fresh = self.record_received(sequence);
+    if let Some(ack) = ack {
+      self.process_ack(ack, now);
+    }
+    // duplicates of reliable messages are recognized by their id
+    for (id, bytes) in reliable {
+      self.receive_reliable(id, bytes);
+    }
+    match unreliable.filter(|_| fresh) {
+      Some(Unreliable::Whole(bytes)) => self.delivered.push((Channel::Unreliable, bytes)),
+      Some(Unreliable::Fragment {
+        group,
+        index,
+        count,
+        bytes,
+      }) => self.receive_fragment(group, index, count, bytes, now),
+      None => {}
+    }
+    self
+      .fragments
+      .retain(|_, group| now.duration_since(group.first_received) < FRAGMENT_TIMEOUT);
+  }
+
+  /// Track the sequence of a received packet for acking it, false for duplicates
+  fn record_received(&mut self, sequence: u16) -> bool {
+    let Some(ack) = &mut self.received else {
+      self.received = Some(Ack { sequence, bits: 0 });
+      return true;
+    };
+    if sequence_newer(sequence, ack.sequence) {
+      let shift = sequence.wrapping_sub(ack.sequence) as u32;
+      // the previous latest packet becomes one of the older ones
+      ack.bits =
+        ack.bits.checked_shl(shift).unwrap_or(0) | 1u32.checked_shl(shift - 1).unwrap_or(0);
+      ack.sequence = sequence;
+      return true;
+    }
+    let age = ack.sequence.wrapping_sub(sequence) as u32;
+    if age == 0 {
+      return false;
+    }
+    let Some(bit) = 1u32.checked_shl(age - 1) else {
+      // too old to be acked, and to be told apart from a duplicate
+      return true;
+    };
+    let fresh = ack.bits & bit == 0;
+    ack.bits |= bit;
+    fresh
+  }
+
+  fn process_ack(&mut self, ack: Ack, now: Instant) {
+    let acked = |sequence: u16| {
+      let age = ack.sequence.wrapping_sub(sequence) as u32;
+      age == 0 || ((1..=32).contains(&age) && ack.bits & (1 << (age - 1)) != 0)
+    };
+    let mut acked_ids = Vec::new();
+    self.sent.retain(|packet| {
+      if !acked(packet.sequence) {
+        return now.duration_since(packet.time) < ACK_TIMEOUT;
+      }
+      acked_ids.extend_from_slice(&packet.reliable);
+      let rtt = now.duration_since(packet.time);
+      self.rtt = Some(match self.rtt {
+        Some(smoothed) => smoothed.mul_f32(0.9) + rtt.mul_f32(0.1),
+        None => rtt,
+      });
+      false
+    });
+    self
+      .reliable_out
+      .retain(|message| !acked_ids.contains(&message.id));
+  }
+
+  fn receive_reliable(&mut self, id: u16, bytes: Vec<u8>) {
+    if sequence_newer(self.next_delivered_id, id) {
+      return;
+    }
+    self.reliable_in.entry(id).or_insert(bytes);
+    while let Some(bytes) = self.reliable_in.remove(&self.next_delivered_id) {
+      self.delivered.push((Channel::ReliableOrdered, bytes));
+      self.next_delivered_id = self.next_delivered_id.wrapping_add(1);
+    }
+  }
+
+  fn receive_fragment(&mut self, group: u16, index: u8, count: u8, bytes: Vec<u8>, now: Instant) {
+    if index >= count {
+      return;
+    }
+    let fragments = self
+      .fragments
+      .entry(group)
+      .or_insert_with(|| FragmentGroup {
+        first_received: now,
+        parts: vec![None; count as usize],
+      });
+    let Some(part) = fragments.parts.get_mut(index as usize) else {
+      return;
+    };
+    *part = Some(bytes);
+    if fragments.parts.iter().all(Option::is_some) {
+      let parts = self.fragments.remove(&group).unwrap().parts;
+      let bytes = parts.into_iter().flatten().flatten().collect();
+      self.delivered.push((Channel::Unreliable, bytes));
+    }
+  }
+
+  /// The packets to send now: every queued unreliable message, reliable messages due
+  /// to be (re)sent, and at least one packet to ack what was received
+  pub fn packets(&mut self, now: Instant) -> Vec<Packet> {
+    let mut unreliable = Vec::new();
+    for bytes in std::mem::take(&mut self.unreliable_out) {
+      if bytes.len() <= MAX_FRAGMENT_SIZE {
+        unreliable.push(Unreliable::Whole(bytes));
+        continue;
+      }
+      let count = bytes.len().div_ceil(MAX_FRAGMENT_SIZE);
+      let Ok(count) = u8::try_from(count) else {
+        warn!(
+          "Dropping a message of {} bytes, too large to send",
+          bytes.len()
+        );
+        continue;
+      };
+      let group = self.next_group;
+      self.next_group = self.next_group.wrapping_add(1);
+      for (index, chunk) in bytes.chunks(MAX_FRAGMENT_SIZE).enumerate() {
+        unreliable.push(Unreliable::Fragment {
+          group,
+          index: index as u8,
+          count,
+          bytes: chunk.to_vec(),
+        });
+      }
+    }
+
+    let mut due = self
+      .reliable_out
+      .iter_mut()
+      .filter(|message| {
+        message
+          .last_sent
+          .is_none_or(|sent| now.duration_since(sent) >= RESEND_DELAY)
+      })
+      .peekable();
+    let mut unreliable = unreliable.into_iter().peekable();
+    let mut packets = Vec::new();
+    while packets.is_empty() || due.peek().is_some() || unreliable.peek().is_some() {
+      let unreliable = unreliable.next();
+      let mut size = 16
+        + match &unreliable {
+          Some(Unreliable::Whole(bytes)) => bytes.len() + 3,
+          Some(Unreliable::Fragment { bytes, .. }) => bytes.len() + 7,
+          None => 0,
+        };
+      let mut reliable = Vec::new();
+      while let Some(message) = due.next_if(|message| {
+        size + message.bytes.len() + 4 <= MAX_PACKET_SIZE && reliable.len() < u8::MAX as usize
+      }) {
+        size += message.bytes.len() + 4;
+        message.last_sent = Some(now);
+        reliable.push((message.id, message.bytes.clone()));
+      }
+      self.sent.push_back(SentPacket {
+        sequence: self.sequence,
+        time: now,
+        reliable: reliable.iter().map(|(id, _)| *id).collect(),
+      });
+      packets.push(Packet::Payload {
+        sequence: self.sequence,
+        ack: self.received,
+        reliable,
+        unreliable,
+      });
+      self.sequence = self.sequence.wrapping_add(1);
+    }
+    packets
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Deliver every packet from one connection to the other, except the dropped ones
+  fn exchange(
+    from: &mut Connection,
+    to: &mut Connection,
+    now: Instant,
+    mut drop: impl FnMut(usize) -> bool,
+  ) {
+    for (index, packet) in from.packets(now).into_iter().enumerate() {
+      if drop(index) {
+        continue;
+      }
+      let Ok(Packet::Payload {
+        sequence,
+        ack,
+        reliable,
+        unreliable,
+      }) = Packet::decode(&packet.encode())
+      else {
+        panic!("not a payload");
+      };
+      to.process(sequence, ack, reliable, unreliable, now);
+    }
+  }
+
+  #[test]
+  fn reliable_messages_arrive_in_order_despite_loss() {
+    let mut now = Instant::now();
+    let mut a = Connection::new(now);
+    let mut b = Connection::new(now);
+    for i in 0..20u8 {
+      a.send(Channel::ReliableOrdered, vec![i; 100]);
+    }
+    let mut received = Vec::new();
+    let mut sent = 0;
+    for _ in 0..20 {
+      // every other packet from a is lost
+      exchange(&mut a, &mut b, now, |_| {
+        sent += 1;
+        sent % 2 == 0
+      });
+      exchange(&mut b, &mut a, now, |_| false);
+      received.extend(b.receive());
+      now += RESEND_DELAY;
+    }
+    let expected: Vec<_> = (0..20u8)
+      .map(|i| (Channel::ReliableOrdered, vec![i; 100]))
+      .collect();
+    assert_eq!(received, expected);
+    assert_eq!(a.unacked(), 0);
+    assert!(a.rtt().is_some());
+  }
+
+  #[test]
+  fn large_unreliable_messages_are_reassembled() {
+    let now = Instant::now();
+    let mut a = Connection::new(now);
+    let mut b = Connection::new(now);
+    let message: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    a.send(Channel::Unreliable, message.clone());
+    let packets = a.packets(now);
+    assert_eq!(packets.len(), 5);
+    assert!(
+      packets
+        .iter()
+        .all(|packet| packet.encode().len() <= MAX_PACKET_SIZE)
+    );
+    // fragments arriving out of order
+    for packet in packets.into_iter().rev() {
+      let Packet::Payload {
+        sequence,
+        ack,
+        reliable,
+        unreliable,
+      } = packet
+      else {
+        unreachable!();
+      };
+      b.process(sequence, ack, reliable, unreliable, now);
+    }
+    assert_eq!(b.receive(), vec![(Channel::Unreliable, message)]);
+  }
+
+  #[test]
+  fn duplicated_packets_are_delivered_once() {
+    let now = Instant::now();
+    let mut a = Connection::new(now);
+    let mut b = Connection::new(now);
+    a.send(Channel::Unreliable, vec![1]);
+    a.send(Channel::ReliableOrdered, vec![2]);
+    for packet in a.packets(now) {
+      let Packet::Payload {
+        sequence,
+        ack,
+        reliable,
+        unreliable,
+      } = packet
+      else {
+        unreachable!();
+      };
+      for _ in 0..2 {
+        b.process(sequence, ack, reliable.clone(), unreliable.clone(), now);
+      }
+    }
+    assert_eq!(b.receive().len(), 2);
+  }
+
+  #[test]
+  fn sequences_wrap_around() {
+    assert!(sequence_newer(1, 0));
+    assert!(sequence_newer(0, u16::MAX));
+    assert!(!sequence_newer(u16::MAX, 0));
+    assert!(!sequence_newer(5, 5));
+  }
+}
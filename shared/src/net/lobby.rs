@@ -0,0 +1,163 @@
+//! Rooms clients gather in before playing. The server lists its rooms to every client,
+//! a room starts once all its members are ready.
+
+use super::codec::{DecodeError, Reader, Writer};
+
+/// Longest room name, in bytes
+pub const MAX_ROOM_NAME: usize = 32;
+/// Most rooms a lobby holds, so their listing fits in one reliable message
+pub const MAX_ROOMS: usize = 16;
+
+/// What clients ask of the lobby
+#[derive(Debug, Clone, PartialEq)]
+pub enum LobbyRequest {
+  /// Open a room and join it as its host
+  CreateRoom {
+    name: String,
+    max_players: u8,
+  },
+  JoinRoom(u64),
+  /// Leave the room, and the game if it is playing
+  LeaveRoom,
+  SetReady(bool),
+  /// Remove a member from the host's room
+  Kick(u64),
+  /// Kick a member and keep them from joining again
+  Ban(u64),
+}
+
+/// A room as listed in the server browser
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomInfo {
+  pub id: u64,
+  pub name: String,
+  pub players: u8,
+  pub max_players: u8,
+  /// Its game started, no one can join
+  pub playing: bool,
+}
+
+/// The room a client is in, as its members see it
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomState {
+  pub id: u64,
+  pub name: String,
+  /// The client who created the room, the only one who can kick and ban
+  pub host: u64,
+  pub max_players: u8,
+  pub members: Vec<RoomMember>,
+  pub playing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoomMember {
+  pub client: u64,
+  pub ready: bool,
+}
+
+impl LobbyRequest {
+  pub(super) fn write(&self, writer: &mut Writer) {
+    match self {
+      LobbyRequest::CreateRoom { name, max_players } => {
+        writer.u8(0);
+        writer.str(name);
+        writer.u8(*max_players);
+      }
+      LobbyRequest::JoinRoom(room) => {
+        writer.u8(1);
+        writer.varint(*room);
+      }
+      LobbyRequest::LeaveRoom => writer.u8(2),
+      LobbyRequest::SetReady(ready) => {
+        writer.u8(3);
+        writer.bool(*ready);
+      }
+      LobbyRequest::Kick(client) => {
+        writer.u8(4);
+        writer.varint(*client);
+      }
+      LobbyRequest::Ban(client) => {
+        writer.u8(5);
+        writer.varint(*client);
+      }
+    }
+  }
+
+  pub(super) fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+    match reader.u8()? {
+      0 => Ok(LobbyRequest::CreateRoom {
+        name: reader.str()?.to_string(),
+        max_players: reader.u8()?,
+      }),
+      1 => Ok(LobbyRequest::JoinRoom(reader.varint()?)),
+      2 => Ok(LobbyRequest::LeaveRoom),
+      3 => Ok(LobbyRequest::SetReady(reader.bool()?)),
+      4 => Ok(LobbyRequest::Kick(reader.varint()?)),
+      5 => Ok(LobbyRequest::Ban(reader.varint()?)),
+      tag => Err(DecodeError::UnknownTag("lobby request", tag)),
+    }
+  }
+}
+
+impl RoomInfo {
+  pub(super) fn write(&self, writer: &mut Writer) {
+    writer.varint(self.id);
+    writer.str(&self.name);
+    writer.u8(self.players);
+    writer.u8(self.max_players);
+    writer.bool(self.playing);
+  }
+
+  pub(super) fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+    Ok(RoomInfo {
+      id: reader.varint()?,
+      name: reader.str()?.to_string(),
+      players: reader.u8()?,
+      max_players: reader.u8()?,
+      playing: reader.bool()?,
+    })
+  }
+}
+
+impl RoomState {
+  pub(super) fn write(&self, writer: &mut Writer) {
+    writer.varint(self.id);
+    writer.str(&self.name);
+    writer.varint(self.host);
+    writer.u8(self.max_players);
+    writer.bool(self.playing);
+    writer.u8(self.members.len() as u8);
+    for member in &self.members {
+      writer.varint(member.client);
+      writer.bool(member.ready);
+    }
+  }
+
+  pub(super) fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+    let id = reader.varint()?;
+    let name = reader.str()?.to_string();
+    let host = reader.varint()?;
+    let max_players = reader.u8()?;
+    let playing = reader.bool()?;
+    let members = (0..reader.u8()?)
+      .map(|_| {
+        Ok(RoomMember {
+          client: reader.varint()?,
+          ready: reader.bool()?,
+        })
+      })
+      .collect::<Result<_, _>>()?;
+    Ok(RoomState {
+      id,
+      name,
+      host,
+      max_players,
+      members,
+      playing,
+    })
+  }
+
+  pub fn member(&self, client: u64) -> Option<&RoomMember> {
+    self.members.iter().find(|member| member.client == client)
+  }
+}
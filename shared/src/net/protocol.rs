@@ -0,0 +1,395 @@
+use super::codec::{DecodeError, Reader, Writer};
+use super::connection::Channel;
+use super::lobby::{LobbyRequest, RoomInfo, RoomState};
+use super::snapshot::DeltaSnapshot;
+use crate::controller::MovementAction;
+
+/// Bumped whenever the wire format changes, clients of another version are turned away
+pub const PROTOCOL_VERSION: u16 = 5;
+
+/// The port servers listen on unless told otherwise
+pub const DEFAULT_PORT: u16 = 5000;
+
+/// Ticks of input clients send in every packet, so a lost packet does not lose an input
+pub const INPUT_REDUNDANCY: usize = 5;
+
+/// Something the server tells clients to spawn or despawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NetEntity {
+  /// The character of a client
+  Player(u64),
+  /// An object of the level, by index in `Level::objects`
+  Object(u32),
+}
+
+/// The movement actions of the local player for one tick
+#[derive(Debug, Clone, PartialEq)]
+pub struct TickInput {
+  pub tick: u64,
+  pub actions: Vec<MovementAction>,
+}
+
+/// What clients send to the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+  /// Inputs of the latest ticks, oldest first.
+  /// Recent ticks are sent again with every new one, so a lost packet loses no input.
+  Inputs {
+    inputs: Vec<TickInput>,
+    /// Tick of the latest snapshot received, later ones are encoded relative to it
+    snapshot_ack: Option<u64>,
+  },
+  Lobby(LobbyRequest),
+}
+
+/// What the server sends to clients
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+  /// The room of the client started, the level to load
+  Welcome {
+    level: String,
+    tick: u64,
+    /// Ticks per second of the server, clients predict at the same rate
+    tick_rate: u32,
+  },
+  Spawn(NetEntity),
+  Despawn(NetEntity),
+  /// The state of everything that moves after a tick
+  Snapshot(DeltaSnapshot),
+  /// Every room, sent after connecting and whenever one changes
+  Rooms(Vec<RoomInfo>),
+  /// The room of the client, none after leaving or being kicked
+  Room(Option<RoomState>),
+  /// Why a request of the client was refused, or what happened to it
+  Notice(String),
+  /// The server ticks at another rate from now on
+  TickRate(u32),
+}
+
+impl NetEntity {
+  fn encode(&self, writer: &mut Writer) {
+    match *self {
+      NetEntity::Player(client) => {
+        writer.u8(0);
+        writer.u64(client);
+      }
+      NetEntity::Object(index) => {
+        writer.u8(1);
+        writer.u32(index);
+      }
+    }
+  }
+
+  fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+    match reader.u8()? {
+      0 => Ok(NetEntity::Player(reader.u64()?)),
+      1 => Ok(NetEntity::Object(reader.u32()?)),
+      tag => Err(DecodeError::UnknownTag("entity", tag)),
+    }
+  }
+}
+
+fn encode_action(action: &MovementAction, writer: &mut Writer) {
+  match *action {
+    MovementAction::Move(direction) => {
+      writer.u8(0);
+      writer.vec2(direction);
+    }
+    MovementAction::Jump => writer.u8(1),
+  }
+}
+
+fn decode_action(reader: &mut Reader) -> Result<MovementAction, DecodeError> {
+  match reader.u8()? {
+    0 => {
+      // NaN or infinite movement would spread through the physics of the server
+      let direction = reader.vec2()?;
+      if !direction.is_finite() {
+        return Err(DecodeError::OutOfRange("movement"));
+      }
+      Ok(MovementAction::Move(direction))
+    }
+    1 => Ok(MovementAction::Jump),
+    tag => Err(DecodeError::UnknownTag("action", tag)),
+  }
+}
+
+impl ClientMessage {
+  /// Inputs are resent anyway, so they are never waited for
+  pub fn channel(&self) -> Channel {
+    match self {
+      ClientMessage::Inputs { .. } => Channel::Unreliable,
+      ClientMessage::Lobby(_) => Channel::ReliableOrdered,
+    }
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut writer = Writer::new();
+    match self {
+      ClientMessage::Inputs {
+        inputs,
+        snapshot_ack,
+      } => {
+        writer.u8(0);
+        // 0 for none, there is no snapshot of tick 0
+        writer.u64(snapshot_ack.unwrap_or(0));
+        writer.u8(inputs.len() as u8);
+        for input in inputs {
+          writer.u64(input.tick);
+          writer.u8(input.actions.len() as u8);
+          for action in &input.actions {
+            encode_action(action, &mut writer);
+          }
+        }
+      }
+      ClientMessage::Lobby(request) => {
+        writer.u8(1);
+        request.write(&mut writer);
+      }
+    }
+    writer.finish()
+  }
+
+  pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    match reader.u8()? {
+      0 => {
+        let snapshot_ack = Some(reader.u64()?).filter(|tick| *tick > 0);
+        let count = reader.u8()?;
+        let mut inputs = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+          let tick = reader.u64()?;
+          let actions = (0..reader.u8()?)
+            .map(|_| decode_action(&mut reader))
+            .collect::<Result<_, _>>()?;
+          inputs.push(TickInput { tick, actions });
+        }
+        Ok(ClientMessage::Inputs {
+          inputs,
+          snapshot_ack,
+        })
+      }
+      1 => Ok(ClientMessage::Lobby(LobbyRequest::read(&mut reader)?)),
+      tag => Err(DecodeError::UnknownTag("client message", tag)),
+    }
+  }
+}
+
+impl ServerMessage {
+  /// Snapshots are outdated by the time they could be resent, everything else has to arrive
+  pub fn channel(&self) -> Channel {
+    match self {
+      ServerMessage::Snapshot(_) => Channel::Unreliable,
+      _ => Channel::ReliableOrdered,
+    }
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut writer = Writer::new();
+    match self {
+      ServerMessage::Welcome {
+        level,
+        tick,
+        tick_rate,
+      } => {
+        writer.u8(0);
+        writer.str(level);
+        writer.u64(*tick);
+        writer.varint(*tick_rate as u64);
+      }
+      ServerMessage::Spawn(entity) => {
+        writer.u8(1);
+        entity.encode(&mut writer);
+      }
+      ServerMessage::Despawn(entity) => {
+        writer.u8(2);
+        entity.encode(&mut writer);
+      }
+      ServerMessage::Snapshot(snapshot) => {
+        writer.u8(3);
+        snapshot.write(&mut writer);
+      }
+      ServerMessage::Rooms(rooms) => {
+        writer.u8(4);
+        writer.varint(rooms.len() as u64);
+        for room in rooms {
+          room.write(&mut writer);
+        }
+      }
+      ServerMessage::Room(room) => {
+        writer.u8(5);
+        writer.bool(room.is_some());
+        if let Some(room) = room {
+          room.write(&mut writer);
+        }
+      }
+      ServerMessage::Notice(notice) => {
+        writer.u8(6);
+        writer.str(notice);
+      }
+      ServerMessage::TickRate(tick_rate) => {
+        writer.u8(7);
+        writer.varint(*tick_rate as u64);
+      }
+    }
+    writer.finish()
+  }
+
+  pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    match reader.u8()? {
+      0 => Ok(ServerMessage::Welcome {
+        level: reader.str()?.to_string(),
+        tick: reader.u64()?,
+        tick_rate: reader.varint()? as u32,
+      }),
+      1 => Ok(ServerMessage::Spawn(NetEntity::decode(&mut reader)?)),
+      2 => Ok(ServerMessage::Despawn(NetEntity::decode(&mut reader)?)),
+      3 => Ok(ServerMessage::Snapshot(DeltaSnapshot::read(&mut reader)?)),
+      4 => Ok(ServerMessage::Rooms(
+        (0..reader.varint()?)
+          .map(|_| RoomInfo::read(&mut reader))
+          .collect::<Result<_, _>>()?,
+      )),
+      5 => Ok(ServerMessage::Room(if reader.bool()? {
+        Some(RoomState::read(&mut reader)?)
+      } else {
+        None
+      })),
+      6 => Ok(ServerMessage::Notice(reader.str()?.to_string())),
+      7 => Ok(ServerMessage::TickRate(reader.varint()? as u32)),
+      tag => Err(DecodeError::UnknownTag("server message", tag)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::net::lobby::RoomMember;
+  use crate::net::snapshot::{ObjectSnapshot, PlayerSnapshot, Snapshot};
+  use bevy::math::{Quat, Vec2, Vec3};
+
+  #[test]
+  fn messages_round_trip() {
+    let client = ClientMessage::Inputs {
+      inputs: vec![
+        TickInput {
+          tick: 41,
+          actions: vec![],
+        },
+        TickInput {
+          tick: 42,
+          actions: vec![
+            MovementAction::Move(Vec2::new(0.5, -1.0)),
+            MovementAction::Jump,
+          ],
+        },
+      ],
+      snapshot_ack: Some(40),
+    };
+    assert_eq!(ClientMessage::decode(&client.encode()), Ok(client));
+    for request in [
+      LobbyRequest::CreateRoom {
+        name: "Friday night".into(),
+        max_players: 4,
+      },
+      LobbyRequest::JoinRoom(300),
+      LobbyRequest::LeaveRoom,
+      LobbyRequest::SetReady(true),
+      LobbyRequest::Kick(2),
+      LobbyRequest::Ban(3),
+    ] {
+      let client = ClientMessage::Lobby(request);
+      assert_eq!(ClientMessage::decode(&client.encode()), Ok(client));
+    }
+
+    for server in [
+      ServerMessage::Welcome {
+        level: "levels/arena.level.ron".into(),
+        tick: 7,
+        tick_rate: 60,
+      },
+      ServerMessage::Spawn(NetEntity::Player(3)),
+      ServerMessage::Despawn(NetEntity::Object(12)),
+      ServerMessage::Snapshot(DeltaSnapshot::encode(
+        &Snapshot {
+          tick: 99,
+          players: vec![PlayerSnapshot {
+            client: 3,
+            last_input_tick: 42,
+            translation: Vec3::new(1.0, 2.0, 3.0),
+            velocity: Vec3::X,
+            grounded: true,
+          }],
+          objects: vec![ObjectSnapshot {
+            index: 5,
+            translation: Vec3::Y,
+            rotation: Quat::from_rotation_y(1.0),
+            velocity: Vec3::ZERO,
+          }],
+        },
+        None,
+      )),
+      ServerMessage::Rooms(vec![RoomInfo {
+        id: 1,
+        name: "Friday night".into(),
+        players: 2,
+        max_players: 4,
+        playing: false,
+      }]),
+      ServerMessage::Room(Some(RoomState {
+        id: 1,
+        name: "Friday night".into(),
+        host: 3,
+        max_players: 4,
+        members: vec![
+          RoomMember {
+            client: 3,
+            ready: true,
+          },
+          RoomMember {
+            client: 5,
+            ready: false,
+          },
+        ],
+        playing: false,
+      })),
+      ServerMessage::Room(None),
+      ServerMessage::Notice("The room is full".into()),
+      ServerMessage::TickRate(30),
+    ] {
+      assert_eq!(ServerMessage::decode(&server.encode()), Ok(server));
+    }
+  }
+
+  #[test]
+  fn truncated_messages_are_errors() {
+    let bytes = ServerMessage::Spawn(NetEntity::Player(3)).encode();
+    assert_eq!(
+      ServerMessage::decode(&bytes[..bytes.len() - 1]),
+      Err(DecodeError::UnexpectedEnd)
+    );
+    assert_eq!(
+      ServerMessage::decode(&[9]),
+      Err(DecodeError::UnknownTag("server message", 9))
+    );
+  }
+
+  #[test]
+  fn movement_must_be_finite() {
+    for direction in [Vec2::new(f32::NAN, 0.0), Vec2::new(0.0, f32::INFINITY)] {
+      let inputs = ClientMessage::Inputs {
+        inputs: vec![TickInput {
+          tick: 1,
+          actions: vec![MovementAction::Move(direction)],
+        }],
+        snapshot_ack: None,
+      };
+      assert_eq!(
+        ClientMessage::decode(&inputs.encode()),
+        Err(DecodeError::OutOfRange("movement"))
+      );
+    }
+  }
+}
@@ -0,0 +1,439 @@
+//! World snapshots and their compact encoding.
+//!
+//! Positions, velocities and rotations are quantized, then written as differences from a
+//! baseline: an earlier snapshot the client acked, so it is sure to have it. What did not
+//! change since the baseline takes a bit.
+
+use bevy::math::{IVec3, Quat, Vec3};
+
+use super::codec::{DecodeError, Reader, Writer};
+
+/// Positions go on the wire in steps of 1/512 m
+const POSITION_SCALE: f32 = 512.0;
+/// Velocities go on the wire in steps of 1/256 m/s
+const VELOCITY_SCALE: f32 = 256.0;
+/// Bits of each of the three smallest components of a rotation
+const ROTATION_BITS: u32 = 10;
+
+const TRANSLATION_CHANGED: u8 = 1;
+const VELOCITY_CHANGED: u8 = 1 << 1;
+const ROTATION_CHANGED: u8 = 1 << 2;
+const GROUNDED: u8 = 1 << 3;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+  pub tick: u64,
+  pub players: Vec<PlayerSnapshot>,
+  pub objects: Vec<ObjectSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerSnapshot {
+  pub client: u64,
+  /// Last input tick of the client the state includes
+  pub last_input_tick: u64,
+  pub translation: Vec3,
+  pub velocity: Vec3,
+  pub grounded: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectSnapshot {
+  pub index: u32,
+  pub translation: Vec3,
+  pub rotation: Quat,
+  pub velocity: Vec3,
+}
+
+/// A snapshot as it goes on the wire, decoded against the same baseline it was encoded with
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeltaSnapshot {
+  pub tick: u64,
+  /// Tick of the snapshot the values are relative to, `None` for a full snapshot
+  pub baseline: Option<u64>,
+  pub data: Vec<u8>,
+}
+
+fn quantize(value: Vec3, scale: f32) -> IVec3 {
+  (value * scale).round().as_ivec3()
+}
+
+fn dequantize(value: IVec3, scale: f32) -> Vec3 {
+  value.as_vec3() / scale
+}
+
+/// The three smallest components, the largest one follows from them as the rotation is
+/// normalized. Its index takes the top two bits.
+fn quantize_rotation(rotation: Quat) -> u32 {
+  let components = rotation.normalize().to_array();
+  let largest = (0..4)
+    .max_by(|a, b| components[*a].abs().total_cmp(&components[*b].abs()))
+    .unwrap();
+  // q and -q are the same rotation, the dropped component is made positive
+  let sign = components[largest].signum();
+  let max = (1 << ROTATION_BITS) - 1;
+  let mut packed = largest as u32;
+  for (index, component) in components.iter().enumerate() {
+    if index == largest {
+      continue;
+    }
+    // the others are within ±1/√2
+    let normalized = (component * sign * std::f32::consts::SQRT_2 + 1.0) / 2.0;
+    let value = (normalized * max as f32).round().clamp(0.0, max as f32) as u32;
+    packed = (packed << ROTATION_BITS) | value;
+  }
+  packed
+}
+
+fn dequantize_rotation(packed: u32) -> Quat {
+  let max = (1 << ROTATION_BITS) - 1;
+  let largest = (packed >> (3 * ROTATION_BITS)) as usize & 3;
+  let mut components = [0.0; 4];
+  let mut shift = 3 * ROTATION_BITS;
+  for (index, component) in components.iter_mut().enumerate() {
+    if index == largest {
+      continue;
+    }
+    shift -= ROTATION_BITS;
+    let value = (packed >> shift) & max;
+    *component = (value as f32 / max as f32 * 2.0 - 1.0) / std::f32::consts::SQRT_2;
+  }
+  let rest: f32 = components
+    .iter()
+    .map(|component| component * component)
+    .sum();
+  components[largest] = (1.0 - rest).max(0.0).sqrt();
+  Quat::from_array(components).normalize()
+}
+
+#[derive(Clone, Copy, Default)]
+struct QuantizedPlayer {
+  last_input_tick: u64,
+  translation: IVec3,
+  velocity: IVec3,
+  grounded: bool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct QuantizedObject {
+  translation: IVec3,
+  rotation: u32,
+  velocity: IVec3,
+}
+
+impl From<&PlayerSnapshot> for QuantizedPlayer {
+  fn from(player: &PlayerSnapshot) -> Self {
+    QuantizedPlayer {
+      last_input_tick: player.last_input_tick,
+      translation: quantize(player.translation, POSITION_SCALE),
+      velocity: quantize(player.velocity, VELOCITY_SCALE),
+      grounded: player.grounded,
+    }
+  }
+}
+
+impl From<&ObjectSnapshot> for QuantizedObject {
+  fn from(object: &ObjectSnapshot) -> Self {
+    QuantizedObject {
+      translation: quantize(object.translation, POSITION_SCALE),
+      rotation: quantize_rotation(object.rotation),
+      velocity: quantize(object.velocity, VELOCITY_SCALE),
+    }
+  }
+}
+
+fn write_delta(writer: &mut Writer, value: IVec3, baseline: IVec3) {
+  for (value, baseline) in value.to_array().into_iter().zip(baseline.to_array()) {
+    writer.signed(value as i64 - baseline as i64);
+  }
+}
+
+fn read_delta(reader: &mut Reader, baseline: IVec3) -> Result<IVec3, DecodeError> {
+  let mut value = baseline.to_array();
+  for component in &mut value {
+    *component = (*component as i64)
+      .checked_add(reader.signed()?)
+      .and_then(|sum| i32::try_from(sum).ok())
+      .ok_or(DecodeError::OutOfRange("snapshot value"))?;
+  }
+  Ok(IVec3::from_array(value))
+}
+
+impl Snapshot {
+  /// The snapshot as a client decodes it, with the precision of the wire
+  pub fn quantized(&self) -> Snapshot {
+    let delta = DeltaSnapshot::encode(self, None);
+    delta
+      .decode(None)
+      .expect("a snapshot decodes as it was encoded")
+  }
+
+  fn player(&self, client: u64) -> Option<&PlayerSnapshot> {
+    self.players.iter().find(|player| player.client == client)
+  }
+
+  fn object(&self, index: u32) -> Option<&ObjectSnapshot> {
+    self.objects.iter().find(|object| object.index == index)
+  }
+}
+
+impl DeltaSnapshot {
+  /// Encode `snapshot` relative to `baseline`, which the receiver has to have
+  pub fn encode(snapshot: &Snapshot, baseline: Option<&Snapshot>) -> Self {
+    let mut writer = Writer::new();
+    writer.varint(snapshot.players.len() as u64);
+    for player in &snapshot.players {
+      let value = QuantizedPlayer::from(player);
+      let base = baseline
+        .and_then(|baseline| baseline.player(player.client))
+        .map(QuantizedPlayer::from)
+        .unwrap_or_default();
+      writer.varint(player.client);
+      writer.signed(value.last_input_tick as i64 - base.last_input_tick as i64);
+      let mut flags = 0;
+      if value.translation != base.translation {
+        flags |= TRANSLATION_CHANGED;
+      }
+      if value.velocity != base.velocity {
+        flags |= VELOCITY_CHANGED;
+      }
+      if value.grounded {
+        flags |= GROUNDED;
+      }
+      writer.u8(flags);
+      if flags & TRANSLATION_CHANGED != 0 {
+        write_delta(&mut writer, value.translation, base.translation);
+      }
+      if flags & VELOCITY_CHANGED != 0 {
+        write_delta(&mut writer, value.velocity, base.velocity);
+      }
+    }
+
+    writer.varint(snapshot.objects.len() as u64);
+    for object in &snapshot.objects {
+      let value = QuantizedObject::from(object);
+      let base = baseline
+        .and_then(|baseline| baseline.object(object.index))
+        .map(QuantizedObject::from)
+        .unwrap_or_default();
+      writer.varint(object.index as u64);
+      let mut flags = 0;
+      if value.translation != base.translation {
+        flags |= TRANSLATION_CHANGED;
+      }
+      if value.velocity != base.velocity {
+        flags |= VELOCITY_CHANGED;
+      }
+      if value.rotation != base.rotation {
+        flags |= ROTATION_CHANGED;
+      }
+      writer.u8(flags);
+      if flags & TRANSLATION_CHANGED != 0 {
+        write_delta(&mut writer, value.translation, base.translation);
+      }
+      if flags & VELOCITY_CHANGED != 0 {
+        write_delta(&mut writer, value.velocity, base.velocity);
+      }
+      if flags & ROTATION_CHANGED != 0 {
+        writer.u32(value.rotation);
+      }
+    }
+
+    DeltaSnapshot {
+      tick: snapshot.tick,
+      baseline: baseline.map(|baseline| baseline.tick),
+      data: writer.finish(),
+    }
+  }
+
+  /// Decode against the snapshot of tick `self.baseline`
+  pub fn decode(&self, baseline: Option<&Snapshot>) -> Result<Snapshot, DecodeError> {
+    let mut reader = Reader::new(&self.data);
+    let mut players = Vec::new();
+    for _ in 0..reader.varint()? {
+      let client = reader.varint()?;
+      let base = baseline
+        .and_then(|baseline| baseline.player(client))
+        .map(QuantizedPlayer::from)
+        .unwrap_or_default();
+      let last_input_tick = base
+        .last_input_tick
+        .checked_add_signed(reader.signed()?)
+        .ok_or(DecodeError::OutOfRange("input tick"))?;
+      let flags = reader.u8()?;
+      let mut translation = base.translation;
+      if flags & TRANSLATION_CHANGED != 0 {
+        translation = read_delta(&mut reader, base.translation)?;
+      }
+      let mut velocity = base.velocity;
+      if flags & VELOCITY_CHANGED != 0 {
+        velocity = read_delta(&mut reader, base.velocity)?;
+      }
+      players.push(PlayerSnapshot {
+        client,
+        last_input_tick,
+        translation: dequantize(translation, POSITION_SCALE),
+        velocity: dequantize(velocity, VELOCITY_SCALE),
+        grounded: flags & GROUNDED != 0,
+      });
+    }
+
+    let mut objects = Vec::new();
+    for _ in 0..reader.varint()? {
+      let index = reader.varint()? as u32;
+      let base = baseline
+        .and_then(|baseline| baseline.object(index))
+        .map(QuantizedObject::from)
+        .unwrap_or_default();
+      let flags = reader.u8()?;
+      let mut translation = base.translation;
+      if flags & TRANSLATION_CHANGED != 0 {
+        translation = read_delta(&mut reader, base.translation)?;
+      }
+      let mut velocity = base.velocity;
+      if flags & VELOCITY_CHANGED != 0 {
+        velocity = read_delta(&mut reader, base.velocity)?;
+      }
+      let mut rotation = base.rotation;
+      if flags & ROTATION_CHANGED != 0 {
+        rotation = reader.u32()?;
+      }
+      objects.push(ObjectSnapshot {
+        index,
+        translation: dequantize(translation, POSITION_SCALE),
+        rotation: dequantize_rotation(rotation),
+        velocity: dequantize(velocity, VELOCITY_SCALE),
+      });
+    }
+
+    Ok(Snapshot {
+      tick: self.tick,
+      players,
+      objects,
+    })
+  }
+
+  pub(super) fn write(&self, writer: &mut Writer) {
+    writer.u64(self.tick);
+    writer.varint(self.baseline.map_or(0, |baseline| self.tick - baseline));
+    writer.bytes(&self.data);
+  }
+
+  pub(super) fn read(reader: &mut Reader) -> Result<Self, DecodeError> {
+    let tick = reader.u64()?;
+    let age = reader.varint()?;
+    Ok(DeltaSnapshot {
+      tick,
+      baseline: (age > 0).then(|| tick.saturating_sub(age)),
+      data: reader.bytes()?.to_vec(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn snapshot(tick: u64, offset: f32) -> Snapshot {
+    Snapshot {
+      tick,
+      players: (1..=4)
+        .map(|client| PlayerSnapshot {
+          client,
+          last_input_tick: tick - 3,
+          translation: Vec3::new(client as f32 * 2.0 + offset, 0.55, -3.21),
+          velocity: Vec3::new(offset, -0.5, 0.0),
+          grounded: client % 2 == 0,
+        })
+        .collect(),
+      objects: (0..20)
+        .map(|index| ObjectSnapshot {
+          index,
+          translation: Vec3::new(index as f32, 1.0, 12.5),
+          rotation: Quat::from_euler(bevy::math::EulerRot::YXZ, index as f32, 0.3, -0.2),
+          velocity: Vec3::ZERO,
+        })
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn rotations_survive_quantization() {
+    for (yaw, pitch) in [(0.0, 0.0), (1.0, -0.4), (3.1, 1.5), (-2.0, 0.2)] {
+      let rotation = Quat::from_euler(bevy::math::EulerRot::YXZ, yaw, pitch, 0.1);
+      let decoded = dequantize_rotation(quantize_rotation(rotation));
+      assert!(rotation.angle_between(decoded) < 0.005, "{rotation}");
+      assert!(rotation.angle_between(-decoded) < 0.005 || rotation.dot(decoded) > 0.0);
+    }
+  }
+
+  #[test]
+  fn full_snapshots_are_quantized() {
+    let snapshot = snapshot(10, 0.123);
+    let decoded = DeltaSnapshot::encode(&snapshot, None).decode(None).unwrap();
+    assert_eq!(decoded.tick, 10);
+    assert_eq!(decoded.players.len(), 4);
+    for (player, decoded) in snapshot.players.iter().zip(&decoded.players) {
+      assert_eq!(player.client, decoded.client);
+      assert_eq!(player.last_input_tick, decoded.last_input_tick);
+      assert_eq!(player.grounded, decoded.grounded);
+      assert!(player.translation.distance(decoded.translation) < 1.0 / POSITION_SCALE);
+      assert!(player.velocity.distance(decoded.velocity) < 1.0 / VELOCITY_SCALE);
+    }
+    assert_eq!(decoded, decoded.quantized());
+  }
+
+  #[test]
+  fn deltas_decode_to_the_quantized_snapshot_in_fewer_bytes() {
+    let baseline = snapshot(10, 0.0).quantized();
+    let snapshot = snapshot(14, 0.5);
+    let full = DeltaSnapshot::encode(&snapshot, None);
+    let delta = DeltaSnapshot::encode(&snapshot, Some(&baseline));
+    assert_eq!(delta.baseline, Some(10));
+    assert_eq!(delta.decode(Some(&baseline)), Ok(snapshot.quantized()));
+    // only the players moved
+    assert!(
+      delta.data.len() * 3 < full.data.len(),
+      "{}",
+      delta.data.len()
+    );
+
+    let mut writer = Writer::new();
+    delta.write(&mut writer);
+    let bytes = writer.finish();
+    assert_eq!(DeltaSnapshot::read(&mut Reader::new(&bytes)), Ok(delta));
+  }
+
+  #[test]
+  fn hostile_deltas_are_errors() {
+    let baseline = snapshot(10, 0.0).quantized();
+    let delta = |last_input_tick: i64, translation: i64| {
+      let mut writer = Writer::new();
+      writer.varint(1);
+      writer.varint(1);
+      writer.signed(last_input_tick);
+      writer.u8(TRANSLATION_CHANGED);
+      for _ in 0..3 {
+        writer.signed(translation);
+      }
+      // no objects
+      writer.varint(0);
+      DeltaSnapshot {
+        tick: 11,
+        baseline: Some(10),
+        data: writer.finish(),
+      }
+      .decode(Some(&baseline))
+    };
+    assert!(delta(1, 1).is_ok());
+    assert_eq!(delta(-100, 0), Err(DecodeError::OutOfRange("input tick")));
+    assert_eq!(
+      delta(0, i64::MAX),
+      Err(DecodeError::OutOfRange("snapshot value"))
+    );
+    assert_eq!(
+      delta(0, i32::MAX as i64),
+      Err(DecodeError::OutOfRange("snapshot value"))
+    );
+  }
+}
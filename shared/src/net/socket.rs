@@ -0,0 +1,705 @@
+use bevy::log::{debug, warn};
+use bevy::utils::HashMap;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use super::conditioner::{LinkConditioner, LinkConditions};
+use super::connection::{
+  Channel, Connection, MAX_PACKET_SIZE, MAX_RELIABLE_SIZE, Packet, RejectReason,
+};
+use super::protocol::{ClientMessage, PROTOCOL_VERSION, ServerMessage};
+
+/// Connect requests are sent again this long apart until the server answers
+const CONNECT_RETRY: Duration = Duration::from_millis(100);
+/// Disconnect packets are sent this many times, it is the last the peer hears
+const DISCONNECT_REPEAT: usize = 3;
+
+/// What went through a socket since it was opened, for bandwidth statistics
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NetStats {
+  pub packets_sent: u64,
+  pub bytes_sent: u64,
+  pub packets_received: u64,
+  pub bytes_received: u64,
+}
+
+/// The bytes of a message, unless it is too long for its channel
+fn encode_sendable(message: &ServerMessage) -> Option<Vec<u8>> {
+  let bytes = message.encode();
+  if message.channel() == Channel::ReliableOrdered && bytes.len() > MAX_RELIABLE_SIZE {
+    warn!(
+      "Dropping a message of {} bytes, reliable messages are at most {MAX_RELIABLE_SIZE}",
+      bytes.len()
+    );
+    return None;
+  }
+  Some(bytes)
+}
+
+/// A non-blocking UDP socket. Packets go through link conditioners both ways,
+/// which let them through untouched unless a bad network is simulated.
+struct Transport {
+  socket: UdpSocket,
+  stats: NetStats,
+  outgoing: LinkConditioner,
+  incoming: LinkConditioner,
+}
+
+impl Transport {
+  fn new(socket: UdpSocket) -> Self {
+    let seed = || RandomState::new().build_hasher().finish();
+    Transport {
+      socket,
+      stats: NetStats::default(),
+      outgoing: LinkConditioner::new(LinkConditions::default(), seed()),
+      incoming: LinkConditioner::new(LinkConditions::default(), seed()),
+    }
+  }
+
+  fn conditions(&self) -> LinkConditions {
+    self.outgoing.conditions
+  }
+
+  fn set_conditions(&mut self, conditions: LinkConditions) {
+    self.outgoing.conditions = conditions;
+    self.incoming.conditions = conditions;
+  }
+
+  /// Every packet that arrived by `now`
+  fn receive(&mut self, now: Instant) -> Vec<(SocketAddr, Packet)> {
+    self.send_due(now);
+    let mut buffer = [0; MAX_PACKET_SIZE];
+    let mut datagrams = Vec::new();
+    loop {
+      match self.socket.recv_from(&mut buffer) {
+        Ok((len, from)) => {
+          self.stats.packets_received += 1;
+          self.stats.bytes_received += len as u64;
+          datagrams.push((from, buffer[..len].to_vec()));
+        }
+        Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+        // a previous send did not reach its peer, timeouts handle that
+        Err(error) if error.kind() == ErrorKind::ConnectionReset => continue,
+        Err(error) => {
+          warn!("Could not receive: {error}");
+          break;
+        }
+      }
+    }
+    if !self.incoming.conditions.is_perfect() || !self.incoming.is_empty() {
+      for (from, bytes) in datagrams {
+        self.incoming.push(now, from, bytes);
+      }
+      datagrams = self.incoming.due(now);
+    }
+    datagrams
+      .into_iter()
+      .filter_map(|(from, bytes)| match Packet::decode(&bytes) {
+        Ok(packet) => Some((from, packet)),
+        Err(error) => {
+          debug!("Dropping a packet from {from}: {error}");
+          None
+        }
+      })
+      .collect()
+  }
+
+  fn send(&mut self, to: SocketAddr, packet: &Packet, now: Instant) {
+    let bytes = packet.encode();
+    if self.outgoing.conditions.is_perfect() && self.outgoing.is_empty() {
+      self.send_datagram(to, &bytes);
+    } else {
+      self.outgoing.push(now, to, bytes);
+      self.send_due(now);
+    }
+  }
+
+  /// Send the packets the outgoing conditioner held back until `now`
+  fn send_due(&mut self, now: Instant) {
+    for (to, bytes) in self.outgoing.due(now) {
+      self.send_datagram(to, &bytes);
+    }
+  }
+
+  fn send_datagram(&mut self, to: SocketAddr, bytes: &[u8]) {
+    match self.socket.send_to(bytes, to) {
+      Ok(_) => {
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += bytes.len() as u64;
+      }
+      Err(error) => debug!("Could not send to {to}: {error}"),
+    }
+  }
+}
+
+/// What happened to the clients of a `NetServer`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerNetEvent {
+  Connected(u64),
+  Message(u64, ClientMessage),
+  /// The client left or timed out
+  Disconnected(u64),
+}
+
+struct Peer {
+  client: u64,
+  connection: Connection,
+}
+
+/// The server end of the protocol, clients connect to it over UDP
+pub struct NetServer {
+  transport: Transport,
+  peers: HashMap<SocketAddr, Peer>,
+  next_client: u64,
+  /// More clients are turned away
+  pub max_clients: usize,
+}
+
+impl NetServer {
+  pub fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+    let socket = UdpSocket::bind(address)?;
+    socket.set_nonblocking(true)?;
+    Ok(NetServer {
+      transport: Transport::new(socket),
+      peers: HashMap::new(),
+      next_client: 1,
+      max_clients: 16,
+    })
+  }
+
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.transport.socket.local_addr()
+  }
+
+  /// The connected clients
+  pub fn clients(&self) -> impl Iterator<Item = u64> + '_ {
+    self.peers.values().map(|peer| peer.client)
+  }
+
+  /// Traffic to and from every client
+  pub fn stats(&self) -> NetStats {
+    self.transport.stats
+  }
+
+  /// The bad network packets go through both ways, none unless simulating one
+  pub fn conditions(&self) -> LinkConditions {
+    self.transport.conditions()
+  }
+
+  pub fn set_conditions(&mut self, conditions: LinkConditions) {
+    self.transport.set_conditions(conditions);
+  }
+
+  /// Where a client connected from
+  pub fn address(&self, client: u64) -> Option<SocketAddr> {
+    self.peer(client).map(|(address, _)| address)
+  }
+
+  /// An id no client will get, for a player without a connection
+  pub fn reserve_client(&mut self) -> u64 {
+    self.next_client += 1;
+    self.next_client - 1
+  }
+
+  /// Round trip time to a client, once measured
+  pub fn rtt(&self, client: u64) -> Option<Duration> {
+    self
+      .peer(client)
+      .and_then(|(_, peer)| peer.connection.rtt())
+  }
+
+  fn peer(&self, client: u64) -> Option<(SocketAddr, &Peer)> {
+    self
+      .peers
+      .iter()
+      .find(|(_, peer)| peer.client == client)
+      .map(|(address, peer)| (*address, peer))
+  }
+
+  /// Handle what arrived on the socket, and notice clients that timed out
+  pub fn receive(&mut self, now: Instant) -> Vec<ServerNetEvent> {
+    let mut events = Vec::new();
+    for (from, packet) in self.transport.receive(now) {
+      match packet {
+        Packet::ConnectRequest { version } => {
+          let reply = if let Some(peer) = self.peers.get(&from) {
+            // the accept was lost
+            Packet::ConnectAccepted {
+              client: peer.client,
+            }
+          } else if version != PROTOCOL_VERSION {
+            Packet::ConnectRejected(RejectReason::VersionMismatch {
+              server: PROTOCOL_VERSION,
+            })
+          } else if self.peers.len() >= self.max_clients {
+            Packet::ConnectRejected(RejectReason::ServerFull)
+          } else {
+            let client = self.next_client;
+            self.next_client += 1;
+            self.peers.insert(
+              from,
+              Peer {
+                client,
+                connection: Connection::new(now),
+              },
+            );
+            events.push(ServerNetEvent::Connected(client));
+            Packet::ConnectAccepted { client }
+          };
+          self.transport.send(from, &reply, now);
+        }
+        Packet::Payload {
+          sequence,
+          ack,
+          reliable,
+          unreliable,
+        } => {
+          let Some(peer) = self.peers.get_mut(&from) else {
+            continue;
+          };
+          peer
+            .connection
+            .process(sequence, ack, reliable, unreliable, now);
+          for (_, bytes) in peer.connection.receive() {
+            match ClientMessage::decode(&bytes) {
+              Ok(message) => events.push(ServerNetEvent::Message(peer.client, message)),
+              Err(error) => debug!("Dropping a message of client {}: {error}", peer.client),
+            }
+          }
+        }
+        Packet::Disconnect => {
+          if let Some(peer) = self.peers.remove(&from) {
+            events.push(ServerNetEvent::Disconnected(peer.client));
+          }
+        }
+        Packet::ConnectAccepted { .. } | Packet::ConnectRejected(_) => {}
+      }
+    }
+    self.peers.retain(|_, peer| {
+      let timed_out = peer.connection.timed_out(now);
+      if timed_out {
+        events.push(ServerNetEvent::Disconnected(peer.client));
+      }
+      !timed_out
+    });
+    events
+  }
+
+  pub fn send(&mut self, client: u64, message: &ServerMessage) {
+    if let Some(peer) = self.peers.values_mut().find(|peer| peer.client == client)
+      && let Some(bytes) = encode_sendable(message)
+    {
+      peer.connection.send(message.channel(), bytes);
+    }
+  }
+
+  pub fn broadcast(&mut self, message: &ServerMessage) {
+    let Some(bytes) = encode_sendable(message) else {
+      return;
+    };
+    for peer in self.peers.values_mut() {
+      peer.connection.send(message.channel(), bytes.clone());
+    }
+  }
+
+  /// Drop a client, telling it so
+  pub fn disconnect(&mut self, client: u64) {
+    let Some((address, _)) = self.peer(client) else {
+      return;
+    };
+    self.peers.remove(&address);
+    for _ in 0..DISCONNECT_REPEAT {
+      self
+        .transport
+        .send(address, &Packet::Disconnect, Instant::now());
+    }
+  }
+
+  /// Send the queued messages, and acks to every client
+  pub fn flush(&mut self, now: Instant) {
+    for (address, peer) in &mut self.peers {
+      for packet in peer.connection.packets(now) {
+        self.transport.send(*address, &packet, now);
+      }
+    }
+    self.transport.send_due(now);
+  }
+}
+
+/// Why a `NetClient` is not connected anymore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+  Rejected(RejectReason),
+  /// The server stopped answering, or never did
+  TimedOut,
+  /// The server closed the connection
+  Closed,
+  /// `NetClient::disconnect` was called
+  Left,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+  Connecting,
+  Connected { client: u64 },
+  Disconnected(DisconnectReason),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientNetEvent {
+  Connected(u64),
+  Message(ServerMessage),
+  Disconnected(DisconnectReason),
+}
+
+/// The client end of the protocol, connecting to a `NetServer` over UDP
+pub struct NetClient {
+  transport: Transport,
+  server: SocketAddr,
+  state: ClientState,
+  connection: Connection,
+  last_request: Option<Instant>,
+  version: u16,
+}
+
+impl NetClient {
+  /// Start connecting, `flush` sends the requests
+  pub fn connect(server: SocketAddr) -> io::Result<Self> {
+    let local: SocketAddr = match server {
+      SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+      SocketAddr::V6(_) => ([0; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_nonblocking(true)?;
+    Ok(NetClient {
+      transport: Transport::new(socket),
+      server,
+      state: ClientState::Connecting,
+      connection: Connection::new(Instant::now()),
+      last_request: None,
+      version: PROTOCOL_VERSION,
+    })
+  }
+
+  /// Claim another protocol version, to test how servers handle old clients
+  pub fn with_version(mut self, version: u16) -> Self {
+    self.version = version;
+    self
+  }
+
+  pub fn state(&self) -> ClientState {
+    self.state
+  }
+
+  pub fn server(&self) -> SocketAddr {
+    self.server
+  }
+
+  /// Traffic to and from the server
+  pub fn stats(&self) -> NetStats {
+    self.transport.stats
+  }
+
+  /// The bad network packets go through both ways, none unless simulating one
+  pub fn conditions(&self) -> LinkConditions {
+    self.transport.conditions()
+  }
+
+  pub fn set_conditions(&mut self, conditions: LinkConditions) {
+    self.transport.set_conditions(conditions);
+  }
+
+  /// Round trip time to the server, once measured
+  pub fn rtt(&self) -> Option<Duration> {
+    self.connection.rtt()
+  }
+
+  fn close(&mut self, reason: DisconnectReason, events: &mut Vec<ClientNetEvent>) {
+    self.state = ClientState::Disconnected(reason);
+    events.push(ClientNetEvent::Disconnected(reason));
+  }
+
+  /// Handle what the server sent, and notice when it stops answering
+  pub fn receive(&mut self, now: Instant) -> Vec<ClientNetEvent> {
+    let mut events = Vec::new();
+    if let ClientState::Disconnected(_) = self.state {
+      return events;
+    }
+    for (from, packet) in self.transport.receive(now) {
+      if from != self.server {
+        continue;
+      }
+      match (packet, self.state) {
+        (Packet::ConnectAccepted { client }, ClientState::Connecting) => {
+          self.state = ClientState::Connected { client };
+          self.connection = Connection::new(now);
+          events.push(ClientNetEvent::Connected(client));
+        }
+        (Packet::ConnectRejected(reason), ClientState::Connecting) => {
+          self.close(DisconnectReason::Rejected(reason), &mut events);
+          return events;
+        }
+        (
+          Packet::Payload {
+            sequence,
+            ack,
+            reliable,
+            unreliable,
+          },
+          ClientState::Connected { .. },
+        ) => {
+          self
+            .connection
+            .process(sequence, ack, reliable, unreliable, now);
+          for (_, bytes) in self.connection.receive() {
+            match ServerMessage::decode(&bytes) {
+              Ok(message) => events.push(ClientNetEvent::Message(message)),
+              Err(error) => debug!("Dropping a message of the server: {error}"),
+            }
+          }
+        }
+        (Packet::Disconnect, ClientState::Connected { .. }) => {
+          self.close(DisconnectReason::Closed, &mut events);
+          return events;
+        }
+        _ => {}
+      }
+    }
+    if self.connection.timed_out(now) {
+      self.close(DisconnectReason::TimedOut, &mut events);
+    }
+    events
+  }
+
+  /// Queue a message, dropped unless connected
+  pub fn send(&mut self, message: &ClientMessage) {
+    if let ClientState::Connected { .. } = self.state {
+      self.connection.send(message.channel(), message.encode());
+    }
+  }
+
+  /// Send the queued messages, or a connect request while connecting
+  pub fn flush(&mut self, now: Instant) {
+    match self.state {
+      ClientState::Connecting => {
+        if self
+          .last_request
+          .is_none_or(|last| now.duration_since(last) >= CONNECT_RETRY)
+        {
+          self.last_request = Some(now);
+          let request = Packet::ConnectRequest {
+            version: self.version,
+          };
+          self.transport.send(self.server, &request, now);
+        }
+      }
+      ClientState::Connected { .. } => {
+        for packet in self.connection.packets(now) {
+          self.transport.send(self.server, &packet, now);
+        }
+      }
+      ClientState::Disconnected(_) => {}
+    }
+    self.transport.send_due(now);
+  }
+
+  /// Leave the server, telling it so
+  pub fn disconnect(&mut self) {
+    if let ClientState::Connected { .. } = self.state {
+      for _ in 0..DISCONNECT_REPEAT {
+        self
+          .transport
+          .send(self.server, &Packet::Disconnect, Instant::now());
+      }
+    }
+    self.state = ClientState::Disconnected(DisconnectReason::Left);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::net::protocol::{NetEntity, TickInput};
+  use crate::net::snapshot::{DeltaSnapshot, PlayerSnapshot, Snapshot};
+  use bevy::math::Vec3;
+
+  fn server() -> NetServer {
+    NetServer::bind("127.0.0.1:0").unwrap()
+  }
+
+  fn client(server: &NetServer) -> NetClient {
+    NetClient::connect(server.local_addr().unwrap()).unwrap()
+  }
+
+  /// Pump both ends until `done` holds for what they received
+  fn run(
+    server: &mut NetServer,
+    client: &mut NetClient,
+    mut done: impl FnMut(&[ServerNetEvent], &[ClientNetEvent]) -> bool,
+  ) -> (Vec<ServerNetEvent>, Vec<ClientNetEvent>) {
+    let (mut server_events, mut client_events) = (Vec::new(), Vec::new());
+    for _ in 0..500 {
+      let now = Instant::now();
+      client.flush(now);
+      server.flush(now);
+      std::thread::sleep(Duration::from_millis(2));
+      let now = Instant::now();
+      server_events.extend(server.receive(now));
+      client_events.extend(client.receive(now));
+      if done(&server_events, &client_events) {
+        return (server_events, client_events);
+      }
+    }
+    panic!("gave up, the server got {server_events:?} and the client {client_events:?}");
+  }
+
+  #[test]
+  fn clients_connect_and_exchange_messages() {
+    let mut server = server();
+    let mut client = client(&server);
+    let (server_events, client_events) =
+      run(&mut server, &mut client, |_, client| !client.is_empty());
+    let ServerNetEvent::Connected(id) = server_events[0] else {
+      panic!("{server_events:?}");
+    };
+    assert_eq!(client_events, vec![ClientNetEvent::Connected(id)]);
+    assert_eq!(client.state(), ClientState::Connected { client: id });
+    assert!(client.stats().packets_sent > 0);
+    assert!(server.stats().bytes_received > 0);
+
+    server.send(id, &ServerMessage::Spawn(NetEntity::Player(id)));
+    server.send(id, &ServerMessage::Despawn(NetEntity::Object(2)));
+    let inputs = ClientMessage::Inputs {
+      inputs: vec![TickInput {
+        tick: 1,
+        actions: vec![],
+      }],
+      snapshot_ack: None,
+    };
+    client.send(&inputs);
+    let (server_events, client_events) = run(&mut server, &mut client, |server, client| {
+      !server.is_empty() && client.len() == 2
+    });
+    assert_eq!(server_events, vec![ServerNetEvent::Message(id, inputs)]);
+    assert_eq!(
+      client_events,
+      vec![
+        ClientNetEvent::Message(ServerMessage::Spawn(NetEntity::Player(id))),
+        ClientNetEvent::Message(ServerMessage::Despawn(NetEntity::Object(2))),
+      ]
+    );
+
+    client.disconnect();
+    let (server_events, _) = run(&mut server, &mut client, |server, _| !server.is_empty());
+    assert_eq!(server_events, vec![ServerNetEvent::Disconnected(id)]);
+    assert_eq!(server.clients().count(), 0);
+  }
+
+  #[test]
+  fn large_snapshots_arrive_whole() {
+    let mut server = server();
+    let mut client = client(&server);
+    run(&mut server, &mut client, |_, client| !client.is_empty());
+    let snapshot = ServerMessage::Snapshot(DeltaSnapshot::encode(
+      &Snapshot {
+        tick: 10,
+        players: (0..400)
+          .map(|client| PlayerSnapshot {
+            client,
+            last_input_tick: 3,
+            translation: Vec3::splat(client as f32),
+            velocity: Vec3::splat(client as f32),
+            grounded: false,
+          })
+          .collect(),
+        objects: Vec::new(),
+      },
+      None,
+    ));
+    assert!(snapshot.encode().len() > 2 * MAX_PACKET_SIZE);
+    server.broadcast(&snapshot);
+    let (_, client_events) = run(&mut server, &mut client, |_, client| !client.is_empty());
+    assert_eq!(client_events, vec![ClientNetEvent::Message(snapshot)]);
+  }
+
+  #[test]
+  fn reliable_messages_too_long_are_dropped() {
+    let mut server = server();
+    let mut client = client(&server);
+    let (server_events, _) = run(&mut server, &mut client, |_, client| !client.is_empty());
+    let ServerNetEvent::Connected(id) = server_events[0] else {
+      panic!("{server_events:?}");
+    };
+    server.send(id, &ServerMessage::Notice("x".repeat(MAX_RELIABLE_SIZE)));
+    server.broadcast(&ServerMessage::Notice("y".repeat(MAX_RELIABLE_SIZE)));
+    let notice = ServerMessage::Notice("short".to_string());
+    server.send(id, &notice);
+    let (_, client_events) = run(&mut server, &mut client, |_, client| !client.is_empty());
+    assert_eq!(client_events, vec![ClientNetEvent::Message(notice)]);
+  }
+
+  #[test]
+  fn reliable_messages_survive_a_bad_network() {
+    let conditions = LinkConditions {
+      latency: Duration::from_millis(10),
+      jitter: Duration::from_millis(5),
+      loss: 0.2,
+      duplication: 0.2,
+      reordering: 0.2,
+    };
+    let mut server = server();
+    server.set_conditions(conditions);
+    let mut client = client(&server);
+    client.set_conditions(conditions);
+    assert_eq!(client.conditions(), conditions);
+    let (server_events, _) = run(&mut server, &mut client, |_, client| !client.is_empty());
+    let ServerNetEvent::Connected(id) = server_events[0] else {
+      panic!("{server_events:?}");
+    };
+
+    let spawns: Vec<_> = (0..20)
+      .map(|object| ServerMessage::Spawn(NetEntity::Object(object)))
+      .collect();
+    for spawn in &spawns {
+      server.send(id, spawn);
+    }
+    let (_, client_events) = run(&mut server, &mut client, |_, client| {
+      client.len() >= spawns.len()
+    });
+    let received: Vec<_> = spawns.into_iter().map(ClientNetEvent::Message).collect();
+    assert_eq!(client_events, received);
+  }
+
+  #[test]
+  fn clients_of_another_version_are_rejected() {
+    let mut server = server();
+    let mut client = client(&server).with_version(PROTOCOL_VERSION + 1);
+    let (server_events, client_events) =
+      run(&mut server, &mut client, |_, client| !client.is_empty());
+    assert!(server_events.is_empty());
+    assert_eq!(
+      client_events,
+      vec![ClientNetEvent::Disconnected(DisconnectReason::Rejected(
+        RejectReason::VersionMismatch {
+          server: PROTOCOL_VERSION
+        }
+      ))]
+    );
+  }
+
+  #[test]
+  fn full_servers_turn_clients_away() {
+    let mut server = server();
+    server.max_clients = 0;
+    let mut client = client(&server);
+    let (_, client_events) = run(&mut server, &mut client, |_, client| !client.is_empty());
+    assert_eq!(
+      client_events,
+      vec![ClientNetEvent::Disconnected(DisconnectReason::Rejected(
+        RejectReason::ServerFull
+      ))]
+    );
+  }
+}
@@ -0,0 +1,377 @@
+//! Client-side prediction: the local player moves as soon as its input is sampled,
+//! and is corrected when the server's state for that input comes back.
+
+use avian3d::prelude::*;
+use avian3d::sync::PreviousGlobalTransform;
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+use crate::controller::{Grounded, MovementSet, PlayerInput, physics_running};
+use crate::tick::Tick;
+
+/// Ticks of input kept for re-simulating, about two seconds
+pub const HISTORY_LENGTH: usize = 128;
+/// Predicted states this close to the server's are not corrected
+pub const TOLERANCE: f32 = 0.01;
+/// Corrections further than this are too large to smooth, the player snaps to them
+pub const SNAP_DISTANCE: f32 = 2.0;
+/// Part of the remaining correction applied on each tick
+const SMOOTHING: f32 = 0.2;
+
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .init_resource::<Resimulating>()
+      .add_event::<AuthoritativeState>()
+      // before the fixed ticks of the frame, so they start from the corrected state
+      .add_systems(PreUpdate, reconcile)
+      .add_systems(
+        FixedUpdate,
+        (smooth_corrections, record_inputs)
+          .chain()
+          .before(MovementSet)
+          .run_if(not(resimulating))
+          .run_if(physics_running),
+      )
+      // the server keeps going while physics is paused here, the player stands still on it
+      .add_systems(
+        FixedUpdate,
+        record_paused_inputs.run_if(not(physics_running)),
+      )
+      .add_systems(
+        FixedPostUpdate,
+        record_states
+          .after(PhysicsSet::Sync)
+          .run_if(not(resimulating))
+          .run_if(physics_running),
+      );
+  }
+}
+
+/// What the controller of a player is at the end of a tick
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControllerState {
+  pub translation: Vec3,
+  pub velocity: Vec3,
+  pub grounded: bool,
+}
+
+impl ControllerState {
+  fn close_to(&self, other: &ControllerState) -> bool {
+    self.translation.distance(other.translation) < TOLERANCE
+      && self.velocity.distance(other.velocity) < TOLERANCE * 10.0
+      && self.grounded == other.grounded
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PredictedTick {
+  pub tick: u64,
+  /// The input applied on the tick
+  pub input: PlayerInput,
+  /// The state after the tick, `None` until it ran or while physics is paused
+  pub state: Option<ControllerState>,
+}
+
+/// The player predicted on this client, with the ticks the server has not confirmed yet
+#[derive(Component, Debug, Default)]
+pub struct PredictionHistory {
+  ticks: VecDeque<PredictedTick>,
+}
+
+impl PredictionHistory {
+  /// Predicted ticks, oldest first
+  pub fn ticks(&self) -> impl DoubleEndedIterator<Item = &PredictedTick> + ExactSizeIterator {
+    self.ticks.iter()
+  }
+
+  fn push(&mut self, tick: PredictedTick) {
+    if self.ticks.len() == HISTORY_LENGTH {
+      self.ticks.pop_front();
+    }
+    self.ticks.push_back(tick);
+  }
+}
+
+/// How far the predicted player is from where it should be after a correction.
+/// It is moved back a bit on every tick, rather than all at once.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct PredictionError(pub Vec3);
+
+/// The server's state of a predicted player after `tick`, the last input tick it applied
+#[derive(Event, Debug, Clone, Copy)]
+pub struct AuthoritativeState {
+  pub entity: Entity,
+  pub tick: u64,
+  pub state: ControllerState,
+}
+
+/// Set while ticks are run again after a correction
+#[derive(Resource, Default)]
+pub struct Resimulating(pub bool);
+
+pub fn resimulating(resimulating: Res<Resimulating>) -> bool {
+  resimulating.0
+}
+
+// Only `Position` is moved, physics sync the transform from it. A transform change would be
+// applied to the position a second time.
+fn smooth_corrections(mut player_q: Query<(&mut PredictionError, &mut Position)>) {
+  for (mut error, mut position) in &mut player_q {
+    if error.0 == Vec3::ZERO {
+      continue;
+    }
+    let step = if error.0.length() < TOLERANCE {
+      error.0
+    } else {
+      error.0 * SMOOTHING
+    };
+    error.0 -= step;
+    position.0 -= step;
+  }
+}
+
+fn record_inputs(tick: Res<Tick>, mut player_q: Query<(&PlayerInput, &mut PredictionHistory)>) {
+  for (input, mut history) in &mut player_q {
+    history.push(PredictedTick {
+      tick: tick.0,
+      input: *input,
+      state: None,
+    });
+  }
+}
+
+/// A neutral input for every tick physics is paused, with no state as nothing ran
+fn record_paused_inputs(tick: Res<Tick>, mut player_q: Query<&mut PredictionHistory>) {
+  for mut history in &mut player_q {
+    history.push(PredictedTick {
+      tick: tick.0,
+      input: PlayerInput::default(),
+      state: None,
+    });
+  }
+}
+
+/// The state of a controller, without what is left to smooth out of a correction
+fn controller_state(
+  position: &Position,
+  velocity: &LinearVelocity,
+  grounded: bool,
+  error: Option<&PredictionError>,
+) -> ControllerState {
+  ControllerState {
+    translation: position.0 - error.map_or(Vec3::ZERO, |error| error.0),
+    velocity: velocity.0,
+    grounded,
+  }
+}
+
+#[allow(clippy::type_complexity)]
+fn record_states(
+  mut player_q: Query<(
+    &Position,
+    &LinearVelocity,
+    Has<Grounded>,
+    Option<&PredictionError>,
+    &mut PredictionHistory,
+  )>,
+) {
+  for (position, velocity, grounded, error, mut history) in &mut player_q {
+    if let Some(last) = history.ticks.back_mut() {
+      last.state = Some(controller_state(position, velocity, grounded, error));
+    }
+  }
+}
+
+fn set_state(world: &mut World, entity: Entity, state: &ControllerState) {
+  let mut player = world.entity_mut(entity);
+  if let Some(mut position) = player.get_mut::<Position>() {
+    position.0 = state.translation;
+  }
+  if let Some(mut velocity) = player.get_mut::<LinearVelocity>() {
+    velocity.0 = state.velocity;
+  }
+  if state.grounded {
+    player.insert(Grounded);
+  } else {
+    player.remove::<Grounded>();
+  }
+}
+
+fn restore_events<E: Event>(world: &mut World, events: Option<Events<E>>) {
+  match events {
+    Some(events) => world.insert_resource(events),
+    None => {
+      world.remove_resource::<Events<E>>();
+    }
+  }
+}
+
+/// Compare the server's states with what was predicted for the same ticks.
+/// On a misprediction, start over from the server's state and run the later ticks again
+/// with the same inputs, smoothing out the difference to where the player was.
+pub fn reconcile(world: &mut World) {
+  let states: Vec<_> = world
+    .resource_mut::<Events<AuthoritativeState>>()
+    .drain()
+    .collect();
+  for authoritative in states {
+    let entity = authoritative.entity;
+    let Some(mut history) = world.get_mut::<PredictionHistory>(entity) else {
+      continue;
+    };
+    // confirmed ticks are not needed anymore
+    while history
+      .ticks
+      .front()
+      .is_some_and(|predicted| predicted.tick < authoritative.tick)
+    {
+      history.ticks.pop_front();
+    }
+    if history
+      .ticks
+      .front()
+      .is_none_or(|predicted| predicted.tick != authoritative.tick)
+    {
+      continue;
+    }
+    let predicted = history.ticks.pop_front().unwrap();
+    if predicted
+      .state
+      .is_none_or(|state| state.close_to(&authoritative.state))
+    {
+      continue;
+    }
+    let inputs: Vec<_> = history
+      .ticks
+      .iter()
+      .map(|predicted| (predicted.tick, predicted.input))
+      .collect();
+
+    let player = world.entity(entity);
+    let Some(seen) = player.get::<Position>().map(|position| position.0) else {
+      continue;
+    };
+    let error = player.get::<PredictionError>().copied().unwrap_or_default();
+    debug!(
+      "Mispredicted tick {} by {}, running {} ticks again",
+      authoritative.tick,
+      (seen - error.0).distance(authoritative.state.translation),
+      inputs.len()
+    );
+    set_state(world, entity, &authoritative.state);
+    resimulate(world, entity, &inputs);
+
+    // the player stays where it was seen, and moves to the corrected state over a few ticks
+    let corrected = world.get::<Position>(entity).unwrap().0;
+    let offset = seen - corrected;
+    let error = if offset.length() < SNAP_DISTANCE {
+      offset
+    } else {
+      Vec3::ZERO
+    };
+    let mut player = world.entity_mut(entity);
+    player.get_mut::<Position>().unwrap().0 += error;
+    player.insert(PredictionError(error));
+  }
+}
+
+/// What a replay changes of the bodies besides the predicted player
+type BodyState = (
+  &'static Transform,
+  &'static GlobalTransform,
+  &'static PreviousGlobalTransform,
+  &'static Position,
+  &'static Rotation,
+  &'static LinearVelocity,
+  &'static AngularVelocity,
+  Has<Grounded>,
+);
+
+/// Run the fixed ticks of `inputs` again, recording the new states.
+/// The ticks step the whole physics world, so the predicted player still bumps into the
+/// rest of it, but only the player keeps the outcome: every other body is put back as it
+/// was, and the collision events of the replay are dropped rather than reported twice.
+fn resimulate(world: &mut World, entity: Entity, inputs: &[(u64, PlayerInput)]) {
+  world.resource_mut::<Resimulating>().0 = true;
+  let bodies = world
+    .query::<(Entity, &RigidBody, BodyState)>()
+    .iter(world)
+    .filter(|(other, rigid_body, _)| *other != entity && !rigid_body.is_static())
+    .map(
+      |(other, _, (transform, global, previous, position, rotation, linear, angular, grounded))| {
+        (
+          other,
+          (
+            *transform, *global, *previous, *position, *rotation, *linear, *angular, grounded,
+          ),
+        )
+      },
+    )
+    .collect::<Vec<_>>();
+  let collision_events = (
+    world.remove_resource::<Events<Collision>>(),
+    world.remove_resource::<Events<CollisionStarted>>(),
+    world.remove_resource::<Events<CollisionEnded>>(),
+  );
+  world.init_resource::<Events<Collision>>();
+  world.init_resource::<Events<CollisionStarted>>();
+  world.init_resource::<Events<CollisionEnded>>();
+  // the systems of the ticks read the generic clock, as in a fixed update
+  let mut fixed = Time::<()>::default();
+  fixed.advance_by(world.resource::<Time<Fixed>>().timestep());
+  let clock = std::mem::replace(&mut *world.resource_mut::<Time>(), fixed);
+  // input sampled since the last tick, for the next one
+  let pending = world.get::<PlayerInput>(entity).copied();
+
+  for (tick, input) in inputs {
+    if let Some(mut player_input) = world.get_mut::<PlayerInput>(entity) {
+      *player_input = *input;
+    }
+    world.run_schedule(FixedUpdate);
+    world.run_schedule(FixedPostUpdate);
+
+    let player = world.entity(entity);
+    let state = controller_state(
+      player.get::<Position>().unwrap(),
+      player.get::<LinearVelocity>().unwrap(),
+      player.contains::<Grounded>(),
+      None,
+    );
+    if let Some(mut history) = world.get_mut::<PredictionHistory>(entity)
+      && let Some(predicted) = history
+        .ticks
+        .iter_mut()
+        .find(|predicted| predicted.tick == *tick)
+    {
+      predicted.state = Some(state);
+    }
+  }
+
+  if let (Some(pending), Some(mut input)) = (pending, world.get_mut::<PlayerInput>(entity)) {
+    *input = pending;
+  }
+  for (other, (transform, global, previous, position, rotation, linear, angular, grounded)) in
+    bodies
+  {
+    let Ok(mut body) = world.get_entity_mut(other) else {
+      continue;
+    };
+    // the transforms too, or syncing them would move the body to where the replay left it
+    body.insert((
+      transform, global, previous, position, rotation, linear, angular,
+    ));
+    if grounded {
+      body.insert(Grounded);
+    } else {
+      body.remove::<Grounded>();
+    }
+  }
+  restore_events(world, collision_events.0);
+  restore_events(world, collision_events.1);
+  restore_events(world, collision_events.2);
+  *world.resource_mut::<Time>() = clock;
+  world.resource_mut::<Resimulating>().0 = false;
+}
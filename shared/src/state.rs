@@ -0,0 +1,31 @@
+use bevy::prelude::*;
+
+#[derive(Clone, Copy, Default, Eq, PartialEq, Debug, Hash, States)]
+pub enum GameState {
+  #[default]
+  MainMenu,
+  /// Preloading the assets of the game, showing the progress or what failed
+  Loading,
+  Game,
+  /// Editing the loaded level, switching back to `Game` playtests it
+  Editor,
+}
+
+#[derive(SubStates, Clone, Copy, Default, Eq, PartialEq, Debug, Hash)]
+#[source(GameState = GameState::Game)]
+pub enum PlayState {
+  #[default]
+  Playing,
+  Paused,
+  PhotoMode,
+}
+
+/// Despawned when leaving the game
+#[derive(Component)]
+pub struct InGameEntity;
+
+pub fn cleanup_game(mut commands: Commands, query: Query<Entity, With<InGameEntity>>) {
+  for entity in query.iter() {
+    commands.entity(entity).despawn_recursive();
+  }
+}
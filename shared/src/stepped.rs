@@ -0,0 +1,57 @@
+//! Headless apps running one simulation tick per update, whatever the real time,
+//! for tests and tools driving the simulation at their own pace
+
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use std::time::Duration;
+
+use crate::HeadlessPlugins;
+use crate::level::Level;
+use crate::tick::TICK_RATE;
+
+/// The assets of the client, from the directory of a crate of the workspace
+pub const WORKSPACE_ASSETS: &str = "../client/assets";
+
+/// Updates to wait for something to load, a millisecond apart
+const MAX_WAIT_UPDATES: usize = 1000;
+
+/// A headless app reading the assets in `assets`, one tick of `TICK_RATE` per update
+pub fn stepped_app(assets: &str) -> App {
+  let mut app = App::new();
+  app.add_plugins(HeadlessPlugins.set(AssetPlugin {
+    file_path: assets.to_string(),
+    ..default()
+  }));
+  set_tick_rate(&mut app, TICK_RATE);
+  app
+}
+
+/// Run the ticks of a stepped app at `tick_rate`, still one per update
+pub fn set_tick_rate(app: &mut App, tick_rate: f64) {
+  let timestep = Duration::from_secs_f64(1.0 / tick_rate.max(1.0));
+  app.insert_resource(TimeUpdateStrategy::ManualDuration(timestep));
+  if let Some(mut fixed) = app.world_mut().get_resource_mut::<Time<Fixed>>() {
+    fixed.set_timestep(timestep);
+  }
+}
+
+/// Update `app` until `ready`, giving up after about a second. Returns whether it got ready.
+pub fn wait_for(app: &mut App, ready: impl Fn(&World) -> bool) -> bool {
+  for _ in 0..MAX_WAIT_UPDATES {
+    app.update();
+    if ready(app.world()) {
+      return true;
+    }
+    std::thread::sleep(Duration::from_millis(1));
+  }
+  false
+}
+
+/// Update `app` until `level` is loaded, false if it cannot be or takes too long
+pub fn wait_for_level(app: &mut App, level: &Handle<Level>) -> bool {
+  let id = level.id();
+  wait_for(app, move |world| {
+    world.resource::<Assets<Level>>().contains(id)
+      || world.resource::<AssetServer>().load_state(id).is_failed()
+  }) && app.world().resource::<Assets<Level>>().contains(id)
+}
@@ -0,0 +1,29 @@
+use bevy::prelude::*;
+
+/// Simulation ticks per second, on the server and for prediction on clients
+pub const TICK_RATE: f64 = 60.0;
+
+/// Runs fixed updates at `TICK_RATE` and counts them
+pub struct TickPlugin;
+
+impl Plugin for TickPlugin {
+  fn build(&self, app: &mut App) {
+    app
+      .insert_resource(Time::<Fixed>::from_hz(TICK_RATE))
+      .init_resource::<Tick>()
+      .add_systems(FixedFirst, advance_tick);
+  }
+}
+
+/// Ticks per second of a fixed clock, rounded
+pub fn ticks_per_second(time: &Time<Fixed>) -> u32 {
+  (1.0 / time.timestep().as_secs_f64()).round() as u32
+}
+
+/// The number of the current simulation step
+#[derive(Resource, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tick(pub u64);
+
+fn advance_tick(mut tick: ResMut<Tick>) {
+  tick.0 += 1;
+}
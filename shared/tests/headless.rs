@@ -0,0 +1,105 @@
+//! The simulation running without a window or a renderer, like on the server
+
+use avian3d::prelude::*;
+use bevy::ecs::system::RunSystemOnce;
+use bevy::prelude::*;
+use shared::controller::{Grounded, MovementAction, MovementEvent, MovementPlugin, Player};
+use shared::level::{Level, LevelLoader, LevelSpawner, PrefabRegistry, player_object};
+use shared::stepped::{WORKSPACE_ASSETS, stepped_app, wait_for_level};
+use shared::tick::TickPlugin;
+
+/// A headless app with `path` loaded and spawned, and the objects of the level
+fn simulation(path: &'static str) -> (App, Handle<Level>, Vec<Option<Entity>>) {
+  let mut app = stepped_app(WORKSPACE_ASSETS);
+  app
+    .add_plugins((MovementPlugin::default(), TickPlugin))
+    .init_asset::<Level>()
+    .register_asset_loader(LevelLoader { textures: false })
+    .init_resource::<PrefabRegistry>();
+  let level = app.world().resource::<AssetServer>().load(path);
+  assert!(wait_for_level(&mut app, &level), "{path} never loaded");
+  let objects = spawn(&mut app, &level, |spawner, level| {
+    spawner.spawn_level(level)
+  });
+  (app, level, objects)
+}
+
+fn spawn<T: 'static>(
+  app: &mut App,
+  level: &Handle<Level>,
+  spawn: impl Fn(&mut LevelSpawner, &Level) -> T + Send + Sync + 'static,
+) -> T {
+  let level = level.clone();
+  app
+    .world_mut()
+    .run_system_once(
+      move |mut spawner: LevelSpawner, levels: Res<Assets<Level>>| {
+        spawn(&mut spawner, levels.get(&level).unwrap())
+      },
+    )
+    .unwrap()
+}
+
+fn run(app: &mut App, ticks: usize) {
+  for _ in 0..ticks {
+    app.update();
+  }
+}
+
+#[test]
+fn props_settle_on_the_ground() {
+  let (mut app, level, objects) = simulation("levels/playground.level.ron");
+  assert!(objects.iter().all(Option::is_some));
+  run(&mut app, 180);
+
+  let world = app.world();
+  let level = world.resource::<Assets<Level>>().get(&level).unwrap();
+  let props = level
+    .objects
+    .iter()
+    .zip(&objects)
+    .filter(|(object, _)| object.prefab == "prop");
+  for (object, entity) in props {
+    let entity = entity.unwrap();
+    let translation = world.get::<Transform>(entity).unwrap().translation;
+    let velocity = world.get::<LinearVelocity>(entity).unwrap();
+    assert!(translation.y > 0.0, "{translation}");
+    assert!(
+      translation.xz().distance(object.translation.xz()) < 1.0,
+      "{translation}"
+    );
+    assert!(velocity.length() < 0.1, "{}", velocity.0);
+  }
+}
+
+#[test]
+fn players_walk_and_jump() {
+  let (mut app, level, _) = simulation("levels/arena.level.ron");
+  let player = spawn(&mut app, &level, |spawner, level| {
+    spawner
+      .spawn_object(&player_object(level.player_spawn_point(0)))
+      .unwrap()
+  });
+  assert!(app.world().get::<Player>(player).is_some());
+  run(&mut app, 60);
+  assert!(app.world().get::<Grounded>(player).is_some());
+
+  let start = app.world().get::<Transform>(player).unwrap().translation;
+  for _ in 0..30 {
+    app.world_mut().send_event(MovementEvent {
+      entity: player,
+      action: MovementAction::Move(Vec2::Y),
+    });
+    app.update();
+  }
+  let walked = app.world().get::<Transform>(player).unwrap().translation;
+  assert!(walked.z - start.z > 1.0, "{start} -> {walked}");
+
+  app.world_mut().send_event(MovementEvent {
+    entity: player,
+    action: MovementAction::Jump,
+  });
+  run(&mut app, 10);
+  let jumped = app.world().get::<Transform>(player).unwrap().translation;
+  assert!(jumped.y - walked.y > 0.3, "{walked} -> {jumped}");
+}